@@ -1,4 +1,128 @@
-use table_collection::parse;
+use table_collection::{Expression, Rule, Span, Table, Visitor, parse, walk_program};
+
+/// Prints each table and rule, and breaks down the content of the `item` table
+struct Printer {
+    in_item_table: bool,
+    index: usize,
+}
+
+impl Visitor for Printer {
+    fn visit_table(&mut self, table: &Table, _span: Span) {
+        println!("\n📋 Table: #{}", table.metadata.id);
+        if table.metadata.export {
+            println!("   (exported)");
+        }
+        self.in_item_table = table.metadata.id == "item";
+    }
+
+    fn visit_rule(&mut self, rule: &Rule, _span: Span) {
+        println!("   {}: {}", rule.weight, rule.content_text());
+
+        // Show the internal structure for the item table
+        if self.in_item_table {
+            println!("      Content breakdown:");
+        }
+        self.index = 0;
+    }
+
+    fn visit_text(&mut self, text: &str, _rule_span: Span) {
+        if self.in_item_table {
+            println!("        [{}] Text: {:?}", self.index, text);
+        }
+        self.index += 1;
+    }
+
+    fn visit_expression(&mut self, expression: &Expression, _span: Span) {
+        if !self.in_item_table {
+            self.index += 1;
+            return;
+        }
+
+        let i = self.index;
+        match expression {
+            Expression::TableReference {
+                table_id,
+                modifiers,
+            } => {
+                if modifiers.is_empty() {
+                    println!("        [{}] Table Reference: {{#{}}}", i, table_id);
+                } else {
+                    println!(
+                        "        [{}] Table Reference with modifiers: {{#{}|{}}}",
+                        i,
+                        table_id,
+                        modifiers
+                            .iter()
+                            .map(|m| m.to_string())
+                            .collect::<Vec<_>>()
+                            .join("|")
+                    );
+                }
+            }
+            Expression::ExternalTableReference {
+                publisher,
+                collection,
+                table_id,
+                modifiers,
+            } => {
+                if modifiers.is_empty() {
+                    println!(
+                        "        [{}] External Table Reference: {{@{}/{}#{}}}",
+                        i, publisher, collection, table_id
+                    );
+                } else {
+                    println!(
+                        "        [{}] External Table Reference with modifiers: {{@{}/{}#{}|{}}}",
+                        i,
+                        publisher,
+                        collection,
+                        table_id,
+                        modifiers
+                            .iter()
+                            .map(|m| m.to_string())
+                            .collect::<Vec<_>>()
+                            .join("|")
+                    );
+                }
+            }
+            Expression::DiceRoll {
+                count,
+                sides,
+                keep,
+                modifier,
+                modifiers,
+            } => {
+                let keep = keep.map(|k| k.to_string()).unwrap_or_default()
+                    + &modifier.map(|m| format!("{:+}", m)).unwrap_or_default()
+                    + &modifiers
+                        .iter()
+                        .map(|m| format!("|{}", m))
+                        .collect::<String>();
+                match count {
+                    Some(c) => println!("        [{}] Dice Roll: {{{}d{}{}}}", i, c, sides, keep),
+                    None => println!("        [{}] Dice Roll: {{d{}{}}}", i, sides, keep),
+                }
+            }
+            Expression::InlineChoice { options, .. } => {
+                println!(
+                    "        [{}] Inline Choice with {} options",
+                    i,
+                    options.len()
+                );
+            }
+            Expression::Binding { name, .. } => {
+                println!("        [{}] Binding to ${}", i, name);
+            }
+            Expression::VariableRef { name, .. } => {
+                println!("        [{}] Variable: {{${}}}", i, name);
+            }
+            Expression::Conditional { var, equals, .. } => {
+                println!("        [{}] Conditional on ${} == {}", i, var, equals);
+            }
+        }
+        self.index += 1;
+    }
+}
 
 pub fn main() {
     let source = r#"#color
@@ -20,54 +144,11 @@ pub fn main() {
         Ok(program) => {
             println!("✅ Successfully parsed {} tables!", program.tables.len());
 
-            for table in &program.tables {
-                println!("\n📋 Table: #{}", table.value.metadata.id);
-                if table.value.metadata.export {
-                    println!("   (exported)");
-                }
-
-                for rule in &table.value.rules {
-                    println!("   {}: {}", rule.value.weight, rule.value.content_text());
-
-                    // Show the internal structure for the item table
-                    if table.value.metadata.id == "item" {
-                        println!("      Content breakdown:");
-                        for (i, content) in rule.value.content.iter().enumerate() {
-                            match content {
-                                table_collection::RuleContent::Text(text) => {
-                                    println!("        [{}] Text: {:?}", i, text);
-                                }
-                                table_collection::RuleContent::Expression(
-                                    table_collection::Expression::TableReference { table_id, modifiers },
-                                ) => {
-                                    if modifiers.is_empty() {
-                                        println!("        [{}] Table Reference: {{#{}}}", i, table_id);
-                                    } else {
-                                        println!("        [{}] Table Reference with modifiers: {{#{}|{}}}", i, table_id, modifiers.join("|"));
-                                    }
-                                }
-                                table_collection::RuleContent::Expression(
-                                    table_collection::Expression::ExternalTableReference { publisher, collection, table_id, modifiers },
-                                ) => {
-                                    if modifiers.is_empty() {
-                                        println!("        [{}] External Table Reference: {{@{}/{}#{}}}", i, publisher, collection, table_id);
-                                    } else {
-                                        println!("        [{}] External Table Reference with modifiers: {{@{}/{}#{}|{}}}", i, publisher, collection, table_id, modifiers.join("|"));
-                                    }
-                                }
-                                table_collection::RuleContent::Expression(
-                                    table_collection::Expression::DiceRoll { count, sides },
-                                ) => {
-                                    match count {
-                                        Some(c) => println!("        [{}] Dice Roll: {{{}d{}}}", i, c, sides),
-                                        None => println!("        [{}] Dice Roll: {{d{}}}", i, sides),
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+            let mut printer = Printer {
+                in_item_table: false,
+                index: 0,
+            };
+            walk_program(&mut printer, &program);
         }
         Err(e) => {
             eprintln!("❌ Parse error: {}", e);
@@ -48,9 +48,23 @@ fn main() {
                                         publisher, collection, table_id, modifiers
                                     );
                                 }
-                                table_collection::Expression::DiceRoll { count, sides } => {
+                                table_collection::Expression::DiceRoll { count, sides, .. } => {
                                     println!("      DiceRoll: {}d{}", count.unwrap_or(1), sides);
                                 }
+                                table_collection::Expression::InlineChoice { options, .. } => {
+                                    println!("      InlineChoice: {} options", options.len());
+                                }
+                                table_collection::Expression::Binding { name, .. } => {
+                                    println!("      Binding: ${}", name);
+                                }
+                                table_collection::Expression::VariableRef { name, .. } => {
+                                    println!("      VariableRef: ${}", name);
+                                }
+                                table_collection::Expression::Conditional {
+                                    var, equals, ..
+                                } => {
+                                    println!("      Conditional: ${} == {}", var, equals);
+                                }
                             },
                         }
                     }
@@ -24,7 +24,7 @@ pub fn main() {
             println!("Valid modifiers:");
             println!("• capitalize - Capitalizes the first letter");
             println!("• uppercase - Converts to uppercase");
-            println!("• lowercase - Converts to lowercase");  
+            println!("• lowercase - Converts to lowercase");
             println!("• indefinite - Adds 'a' or 'an' article");
             println!("• definite - Adds 'the' article");
             println!("• Multiple modifiers can be chained with '|'\n");
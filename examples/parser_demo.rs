@@ -100,8 +100,8 @@ pub fn main() {
         ("   \n  \n", "Only whitespace"),
         ("#test\n1.5.5.5: too many dots", "Invalid number format"),
         (
-            "#test\n1e5: scientific notation",
-            "Scientific notation (not supported)",
+            "#test\n1e: scientific notation",
+            "Scientific notation without exponent digits",
         ),
     ];
 
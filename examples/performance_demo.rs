@@ -86,6 +86,22 @@ fn main() {
                 duration.as_micros() as f64 / iterations as f64
             );
 
+            // Test constant-only generation (pure-text rules take a fast path)
+            println!("\n🔬 Performance Test: Constant Rules in a Hot Loop");
+            let hot_iterations = 100_000;
+
+            let start = Instant::now();
+            for _ in 0..hot_iterations {
+                let _ = collection.roll("performance_test");
+            }
+            let duration = start.elapsed();
+
+            println!("   Rolled {} constants in {:?}", hot_iterations, duration);
+            println!(
+                "   Average: {:.3}μs per roll",
+                duration.as_micros() as f64 / hot_iterations as f64
+            );
+
             // Show some sample outputs
             println!("\n📋 Sample Outputs:");
             for i in 1..=5 {
@@ -100,6 +116,7 @@ fn main() {
             println!("   ✅ Cached total weights (parse-time)");
             println!("   ✅ Binary search for rule selection (O(log n) vs O(n))");
             println!("   ✅ No weight recalculation during generation");
+            println!("   ✅ Pure-text rules skip the expansion loop");
         }
         Err(e) => {
             println!("❌ Failed to create collection: {}", e);
@@ -1,9 +1,13 @@
+use std::collections::HashMap;
 use std::fmt;
 
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
 /// Represents a position in the source code
+///
+/// `start` and `end` are char offsets, not byte offsets; use `Span::slice`
+/// to get the source text a span covers.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Span {
@@ -23,6 +27,20 @@ impl Span {
     pub fn is_empty(&self) -> bool {
         self.start == self.end
     }
+
+    /// The text this span covers in `source`, the text it was measured in
+    ///
+    /// Offsets past the end of `source` are clamped to it.
+    pub fn slice<'a>(&self, source: &'a str) -> &'a str {
+        let byte_offset = |offset| {
+            source
+                .char_indices()
+                .nth(offset)
+                .map_or(source.len(), |(index, _)| index)
+        };
+        let start = byte_offset(self.start);
+        &source[start..byte_offset(self.end).max(start)]
+    }
 }
 
 /// A node in the AST with position information
@@ -39,6 +57,58 @@ impl<T> Node<T> {
     }
 }
 
+/// A modifier applied to generated text, like `capitalize` or `truncate:10`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Modifier {
+    pub name: String,
+    /// Argument written after a colon, as the `10` in `truncate:10`
+    pub arg: Option<String>,
+}
+
+impl Modifier {
+    /// A modifier without an argument
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            arg: None,
+        }
+    }
+
+    /// A modifier with an argument, like `repeat:3`
+    pub fn with_arg(name: impl Into<String>, arg: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            arg: Some(arg.into()),
+        }
+    }
+}
+
+impl From<&str> for Modifier {
+    fn from(name: &str) -> Self {
+        Self::new(name)
+    }
+}
+
+/// Compares with a modifier as written in source, like `"capitalize"` or `"repeat:3"`
+impl PartialEq<&str> for Modifier {
+    fn eq(&self, other: &&str) -> bool {
+        match &self.arg {
+            None => self.name == *other,
+            Some(arg) => other.split_once(':') == Some((self.name.as_str(), arg.as_str())),
+        }
+    }
+}
+
+impl fmt::Display for Modifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.arg {
+            Some(arg) => write!(f, "{}:{}", self.name, arg),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
 /// Expression that can appear within rule text
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -46,17 +116,105 @@ pub enum Expression {
     /// Reference to another table by ID with optional modifiers
     TableReference {
         table_id: String,
-        modifiers: Vec<String>,
+        modifiers: Vec<Modifier>,
     },
     /// Reference to a table in an external collection
     ExternalTableReference {
-        publisher: String,      // @username
-        collection: String,     // collection name
-        table_id: String,       // table within that collection
-        modifiers: Vec<String>, // same modifiers as internal refs
+        publisher: String,        // @username
+        collection: String,       // collection name
+        table_id: String,         // table within that collection
+        modifiers: Vec<Modifier>, // same modifiers as internal refs
+    },
+    /// Dice roll expression like "d6", "2d10", "100d20", or "4d6kh3"
+    DiceRoll {
+        count: Option<u32>,
+        sides: u32,
+        keep: Option<KeepMode>,
+        /// Flat bonus or penalty added to the total, as in `2d6+3`
+        ///
+        /// The total isn't clamped, so a penalty can take it below zero.
+        modifier: Option<i32>,
+        /// Text modifiers applied to the total, as in `{d6|ordinal}`
+        modifiers: Vec<Modifier>,
+    },
+    /// One of several pipe-separated alternatives: `{red|blue|green}` or `{3:red|1:blue}`
+    ///
+    /// Each option is rule content of its own, so it can hold nested expressions.
+    /// `weights` holds one weight per option, 1.0 where none was written.
+    InlineChoice {
+        options: Vec<Vec<RuleContent>>,
+        weights: Vec<f64>,
+    },
+    /// An expression whose result is kept under a name: `{#name > hero}`
+    ///
+    /// The result is output as usual, and `{$hero}` repeats it for the rest
+    /// of the same top-level generation.
+    Binding {
+        name: String,
+        expression: Box<Expression>,
     },
-    /// Dice roll expression like "d6", "2d10", "100d20"
-    DiceRoll { count: Option<u32>, sides: u32 },
+    /// The result bound to a name earlier in the generation: `{$hero}` or `{$hero|uppercase}`
+    VariableRef {
+        name: String,
+        modifiers: Vec<Modifier>,
+    },
+    /// A choice made by a bound variable: `{$gender == she ? her : his}`
+    ///
+    /// `then` is output when the variable's value is exactly `equals`, and
+    /// `otherwise` (if written) when it isn't. Both are rule content of their
+    /// own, so they can hold nested expressions.
+    Conditional {
+        var: String,
+        equals: String,
+        then: Vec<RuleContent>,
+        otherwise: Option<Vec<RuleContent>>,
+    },
+}
+
+impl Expression {
+    /// Text modifiers written after the expression
+    ///
+    /// Empty for an inline choice or conditional, and for a binding, whose
+    /// modifiers belong to the expression it binds.
+    pub(crate) fn modifiers(&self) -> &[Modifier] {
+        match self {
+            Expression::TableReference { modifiers, .. }
+            | Expression::ExternalTableReference { modifiers, .. }
+            | Expression::DiceRoll { modifiers, .. }
+            | Expression::VariableRef { modifiers, .. } => modifiers,
+            Expression::InlineChoice { .. }
+            | Expression::Binding { .. }
+            | Expression::Conditional { .. } => &[],
+        }
+    }
+}
+
+/// Which dice of a roll count toward the total, from a `kh`/`kl` suffix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum KeepMode {
+    /// Keep the N highest dice, as in `4d6kh3`
+    Highest(u32),
+    /// Keep the N lowest dice, as in `2d20kl1`
+    Lowest(u32),
+}
+
+impl KeepMode {
+    /// How many dice are kept
+    pub fn count(self) -> u32 {
+        match self {
+            KeepMode::Highest(n) | KeepMode::Lowest(n) => n,
+        }
+    }
+}
+
+impl fmt::Display for KeepMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeepMode::Highest(n) => write!(f, "kh{}", n),
+            KeepMode::Lowest(n) => write!(f, "kl{}", n),
+        }
+    }
 }
 
 /// A piece of rule text content - either literal text or an expression
@@ -69,71 +227,194 @@ pub enum RuleContent {
     Expression(Expression),
 }
 
+/// Source for a rule with no content, as in `1.0: ~`
+///
+/// Such a rule parses to an empty content list and generates an empty string.
+pub(crate) const EMPTY_RULE: &str = "~";
+
 /// A single rule in our language: weight: rule_content_list
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rule {
     pub weight: f64,
     pub content: Vec<RuleContent>,
+    /// Where each expression in `content` occurs in the source, braces included
+    ///
+    /// Spans are in source order, each inline choice before the expressions in
+    /// its options. Rules built in code rather than parsed have none.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub expression_spans: Vec<Span>,
+    /// Comments on the lines just before the rule, when parsed with comments
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub doc: Option<String>,
 }
 
 impl Rule {
     /// Create a new rule with text content (for backward compatibility)
     pub fn new_text(weight: f64, text: String) -> Self {
+        Self::new(weight, vec![RuleContent::Text(text)])
+    }
+
+    /// Create a new rule with mixed content
+    pub fn new(weight: f64, content: Vec<RuleContent>) -> Self {
         Self {
             weight,
-            content: vec![RuleContent::Text(text)],
+            content,
+            expression_spans: Vec::new(),
+            doc: None,
         }
     }
 
-    /// Create a new rule with mixed content
-    pub fn new(weight: f64, content: Vec<RuleContent>) -> Self {
-        Self { weight, content }
+    /// Compare weight, content and doc, ignoring source spans
+    ///
+    /// Two rules parsed from differently formatted sources compare equal
+    /// here even though the derived `PartialEq` sees different spans.
+    pub fn eq_ignoring_spans(&self, other: &Rule) -> bool {
+        self.weight == other.weight && self.content == other.content && self.doc == other.doc
+    }
+
+    /// Every expression in the rule with its source span, if known
+    ///
+    /// Expressions nested in inline choice options are included, after the
+    /// choice that contains them.
+    pub fn expressions(&self) -> Vec<(&Expression, Option<Span>)> {
+        nested_expressions(&self.content)
+            .into_iter()
+            .enumerate()
+            .map(|(i, expression)| (expression, self.expression_spans.get(i).copied()))
+            .collect()
     }
 
     /// Get just the content text without weight and colon (for backward compatibility)
+    ///
+    /// Literal braces and backslashes are escaped, as they would be in source,
+    /// and a rule with no content is written as `~`.
     pub fn content_text(&self) -> String {
-        self.content
-            .iter()
-            .map(|c| match c {
-                RuleContent::Text(text) => text.clone(),
-                RuleContent::Expression(Expression::TableReference {
+        let text = content_source(&self.content, None).trim().to_string();
+        escape_empty_rule(&self.content, text)
+    }
+
+    /// Check that a rule is well-formed, collecting every problem found
+    ///
+    /// Useful for rules built by hand rather than parsed: the weight must be
+    /// finite and positive, reference ids and modifiers non-empty, and dice
+    /// must roll at least one die with at least one side.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut issues = Vec::new();
+
+        if !self.weight.is_finite() {
+            issues.push(format!("weight {} is not finite", self.weight));
+        } else if self.weight <= 0.0 {
+            issues.push(format!("weight {} must be positive", self.weight));
+        }
+
+        for expression in nested_expressions(&self.content) {
+            match expression {
+                Expression::TableReference {
                     table_id,
                     modifiers,
-                }) => {
-                    if modifiers.is_empty() {
-                        format!("{{#{}}}", table_id)
-                    } else {
-                        format!("{{#{}|{}}}", table_id, modifiers.join("|"))
+                } => {
+                    if table_id.is_empty() {
+                        issues.push("table reference has an empty table id".to_string());
+                    }
+                    if modifiers.iter().any(is_empty_modifier) {
+                        issues.push(format!("reference to '{}' has an empty modifier", table_id));
                     }
                 }
-                RuleContent::Expression(Expression::ExternalTableReference {
+                Expression::ExternalTableReference {
                     publisher,
                     collection,
                     table_id,
                     modifiers,
-                }) => {
-                    if modifiers.is_empty() {
-                        format!("{{@{}/{}#{}}}", publisher, collection, table_id)
-                    } else {
-                        format!(
-                            "{{@{}/{}#{}|{}}}",
-                            publisher,
-                            collection,
-                            table_id,
-                            modifiers.join("|")
-                        )
+                } => {
+                    if publisher.is_empty() || collection.is_empty() || table_id.is_empty() {
+                        issues.push(format!(
+                            "external reference '@{}/{}#{}' has an empty component",
+                            publisher, collection, table_id
+                        ));
+                    }
+                    if modifiers.iter().any(is_empty_modifier) {
+                        issues.push(format!(
+                            "external reference to '{}' has an empty modifier",
+                            table_id
+                        ));
                     }
                 }
-                RuleContent::Expression(Expression::DiceRoll { count, sides }) => match count {
-                    Some(c) => format!("{{{}d{}}}", c, sides),
-                    None => format!("{{d{}}}", sides),
-                },
-            })
-            .collect::<Vec<_>>()
-            .join("")
-            .trim()
-            .to_string()
+                Expression::DiceRoll {
+                    count,
+                    sides,
+                    keep,
+                    modifiers,
+                    ..
+                } => {
+                    if *sides == 0 {
+                        issues.push("dice roll must have at least one side".to_string());
+                    }
+                    if *count == Some(0) {
+                        issues.push("dice roll must roll at least one die".to_string());
+                    }
+                    if modifiers.iter().any(is_empty_modifier) {
+                        issues.push("dice roll has an empty modifier".to_string());
+                    }
+                    if let Some(keep) = keep
+                        && (keep.count() == 0 || keep.count() > count.unwrap_or(1))
+                    {
+                        issues.push(format!(
+                            "dice roll keeps {} dice but rolls {}",
+                            keep.count(),
+                            count.unwrap_or(1)
+                        ));
+                    }
+                }
+                Expression::InlineChoice { options, weights } => {
+                    if options.len() < 2 {
+                        issues.push("inline choice needs at least two options".to_string());
+                    }
+                    if weights.len() != options.len() {
+                        issues.push(format!(
+                            "inline choice has {} options but {} weights",
+                            options.len(),
+                            weights.len()
+                        ));
+                    }
+                    for weight in weights {
+                        if !weight.is_finite() || *weight <= 0.0 {
+                            issues.push(format!(
+                                "inline choice weight {} must be finite and positive",
+                                weight
+                            ));
+                        }
+                    }
+                }
+                Expression::Binding { name, .. } => {
+                    if name.is_empty() {
+                        issues.push("binding has an empty variable name".to_string());
+                    }
+                }
+                Expression::VariableRef { name, modifiers } => {
+                    if name.is_empty() {
+                        issues.push("variable reference has an empty name".to_string());
+                    }
+                    if modifiers.iter().any(is_empty_modifier) {
+                        issues.push(format!("variable '${}' has an empty modifier", name));
+                    }
+                }
+                Expression::Conditional { var, equals, .. } => {
+                    if var.is_empty() {
+                        issues.push("conditional has an empty variable name".to_string());
+                    }
+                    if equals.is_empty() {
+                        issues.push(format!("conditional on '${}' compares to nothing", var));
+                    }
+                }
+            }
+        }
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(issues)
+        }
     }
 }
 
@@ -143,17 +424,54 @@ impl Rule {
 pub struct TableMetadata {
     pub id: String,
     pub export: bool,
+    /// Modifiers applied to every output of this table, from `[finish: ...]`
+    pub finish_modifiers: Vec<String>,
+    /// Weight of this table when choosing between tables, from `[weight: N]`
+    pub default_weight: Option<f64>,
+    /// Rule weights are percentages that must sum to 100, from `[percent]`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub percent: bool,
+    /// Free-form metadata for tools, from `key=value` or `key="value"` flags
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub attributes: HashMap<String, String>,
 }
 
 impl TableMetadata {
     pub fn new(id: String) -> Self {
-        Self { id, export: false }
+        Self {
+            id,
+            export: false,
+            finish_modifiers: Vec::new(),
+            default_weight: None,
+            percent: false,
+            attributes: HashMap::new(),
+        }
     }
 
     pub fn with_export(mut self, export: bool) -> Self {
         self.export = export;
         self
     }
+
+    pub fn with_finish_modifiers(mut self, finish_modifiers: Vec<String>) -> Self {
+        self.finish_modifiers = finish_modifiers;
+        self
+    }
+
+    pub fn with_default_weight(mut self, weight: f64) -> Self {
+        self.default_weight = Some(weight);
+        self
+    }
+
+    pub fn with_percent(mut self, percent: bool) -> Self {
+        self.percent = percent;
+        self
+    }
+
+    pub fn with_attribute(mut self, key: String, value: String) -> Self {
+        self.attributes.insert(key, value);
+        self
+    }
 }
 
 /// A table containing metadata and a list of rules
@@ -162,11 +480,63 @@ impl TableMetadata {
 pub struct Table {
     pub metadata: TableMetadata,
     pub rules: Vec<Node<Rule>>,
+    /// Comments on the lines just before the declaration, when parsed with comments
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub doc: Option<String>,
 }
 
 impl Table {
     pub fn new(metadata: TableMetadata, rules: Vec<Node<Rule>>) -> Self {
-        Self { metadata, rules }
+        Self {
+            metadata,
+            rules,
+            doc: None,
+        }
+    }
+
+    /// Compare metadata, doc and rules, ignoring source spans
+    pub fn eq_ignoring_spans(&self, other: &Table) -> bool {
+        self.metadata == other.metadata
+            && self.doc == other.doc
+            && nodes_eq_ignoring_spans(&self.rules, &other.rules, Rule::eq_ignoring_spans)
+    }
+}
+
+/// Compare two node lists item by item with `eq`, ignoring the nodes' spans
+fn nodes_eq_ignoring_spans<T>(a: &[Node<T>], b: &[Node<T>], eq: fn(&T, &T) -> bool) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| eq(&a.value, &b.value))
+}
+
+/// Write a doc as `//` comment lines, each followed by a newline
+fn doc_comment(doc: &Option<String>) -> String {
+    doc.iter()
+        .flat_map(|doc| doc.lines())
+        .map(|line| match line {
+            "" => "//\n".to_string(),
+            line => format!("// {}\n", line),
+        })
+        .collect()
+}
+
+/// Escape a quoted attribute value's quotes, backslashes and line breaks
+fn escape_attribute_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// A top-level macro definition: `%name% = "value"`
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MacroDefinition {
+    pub name: String,
+    pub value: String,
+}
+
+impl MacroDefinition {
+    pub fn new(name: String, value: String) -> Self {
+        Self { name, value }
     }
 }
 
@@ -175,56 +545,1116 @@ impl Table {
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Program {
     pub tables: Vec<Node<Table>>,
+    pub macros: Vec<Node<MacroDefinition>>,
 }
 
 impl Program {
     pub fn new(tables: Vec<Node<Table>>) -> Self {
-        Self { tables }
+        Self {
+            tables,
+            macros: Vec::new(),
+        }
+    }
+
+    pub fn with_macros(mut self, macros: Vec<Node<MacroDefinition>>) -> Self {
+        self.macros = macros;
+        self
+    }
+
+    /// Compare tables and macros, ignoring source spans
+    ///
+    /// Useful for round-trip tests, where reformatting the source shifts
+    /// every span but should leave the program itself unchanged.
+    pub fn eq_ignoring_spans(&self, other: &Program) -> bool {
+        nodes_eq_ignoring_spans(&self.tables, &other.tables, Table::eq_ignoring_spans)
+            && nodes_eq_ignoring_spans(&self.macros, &other.macros, MacroDefinition::eq)
+    }
+
+    /// Render the program back to canonical TBL source
+    ///
+    /// Shorthand for `to_source_with` using the default `FormatOptions`.
+    /// Parsing the result yields the same tables, rules and macros, with only
+    /// spans, comments and whitespace differing from the original source.
+    pub fn to_source(&self) -> String {
+        self.to_source_with(&FormatOptions::default())
+    }
+
+    /// Render the program back to TBL source using the given formatting options
+    ///
+    /// Only comments attached as a table's or rule's `doc` (see
+    /// `parse_with_comments`) are preserved, written on the lines before it.
+    /// Macro definitions are written first, followed by the tables.
+    pub fn to_source_with(&self, options: &FormatOptions) -> String {
+        let separator = "\n".repeat(options.blank_lines_between_tables + 1);
+
+        let mut sections: Vec<String> = Vec::new();
+        if !self.macros.is_empty() {
+            sections.push(
+                self.macros
+                    .iter()
+                    .map(|definition| {
+                        format!(
+                            "%{}% = \"{}\"",
+                            definition.value.name, definition.value.value
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            );
+        }
+        sections.extend(
+            self.tables
+                .iter()
+                .map(|table| table.value.to_source_with(options)),
+        );
+
+        let mut output = sections.join(&separator);
+        output.push('\n');
+        output
     }
 }
 
-impl fmt::Display for Rule {
+impl fmt::Display for Program {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let content_str = self
-            .content
+        write!(f, "{}", self.to_source())
+    }
+}
+
+/// How rules are ordered within each table by `Program::to_source_with`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RuleOrder {
+    /// Keep rules in source order
+    #[default]
+    Source,
+    /// Heaviest rules first; equal weights keep source order
+    WeightDescending,
+    /// Alphabetical by rule content
+    Alphabetical,
+}
+
+/// How weights are written by `Program::to_source_with`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WeightStyle {
+    /// Whole numbers without a decimal point: `2`, `1.5`
+    #[default]
+    Integer,
+    /// Always include a decimal point: `2.0`, `1.5`
+    Decimal,
+}
+
+/// Formatting options for rendering a `Program` back to source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// Pad after each colon so rule content starts in one column per table
+    pub align_weights: bool,
+    /// Order of rules within each table
+    pub rule_order: RuleOrder,
+    /// Number of blank lines between tables
+    pub blank_lines_between_tables: usize,
+    /// Whether whole-number weights get a decimal point
+    pub weight_style: WeightStyle,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            align_weights: false,
+            rule_order: RuleOrder::Source,
+            blank_lines_between_tables: 1,
+            weight_style: WeightStyle::Integer,
+        }
+    }
+}
+
+impl WeightStyle {
+    fn render(self, weight: f64) -> String {
+        match self {
+            WeightStyle::Decimal if weight.fract() == 0.0 => format!("{:.1}", weight),
+            _ => weight.to_string(),
+        }
+    }
+}
+
+impl Table {
+    /// Render this table's header and rules as TBL source, without a trailing newline
+    fn to_source_with(&self, options: &FormatOptions) -> String {
+        let metadata = &self.metadata;
+        let mut flags = Vec::new();
+        if metadata.export {
+            flags.push("export".to_string());
+        }
+        if metadata.percent {
+            flags.push("percent".to_string());
+        }
+        if !metadata.finish_modifiers.is_empty() {
+            flags.push(format!("finish: {}", metadata.finish_modifiers.join(" ")));
+        }
+        if let Some(weight) = metadata.default_weight {
+            flags.push(format!("weight: {}", options.weight_style.render(weight)));
+        }
+        let mut attributes: Vec<_> = metadata.attributes.iter().collect();
+        attributes.sort();
+        for (key, value) in attributes {
+            flags.push(format!("{}=\"{}\"", key, escape_attribute_value(value)));
+        }
+
+        let mut section = format!("{}#{}", doc_comment(&self.doc), metadata.id);
+        if !flags.is_empty() {
+            section.push_str(&format!("[{}]", flags.join(" ")));
+        }
+
+        let mut rules: Vec<(String, String, String)> = self
+            .rules
             .iter()
-            .map(|c| match c {
-                RuleContent::Text(text) => text.clone(),
-                RuleContent::Expression(Expression::TableReference {
-                    table_id,
-                    modifiers,
-                }) => {
-                    if modifiers.is_empty() {
-                        format!("{{#{}}}", table_id)
-                    } else {
-                        format!("{{#{}|{}}}", table_id, modifiers.join("|"))
-                    }
-                }
-                RuleContent::Expression(Expression::ExternalTableReference {
-                    publisher,
-                    collection,
-                    table_id,
-                    modifiers,
-                }) => {
-                    if modifiers.is_empty() {
-                        format!("{{@{}/{}#{}}}", publisher, collection, table_id)
-                    } else {
-                        format!(
-                            "{{@{}/{}#{}|{}}}",
-                            publisher,
-                            collection,
-                            table_id,
-                            modifiers.join("|")
-                        )
-                    }
+            .map(|rule| {
+                (
+                    options.weight_style.render(rule.value.weight),
+                    rule.value.content_text(),
+                    doc_comment(&rule.value.doc),
+                )
+            })
+            .collect();
+
+        match options.rule_order {
+            RuleOrder::Source => {}
+            RuleOrder::WeightDescending => {
+                let mut weighted: Vec<_> = self
+                    .rules
+                    .iter()
+                    .map(|r| r.value.weight)
+                    .zip(rules)
+                    .collect();
+                weighted.sort_by(|a, b| b.0.total_cmp(&a.0));
+                rules = weighted.into_iter().map(|(_, rule)| rule).collect();
+            }
+            RuleOrder::Alphabetical => rules.sort_by(|a, b| a.1.cmp(&b.1)),
+        }
+
+        let width = if options.align_weights {
+            rules
+                .iter()
+                .map(|(weight, _, _)| weight.len())
+                .max()
+                .unwrap_or(0)
+        } else {
+            0
+        };
+
+        for (weight, content, doc) in rules {
+            let colon = format!("{}:", weight);
+            section.push_str(&format!(
+                "\n{}{:<width$} {}",
+                doc,
+                colon,
+                content,
+                width = width + 1
+            ));
+        }
+
+        section
+    }
+}
+
+/// Write a dice roll as it appears in source, without braces: `2d6`, `4d6kh3+1`
+pub(crate) fn dice_notation(
+    count: Option<u32>,
+    sides: u32,
+    keep: Option<KeepMode>,
+    modifier: Option<i32>,
+) -> String {
+    let mut notation = String::new();
+    if let Some(count) = count {
+        notation.push_str(&count.to_string());
+    }
+    notation.push_str(&format!("d{}", sides));
+    if let Some(keep) = keep {
+        notation.push_str(&keep.to_string());
+    }
+    if let Some(modifier) = modifier {
+        notation.push_str(&format!("{:+}", modifier));
+    }
+    notation
+}
+
+/// Write modifiers as they follow an expression in source: `|capitalize|truncate:10`
+fn modifier_source(modifiers: &[Modifier]) -> String {
+    modifiers.iter().map(|m| format!("|{}", m)).collect()
+}
+
+/// Whether a modifier has an empty name or an empty argument after its colon
+fn is_empty_modifier(modifier: &Modifier) -> bool {
+    modifier.name.is_empty() || modifier.arg.as_deref() == Some("")
+}
+
+/// Write rule content as it appears in source
+///
+/// Inside an inline choice option or a conditional branch, `separator` (`|`
+/// or `:`) is escaped too, so it isn't read back as a separator.
+fn content_source(content: &[RuleContent], separator: Option<char>) -> String {
+    content
+        .iter()
+        .map(|c| match c {
+            RuleContent::Text(text) => escape_text(text, separator),
+            RuleContent::Expression(expression) => format!("{{{}}}", expression_source(expression)),
+        })
+        .collect()
+}
+
+/// Write an expression as it appears in source, without its surrounding braces
+fn expression_source(expression: &Expression) -> String {
+    match expression {
+        Expression::TableReference {
+            table_id,
+            modifiers,
+        } => format!("#{}{}", table_id, modifier_source(modifiers)),
+        Expression::ExternalTableReference {
+            publisher,
+            collection,
+            table_id,
+            modifiers,
+        } => format!(
+            "@{}/{}#{}{}",
+            publisher,
+            collection,
+            table_id,
+            modifier_source(modifiers)
+        ),
+        Expression::DiceRoll {
+            count,
+            sides,
+            keep,
+            modifier,
+            modifiers,
+        } => format!(
+            "{}{}",
+            dice_notation(*count, *sides, *keep, *modifier),
+            modifier_source(modifiers)
+        ),
+        Expression::InlineChoice { options, weights } => options
+            .iter()
+            .zip(weights)
+            .map(|(option, &weight)| {
+                let option = content_source(option, Some('|'));
+                // Text that looks like a weight needs an explicit one in front
+                if weight != 1.0 || starts_with_choice_weight(&option) {
+                    format!("{}:{}", weight, option)
+                } else {
+                    option
                 }
-                RuleContent::Expression(Expression::DiceRoll { count, sides }) => match count {
-                    Some(c) => format!("{{{}d{}}}", c, sides),
-                    None => format!("{{d{}}}", sides),
-                },
             })
             .collect::<Vec<_>>()
-            .join("");
+            .join("|"),
+        Expression::Binding { name, expression } => {
+            format!("{} > {}", expression_source(expression), name)
+        }
+        Expression::VariableRef { name, modifiers } => {
+            format!("${}{}", name, modifier_source(modifiers))
+        }
+        Expression::Conditional {
+            var,
+            equals,
+            then,
+            otherwise,
+        } => {
+            let mut source = format!(
+                "${} == {} ? {}",
+                var,
+                equals,
+                content_source(then, Some(':'))
+            );
+            if let Some(otherwise) = otherwise {
+                source.push_str(" : ");
+                source.push_str(&content_source(otherwise, Some(':')));
+            }
+            source
+        }
+    }
+}
+
+/// Whether an inline choice option starts with a weight, as in `3:red` or ` 1.5:blue`
+///
+/// Leading spaces and tabs are allowed, as is a '-' so that negative weights
+/// are reported rather than read as text.
+pub(crate) fn starts_with_choice_weight(option: &str) -> bool {
+    let rest = option.trim_start_matches([' ', '\t']);
+    let rest = rest.strip_prefix('-').unwrap_or(rest);
+    let digits = rest.len()
+        - rest
+            .trim_start_matches(|c: char| c.is_ascii_digit() || c == '.')
+            .len();
+    digits > 0 && rest.as_bytes()[0].is_ascii_digit() && rest[digits..].starts_with(':')
+}
+
+/// Every expression in `content`, including those nested in inline choice options
+/// and bindings
+///
+/// Expressions are listed in source order, each choice before its options and
+/// each binding before the expression it binds.
+pub(crate) fn nested_expressions(content: &[RuleContent]) -> Vec<&Expression> {
+    let mut expressions = Vec::new();
+    for content in content {
+        if let RuleContent::Expression(expression) = content {
+            push_nested_expressions(expression, &mut expressions);
+        }
+    }
+    expressions
+}
+
+/// Push `expression`, then whatever it contains, for `nested_expressions`
+fn push_nested_expressions<'a>(expression: &'a Expression, expressions: &mut Vec<&'a Expression>) {
+    expressions.push(expression);
+    match expression {
+        Expression::InlineChoice { options, .. } => {
+            for option in options {
+                expressions.extend(nested_expressions(option));
+            }
+        }
+        Expression::Binding { expression, .. } => push_nested_expressions(expression, expressions),
+        Expression::Conditional {
+            then, otherwise, ..
+        } => {
+            expressions.extend(nested_expressions(then));
+            expressions.extend(nested_expressions(otherwise.as_deref().unwrap_or_default()));
+        }
+        _ => {}
+    }
+}
+
+/// Call `f` on every expression in `content`, including those nested in inline choices
+///
+/// Stops at the first error. A choice is visited before its options.
+pub(crate) fn try_for_each_expression_mut<E>(
+    content: &mut [RuleContent],
+    f: &mut impl FnMut(&mut Expression) -> Result<(), E>,
+) -> Result<(), E> {
+    for content in content {
+        if let RuleContent::Expression(expression) = content {
+            try_expression_mut(expression, f)?;
+        }
+    }
+    Ok(())
+}
+
+/// Call `f` on `expression`, then on whatever it contains, for `try_for_each_expression_mut`
+fn try_expression_mut<E>(
+    expression: &mut Expression,
+    f: &mut impl FnMut(&mut Expression) -> Result<(), E>,
+) -> Result<(), E> {
+    f(expression)?;
+    match expression {
+        Expression::InlineChoice { options, .. } => {
+            for option in options {
+                try_for_each_expression_mut(option, f)?;
+            }
+        }
+        Expression::Binding { expression, .. } => try_expression_mut(expression, f)?,
+        Expression::Conditional {
+            then, otherwise, ..
+        } => {
+            try_for_each_expression_mut(then, f)?;
+            if let Some(otherwise) = otherwise {
+                try_for_each_expression_mut(otherwise, f)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Escape literal text so it reads back as text: `{`, `}` and `\` get a backslash
+///
+/// So does `separator`, if given.
+fn escape_text(text: &str, separator: Option<char>) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '{' | '}' | '\\') || separator == Some(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Write empty content as `~`, and escape text that would otherwise read back as it
+fn escape_empty_rule(content: &[RuleContent], text: String) -> String {
+    if content.is_empty() {
+        EMPTY_RULE.to_string()
+    } else if text.trim() == EMPTY_RULE {
+        text.replace(EMPTY_RULE, "\\~")
+    } else {
+        text
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let content_str = content_source(&self.content, None);
+        let content_str = escape_empty_rule(&self.content, content_str);
         write!(f, "{}: {}", self.weight, content_str)
     }
 }
+
+/// Callbacks for traversing a `Program` with `walk_program`
+///
+/// Every method defaults to doing nothing, so implementors only override the
+/// nodes they care about. Expressions are visited with their own span when
+/// the rule records one (see `Rule::expression_spans`) and the rule's span
+/// otherwise. Text doesn't carry its own span; it gets the rule's.
+pub trait Visitor {
+    fn visit_macro(&mut self, _definition: &MacroDefinition, _span: Span) {}
+
+    fn visit_table(&mut self, _table: &Table, _span: Span) {}
+
+    fn visit_rule(&mut self, _rule: &Rule, _span: Span) {}
+
+    fn visit_text(&mut self, _text: &str, _rule_span: Span) {}
+
+    fn visit_expression(&mut self, _expression: &Expression, _span: Span) {}
+}
+
+/// Walk a program in source order, calling `visitor` for each node
+///
+/// Macro definitions and tables are visited in the order they appear in the
+/// source. Each table is followed by its rules, and each rule by its content;
+/// an inline choice is followed by the content of its options.
+pub fn walk_program(visitor: &mut impl Visitor, program: &Program) {
+    let mut macros = program.macros.iter().peekable();
+    for table in &program.tables {
+        while let Some(definition) = macros.next_if(|d| d.span.start < table.span.start) {
+            visitor.visit_macro(&definition.value, definition.span);
+        }
+        walk_table(visitor, table);
+    }
+    for definition in macros {
+        visitor.visit_macro(&definition.value, definition.span);
+    }
+}
+
+/// Walk one table and everything in it
+pub fn walk_table(visitor: &mut impl Visitor, table: &Node<Table>) {
+    visitor.visit_table(&table.value, table.span);
+    for rule in &table.value.rules {
+        visitor.visit_rule(&rule.value, rule.span);
+        let mut spans = rule.value.expression_spans.iter().copied();
+        walk_content(visitor, &rule.value.content, rule.span, &mut spans);
+    }
+}
+
+/// Visit rule content, following each inline choice into its options
+///
+/// `spans` yields the expression spans in the order expressions are visited.
+fn walk_content(
+    visitor: &mut impl Visitor,
+    content: &[RuleContent],
+    rule_span: Span,
+    spans: &mut impl Iterator<Item = Span>,
+) {
+    for content in content {
+        match content {
+            RuleContent::Text(text) => visitor.visit_text(text, rule_span),
+            RuleContent::Expression(expression) => {
+                walk_expression(visitor, expression, rule_span, spans)
+            }
+        }
+    }
+}
+
+/// Visit an expression, then any expressions inside it
+fn walk_expression(
+    visitor: &mut impl Visitor,
+    expression: &Expression,
+    rule_span: Span,
+    spans: &mut impl Iterator<Item = Span>,
+) {
+    visitor.visit_expression(expression, spans.next().unwrap_or(rule_span));
+    match expression {
+        Expression::InlineChoice { options, .. } => {
+            for option in options {
+                walk_content(visitor, option, rule_span, spans);
+            }
+        }
+        Expression::Binding { expression, .. } => {
+            walk_expression(visitor, expression, rule_span, spans)
+        }
+        Expression::Conditional {
+            then, otherwise, ..
+        } => {
+            walk_content(visitor, then, rule_span, spans);
+            if let Some(otherwise) = otherwise {
+                walk_content(visitor, otherwise, rule_span, spans);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    const FORMAT_SOURCE: &str =
+        "#b[export]\n1: zebra\n3.5: {#a|capitalize}\n3.5: apple\n#a\n10: x {2d6}";
+
+    #[test]
+    fn test_to_source_with_defaults() {
+        let program = parse(FORMAT_SOURCE).unwrap();
+        let source = program.to_source_with(&FormatOptions::default());
+        assert_eq!(
+            source,
+            "#b[export]\n1: zebra\n3.5: {#a|capitalize}\n3.5: apple\n\n#a\n10: x {2d6}\n"
+        );
+        assert_eq!(normalize(&parse(&source).unwrap()), normalize(&program));
+    }
+
+    #[test]
+    fn test_to_source_with_options() {
+        let program = parse(FORMAT_SOURCE).unwrap();
+
+        let aligned = program.to_source_with(&FormatOptions {
+            align_weights: true,
+            ..FormatOptions::default()
+        });
+        assert!(aligned.starts_with("#b[export]\n1:   zebra\n3.5: {#a|capitalize}\n"));
+
+        let by_weight = program.to_source_with(&FormatOptions {
+            rule_order: RuleOrder::WeightDescending,
+            ..FormatOptions::default()
+        });
+        assert!(by_weight.starts_with("#b[export]\n3.5: {#a|capitalize}\n3.5: apple\n1: zebra\n"));
+
+        let alphabetical = program.to_source_with(&FormatOptions {
+            rule_order: RuleOrder::Alphabetical,
+            ..FormatOptions::default()
+        });
+        assert!(
+            alphabetical.starts_with("#b[export]\n3.5: apple\n1: zebra\n3.5: {#a|capitalize}\n")
+        );
+
+        let compact = program.to_source_with(&FormatOptions {
+            blank_lines_between_tables: 0,
+            ..FormatOptions::default()
+        });
+        assert!(compact.contains("{#a|capitalize}\n3.5: apple\n#a\n"));
+
+        let decimal = program.to_source_with(&FormatOptions {
+            weight_style: WeightStyle::Decimal,
+            ..FormatOptions::default()
+        });
+        assert!(decimal.contains("\n1.0: zebra\n3.5: {#a|capitalize}\n"));
+        assert!(decimal.contains("\n10.0: x {2d6}\n"));
+
+        // Every option combination still parses back to the same tables
+        for options in [aligned, by_weight, alphabetical, compact, decimal] {
+            let reparsed = parse(&options).unwrap();
+            assert_eq!(reparsed.tables.len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_to_source_with_weight_flag() {
+        let program = parse("#t[finish: capitalize weight: 2.5]\n1: x").unwrap();
+        assert_eq!(program.tables[0].value.metadata.default_weight, Some(2.5));
+        let source = program.to_source_with(&FormatOptions::default());
+        assert_eq!(source, "#t[finish: capitalize weight: 2.5]\n1: x\n");
+        let reparsed = parse(&source).unwrap();
+        assert_eq!(
+            reparsed.tables[0].value.metadata,
+            program.tables[0].value.metadata
+        );
+    }
+
+    #[test]
+    fn test_to_source_with_macros() {
+        let program = parse("#t\n1: %a%\n%a% = \"x y\"\n%b% = \"\"").unwrap();
+        let source = program.to_source_with(&FormatOptions::default());
+        assert_eq!(source, "%a% = \"x y\"\n%b% = \"\"\n\n#t\n1: %a%\n");
+        assert_eq!(parse(&source).unwrap().macros.len(), 2);
+    }
+
+    #[test]
+    fn test_walk_program_visits_in_source_order() {
+        #[derive(Default)]
+        struct Recorder {
+            events: Vec<String>,
+        }
+
+        impl Visitor for Recorder {
+            fn visit_macro(&mut self, definition: &MacroDefinition, _span: Span) {
+                self.events.push(format!("macro {}", definition.name));
+            }
+
+            fn visit_table(&mut self, table: &Table, _span: Span) {
+                self.events.push(format!("table {}", table.metadata.id));
+            }
+
+            fn visit_rule(&mut self, rule: &Rule, _span: Span) {
+                self.events.push(format!("rule {}", rule.weight));
+            }
+
+            fn visit_expression(&mut self, expression: &Expression, span: Span) {
+                let name = match expression {
+                    Expression::TableReference { table_id, .. } => table_id.as_str(),
+                    _ => "other",
+                };
+                self.events.push(format!(
+                    "expression {} at {}..{}",
+                    name, span.start, span.end
+                ));
+            }
+        }
+
+        let source = "%a% = \"1\"\n#t\n1: x {#u} {d6}\n%b% = \"2\"\n#u\n2: y";
+        let program = parse(source).unwrap();
+        let mut recorder = Recorder::default();
+        walk_program(&mut recorder, &program);
+
+        assert_eq!(
+            recorder.events,
+            vec![
+                "macro a",
+                "table t",
+                "rule 1",
+                "expression u at 18..22",
+                "expression other at 23..27",
+                "macro b",
+                "table u",
+                "rule 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_source_is_idempotent() {
+        let source = r#"#shapes
+1.0: circle
+2.5: square
+
+#colors[export]
+1.0: red
+3.0: blue"#;
+        let program = parse(source).unwrap();
+        let canonical = program.to_source();
+        assert_eq!(
+            canonical,
+            "#shapes\n1: circle\n2.5: square\n\n#colors[export]\n1: red\n3: blue\n"
+        );
+        assert_eq!(program.to_string(), canonical);
+
+        let reparsed = parse(&canonical).unwrap();
+        assert!(reparsed.eq_ignoring_spans(&program));
+        assert_ne!(reparsed, program);
+        assert_eq!(reparsed.to_source(), canonical);
+    }
+
+    #[test]
+    fn test_empty_rules_round_trip_through_source() {
+        let program = parse("#t\n1: ~\n2: \\~\n3: ~ tilde").unwrap();
+        let rules = &program.tables[0].value.rules;
+        assert!(rules[0].value.content.is_empty());
+        assert_eq!(rules[1].value.content_text(), "\\~");
+        assert_eq!(rules[2].value.content_text(), "~ tilde");
+
+        let source = program.to_source();
+        assert_eq!(source, "#t\n1: ~\n2: \\~\n3: ~ tilde\n");
+        assert_eq!(normalize(&parse(&source).unwrap()), normalize(&program));
+        assert_eq!(rules[0].value.to_string(), "1: ~");
+    }
+
+    #[test]
+    fn test_inline_choice_round_trips_through_source() {
+        let source = "#t\n1: {red|{#c|uppercase} \\| x|} {2d6}\n\n#c\n1: y\n";
+        let program = parse(source).unwrap();
+        assert_eq!(program.to_source(), source);
+
+        // Default weights are left out, and text that looks like a weight gets one
+        let program = parse("#t\n1: {2.5:red|1:blue|1:10:30}").unwrap();
+        assert_eq!(program.to_source(), "#t\n1: {2.5:red|blue|1:10:30}\n");
+        assert!(program.tables[0].value.rules[0].value.validate().is_ok());
+
+        let lonely = Rule::new(
+            1.0,
+            vec![RuleContent::Expression(Expression::InlineChoice {
+                options: vec![vec![RuleContent::Text("a".to_string())]],
+                weights: vec![0.0],
+            })],
+        );
+        assert_eq!(
+            lonely.validate(),
+            Err(vec![
+                "inline choice needs at least two options".to_string(),
+                "inline choice weight 0 must be finite and positive".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_expression_spans() {
+        let source = "#t\n1: é {#color|capitalize} {x|{d6}|z} {@p/c#u}";
+        let program = parse(source).unwrap();
+        let rule = &program.tables[0].value.rules[0].value;
+        let spans: Vec<&str> = rule
+            .expressions()
+            .into_iter()
+            .map(|(_, span)| span.unwrap().slice(source))
+            .collect();
+        assert_eq!(
+            spans,
+            vec!["{#color|capitalize}", "{x|{d6}|z}", "{d6}", "{@p/c#u}"]
+        );
+
+        // Rules built in code have no spans
+        let rule = Rule::new(
+            1.0,
+            vec![RuleContent::Expression(Expression::TableReference {
+                table_id: "t".to_string(),
+                modifiers: vec![],
+            })],
+        );
+        assert_eq!(rule.expressions()[0].1, None);
+    }
+
+    #[test]
+    fn test_eq_ignoring_spans() {
+        let program =
+            parse("%x% = \"1\"\n#t[export]\n1.0: a {#u|capitalize}\n\n#u\n1.0: b").unwrap();
+        let spaced =
+            parse("\n\n%x% = \"1\"\n\n\n#t[export]\n\n1.0: a {#u|capitalize}\n#u\n1: b").unwrap();
+        assert!(program.eq_ignoring_spans(&spaced));
+        assert!(
+            program.tables[0]
+                .value
+                .eq_ignoring_spans(&spaced.tables[0].value)
+        );
+        assert_ne!(program, spaced);
+
+        // Values still count: weights, content, flags, rule count and macros
+        for different in [
+            "%x% = \"1\"\n#t[export]\n2.0: a {#u|capitalize}\n\n#u\n1.0: b",
+            "%x% = \"1\"\n#t[export]\n1.0: a {#u|uppercase}\n\n#u\n1.0: b",
+            "%x% = \"1\"\n#t\n1.0: a {#u|capitalize}\n\n#u\n1.0: b",
+            "%x% = \"1\"\n#t[export]\n1.0: a {#u|capitalize}\n\n#u\n1.0: b\n1.0: c",
+            "%x% = \"2\"\n#t[export]\n1.0: a {#u|capitalize}\n\n#u\n1.0: b",
+        ] {
+            assert!(
+                !program.eq_ignoring_spans(&parse(different).unwrap()),
+                "{}",
+                different
+            );
+        }
+    }
+
+    #[test]
+    fn test_to_source_with_finish_flag() {
+        let program = parse("#t[export finish: capitalize titlecase]\n1: x").unwrap();
+        let source = program.to_source_with(&FormatOptions::default());
+        assert_eq!(source, "#t[export finish: capitalize titlecase]\n1: x\n");
+        assert_eq!(
+            parse(&source).unwrap().tables[0].value.metadata,
+            program.tables[0].value.metadata
+        );
+    }
+
+    #[test]
+    fn test_rule_validate() {
+        let program = parse("#t\n2: a {#color|capitalize} {2d6} {@alice/gear#sword}").unwrap();
+        assert_eq!(program.tables[0].value.rules[0].value.validate(), Ok(()));
+
+        let rule = Rule::new(
+            f64::NAN,
+            vec![
+                RuleContent::Expression(Expression::TableReference {
+                    table_id: String::new(),
+                    modifiers: vec![],
+                }),
+                RuleContent::Expression(Expression::DiceRoll {
+                    count: Some(0),
+                    sides: 0,
+                    keep: None,
+                    modifier: None,
+                    modifiers: vec![Modifier::with_arg("truncate", "")],
+                }),
+            ],
+        );
+        let issues = rule.validate().unwrap_err();
+        assert_eq!(issues.len(), 5, "{:?}", issues);
+
+        assert_eq!(
+            Rule::new_text(0.0, "x".to_string())
+                .validate()
+                .unwrap_err()
+                .len(),
+            1
+        );
+        assert!(Rule::new_text(-1.0, "x".to_string()).validate().is_err());
+    }
+
+    const MODIFIERS: [&str; 5] = [
+        "capitalize",
+        "uppercase",
+        "lowercase",
+        "indefinite",
+        "definite",
+    ];
+    const WORDS: [&str; 9] = [
+        "red",
+        "Big",
+        "old-timer",
+        "x_1",
+        "two words",
+        "a:b",
+        "50%",
+        "(ok)",
+        "~",
+    ];
+    /// Text that needs escaping: braces and backslashes anywhere, `|` inside choice options
+    const ESCAPED: [&str; 4] = ["{", "}", "\\", "|"];
+    const ATTRIBUTE_VALUES: [&str; 5] = ["rare", "two words", "say \"hi\"", "a\\b", "x, [y]\nz"];
+
+    /// Identifiers are prefixed so they never collide with keywords or dice like `d6`
+    fn gen_identifier(rng: &mut SmallRng, prefix: &str) -> String {
+        let len = rng.gen_range(0..6);
+        let tail: String = (0..len)
+            .map(|_| {
+                let chars = b"abcxyz019_-";
+                chars[rng.gen_range(0..chars.len())] as char
+            })
+            .collect();
+        format!("{}{}", prefix, tail)
+    }
+
+    fn gen_modifiers(rng: &mut SmallRng) -> Vec<Modifier> {
+        (0..rng.gen_range(0..3))
+            .map(|_| match rng.gen_range(0..4) {
+                0 => Modifier::with_arg("truncate", rng.gen_range(1..50).to_string()),
+                _ => Modifier::new(MODIFIERS[rng.gen_range(0..MODIFIERS.len())]),
+            })
+            .collect()
+    }
+
+    /// Generate an expression; `depth` counts the choices and conditionals around it
+    fn gen_expression(rng: &mut SmallRng, table_ids: &[String], depth: usize) -> Expression {
+        // Only the leaf kinds once nesting is two deep
+        let kinds = if depth < 2 { 7 } else { 4 };
+        match rng.gen_range(0..kinds) {
+            0 => Expression::TableReference {
+                table_id: table_ids[rng.gen_range(0..table_ids.len())].clone(),
+                modifiers: gen_modifiers(rng),
+            },
+            1 => Expression::ExternalTableReference {
+                publisher: gen_identifier(rng, "p"),
+                collection: gen_identifier(rng, "c"),
+                table_id: gen_identifier(rng, "t"),
+                modifiers: gen_modifiers(rng),
+            },
+            2 => gen_dice(rng),
+            3 => Expression::VariableRef {
+                name: gen_identifier(rng, "v"),
+                modifiers: gen_modifiers(rng),
+            },
+            4 => {
+                let options: Vec<Vec<RuleContent>> = (0..rng.gen_range(2..4))
+                    .map(|_| gen_nested_content(rng, table_ids, depth + 1))
+                    .collect();
+                let weights = options
+                    .iter()
+                    .map(|_| match rng.gen_range(0..3) {
+                        0 => rng.gen_range(0.001..1000.0),
+                        _ => 1.0,
+                    })
+                    .collect();
+                Expression::InlineChoice { options, weights }
+            }
+            5 => Expression::Binding {
+                name: gen_identifier(rng, "v"),
+                expression: Box::new(match rng.gen_range(0..3) {
+                    0 => Expression::TableReference {
+                        table_id: table_ids[rng.gen_range(0..table_ids.len())].clone(),
+                        modifiers: gen_modifiers(rng),
+                    },
+                    1 => Expression::ExternalTableReference {
+                        publisher: gen_identifier(rng, "p"),
+                        collection: gen_identifier(rng, "c"),
+                        table_id: gen_identifier(rng, "t"),
+                        modifiers: gen_modifiers(rng),
+                    },
+                    _ => gen_dice(rng),
+                }),
+            },
+            _ => Expression::Conditional {
+                var: gen_identifier(rng, "v"),
+                equals: gen_identifier(rng, "w"),
+                then: gen_nested_content(rng, table_ids, depth + 1),
+                otherwise: rng
+                    .gen_bool(0.5)
+                    .then(|| gen_nested_content(rng, table_ids, depth + 1)),
+            },
+        }
+    }
+
+    fn gen_dice(rng: &mut SmallRng) -> Expression {
+        let count = if rng.gen_bool(0.5) {
+            Some(rng.gen_range(1..=20))
+        } else {
+            None
+        };
+        let kept = rng.gen_range(1..=count.unwrap_or(1));
+        Expression::DiceRoll {
+            count,
+            sides: rng.gen_range(1..=100),
+            keep: match rng.gen_range(0..3) {
+                0 => Some(KeepMode::Highest(kept)),
+                1 => Some(KeepMode::Lowest(kept)),
+                _ => None,
+            },
+            modifier: match rng.gen_range(0..3) {
+                0 => Some(rng.gen_range(-20..=20)),
+                _ => None,
+            },
+            modifiers: gen_modifiers(rng),
+        }
+    }
+
+    /// Generate rule content the way the lexer would produce it: never two text
+    /// pieces in a row and never empty text; an empty rule is written as `~`
+    fn gen_content(rng: &mut SmallRng, table_ids: &[String], depth: usize) -> Vec<RuleContent> {
+        let mut content = Vec::new();
+        for _ in 0..rng.gen_range(0..5) {
+            let previous_is_text = matches!(content.last(), Some(RuleContent::Text(_)));
+            if !previous_is_text && rng.gen_bool(0.5) {
+                let words: Vec<&str> = (0..rng.gen_range(1..4))
+                    .map(|_| match rng.gen_range(0..4) {
+                        0 => ESCAPED[rng.gen_range(0..ESCAPED.len())],
+                        _ => WORDS[rng.gen_range(0..WORDS.len())],
+                    })
+                    .collect();
+                content.push(RuleContent::Text(format!(" {} ", words.join(" "))));
+            } else {
+                content.push(RuleContent::Expression(gen_expression(
+                    rng, table_ids, depth,
+                )));
+            }
+        }
+        content
+    }
+
+    /// Generate a choice option or conditional branch, which the parser trims
+    /// and never leaves empty
+    fn gen_nested_content(
+        rng: &mut SmallRng,
+        table_ids: &[String],
+        depth: usize,
+    ) -> Vec<RuleContent> {
+        let content = trim_content(gen_content(rng, table_ids, depth));
+        if content.is_empty() {
+            vec![RuleContent::Text(
+                WORDS[rng.gen_range(0..WORDS.len())].to_string(),
+            )]
+        } else {
+            content
+        }
+    }
+
+    fn gen_program(rng: &mut SmallRng) -> Program {
+        let table_ids: Vec<String> = (0..rng.gen_range(1..5))
+            .map(|i| format!("{}{}", gen_identifier(rng, "t"), i))
+            .collect();
+
+        let tables = table_ids
+            .iter()
+            .map(|id| {
+                let rules = (0..rng.gen_range(1..5))
+                    .map(|_| {
+                        let weight = rng.gen_range(0.001..1000.0);
+                        Node::new(
+                            Rule::new(weight, gen_content(rng, &table_ids, 0)),
+                            Span::new(0, 0),
+                        )
+                    })
+                    .collect();
+                let mut metadata = TableMetadata::new(id.clone())
+                    .with_export(rng.gen_bool(0.5))
+                    .with_percent(rng.gen_bool(0.2))
+                    .with_finish_modifiers(
+                        (0..rng.gen_range(0..3))
+                            .map(|_| MODIFIERS[rng.gen_range(0..MODIFIERS.len())].to_string())
+                            .collect(),
+                    );
+                if rng.gen_bool(0.3) {
+                    metadata = metadata.with_default_weight(rng.gen_range(0.001..1000.0));
+                }
+                for _ in 0..rng.gen_range(0..3) {
+                    metadata = metadata.with_attribute(
+                        gen_identifier(rng, "k"),
+                        ATTRIBUTE_VALUES[rng.gen_range(0..ATTRIBUTE_VALUES.len())].to_string(),
+                    );
+                }
+                Node::new(Table::new(metadata, rules), Span::new(0, 0))
+            })
+            .collect();
+
+        Program::new(tables)
+    }
+
+    /// A table reduced to its metadata and `(weight, content)` rules
+    type NormalizedTable = (TableMetadata, Vec<(f64, Vec<RuleContent>)>);
+
+    /// Strip the whitespace around content that the parser doesn't keep
+    fn trim_content(mut content: Vec<RuleContent>) -> Vec<RuleContent> {
+        if let Some(RuleContent::Text(text)) = content.first_mut() {
+            *text = text.trim_start().to_string();
+            if text.is_empty() {
+                content.remove(0);
+            }
+        }
+        if let Some(RuleContent::Text(text)) = content.last_mut() {
+            *text = text.trim_end().to_string();
+            if text.is_empty() {
+                content.pop();
+            }
+        }
+        content
+    }
+
+    /// Strip spans, and the whitespace around content that rendering doesn't keep
+    fn normalize(program: &Program) -> Vec<NormalizedTable> {
+        program
+            .tables
+            .iter()
+            .map(|table| {
+                let rules = table
+                    .value
+                    .rules
+                    .iter()
+                    .map(|rule| (rule.value.weight, trim_content(rule.value.content.clone())))
+                    .collect();
+                (table.value.metadata.clone(), rules)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_generated_programs_round_trip_through_source() {
+        for seed in 0..300 {
+            let mut rng = SmallRng::seed_from_u64(seed);
+            let program = gen_program(&mut rng);
+            let source = program.to_source();
+
+            let reparsed = parse(&source).unwrap_or_else(|e| {
+                panic!(
+                    "seed {} produced unparseable source:\n{}\n{}",
+                    seed, source, e
+                )
+            });
+            assert_eq!(
+                normalize(&reparsed),
+                normalize(&program),
+                "seed {} did not round-trip:\n{}",
+                seed,
+                source
+            );
+        }
+    }
+}
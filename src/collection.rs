@@ -1,7 +1,19 @@
-use crate::ast::{Expression, RuleContent, Table};
-use crate::parse;
-use rand::rngs::SmallRng;
-use rand::{Rng, SeedableRng};
+use crate::ast::{
+    Expression, KeepMode, MacroDefinition, Modifier, Node, Program, Rule, RuleContent, Span, Table,
+    dice_notation, nested_expressions, try_for_each_expression_mut,
+};
+use crate::diagnostic::Diagnostic;
+use crate::diagnostic_collector::DiagnosticCollector;
+use crate::errors::ParseError;
+use crate::lexer::Lexer;
+use crate::lexer::{MAX_DICE_COUNT, MODIFIER_KEYWORDS};
+use crate::parse_with_lexer;
+use rand::rngs::mock::StepRng;
+use rand::rngs::{SmallRng, StdRng};
+use rand::{Rng, RngCore, SeedableRng};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
 use thiserror::Error;
 
 #[cfg(feature = "wasm")]
@@ -9,6 +21,14 @@ type HashMapType<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
 #[cfg(not(feature = "wasm"))]
 type HashMapType<K, V> = std::collections::HashMap<K, V>;
 
+/// Create an empty map using the hasher for the current build
+fn new_map<K, V>() -> HashMapType<K, V> {
+    #[cfg(feature = "wasm")]
+    return HashMapType::with_hasher(ahash::RandomState::new());
+    #[cfg(not(feature = "wasm"))]
+    HashMapType::default()
+}
+
 /// Optimized table for fast generation with pre-computed weights
 #[derive(Debug, Clone)]
 struct OptimizedTable {
@@ -18,6 +38,413 @@ struct OptimizedTable {
     pub cumulative_weights: Vec<f64>,
     /// Total weight of all rules (cached for performance)
     pub total_weight: f64,
+    /// Exact integer cumulative weights, present when integer-weight mode is enabled
+    pub integer_cumulative_weights: Option<Vec<u64>>,
+    /// Where the table is declared in the source
+    pub span: Span,
+    /// Comment lines just above the table's declaration
+    pub doc: Option<String>,
+}
+
+/// Size limits enforced while building a collection from untrusted source
+///
+/// Every limit but `max_dice` defaults to unlimited; set only the ones you
+/// need, e.g. `Limits { max_tables: 100, ..Limits::default() }`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum source length in bytes, checked before parsing
+    pub max_source_len: usize,
+    /// Maximum number of tables
+    pub max_tables: usize,
+    /// Maximum number of rules in any single table
+    pub max_rules_per_table: usize,
+    /// Most dice a single roll like `{300d6}` may use (default 1000)
+    ///
+    /// Larger rolls are rejected while parsing, and the built collection
+    /// starts with this as its `set_max_dice` limit.
+    pub max_dice: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_source_len: usize::MAX,
+            max_tables: usize::MAX,
+            max_rules_per_table: usize::MAX,
+            max_dice: MAX_DICE_COUNT,
+        }
+    }
+}
+
+impl Limits {
+    /// Check a measured size against one limit
+    fn check(which: &'static str, actual: usize, limit: usize) -> CollectionResult<()> {
+        if actual > limit {
+            return Err(CollectionError::LimitExceeded { which, limit });
+        }
+        Ok(())
+    }
+}
+
+/// Chainable configuration for building a [`Collection`]
+///
+/// Settings left unset keep the defaults `Collection::new` uses, so
+/// `CollectionBuilder::new().build(source)` is equivalent to it:
+///
+/// ```
+/// use table_collection::CollectionBuilder;
+///
+/// let mut collection = CollectionBuilder::new()
+///     .seed(42)
+///     .max_depth(20)
+///     .build("#greeting\n1.0: hello")?;
+/// assert_eq!(collection.generate("greeting", 1)?, "hello");
+/// # Ok::<(), table_collection::CollectionError>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct CollectionBuilder {
+    seed: Option<u64>,
+    max_depth: Option<usize>,
+    max_dice: Option<u32>,
+    allow_unknown_modifiers: bool,
+    modifiers: Vec<(String, CustomModifier)>,
+    options: BuildOptions,
+}
+
+impl CollectionBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Seed the RNG deterministically, see `Collection::with_seed` (random by default)
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// See `Collection::set_max_depth`
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// See `Collection::set_max_dice`
+    ///
+    /// Set above 1000, the source may also write rolls of up to `count` dice.
+    pub fn max_dice(mut self, count: u32) -> Self {
+        self.max_dice = Some(count);
+        self
+    }
+
+    /// See `Collection::allow_unknown_modifiers`
+    pub fn allow_unknown_modifiers(mut self, allowed: bool) -> Self {
+        self.allow_unknown_modifiers = allowed;
+        self
+    }
+
+    /// Register a custom modifier, see `Collection::register_modifier`
+    ///
+    /// Modifiers registered here are known when `build` checks the source, so
+    /// it can reject unknown ones up front.
+    pub fn modifier(mut self, name: &str, f: Box<dyn Fn(&str) -> String + Send + Sync>) -> Self {
+        self.modifiers.push((name.to_string(), CustomModifier(f)));
+        self
+    }
+
+    /// See `Collection::set_case_insensitive`
+    ///
+    /// Set here, references like `{#Color}` to a `#color` table are accepted
+    /// when the collection is built, not only lookups made afterwards.
+    pub fn case_insensitive(mut self, enabled: bool) -> Self {
+        self.options.case_insensitive = enabled;
+        self
+    }
+
+    /// Accept references to tables the source doesn't define (off by default)
+    ///
+    /// For sources split across files that reference each other: build each
+    /// part with this on, `merge` them together, then call
+    /// `Collection::validate` to check the references and recursion of the
+    /// whole. Until then, `merge` and `rebuild` skip those checks too, and
+    /// generating a missing table fails with `TableNotFound`.
+    pub fn defer_validation(mut self, deferred: bool) -> Self {
+        self.options.defer_validation = deferred;
+        self
+    }
+
+    /// Parse `source` and build a collection with these settings
+    ///
+    /// Fails with `UnknownModifier` when the source uses a modifier that is
+    /// neither built in nor registered with `modifier`, unless
+    /// `allow_unknown_modifiers` is set.
+    pub fn build(self, source: &str) -> CollectionResult<Collection> {
+        let seed = self.seed.unwrap_or_else(rand::random::<u64>);
+        // Lowering the limit fails the rolls that exceed it when they're
+        // generated, as `set_max_dice` does; only raising it changes parsing
+        let limits = Limits {
+            max_dice: self
+                .max_dice
+                .map_or(MAX_DICE_COUNT, |count| count.max(MAX_DICE_COUNT)),
+            ..Limits::default()
+        };
+        let program = Collection::parse_with_limits(source, &limits)?;
+        let mut collection = Collection::from_program(
+            program,
+            Some(source.to_string()),
+            HashMap::new(),
+            seed,
+            self.options,
+        )?;
+        if let Some(depth) = self.max_depth {
+            collection.max_depth = depth;
+        }
+        if let Some(count) = self.max_dice {
+            collection.max_dice = count;
+        }
+        collection.limits = limits;
+        collection.allow_unknown_modifiers = self.allow_unknown_modifiers;
+        collection.custom_modifiers.extend(self.modifiers);
+        collection.check_modifiers()?;
+        Ok(collection)
+    }
+}
+
+/// Settings that change how a program's tables are built into a collection
+#[derive(Debug, Clone, Copy, Default)]
+struct BuildOptions {
+    case_insensitive: bool, // Key tables by lowercased id, see `Collection::set_case_insensitive`
+    defer_validation: bool, // Skip reference checks until `Collection::validate`
+}
+
+/// Unit used to measure generated output length
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LengthUnit {
+    /// UTF-8 bytes, the cheapest measure and the default
+    #[default]
+    Bytes,
+    /// Unicode scalar values (`char`s)
+    Chars,
+    /// User-perceived characters (extended grapheme clusters), so an emoji
+    /// sequence or a letter with combining marks counts as one
+    #[cfg(feature = "graphemes")]
+    Graphemes,
+}
+
+impl LengthUnit {
+    /// Split text into its measurable pieces, each paired with its length in this unit
+    fn pieces(self, text: &str) -> Vec<(&str, usize)> {
+        match self {
+            LengthUnit::Bytes | LengthUnit::Chars => text
+                .char_indices()
+                .map(|(i, c)| {
+                    let piece = &text[i..i + c.len_utf8()];
+                    let len = if self == LengthUnit::Bytes {
+                        piece.len()
+                    } else {
+                        1
+                    };
+                    (piece, len)
+                })
+                .collect(),
+            #[cfg(feature = "graphemes")]
+            LengthUnit::Graphemes => {
+                use unicode_segmentation::UnicodeSegmentation;
+                text.graphemes(true).map(|grapheme| (grapheme, 1)).collect()
+            }
+        }
+    }
+
+    /// Length of `text` in this unit
+    pub fn measure(self, text: &str) -> usize {
+        match self {
+            LengthUnit::Bytes => text.len(),
+            LengthUnit::Chars => text.chars().count(),
+            #[cfg(feature = "graphemes")]
+            LengthUnit::Graphemes => self.pieces(text).len(),
+        }
+    }
+
+    /// Cut `text` to at most `max_len` units, ending in an ellipsis when shortened
+    pub fn truncate(self, text: &str, max_len: usize) -> String {
+        if self.measure(text) <= max_len {
+            return text.to_string();
+        }
+
+        let ellipsis_len = self.measure(ELLIPSIS);
+        let (budget, ellipsis) = if max_len >= ellipsis_len {
+            (max_len - ellipsis_len, ELLIPSIS)
+        } else {
+            (max_len, "")
+        };
+
+        let mut used = 0;
+        let mut truncated = String::new();
+        for (piece, len) in self.pieces(text) {
+            if used + len > budget {
+                break;
+            }
+            used += len;
+            truncated.push_str(piece);
+        }
+
+        let mut truncated = truncated.trim_end().to_string();
+        truncated.push_str(ellipsis);
+        truncated
+    }
+}
+
+const ELLIPSIS: &str = "…";
+
+/// Re-rolls allowed per reference when avoiding adjacent repeats
+const MAX_REPEAT_REROLLS: usize = 10;
+
+/// Default limit on nested table expansions, see `Collection::set_max_depth`
+const DEFAULT_MAX_DEPTH: usize = 100;
+
+/// Attempts `generate_fitting` makes before giving up on a too-long table
+const MAX_FIT_ATTEMPTS: usize = 100;
+
+/// Most copies the `repeat` modifier makes, whatever its argument
+const MAX_MODIFIER_REPEAT: usize = 100;
+
+/// Consecutive duplicate results `generate_unique` tolerates from an unbounded table
+const MAX_UNIQUE_ATTEMPTS: usize = 100;
+
+/// Consecutive duplicates `generate_unique` tolerates when the variant count allows `count`
+///
+/// Only a backstop for tables whose paths render the same text, where
+/// `count_variants` overestimates; heavily skewed weights are well within it.
+const MAX_BOUNDED_UNIQUE_ATTEMPTS: usize = 100_000;
+
+/// How far the weights of a `[percent]` table may sum from 100, for float rounding
+const PERCENT_TOLERANCE: f64 = 1e-6;
+
+/// Maximum length for generated output, and what to do when it's exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputLimit {
+    /// Longest allowed output, measured in `unit`
+    pub max_len: usize,
+    /// How output length is measured
+    pub unit: LengthUnit,
+    /// Cut long output with an ellipsis instead of generating again
+    pub truncate: bool,
+}
+
+impl OutputLimit {
+    /// Limit output to `max_len` bytes, retrying when it's too long
+    pub fn bytes(max_len: usize) -> Self {
+        Self {
+            max_len,
+            unit: LengthUnit::Bytes,
+            truncate: false,
+        }
+    }
+
+    /// Limit output to `max_len` chars, retrying when it's too long
+    pub fn chars(max_len: usize) -> Self {
+        Self {
+            unit: LengthUnit::Chars,
+            ..Self::bytes(max_len)
+        }
+    }
+
+    /// Limit output to `max_len` grapheme clusters, retrying when it's too long
+    #[cfg(feature = "graphemes")]
+    pub fn graphemes(max_len: usize) -> Self {
+        Self {
+            unit: LengthUnit::Graphemes,
+            ..Self::bytes(max_len)
+        }
+    }
+
+    /// Truncate long output with an ellipsis rather than retrying
+    pub fn truncating(mut self) -> Self {
+        self.truncate = true;
+        self
+    }
+}
+
+/// Random number generator algorithm used for generation
+///
+/// `Small` is fast and has good statistical quality for games and content
+/// generation, but it is not cryptographically secure and its exact stream is
+/// not guaranteed to be stable across platforms or `rand` releases. `Std` is a
+/// ChaCha-based generator with much stronger stream quality at some speed cost,
+/// which suits simulations that care about the distribution of long runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RngKind {
+    #[default]
+    Small,
+    Std,
+}
+
+impl RngKind {
+    fn seeded(self, seed: u64) -> CollectionRng {
+        match self {
+            RngKind::Small => CollectionRng::Small(SmallRng::seed_from_u64(seed)),
+            RngKind::Std => CollectionRng::Std(Box::new(StdRng::seed_from_u64(seed))),
+        }
+    }
+}
+
+/// The concrete RNG behind a collection, selected by `RngKind` or supplied
+/// with `Collection::with_rng`
+enum CollectionRng {
+    Small(SmallRng),
+    Std(Box<StdRng>), // ChaCha state is much larger than SmallRng's
+    Custom(Box<dyn RngCore + Send + Sync>),
+}
+
+impl CollectionRng {
+    /// Move the RNG out for a generation that borrows the collection,
+    /// leaving a placeholder until it is put back
+    fn take(&mut self) -> CollectionRng {
+        std::mem::replace(self, CollectionRng::Small(SmallRng::seed_from_u64(0)))
+    }
+}
+
+impl std::fmt::Debug for CollectionRng {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CollectionRng::Small(rng) => f.debug_tuple("Small").field(rng).finish(),
+            CollectionRng::Std(rng) => f.debug_tuple("Std").field(rng).finish(),
+            CollectionRng::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+impl RngCore for CollectionRng {
+    fn next_u32(&mut self) -> u32 {
+        match self {
+            CollectionRng::Small(rng) => rng.next_u32(),
+            CollectionRng::Std(rng) => rng.next_u32(),
+            CollectionRng::Custom(rng) => rng.next_u32(),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        match self {
+            CollectionRng::Small(rng) => rng.next_u64(),
+            CollectionRng::Std(rng) => rng.next_u64(),
+            CollectionRng::Custom(rng) => rng.next_u64(),
+        }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        match self {
+            CollectionRng::Small(rng) => rng.fill_bytes(dest),
+            CollectionRng::Std(rng) => rng.fill_bytes(dest),
+            CollectionRng::Custom(rng) => rng.fill_bytes(dest),
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        match self {
+            CollectionRng::Small(rng) => rng.try_fill_bytes(dest),
+            CollectionRng::Std(rng) => rng.try_fill_bytes(dest),
+            CollectionRng::Custom(rng) => rng.try_fill_bytes(dest),
+        }
+    }
 }
 
 /// Errors that can occur during collection generation
@@ -54,7 +481,7 @@ pub enum CollectionError {
     },
 
     #[error(
-        "External table not found: External reference '@{publisher}/{collection}#{table_id}' in table '{referencing_table}' refers to a table that does not exist in the provided collection."
+        "External table not found: External reference '@{publisher}/{collection}#{table_id}' in table '{referencing_table}' refers to a table that does not exist or is not exported in the provided collection."
     )]
     ExternalTableNotFound {
         publisher: String,
@@ -62,6 +489,111 @@ pub enum CollectionError {
         table_id: String,
         referencing_table: String,
     },
+
+    #[error(
+        "Non-integer weight: Table '{table_id}' has weight {weight}, but integer weight mode requires whole numbers"
+    )]
+    NonIntegerWeight { table_id: String, weight: f64 },
+
+    #[error(
+        "Infinite recursion: tables {} reference each other with no rule that stops expanding",
+        cycle.join(" -> ")
+    )]
+    InfiniteRecursion { cycle: Vec<String> },
+
+    #[error("Recursive macro: {} expand into each other", cycle.join(" -> "))]
+    RecursiveMacro { cycle: Vec<String> },
+
+    #[error(
+        "Maximum depth exceeded: expanding table '{table_id}' would nest more than {depth} tables deep"
+    )]
+    MaxDepthExceeded { table_id: String, depth: usize },
+
+    #[error("Dice limit exceeded: {count}d{sides} rolls more dice than allowed")]
+    DiceLimitExceeded { count: u32, sides: u32 },
+
+    #[error("Invalid dependency key '{0}': expected the form '@publisher/collection'")]
+    InvalidDependencyKey(String),
+
+    #[error(
+        "Invalid namespace '{0}': namespaces must be non-empty names of letters, digits, '_' or '-'"
+    )]
+    InvalidNamespace(String),
+
+    #[error("Duplicate namespace '{0}': each source needs its own namespace")]
+    DuplicateNamespace(String),
+
+    #[error(
+        "Ambiguous table reference: Table '{table_id}' referenced in table '{referencing_table}' is defined in namespaces {}; qualify it as {{#namespace:{table_id}}}",
+        namespaces.join(", ")
+    )]
+    AmbiguousTableReference {
+        table_id: String,
+        referencing_table: String,
+        namespaces: Vec<String>,
+    },
+
+    #[error("Conflicting macro: '%{0}%' is defined differently in more than one source")]
+    ConflictingMacro(String),
+
+    #[error("Unwritable table id: '{0}' can't be declared in TBL source")]
+    UnwritableTableId(String),
+
+    #[error(
+        "Duplicate table: '{id}' is declared twice, at source positions {} and {}",
+        first_span.start,
+        second_span.start
+    )]
+    DuplicateTable {
+        id: String,
+        first_span: Span,
+        second_span: Span,
+    },
+
+    #[error(
+        "Duplicate table: '{id}' is declared in both merged collections, at source position {} in this one and {} in the other",
+        span.start,
+        other_span.start
+    )]
+    DuplicateMergedTable {
+        id: String,
+        span: Span,
+        other_span: Span,
+    },
+
+    #[error(
+        "Not enough variants: requested {requested} distinct results from table '{table_id}', but only {available} could be produced"
+    )]
+    NotEnoughVariants {
+        table_id: String,
+        requested: usize,
+        available: usize,
+    },
+
+    #[error(
+        "Unknown modifier: '{modifier}' in table '{table_id}' is neither built in nor registered"
+    )]
+    UnknownModifier { modifier: String, table_id: String },
+
+    #[error("Source not retained: the collection was built without keeping its source")]
+    SourceNotRetained,
+
+    #[error("Limit exceeded: {which} is limited to {limit}")]
+    LimitExceeded { which: &'static str, limit: usize },
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Percent weights of table '{table_id}' sum to {sum}, not 100")]
+    PercentSumMismatch { table_id: String, sum: f64 },
+
+    #[error("Weights in table '{table_id}' sum to {sum}, which is too large to choose between")]
+    WeightOverflow { table_id: String, sum: f64 },
+
+    #[error(
+        "Unbound variable: '${name}' in table '{table_id}' is used before anything is bound to it"
+    )]
+    UnboundVariable { name: String, table_id: String },
 }
 
 /// Result type for collection operations
@@ -70,340 +602,6890 @@ pub type CollectionResult<T> = Result<T, CollectionError>;
 /// Result type specifically for generation operations
 pub type CollectionGenResult = CollectionResult<String>;
 
-impl OptimizedTable {
-    /// Create an optimized table from a parsed table with pre-computed weights
-    fn from_table(table: Table) -> CollectionResult<Self> {
-        if table.rules.is_empty() {
-            return Err(CollectionError::EmptyTable(table.metadata.id.clone()));
-        }
+impl OptimizedTable {
+    /// Create an optimized table from a parsed table with pre-computed weights
+    fn from_table(table: Table, span: Span) -> CollectionResult<Self> {
+        if table.rules.is_empty() {
+            return Err(CollectionError::EmptyTable(table.metadata.id.clone()));
+        }
+
+        let mut cumulative_weights = Vec::with_capacity(table.rules.len());
+        let mut cumulative = 0.0;
+
+        // Pre-compute cumulative weights for O(log n) binary search during generation
+        for rule in &table.rules {
+            cumulative += rule.value.weight;
+            cumulative_weights.push(cumulative);
+        }
+
+        let total_weight = cumulative;
+        if !total_weight.is_finite() {
+            return Err(CollectionError::WeightOverflow {
+                table_id: table.metadata.id.clone(),
+                sum: total_weight,
+            });
+        }
+        for rule in &table.rules {
+            for expression in nested_expressions(&rule.value.content) {
+                if let Expression::InlineChoice { weights, .. } = expression {
+                    let sum: f64 = weights.iter().sum();
+                    if !sum.is_finite() {
+                        return Err(CollectionError::WeightOverflow {
+                            table_id: table.metadata.id.clone(),
+                            sum,
+                        });
+                    }
+                }
+            }
+        }
+        if table.metadata.percent && (total_weight - 100.0).abs() > PERCENT_TOLERANCE {
+            return Err(CollectionError::PercentSumMismatch {
+                table_id: table.metadata.id.clone(),
+                sum: total_weight,
+            });
+        }
+
+        Ok(Self {
+            metadata: table.metadata,
+            doc: table.doc,
+            rules: table.rules,
+            cumulative_weights,
+            total_weight,
+            integer_cumulative_weights: None,
+            span,
+        })
+    }
+
+    /// Pre-compute exact cumulative weights, failing if any weight is not a whole number
+    fn integer_weights(&self) -> CollectionResult<Vec<u64>> {
+        let mut cumulative: u64 = 0;
+        let mut cumulative_weights = Vec::with_capacity(self.rules.len());
+
+        for rule in &self.rules {
+            let weight = rule.value.weight;
+            let non_integer = || CollectionError::NonIntegerWeight {
+                table_id: self.metadata.id.clone(),
+                weight,
+            };
+
+            if weight.fract() != 0.0 || weight > u64::MAX as f64 {
+                return Err(non_integer());
+            }
+            cumulative = cumulative
+                .checked_add(weight as u64)
+                .ok_or_else(non_integer)?;
+            cumulative_weights.push(cumulative);
+        }
+
+        Ok(cumulative_weights)
+    }
+
+    /// Pick a rule index at random, respecting the table's weights
+    fn choose_rule_index<R: Rng + ?Sized>(&self, rng: &mut R) -> usize {
+        match &self.integer_cumulative_weights {
+            // Integer mode: exact arithmetic, no floating-point rounding at all
+            Some(cumulative) => {
+                let total = cumulative[cumulative.len() - 1];
+                let random_value = rng.gen_range(0..total);
+                cumulative.partition_point(|&weight| weight <= random_value)
+            }
+            None => {
+                // Use pre-computed total weight (O(1) instead of O(n))
+                let random_value: f64 = rng.gen_range(0.0..self.total_weight);
+
+                // Use binary search on pre-computed cumulative weights (O(log n) instead of O(n))
+                self.select_rule_index(random_value)
+            }
+        }
+    }
+
+    /// IDs of internal tables referenced directly by this table's rules, in rule order
+    fn referenced_table_ids(&self) -> impl Iterator<Item = &str> {
+        self.rules
+            .iter()
+            .flat_map(|rule| internal_references(&rule.value.content))
+    }
+
+    /// Distinct tables referenced directly by this table's rules, in first-use order
+    ///
+    /// References inside inline choices count. External references are
+    /// formatted as `@publisher/collection#table`.
+    fn dependency_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for expression in self
+            .rules
+            .iter()
+            .flat_map(|rule| nested_expressions(&rule.value.content))
+        {
+            let name = match expression {
+                Expression::TableReference { table_id, .. } => table_id.clone(),
+                Expression::ExternalTableReference {
+                    publisher,
+                    collection,
+                    table_id,
+                    ..
+                } => format!("@{}/{}#{}", publisher, collection, table_id),
+                _ => continue,
+            };
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    /// Weighted selection with each rule's weight scaled by the matching entry of `biases`
+    ///
+    /// Returns `None` if the biases leave every rule with zero weight.
+    fn choose_biased_rule_index<R: Rng + ?Sized>(
+        &self,
+        biases: &[f64],
+        rng: &mut R,
+    ) -> Option<usize> {
+        let weights: Vec<f64> = self
+            .rules
+            .iter()
+            .zip(biases)
+            .map(|(rule, bias)| rule.value.weight * bias)
+            .collect();
+
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            return None;
+        }
+
+        let mut random_value = rng.gen_range(0.0..total);
+        for (index, &weight) in weights.iter().enumerate() {
+            if random_value < weight {
+                return Some(index);
+            }
+            random_value -= weight;
+        }
+        // Rounding can leave a sliver past the last rule; give it to the last weighted rule
+        weights.iter().rposition(|&weight| weight > 0.0)
+    }
+
+    /// Fast weighted rule selection using binary search on pre-computed cumulative weights
+    /// This is O(log n) instead of O(n) linear search
+    fn select_rule_index(&self, random_value: f64) -> usize {
+        select_weighted_index(&self.cumulative_weights, random_value)
+    }
+}
+
+/// The weighted-selection math behind one rule choice, from `Collection::explain_selection`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectionExplanation {
+    /// The random value that drove the selection, in `0.0..total_weight`
+    pub random_value: f64,
+    /// Sum of all rule weights in the table
+    pub total_weight: f64,
+    /// Running weight totals, one per rule; rule `i` owns `(cumulative[i-1], cumulative[i]]`
+    pub cumulative_weights: Vec<f64>,
+    /// Index of the selected rule
+    pub rule_index: usize,
+    /// Source text of the selected rule's content
+    pub rule_content: String,
+}
+
+/// How deeply a table's references can nest, from `Collection::max_reference_depth`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReferenceDepth {
+    /// The longest chain of nested tables, counting the table itself
+    Bounded(usize),
+    /// The table can reach a reference cycle, so generation may nest arbitrarily deep
+    ///
+    /// `cycle` lists the tables around it, starting and ending with the same
+    /// one, as in `["place", "place"]`.
+    Cyclic { cycle: Vec<String> },
+}
+
+/// Post-processing function applied to every top-level generation result
+struct GenerationHook(Box<dyn FnMut(String) -> String + Send + Sync>);
+
+impl std::fmt::Debug for GenerationHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("GenerationHook")
+    }
+}
+
+/// Text modifier added with `Collection::register_modifier`
+struct CustomModifier(Box<dyn Fn(&str) -> String + Send + Sync>);
+
+impl std::fmt::Debug for CustomModifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CustomModifier")
+    }
+}
+
+/// State threaded through one top-level generation
+#[derive(Debug, Default)]
+struct Generation {
+    /// Variables bound so far, by name
+    bindings: HashMap<String, String>,
+    /// Take every table's first rule and record no coverage, for `dry_run`
+    first_rules: bool,
+}
+
+/// Content being rendered on the expansion stack, see `Collection::run`
+struct Frame<'c> {
+    collection: &'c Collection, // Collection the content belongs to
+    table_id: &'c str,          // Table the content belongs to
+    content: &'c [RuleContent],
+    next: usize, // Index of the next item of `content` to render
+    output: String,
+    literal_modifiers: Vec<&'c str>, // Applied to the content's own text segments
+    depth: usize,                    // Tables being expanded, including this one
+    previous_reference: Option<String>, // Last reference output, for `avoid_adjacent_repeats`
+    rerolls: usize,                  // Re-rolls of the expression at `next` so far
+    table: Option<&'c OptimizedTable>, // Set when the content is a selected rule, for finish modifiers
+    after: Vec<After<'c>>,             // Applied to the output once it's rendered
+}
+
+impl<'c> Frame<'c> {
+    /// A frame for content nested in this one, like a choice option or conditional branch
+    fn nested(
+        &self,
+        collection: &'c Collection,
+        content: &'c [RuleContent],
+        after: Vec<After<'c>>,
+    ) -> Self {
+        Frame {
+            collection,
+            table_id: self.table_id,
+            content,
+            next: 0,
+            output: String::new(),
+            literal_modifiers: self.literal_modifiers.clone(),
+            depth: self.depth,
+            previous_reference: None,
+            rerolls: 0,
+            table: None,
+            after,
+        }
+    }
+
+    /// Take the output of the expression at `next`, unless it should be rolled again
+    ///
+    /// With `avoid_adjacent_repeats`, output repeating the previous reference's
+    /// is rejected up to `MAX_REPEAT_REROLLS` times, leaving `next` in place so
+    /// the expression is rendered again. Dice and variables are never re-rolled
+    /// (a variable always repeats its bound value).
+    fn accept(&mut self, output: String) {
+        let rerollable = self.collection.avoid_adjacent_repeats
+            && !matches!(
+                self.content[self.next],
+                RuleContent::Expression(
+                    Expression::DiceRoll { .. } | Expression::VariableRef { .. }
+                )
+            );
+        if rerollable {
+            if self.rerolls < MAX_REPEAT_REROLLS
+                && self.previous_reference.as_deref() == Some(output.as_str())
+            {
+                self.rerolls += 1;
+                return;
+            }
+            self.previous_reference = Some(output.clone());
+        }
+
+        self.output.push_str(&output);
+        self.next += 1;
+        self.rerolls = 0;
+    }
+
+    /// The finished output, with finish modifiers and then `after` applied
+    fn finish(self, generation: &mut Generation) -> String {
+        let output = match self.table {
+            Some(table) => self
+                .collection
+                .apply_finish_modifiers(table, self.content, self.output),
+            None => self.output,
+        };
+        apply_after(output, self.after, generation)
+    }
+}
+
+/// A step applied to a frame's output for the content that's waiting on it
+enum After<'c> {
+    /// Reference-site modifiers, applied by the referencing collection
+    Modifiers(&'c Collection, Vec<&'c Modifier>),
+    /// Trim a chosen inline-choice option
+    Trim(&'c Collection),
+    /// Bind the output to a variable
+    Bind(&'c str),
+}
+
+/// Apply `after` to an expression's output, in order
+fn apply_after(mut output: String, after: Vec<After>, generation: &mut Generation) -> String {
+    for step in after {
+        output = match step {
+            After::Modifiers(collection, modifiers) => {
+                collection.apply_modifiers(&output, &modifiers)
+            }
+            After::Trim(collection) => collection.trim_result(output),
+            After::Bind(name) => {
+                generation.bindings.insert(name.to_string(), output.clone());
+                output
+            }
+        };
+    }
+    output
+}
+
+/// An expression's output, or the frame that will render it
+enum Step<'c> {
+    Done(String),
+    Frame(Frame<'c>),
+}
+
+/// Least-recently-used cache of `generate_seeded` results
+///
+/// Entries are kept most-recent first and looked up linearly, which is plenty
+/// for the handful of previews an editor shows at once.
+#[derive(Debug)]
+struct PreviewCache {
+    capacity: usize,
+    entries: VecDeque<((String, usize, u64), Vec<String>)>,
+}
+
+impl PreviewCache {
+    fn get(&mut self, key: &(String, usize, u64)) -> Option<Vec<String>> {
+        let position = self.entries.iter().position(|(cached, _)| cached == key)?;
+        let entry = self.entries.remove(position)?;
+        let results = entry.1.clone();
+        self.entries.push_front(entry);
+        Some(results)
+    }
+
+    fn insert(&mut self, key: (String, usize, u64), results: Vec<String>) {
+        self.entries.truncate(self.capacity - 1);
+        self.entries.push_front((key, results));
+    }
+}
+
+/// A collection of tables that can generate random content
+#[derive(Debug)]
+pub struct Collection {
+    tables: HashMapType<String, OptimizedTable>,
+    dependencies: HashMap<(String, String), Collection>, // Resolved external collections
+    decks: HashMapType<String, Vec<usize>>, // Undrawn rule indices for tables used as decks
+    rng: CollectionRng,
+    rng_kind: RngKind,
+    seed: u64, // Base seed the RNG was created from, used for indexed generation
+    table_order: Vec<String>, // Preserve the order tables appear in source
+    source: Option<String>, // Original source, kept for lint locations and `rebuild`
+    limits: Limits, // Limits the source was parsed with, reused by `rebuild`
+    generation_hook: Option<GenerationHook>,
+    custom_modifiers: HashMap<String, CustomModifier>, // Consulted before the built-in modifiers
+    unknown_modifiers: Vec<(String, String, Option<usize>)>, // Non-built-in modifier uses, by table and rule
+    allow_unknown_modifiers: bool, // Let unregistered modifiers pass text through unchanged
+    call_hashing: bool,            // Seed each top-level generation from (table_id, call_counter)
+    call_counter: u64,
+    cascading_modifiers: bool, // Reference-site modifiers also reach nested reference output
+    max_output_len: Option<OutputLimit>,
+    max_depth: usize,          // Deepest allowed chain of nested table expansions
+    max_dice: u32,             // Most dice a single roll may use
+    warnings: Vec<Diagnostic>, // Non-fatal problems found while building
+    macros: HashMap<String, String>, // `%name%` replacements for literal rule text
+    coverage: Option<Mutex<HashMap<String, Vec<bool>>>>, // Rules hit per table, when tracked
+    avoid_adjacent_repeats: bool, // Re-roll references that repeat the previous reference
+    biases: Option<HashMap<String, f64>>, // Weight multipliers active during `generate_with_bias`
+    annotate_dice: bool,       // Follow dice totals with their individual rolls
+    trim_output: bool,         // Trim the whitespace around every rendered rule
+    case_insensitive: bool,    // Key and look up table ids in lowercase
+    references_validated: bool, // False while `defer_validation` postpones reference checks
+    preview_cache: Option<Mutex<PreviewCache>>, // Cached `generate_seeded` results, when enabled
+}
+
+impl Collection {
+    /// Create a new collection from TBL source code
+    ///
+    /// Uses the default settings; see [`CollectionBuilder`] to configure them.
+    /// Fails with `UnknownModifier` when the source uses a modifier that isn't
+    /// built in; register custom ones with `CollectionBuilder::modifier`.
+    pub fn new(source: &str) -> CollectionResult<Self> {
+        CollectionBuilder::new().build(source)
+    }
+
+    /// Start configuring a collection, see [`CollectionBuilder`]
+    pub fn builder() -> CollectionBuilder {
+        CollectionBuilder::new()
+    }
+
+    /// Create a collection whose RNG is seeded deterministically
+    ///
+    /// The same seed and source always produce the same sequence of outputs
+    /// (for a given build), which makes golden-output tests and bug reports
+    /// reproducible.
+    pub fn with_seed(source: &str, seed: u64) -> CollectionResult<Self> {
+        CollectionBuilder::new().seed(seed).build(source)
+    }
+
+    /// Create a collection that generates from the given RNG
+    ///
+    /// Use this for a cryptographically secure RNG, or for a mock RNG that
+    /// makes tests select exactly the rules they expect. The RNG drives
+    /// `generate`, `roll` and `draw`. Indexed generation, call hashing and
+    /// `generate_seeded` still derive their own RNGs from the seed and
+    /// `RngKind`, and `set_seed` or `with_rng_algorithm` replace it.
+    pub fn with_rng<R: RngCore + Send + Sync + 'static>(
+        source: &str,
+        rng: R,
+    ) -> CollectionResult<Self> {
+        let mut collection = Self::new(source)?;
+        collection.rng = CollectionRng::Custom(Box::new(rng));
+        Ok(collection)
+    }
+
+    /// Create a collection whose external references resolve against `dependencies`
+    ///
+    /// `dependencies` maps `(publisher, collection)` to an already-built
+    /// collection. A reference like `{@alice/weapons#sword}` generates from the
+    /// `sword` table of the `("alice", "weapons")` dependency. Construction fails
+    /// with `MissingDependency` when a referenced collection is absent, and with
+    /// `ExternalTableNotFound` when it is present but lacks the table or does
+    /// not export it.
+    pub fn with_external(
+        source: &str,
+        dependencies: HashMap<(String, String), Collection>,
+    ) -> CollectionResult<Self> {
+        Self::build(
+            source,
+            dependencies,
+            &Limits::default(),
+            rand::random::<u64>(),
+        )
+    }
+
+    /// Create a collection from dependencies keyed by `"@publisher/collection"`
+    ///
+    /// Equivalent to `with_external`, but with the keys written the way
+    /// references name them. A key that isn't of that form fails with
+    /// `InvalidDependencyKey`.
+    pub fn with_dependencies(
+        source: &str,
+        dependencies: HashMap<String, Collection>,
+    ) -> CollectionResult<Self> {
+        let dependencies = dependencies
+            .into_iter()
+            .map(|(key, collection)| {
+                let (publisher, name) = key
+                    .strip_prefix('@')
+                    .and_then(|rest| rest.split_once('/'))
+                    .filter(|(publisher, name)| {
+                        !publisher.is_empty() && !name.is_empty() && !name.contains('/')
+                    })
+                    .ok_or_else(|| CollectionError::InvalidDependencyKey(key.clone()))?;
+                Ok(((publisher.to_string(), name.to_string()), collection))
+            })
+            .collect::<CollectionResult<_>>()?;
+        Self::with_external(source, dependencies)
+    }
+
+    /// Create a collection, rejecting sources that exceed `limits`
+    ///
+    /// Intended for untrusted input: the source length is checked before
+    /// parsing, and table and rule counts before any tables are optimized.
+    /// Exceeding a limit returns `LimitExceeded`.
+    pub fn new_with_limits(source: &str, limits: Limits) -> CollectionResult<Self> {
+        Self::build(source, HashMap::new(), &limits, rand::random::<u64>())
+    }
+
+    /// Create a collection from several named sources, one namespace each
+    ///
+    /// Each `(namespace, source)` pair is parsed on its own and its tables are
+    /// renamed to `namespace:table`, so same-named tables in different sources
+    /// don't collide. An unqualified `{#table}` resolves within its own
+    /// namespace first, then to the one other namespace that defines it;
+    /// `{#namespace:table}` names a table explicitly. Macros are shared by all
+    /// sources.
+    ///
+    /// Fails with `InvalidNamespace` or `DuplicateNamespace` for bad namespace
+    /// names, `AmbiguousTableReference` when an unqualified reference matches
+    /// tables in several other namespaces, and `ConflictingMacro` when two
+    /// sources define a macro differently. The sources are not retained, so
+    /// `rebuild` is unavailable.
+    pub fn from_sources(sources: &[(&str, &str)]) -> CollectionResult<Self> {
+        let mut programs = Vec::with_capacity(sources.len());
+        for &(namespace, source) in sources {
+            let valid = !namespace.is_empty()
+                && namespace
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || c == '_' || c == '-');
+            if !valid {
+                return Err(CollectionError::InvalidNamespace(namespace.to_string()));
+            }
+            if programs.iter().any(|(existing, _)| *existing == namespace) {
+                return Err(CollectionError::DuplicateNamespace(namespace.to_string()));
+            }
+            programs.push((
+                namespace,
+                Self::parse_with_limits(source, &Limits::default())?,
+            ));
+        }
+
+        // Which namespaces define each unqualified table id, in source order
+        let mut defined_in: HashMap<String, Vec<&str>> = HashMap::new();
+        for (namespace, program) in &programs {
+            for table_node in &program.tables {
+                defined_in
+                    .entry(table_node.value.metadata.id.clone())
+                    .or_default()
+                    .push(namespace);
+            }
+        }
+
+        let mut merged = Program::new(Vec::new());
+        for (namespace, program) in &programs {
+            for definition in &program.macros {
+                let conflicting = merged.macros.iter().any(|existing| {
+                    existing.value.name == definition.value.name
+                        && existing.value.value != definition.value.value
+                });
+                if conflicting {
+                    return Err(CollectionError::ConflictingMacro(
+                        definition.value.name.clone(),
+                    ));
+                }
+                merged.macros.push(definition.clone());
+            }
+
+            for table_node in &program.tables {
+                let mut table_node = table_node.clone();
+                let table = &mut table_node.value;
+                let referencing_table = format!("{}:{}", namespace, table.metadata.id);
+
+                for rule in &mut table.rules {
+                    try_for_each_expression_mut(&mut rule.value.content, &mut |expression| {
+                        let Expression::TableReference { table_id, .. } = expression else {
+                            return Ok(());
+                        };
+                        if table_id.contains(':') {
+                            return Ok(());
+                        }
+                        let owners = defined_in
+                            .get(table_id.as_str())
+                            .map_or(&[][..], Vec::as_slice);
+                        let owner = if owners.contains(namespace) {
+                            namespace
+                        } else {
+                            match owners {
+                                [] => return Ok(()), // Reported as an invalid reference when building
+                                [owner] => owner,
+                                _ => {
+                                    return Err(CollectionError::AmbiguousTableReference {
+                                        table_id: table_id.clone(),
+                                        referencing_table: referencing_table.clone(),
+                                        namespaces: owners.iter().map(|n| n.to_string()).collect(),
+                                    });
+                                }
+                            }
+                        };
+                        *table_id = format!("{}:{}", owner, table_id);
+                        Ok(())
+                    })?;
+                }
+
+                table.metadata.id = referencing_table;
+                merged.tables.push(table_node);
+            }
+        }
+
+        let collection = Self::from_program(
+            merged,
+            None,
+            HashMap::new(),
+            rand::random::<u64>(),
+            BuildOptions::default(),
+        )?;
+        collection.check_modifiers()?;
+        Ok(collection)
+    }
+
+    fn build(
+        source: &str,
+        dependencies: HashMap<(String, String), Collection>,
+        limits: &Limits,
+        seed: u64,
+    ) -> CollectionResult<Self> {
+        let program = Self::parse_with_limits(source, limits)?;
+        let mut collection = Self::from_program(
+            program,
+            Some(source.to_string()),
+            dependencies,
+            seed,
+            BuildOptions::default(),
+        )?;
+        collection.max_dice = limits.max_dice;
+        collection.limits = *limits;
+        collection.check_modifiers()?;
+        Ok(collection)
+    }
+
+    /// Build a collection from an already-parsed program
+    fn from_program(
+        program: Program,
+        source: Option<String>,
+        dependencies: HashMap<(String, String), Collection>,
+        seed: u64,
+        options: BuildOptions,
+    ) -> CollectionResult<Self> {
+        let macros: HashMap<String, String> = program
+            .macros
+            .iter()
+            .map(|definition| {
+                (
+                    definition.value.name.clone(),
+                    definition.value.value.clone(),
+                )
+            })
+            .collect();
+        if let Some(cycle) = macro_cycle(&macros) {
+            return Err(CollectionError::RecursiveMacro { cycle });
+        }
+        let (tables, table_order) = Self::build_tables(program, &dependencies, options)?;
+
+        let mut collection = Self {
+            tables,
+            dependencies,
+            decks: new_map(),
+            rng: RngKind::default().seeded(seed),
+            rng_kind: RngKind::default(),
+            seed,
+            table_order,
+            source,
+            generation_hook: None,
+            custom_modifiers: HashMap::new(),
+            unknown_modifiers: Vec::new(),
+            allow_unknown_modifiers: false,
+            call_hashing: false,
+            call_counter: 0,
+            cascading_modifiers: true,
+            max_output_len: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_dice: MAX_DICE_COUNT,
+            limits: Limits::default(),
+            warnings: Vec::new(),
+            macros,
+            coverage: None,
+            avoid_adjacent_repeats: false,
+            biases: None,
+            annotate_dice: false,
+            trim_output: true,
+            case_insensitive: options.case_insensitive,
+            references_validated: !options.defer_validation,
+            preview_cache: None,
+        };
+        collection.warnings = collection.blank_rule_warnings();
+        collection.unknown_modifiers =
+            unknown_modifier_uses(&collection.tables, &collection.table_order);
+        Ok(collection)
+    }
+
+    /// Parse a source, enforcing `limits` on its size
+    fn parse_with_limits(source: &str, limits: &Limits) -> CollectionResult<Program> {
+        Limits::check("source length", source.len(), limits.max_source_len)?;
+
+        // Comments just above tables and rules are kept for `to_source`
+        let lexer = Lexer::new(source)
+            .with_comments(true)
+            .with_max_dice(limits.max_dice);
+        let program = parse_with_lexer(source, lexer)
+            .map_err(|e| CollectionError::ParseError(format!("{}", e)))?;
+
+        Limits::check("tables", program.tables.len(), limits.max_tables)?;
+        for table_node in &program.tables {
+            Limits::check(
+                "rules per table",
+                table_node.value.rules.len(),
+                limits.max_rules_per_table,
+            )?;
+        }
+
+        Ok(program)
+    }
+
+    /// Optimize a program's tables, returning them with their declaration order
+    ///
+    /// With `case_insensitive`, tables are keyed by their lowercased ids and
+    /// internal references are lowercased to match. With `defer_validation`,
+    /// references and recursion aren't checked.
+    fn build_tables(
+        program: Program,
+        dependencies: &HashMap<(String, String), Collection>,
+        options: BuildOptions,
+    ) -> CollectionResult<(HashMapType<String, OptimizedTable>, Vec<String>)> {
+        let mut tables: HashMapType<String, OptimizedTable> = new_map();
+        let mut table_order = Vec::new();
+
+        // First pass: collect all tables and preserve order, optimizing during parse-time
+        for table_node in program.tables {
+            let table = table_node.value;
+            let table_id = if options.case_insensitive {
+                table.metadata.id.to_lowercase()
+            } else {
+                table.metadata.id.clone()
+            };
+            if let Some(first) = tables.get(&table_id) {
+                return Err(CollectionError::DuplicateTable {
+                    id: table_id,
+                    first_span: first.span,
+                    second_span: table_node.span,
+                });
+            }
+
+            // Convert to optimized table with pre-computed weights (parse-time optimization)
+            let optimized_table = OptimizedTable::from_table(table, table_node.span)?;
+
+            table_order.push(table_id.clone());
+            tables.insert(table_id, optimized_table);
+        }
+
+        if options.case_insensitive {
+            rename_references(&mut tables, |table_id| table_id.to_lowercase());
+        }
+
+        // Second pass: validate all table references
+        if !options.defer_validation {
+            Self::validate_tables(&tables, &table_order, dependencies)?;
+        }
+
+        Ok((tables, table_order))
+    }
+
+    /// Stop retaining the original source, freeing its memory
+    ///
+    /// A collection keeps a copy of its source by default, so its memory use
+    /// is roughly the source size on top of the optimized tables. Without it,
+    /// `rebuild` fails with `SourceNotRetained` and lint diagnostics lose
+    /// their line and column information.
+    pub fn without_source(mut self) -> Self {
+        self.source = None;
+        self
+    }
+
+    /// Re-parse and re-optimize the tables from the retained source
+    ///
+    /// The source is parsed with the `Limits` it was first built with, except
+    /// that a dice limit raised by `set_max_dice` applies. The RNG state,
+    /// seed, and generation settings are kept; deck state is reset since the
+    /// rules are rebuilt. On error the collection is left unchanged.
+    pub fn rebuild(&mut self) -> CollectionResult<()> {
+        let source = self
+            .source
+            .as_deref()
+            .ok_or(CollectionError::SourceNotRetained)?;
+        let limits = Limits {
+            max_dice: self.max_dice.max(self.limits.max_dice),
+            ..self.limits
+        };
+        let program = Self::parse_with_limits(source, &limits)?;
+        let options = BuildOptions {
+            case_insensitive: self.case_insensitive,
+            defer_validation: !self.references_validated,
+        };
+        let (mut tables, table_order) = Self::build_tables(program, &self.dependencies, options)?;
+
+        // Keep integer-weight mode if it was enabled
+        if self
+            .tables
+            .values()
+            .any(|table| table.integer_cumulative_weights.is_some())
+        {
+            for table in tables.values_mut() {
+                table.integer_cumulative_weights = Some(table.integer_weights()?);
+            }
+        }
+
+        self.tables = tables;
+        self.table_order = table_order;
+        self.unknown_modifiers = unknown_modifier_uses(&self.tables, &self.table_order);
+        self.decks.clear();
+        let table_ids = self.table_order.clone();
+        self.reset_coverage(&table_ids);
+        self.clear_preview_cache();
+        self.warnings = self.blank_rule_warnings();
+        Ok(())
+    }
+
+    /// Write the collection back out as TBL source
+    ///
+    /// Macros come first, sorted by name, then the tables in source order with
+    /// their flags, `[export]` included. Parsing the result builds an equivalent
+    /// collection, so a collection changed with `merge` or `define_macro` can be
+    /// saved again. Comments just above a table or rule are kept and others
+    /// dropped, and modifiers given to `register_modifier` must be registered
+    /// again. References are written with the ids tables were declared with,
+    /// so a case-insensitive collection parses back without the setting.
+    ///
+    /// Fails with `UnwritableTableId` when a table id can't be declared in
+    /// source, like the `namespace:table` ids of `from_sources`.
+    pub fn to_source(&self) -> CollectionResult<String> {
+        let unwritable = self
+            .table_order
+            .iter()
+            .map(|table_id| self.declared_id(table_id))
+            .find(|table_id| !is_table_name(table_id));
+        if let Some(table_id) = unwritable {
+            return Err(CollectionError::UnwritableTableId(table_id.to_string()));
+        }
+        Ok(self.source_program().to_source())
+    }
+
+    /// The program `to_source` writes, whether or not its ids parse back
+    fn source_program(&self) -> Program {
+        let mut macros: Vec<(&String, &String)> = self.macros.iter().collect();
+        macros.sort();
+
+        Program {
+            tables: self
+                .table_order
+                .iter()
+                .map(|table_id| {
+                    let table = &self.tables[table_id];
+                    let mut rules = table.rules.clone();
+                    for rule in &mut rules {
+                        let declared = try_for_each_expression_mut(
+                            &mut rule.value.content,
+                            &mut |expression| {
+                                if let Expression::TableReference { table_id, .. } = expression {
+                                    *table_id = self.declared_id(table_id).to_string();
+                                }
+                                Ok::<(), std::convert::Infallible>(())
+                            },
+                        );
+                        let Ok(()) = declared;
+                    }
+                    let mut written = Table::new(table.metadata.clone(), rules);
+                    written.doc = table.doc.clone();
+                    Node::new(written, table.span)
+                })
+                .collect(),
+            macros: macros
+                .into_iter()
+                .map(|(name, value)| {
+                    let definition = MacroDefinition::new(name.clone(), value.clone());
+                    Node::new(definition, Span::new(0, 0))
+                })
+                .collect(),
+        }
+    }
+
+    /// List the external collections a source depends on, without resolving them
+    ///
+    /// Parses the source and returns each distinct `(publisher, collection)`
+    /// pair referenced by an external table reference, in order of first
+    /// appearance. Unlike `new`, missing dependencies are not an error, so a
+    /// package manager can use this to fetch dependencies up front.
+    pub fn required_dependencies(source: &str) -> Result<Vec<(String, String)>, ParseError> {
+        let program = crate::parse(source)?;
+        let mut dependencies: Vec<(String, String)> = Vec::new();
+
+        for table in &program.tables {
+            for rule in &table.value.rules {
+                for expression in nested_expressions(&rule.value.content) {
+                    if let Expression::ExternalTableReference {
+                        publisher,
+                        collection,
+                        ..
+                    } = expression
+                    {
+                        let dependency = (publisher.clone(), collection.clone());
+                        if !dependencies.contains(&dependency) {
+                            dependencies.push(dependency);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(dependencies)
+    }
+
+    /// Define a macro, replacing `%name%` in literal rule text with `value`
+    ///
+    /// Macros can also be defined in source with a top-level line like
+    /// `%game% = "Dragon Quest"`; this overrides any definition of the same
+    /// name. Macro values may use other macros. Text like `%unknown%` that
+    /// names no macro is left as-is.
+    ///
+    /// Fails with `RecursiveMacro`, leaving the macros unchanged, if the new
+    /// value would make macros expand into each other.
+    pub fn define_macro(&mut self, name: &str, value: &str) -> CollectionResult<()> {
+        let previous = self.macros.insert(name.to_string(), value.to_string());
+        if let Some(cycle) = macro_cycle(&self.macros) {
+            match previous {
+                Some(previous) => self.macros.insert(name.to_string(), previous),
+                None => self.macros.remove(name),
+            };
+            return Err(CollectionError::RecursiveMacro { cycle });
+        }
+        self.clear_preview_cache();
+        Ok(())
+    }
+
+    /// Register a text modifier usable as `{#table|name}` or in `[finish: name]`
+    ///
+    /// Registered modifiers take precedence over built-ins of the same name,
+    /// so registering `capitalize` replaces the built-in everywhere it is
+    /// used, including inside `titlecase`. Sources using modifiers that aren't
+    /// built in only build with `CollectionBuilder::modifier` or
+    /// `allow_unknown_modifiers`; see `allow_unknown_modifiers` for
+    /// registering them afterwards.
+    pub fn register_modifier(&mut self, name: &str, f: Box<dyn Fn(&str) -> String + Send + Sync>) {
+        self.custom_modifiers
+            .insert(name.to_string(), CustomModifier(f));
+        self.clear_preview_cache();
+    }
+
+    /// Let modifiers that are neither built in nor registered leave text unchanged
+    ///
+    /// By default every constructor fails with `UnknownModifier` when the
+    /// source uses a modifier that is neither built in nor registered, so
+    /// typos like `{#x|capitalise}` don't go unnoticed. To register modifiers
+    /// after building, build with `CollectionBuilder::allow_unknown_modifiers`
+    /// and turn this off again once they're registered; generating a rule
+    /// that still uses an unregistered one then fails with `UnknownModifier`.
+    /// With this on, unknown modifiers pass text through unchanged;
+    /// `lint_modifiers` still warns about them.
+    pub fn allow_unknown_modifiers(&mut self, allowed: bool) {
+        self.allow_unknown_modifiers = allowed;
+    }
+
+    /// Uses of non-built-in modifiers that aren't registered, unless unknown modifiers are allowed
+    fn unregistered_modifiers(&self) -> impl Iterator<Item = &(String, String, Option<usize>)> {
+        self.unknown_modifiers.iter().filter(|(modifier, _, _)| {
+            !self.allow_unknown_modifiers && !self.custom_modifiers.contains_key(modifier)
+        })
+    }
+
+    /// Fail with `UnknownModifier` if any rule uses an unregistered modifier
+    fn check_modifiers(&self) -> CollectionResult<()> {
+        match self.unregistered_modifiers().next() {
+            Some((modifier, table_id, _)) => Err(CollectionError::UnknownModifier {
+                modifier: modifier.clone(),
+                table_id: table_id.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Fail with `UnknownModifier` if generating this rule would use an unregistered modifier
+    fn check_rule_modifiers(&self, table_id: &str, rule_index: usize) -> CollectionResult<()> {
+        let unknown = self.unregistered_modifiers().find(|(_, table, rule)| {
+            table == table_id && rule.is_none_or(|rule| rule == rule_index)
+        });
+        match unknown {
+            Some((modifier, table_id, _)) => Err(CollectionError::UnknownModifier {
+                modifier: modifier.clone(),
+                table_id: table_id.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Re-roll references whose output repeats the previous reference in the same rule
+    ///
+    /// With this on, a rule like `{#adj} {#adj} sword` avoids "red red sword":
+    /// a reference whose output (after modifiers) equals the output of the
+    /// previous reference in the rule is rolled again, up to 10 times, before
+    /// the repeat is accepted. Dice rolls are never re-rolled. Off by default.
+    pub fn avoid_adjacent_repeats(&mut self, enabled: bool) {
+        self.avoid_adjacent_repeats = enabled;
+        self.clear_preview_cache();
+    }
+
+    /// Show each dice roll's breakdown next to its total
+    ///
+    /// With this on, `{2d6}` renders as `7 (2d6: 3+4)` instead of `7`. The
+    /// breakdown lists every die in the order rolled, followed by the flat
+    /// modifier. Dice a `kh`/`kl` suffix drops are bracketed, so `{4d6kh3}`
+    /// renders like `9 (4d6kh3: 4+[1]+4+1)`. Off by default.
+    pub fn annotate_dice(&mut self, enabled: bool) {
+        self.annotate_dice = enabled;
+        self.clear_preview_cache();
+    }
+
+    /// Choose whether generated results have their surrounding whitespace trimmed
+    ///
+    /// On by default: the final result and each inline choice option are
+    /// trimmed, while the output of referenced tables keeps its whitespace, so
+    /// a `1.0: big ` rule can sit directly before more text. Turned off, nothing
+    /// is trimmed; only the one space or tab after a rule's `weight:` is
+    /// dropped, so `1.0:  x` generates `" x"`.
+    pub fn set_trim_output(&mut self, trim: bool) {
+        self.trim_output = trim;
+        self.clear_preview_cache();
+    }
+
+    /// Match table ids regardless of case (off by default)
+    ///
+    /// Enabled, tables are stored under their lowercased ids, references
+    /// between them are lowercased, and the id given to any method taking one
+    /// is lowercased before lookup, so `roll("Color")` finds `#color`. Methods
+    /// returning ids, like `get_table_ids` and `get_table_dependencies`, still
+    /// give them as declared. References are checked when a collection is
+    /// built, so to accept `{#Color}` for a `#color` table, enable this with
+    /// `CollectionBuilder::case_insensitive` instead.
+    ///
+    /// Fails with `DuplicateTable` when two tables' ids differ only in case;
+    /// the collection is then left unchanged. Disabling it again restores the
+    /// declared ids, and references follow the tables they point at.
+    pub fn set_case_insensitive(&mut self, enabled: bool) -> CollectionResult<()> {
+        if enabled == self.case_insensitive {
+            return Ok(());
+        }
+
+        let mut keys: HashMap<String, String> = HashMap::with_capacity(self.table_order.len());
+        let mut claimed: HashMap<String, &str> = HashMap::with_capacity(self.table_order.len());
+        for table_id in &self.table_order {
+            let table = &self.tables[table_id];
+            let key = if enabled {
+                table.metadata.id.to_lowercase()
+            } else {
+                table.metadata.id.clone()
+            };
+            if let Some(first) = claimed.insert(key.clone(), table_id) {
+                return Err(CollectionError::DuplicateTable {
+                    id: key,
+                    first_span: self.tables[first].span,
+                    second_span: table.span,
+                });
+            }
+            keys.insert(table_id.clone(), key);
+        }
+
+        rename_references(&mut self.tables, |table_id| keys[table_id].clone());
+        let mut tables = new_map();
+        tables.extend(
+            self.tables
+                .drain()
+                .map(|(table_id, table)| (keys[&table_id].clone(), table)),
+        );
+        self.tables = tables;
+        let mut decks = new_map();
+        decks.extend(
+            self.decks
+                .drain()
+                .map(|(table_id, deck)| (keys[&table_id].clone(), deck)),
+        );
+        self.decks = decks;
+        if let Some(coverage) = &mut self.coverage {
+            let coverage = coverage.get_mut().unwrap_or_else(|e| e.into_inner());
+            *coverage = coverage
+                .drain()
+                .map(|(table_id, hits)| (keys[&table_id].clone(), hits))
+                .collect();
+        }
+        for table_id in &mut self.table_order {
+            *table_id = keys[table_id.as_str()].clone();
+        }
+        self.unknown_modifiers = unknown_modifier_uses(&self.tables, &self.table_order);
+        self.case_insensitive = enabled;
+        self.clear_preview_cache();
+        Ok(())
+    }
+
+    /// The key `table_id` is stored under, lowercased when matching ignores case
+    ///
+    /// Every public method taking a table id looks it up through this.
+    fn table_key<'a>(&self, table_id: &'a str) -> Cow<'a, str> {
+        if self.case_insensitive {
+            Cow::Owned(table_id.to_lowercase())
+        } else {
+            Cow::Borrowed(table_id)
+        }
+    }
+
+    /// The id a stored table was declared with, as every public method returns ids
+    ///
+    /// Differs from its key only when matching ignores case; other ids, such as
+    /// external `@publisher/collection#table` names, come back unchanged.
+    fn declared_id<'a>(&'a self, table_id: &'a str) -> &'a str {
+        self.tables
+            .get(table_id)
+            .map_or(table_id, |table| table.metadata.id.as_str())
+    }
+
+    /// Start or stop recording which rules generation selects (off by default)
+    ///
+    /// While enabled, every rule selection, including those for nested
+    /// references, `draw`, and `generate_indexed_batch`, takes a mutex lock
+    /// and marks the rule in `coverage_report`. That cost is small next to
+    /// expansion but not free, so leave it off outside of content tests.
+    /// Enabling again keeps the hits so far; disabling discards them.
+    /// `rebuild` and `merge_tables` reset the hits of the tables they rebuild.
+    pub fn enable_coverage(&mut self, enabled: bool) {
+        if !enabled {
+            self.coverage = None;
+        } else if self.coverage.is_none() {
+            self.coverage = Some(Mutex::new(HashMap::new()));
+        }
+    }
+
+    /// Which rules of each table have been selected since coverage was enabled
+    ///
+    /// Every table is listed, with one entry per rule in source order. The
+    /// report is empty when coverage tracking is disabled.
+    pub fn coverage_report(&self) -> HashMap<String, Vec<bool>> {
+        let Some(coverage) = &self.coverage else {
+            return HashMap::new();
+        };
+
+        let coverage = coverage.lock().unwrap_or_else(|e| e.into_inner());
+        self.tables
+            .iter()
+            .map(|(table_id, table)| {
+                let hits = coverage
+                    .get(table_id)
+                    .cloned()
+                    .unwrap_or_else(|| vec![false; table.rules.len()]);
+                (table_id.clone(), hits)
+            })
+            .collect()
+    }
+
+    /// Limit how many tables deep a single generation may nest (default 100)
+    ///
+    /// Self-referential tables like `#name\n1: {#name} jr.\n1: Bob` usually
+    /// stop quickly but can occasionally recurse very deep. Exceeding the limit
+    /// fails that generation with `MaxDepthExceeded` instead of growing
+    /// without bound. The top-level table counts as depth 1. Expansion doesn't
+    /// recurse on the thread's stack, so any limit is safe to set.
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
+        self.clear_preview_cache();
+    }
+
+    /// Limit how many dice a single roll like `{300d6}` may use (default 1000)
+    ///
+    /// Rolling more fails that generation with `DiceLimitExceeded`. Sources
+    /// are parsed before this can be called, so a roll of more than 1000 dice
+    /// is rejected unless the collection was built with a higher limit by
+    /// `CollectionBuilder::max_dice` or `Limits::max_dice`. Raising it here
+    /// lets `rebuild` parse larger rolls.
+    pub fn set_max_dice(&mut self, count: u32) {
+        self.max_dice = count;
+        self.clear_preview_cache();
+    }
+
+    /// Re-seed the RNG, restarting its sequence as if built with `with_seed(source, seed)`
+    ///
+    /// Also changes the base seed used by indexed generation.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = self.rng_kind.seeded(seed);
+    }
+
+    /// The base seed the RNG was last seeded from
+    ///
+    /// For a collection built without a seed this is the random one chosen at
+    /// construction, so passing it to `with_seed` reproduces its outputs.
+    pub fn current_seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Re-seed the RNG from `current_seed`, so the following generations replay
+    /// the ones made since it was seeded
+    ///
+    /// The call hashing counter restarts too. Deck draws are unaffected, and an
+    /// RNG given to `with_rng` is replaced by a seeded one.
+    pub fn reset_rng(&mut self) {
+        self.set_seed(self.seed);
+        self.call_counter = 0;
+    }
+
+    /// Switch the RNG algorithm, re-seeding it from the collection's base seed
+    ///
+    /// See [`RngKind`] for the speed/quality tradeoff. Indexed generation uses
+    /// the same algorithm.
+    pub fn with_rng_algorithm(mut self, kind: RngKind) -> Self {
+        self.rng_kind = kind;
+        self.rng = kind.seeded(self.seed);
+        self.clear_preview_cache();
+        self
+    }
+
+    /// Choose whether reference-site modifiers cascade into nested references
+    ///
+    /// Modifiers on a reference are applied after the referenced table has been
+    /// fully expanded, so by default `{#sentence|uppercase}` uppercases nested
+    /// `{#noun}` output as well. With cascading disabled, the case modifiers
+    /// (`uppercase`, `lowercase`) only touch the referenced rule's own literal
+    /// text, leaving nested reference output as generated. Positional modifiers
+    /// (`capitalize`, `indefinite`, `definite`) act on the start of the result
+    /// and are applied to the whole string either way.
+    pub fn with_cascading_modifiers(mut self, enabled: bool) -> Self {
+        self.cascading_modifiers = enabled;
+        self.clear_preview_cache();
+        self
+    }
+
+    /// Select rules using exact integer arithmetic instead of floating point
+    ///
+    /// Cumulative `f64` sums can introduce tiny rounding errors, so when exact
+    /// probabilities matter every weight can instead be kept as a `u64` and
+    /// selection done with `gen_range(0..total)` over integer cumulative
+    /// weights. Enabling requires every weight to be a whole number (`3` or
+    /// `3.0`), otherwise `NonIntegerWeight` is returned and the collection is
+    /// left as it was. Affects table selection in `generate`, `roll`, and
+    /// indexed generation; deck draws keep using floating-point weights.
+    pub fn set_integer_weights(&mut self, enabled: bool) -> CollectionResult<()> {
+        let mut weights = Vec::with_capacity(self.tables.len());
+        for (table_id, table) in &self.tables {
+            let integer_weights = if enabled {
+                Some(table.integer_weights()?)
+            } else {
+                None
+            };
+            weights.push((table_id.clone(), integer_weights));
+        }
+        for (table_id, integer_weights) in weights {
+            if let Some(table) = self.tables.get_mut(&table_id) {
+                table.integer_cumulative_weights = integer_weights;
+            }
+        }
+
+        self.clear_preview_cache();
+        Ok(())
+    }
+
+    /// The RNG algorithm currently used for generation
+    pub fn rng_algorithm(&self) -> RngKind {
+        self.rng_kind
+    }
+
+    /// Set a function that post-processes every top-level generation result
+    ///
+    /// The hook runs once per result from `generate`, `roll`, and `draw`, after
+    /// expansion and trimming. It never sees the intermediate output of nested
+    /// table references, only the final string. `generate_indexed_batch` takes
+    /// `&self` and therefore bypasses the hook.
+    pub fn set_generation_hook(&mut self, hook: Box<dyn FnMut(String) -> String + Send + Sync>) {
+        self.generation_hook = Some(GenerationHook(hook));
+    }
+
+    /// Remove the generation hook, if one is set
+    pub fn clear_generation_hook(&mut self) {
+        self.generation_hook = None;
+    }
+
+    /// Generate content from a table by ID, joining the results with ", "
+    pub fn generate(&mut self, table_id: &str, count: usize) -> CollectionGenResult {
+        self.generate_with_separator(table_id, count, ", ")
+    }
+
+    /// Generate content from a table by ID, joining the results with `sep`
+    pub fn generate_with_separator(
+        &mut self,
+        table_id: &str,
+        count: usize,
+        sep: &str,
+    ) -> CollectionGenResult {
+        Ok(self.generate_many(table_id, count)?.join(sep))
+    }
+
+    /// Generate `count` results from a table by ID, writing each to `out` as it is made
+    ///
+    /// Results are separated by `sep`, as with `generate_with_separator`, but
+    /// only one result is held in memory at a time, so millions of lines can
+    /// be streamed to stdout or a file. Wrap unbuffered writers in a
+    /// `BufWriter`. A failed write stops generation with `Io`; results
+    /// written before an error stay written.
+    pub fn generate_to<W: std::io::Write>(
+        &mut self,
+        table_id: &str,
+        count: usize,
+        sep: &str,
+        out: &mut W,
+    ) -> CollectionResult<()> {
+        for i in 0..count {
+            let output = self.generate_single(table_id)?;
+            if i > 0 {
+                out.write_all(sep.as_bytes())?;
+            }
+            out.write_all(output.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Generate `count` results from a table by ID, one string per result
+    ///
+    /// Unlike `generate`, results containing commas stay separable.
+    pub fn generate_many(&mut self, table_id: &str, count: usize) -> CollectionResult<Vec<String>> {
+        (0..count).map(|_| self.generate_single(table_id)).collect()
+    }
+
+    /// Generate `count` distinct results from a table by ID, in generation order
+    ///
+    /// Results are compared as final rendered strings (after modifiers, the
+    /// generation hook and any output limit), so two rules producing the same
+    /// text count as one variant. If the table has a finite `count_variants`
+    /// below `count`, this fails up front with `NotEnoughVariants`, reporting
+    /// that count as `available`. Otherwise duplicates are generated again:
+    /// for a finite table until enough distinct results turn up, however
+    /// rare (with a backstop of 100,000 duplicates in a row, for tables whose
+    /// paths render the same text), and for an unbounded one up to 100 times
+    /// in a row. Running out fails with `NotEnoughVariants`, whose `available`
+    /// is the number of distinct results found.
+    pub fn generate_unique(
+        &mut self,
+        table_id: &str,
+        count: usize,
+    ) -> CollectionResult<Vec<String>> {
+        let max_attempts = match self.count_variants(table_id)? {
+            Some(variants) if variants < count => {
+                return Err(CollectionError::NotEnoughVariants {
+                    table_id: table_id.to_string(),
+                    requested: count,
+                    available: variants,
+                });
+            }
+            Some(_) => MAX_BOUNDED_UNIQUE_ATTEMPTS,
+            None => MAX_UNIQUE_ATTEMPTS,
+        };
+        let mut seen = HashSet::with_capacity(count);
+        let mut results = Vec::with_capacity(count);
+        let mut attempts = 0;
+
+        while results.len() < count {
+            let output = self.generate_single(table_id)?;
+            if seen.insert(output.clone()) {
+                results.push(output);
+                attempts = 0;
+            } else {
+                attempts += 1;
+                if attempts == max_attempts {
+                    return Err(CollectionError::NotEnoughVariants {
+                        table_id: table_id.to_string(),
+                        requested: count,
+                        available: results.len(),
+                    });
+                }
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Limit the length of every top-level result from `generate` and `roll`
+    ///
+    /// See `generate_fitting` for how the limit is enforced. Pass `None` to
+    /// remove the limit.
+    pub fn set_max_output_len(&mut self, limit: Option<OutputLimit>) {
+        self.max_output_len = limit;
+    }
+
+    /// Generate one result no longer than `limit`
+    ///
+    /// By default this generates again until a result fits, failing with
+    /// `GenerationError` after 100 attempts. With `limit.truncate` set, the
+    /// first result is cut instead, ending in "…" (which counts toward the
+    /// limit). Lengths are measured in bytes unless another `LengthUnit` is
+    /// chosen; grapheme clusters need the `graphemes` feature. The generation
+    /// hook runs once, on the result returned, so the limit applies to the
+    /// output before the hook changes it.
+    pub fn generate_fitting(&mut self, table_id: &str, limit: OutputLimit) -> CollectionGenResult {
+        if limit.truncate {
+            let output = self.generate_unlimited(table_id)?;
+            let output = limit.unit.truncate(&output, limit.max_len);
+            return Ok(self.finish(output));
+        }
+
+        for _ in 0..MAX_FIT_ATTEMPTS {
+            let output = self.generate_unlimited(table_id)?;
+            if limit.unit.measure(&output) <= limit.max_len {
+                return Ok(self.finish(output));
+            }
+        }
+
+        Err(CollectionError::GenerationError(format!(
+            "No output from table '{}' fit within {} {:?} after {} attempts",
+            table_id, limit.max_len, limit.unit, MAX_FIT_ATTEMPTS
+        )))
+    }
+
+    /// Generate exactly one result from a table by ID
+    pub fn roll(&mut self, table_id: &str) -> CollectionGenResult {
+        self.generate_single(table_id)
+    }
+
+    /// Lazily generate results from a table by ID, one `roll` per `next()`
+    ///
+    /// The iterator never ends on its own, so bound it with `take` or stop
+    /// when you've seen enough. It borrows the collection mutably for its RNG.
+    pub fn iter_generate(
+        &mut self,
+        table_id: &str,
+    ) -> impl Iterator<Item = CollectionResult<String>> + '_ {
+        let table_id = table_id.to_string();
+        std::iter::repeat_with(move || self.generate_single(&table_id))
+    }
+
+    /// Each rule's content and its chance of being selected, in source order
+    ///
+    /// Probabilities are `weight / total_weight` from the pre-computed
+    /// weights, so they sum to 1.0 (up to rounding) without generating.
+    pub fn rule_probabilities(&self, table_id: &str) -> CollectionResult<Vec<(String, f64)>> {
+        let table_id = self.table_key(table_id);
+        let table = self
+            .tables
+            .get(&*table_id)
+            .ok_or_else(|| CollectionError::TableNotFound(table_id.to_string()))?;
+
+        Ok(table
+            .rules
+            .iter()
+            .map(|rule| {
+                (
+                    rule.value.content_text(),
+                    rule.value.weight / table.total_weight,
+                )
+            })
+            .collect())
+    }
+
+    /// Show how a given random value maps to a rule in a table
+    ///
+    /// Runs the same binary search as generation does, so this is a faithful
+    /// view of which rule a roll of `random_value` picks. Returns `None` if the
+    /// table doesn't exist or `random_value` lies outside `0.0..total_weight`.
+    pub fn explain_selection(
+        &self,
+        table_id: &str,
+        random_value: f64,
+    ) -> Option<SelectionExplanation> {
+        let table_id = self.table_key(table_id);
+        let table = self.tables.get(&*table_id)?;
+        if !(0.0..table.total_weight).contains(&random_value) {
+            return None;
+        }
+
+        let rule_index = table.select_rule_index(random_value);
+        Some(SelectionExplanation {
+            random_value,
+            total_weight: table.total_weight,
+            cumulative_weights: table.cumulative_weights.clone(),
+            rule_index,
+            rule_content: table.rules[rule_index].value.content_text(),
+        })
+    }
+
+    /// Generate `count` results and tally the distinct outputs
+    ///
+    /// Returns `(output, occurrences)` pairs sorted by descending frequency,
+    /// with ties broken alphabetically so the ordering is deterministic.
+    pub fn generate_frequencies(
+        &mut self,
+        table_id: &str,
+        count: usize,
+    ) -> CollectionResult<Vec<(String, usize)>> {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+
+        for _ in 0..count {
+            let result = self.generate_single(table_id)?;
+            *counts.entry(result).or_insert(0) += 1;
+        }
+
+        let mut frequencies: Vec<(String, usize)> = counts.into_iter().collect();
+        frequencies.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        Ok(frequencies)
+    }
+
+    /// Generate `count` results with some tables' weights scaled for this call
+    ///
+    /// `biases` maps table IDs to weight multipliers. For the duration of
+    /// this call, a table's bias scales its weight wherever a choice selects
+    /// between tables: a rule or inline option that consists of a single
+    /// reference to it. With `#room 1: {#treasure} / 1: {#empty}`,
+    /// `{"treasure": 10.0}` makes the first rule ten times as likely, and in
+    /// `{nothing|{#treasure}}` it does the same for the `#treasure` option.
+    /// Rules and options that merely contain a reference are chosen as usual.
+    /// A bias of 0.0 also makes the table itself fail to generate. Biases
+    /// apply to nested references too, but not to `draw` or to external
+    /// collections. Biased selection is a linear scan over float weights,
+    /// even in integer-weight mode.
+    ///
+    /// Fails with `TableNotFound` if a bias names an unknown table, and with
+    /// `GenerationError` if a bias is negative or not finite, or if a table
+    /// is generated whose biases leave no selectable rule.
+    pub fn generate_with_bias(
+        &mut self,
+        table_id: &str,
+        count: usize,
+        biases: &HashMap<String, f64>,
+    ) -> CollectionResult<Vec<String>> {
+        if let Some((name, bias)) = biases
+            .iter()
+            .find(|(_, bias)| !bias.is_finite() || **bias < 0.0)
+        {
+            return Err(CollectionError::GenerationError(format!(
+                "Bias for '{}' must be a finite, non-negative number, but got {}",
+                name, bias
+            )));
+        }
+
+        let mut keyed_biases = HashMap::with_capacity(biases.len());
+        for (name, &bias) in biases {
+            let key = self.table_key(name);
+            if !self.tables.contains_key(&*key) {
+                return Err(CollectionError::TableNotFound(name.clone()));
+            }
+            keyed_biases.insert(key.into_owned(), bias);
+        }
+
+        self.biases = Some(keyed_biases);
+        let results = self.generate_many(table_id, count);
+        self.biases = None;
+        results
+    }
+
+    /// Generate `count` results from a table, drawing all randomness from `rng`
+    ///
+    /// Takes `&self` and never touches the collection's own RNG, so callers
+    /// can bring their own generator (seeded, cryptographic, or one per
+    /// thread) and share the collection between threads. Like
+    /// `generate_indexed_batch`, this bypasses the generation hook, call
+    /// hashing, and any output length limit.
+    pub fn generate_with<R: Rng + ?Sized>(
+        &self,
+        table_id: &str,
+        count: usize,
+        rng: &mut R,
+    ) -> CollectionResult<Vec<String>> {
+        (0..count).map(|_| self.expand(table_id, rng)).collect()
+    }
+
+    /// Generate one result per index, each derived deterministically from `(seed, index)`
+    ///
+    /// Takes `&self` and never advances the collection's own RNG, so the same
+    /// indices always yield the same outputs for a given seed and build. Since
+    /// every index gets an independent RNG stream, the index list can be split
+    /// across threads (e.g. with rayon) and the results recombined.
+    pub fn generate_indexed_batch(
+        &self,
+        table_id: &str,
+        indices: &[u64],
+    ) -> CollectionResult<Vec<String>> {
+        indices
+            .iter()
+            .map(|&index| {
+                let mut rng = self.indexed_rng(index);
+                self.expand(table_id, &mut rng)
+            })
+            .collect()
+    }
+
+    /// Generate `count` results from a fresh RNG seeded with `seed`
+    ///
+    /// Takes `&self` and leaves the collection's own RNG alone, so the same
+    /// `(table_id, count, seed)` always gives the same results for a given
+    /// build, which suits preview panes that re-render the same sample. Like
+    /// `generate_indexed_batch`, the generation hook and output limit don't
+    /// apply. With `set_preview_cache_size`, repeated calls are answered from
+    /// a cache without generating (or recording coverage) again.
+    pub fn generate_seeded(
+        &self,
+        table_id: &str,
+        count: usize,
+        seed: u64,
+    ) -> CollectionResult<Vec<String>> {
+        let table_id = self.table_key(table_id);
+        let key = (table_id.to_string(), count, seed);
+        if let Some(cache) = &self.preview_cache {
+            let cached = cache.lock().unwrap_or_else(|e| e.into_inner()).get(&key);
+            if let Some(results) = cached {
+                return Ok(results);
+            }
+        }
+
+        let mut rng = self.rng_kind.seeded(seed);
+        let results = (0..count)
+            .map(|_| self.expand(&table_id, &mut rng))
+            .collect::<CollectionResult<Vec<_>>>()?;
+
+        if let Some(cache) = &self.preview_cache {
+            cache
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(key, results.clone());
+        }
+        Ok(results)
+    }
+
+    /// Cache up to `capacity` `generate_seeded` results, least recently used first out
+    ///
+    /// Off (0) by default. Changing the capacity drops cached results, as does
+    /// any change to the tables or generation options, so cached results
+    /// always match what generating afresh would produce.
+    pub fn set_preview_cache_size(&mut self, capacity: usize) {
+        self.preview_cache = (capacity > 0).then(|| {
+            Mutex::new(PreviewCache {
+                capacity,
+                entries: VecDeque::with_capacity(capacity),
+            })
+        });
+    }
+
+    /// Draw a rule from a table as if it were a deck of cards
+    ///
+    /// Rules are drawn weighted-randomly without replacement: each draw
+    /// re-normalizes over the weights of the rules still in the deck. Once every
+    /// rule has been drawn, the next draw reshuffles the full deck first. Deck
+    /// state is independent of `generate`, which always samples with replacement.
+    pub fn draw(&mut self, table_id: &str) -> CollectionGenResult {
+        let table_id = self.table_key(table_id);
+        let table = self
+            .tables
+            .get(&*table_id)
+            .ok_or_else(|| CollectionError::TableNotFound(table_id.to_string()))?;
+
+        let deck = self.decks.entry(table_id.to_string()).or_default();
+        if deck.is_empty() {
+            deck.extend(0..table.rules.len());
+        }
+
+        let remaining_weight: f64 = deck.iter().map(|&i| table.rules[i].value.weight).sum();
+        let mut random_value: f64 = self.rng.gen_range(0.0..remaining_weight);
+
+        // Linear scan: the remaining weights change on every draw, so there are
+        // no cumulative weights to binary search
+        let mut position = deck.len() - 1;
+        for (i, &rule_index) in deck.iter().enumerate() {
+            let weight = table.rules[rule_index].value.weight;
+            if random_value < weight {
+                position = i;
+                break;
+            }
+            random_value -= weight;
+        }
+        let rule_index = deck[position];
+        self.check_rule_modifiers(&table_id, rule_index)?;
+        if let Some(deck) = self.decks.get_mut(&*table_id) {
+            deck.swap_remove(position);
+        }
+
+        let mut rng = self.rng.take();
+        let table = &self.tables[&*table_id];
+        self.record_coverage(&table_id, table, rule_index);
+        let rule_content = &table.rules[rule_index].value.content;
+        let result = self
+            .render_content(&table_id, rule_content, &mut rng)
+            .map(|output| self.apply_finish_modifiers(table, rule_content, output))
+            .map(|output| self.trim_result(output));
+        self.rng = rng;
+        result.map(|output| self.finish(output))
+    }
+
+    /// Put every rule of a table back into its deck
+    pub fn reshuffle(&mut self, table_id: &str) -> CollectionResult<()> {
+        let table_id = self.table_key(table_id);
+        if !self.tables.contains_key(&*table_id) {
+            return Err(CollectionError::TableNotFound(table_id.to_string()));
+        }
+
+        self.decks.remove(&*table_id);
+        Ok(())
+    }
+
+    /// Number of rules left in a table's deck before it reshuffles
+    pub fn remaining(&self, table_id: &str) -> CollectionResult<usize> {
+        let table_id = self.table_key(table_id);
+        let table = self
+            .tables
+            .get(&*table_id)
+            .ok_or_else(|| CollectionError::TableNotFound(table_id.to_string()))?;
+
+        Ok(self
+            .decks
+            .get(&*table_id)
+            .map(|deck| deck.len())
+            .unwrap_or(table.rules.len()))
+    }
+
+    /// Append all of `from`'s rules to `into`, optionally removing `from`
+    ///
+    /// Rules keep their original weights, so merging a 3-rule table into a
+    /// 2-rule table yields 5 rules whose total weight is the sum of both.
+    /// `into` keeps its own flags and its deck is reset. Removing `from` fails
+    /// with `InvalidTableReference` while another table still references it.
+    /// On error the collection is left unchanged.
+    pub fn merge_tables(
+        &mut self,
+        into: &str,
+        from: &str,
+        remove_from: bool,
+    ) -> CollectionResult<()> {
+        let (into_key, from_key) = (self.table_key(into), self.table_key(from));
+        let (into, from): (&str, &str) = (&into_key, &from_key);
+        let target = self
+            .tables
+            .get(into)
+            .ok_or_else(|| CollectionError::TableNotFound(into.to_string()))?;
+        let source = self
+            .tables
+            .get(from)
+            .ok_or_else(|| CollectionError::TableNotFound(from.to_string()))?;
+
+        if remove_from {
+            let references_from = |rules: &[Node<Rule>]| {
+                rules
+                    .iter()
+                    .any(|rule| internal_references(&rule.value.content).any(|id| id == from))
+            };
+            // `into` will also hold `from`'s rules, including any self-references
+            let still_referenced = self.table_order.iter().find(|table_id| {
+                *table_id != from
+                    && (references_from(&self.tables[table_id.as_str()].rules)
+                        || (*table_id == into && references_from(&source.rules)))
+            });
+            if let Some(referencing_table) = still_referenced {
+                return Err(CollectionError::InvalidTableReference {
+                    table_id: from.to_string(),
+                    referencing_table: referencing_table.clone(),
+                });
+            }
+        }
+
+        let rules = target.rules.iter().chain(&source.rules).cloned().collect();
+        let mut merged =
+            OptimizedTable::from_table(Table::new(target.metadata.clone(), rules), target.span)?;
+        if target.integer_cumulative_weights.is_some() {
+            merged.integer_cumulative_weights = Some(merged.integer_weights()?);
+        }
+
+        self.tables.insert(into.to_string(), merged);
+        self.unknown_modifiers = unknown_modifier_uses(&self.tables, &self.table_order);
+        self.decks.remove(into);
+        self.reset_coverage(&[into]);
+        if remove_from && into != from {
+            self.tables.remove(from);
+            self.decks.remove(from);
+            self.reset_coverage(&[from]);
+            self.table_order.retain(|table_id| table_id != from);
+        }
+        self.clear_preview_cache();
+        Ok(())
+    }
+
+    /// Fold another collection's tables into this one
+    ///
+    /// `other`'s tables are appended after this collection's, keeping their
+    /// order, along with its macros, dependencies, registered modifiers, deck
+    /// state and warnings; where both collections have one, this collection's
+    /// is kept. References are then validated across the combined tables,
+    /// unless this collection was built with `defer_validation` and hasn't
+    /// been through `validate` yet.
+    ///
+    /// Fails with `DuplicateMergedTable` when both define a table of the same
+    /// id, and with `ConflictingMacro` when they define a macro differently.
+    /// On error the collection is left unchanged. The merged tables don't
+    /// come from this collection's source, so the source is no longer
+    /// retained and `rebuild` fails with `SourceNotRetained`.
+    pub fn merge(&mut self, mut other: Collection) -> CollectionResult<()> {
+        other.set_case_insensitive(self.case_insensitive)?;
+        for table_id in &other.table_order {
+            if let Some(first) = self.tables.get(table_id) {
+                return Err(CollectionError::DuplicateMergedTable {
+                    id: first.metadata.id.clone(),
+                    span: first.span,
+                    other_span: other.tables[table_id].span,
+                });
+            }
+        }
+        let conflicting = other.macros.iter().find(|(name, value)| {
+            self.macros
+                .get(*name)
+                .is_some_and(|existing| existing != *value)
+        });
+        if let Some((name, _)) = conflicting {
+            return Err(CollectionError::ConflictingMacro(name.clone()));
+        }
+        let mut macros = self.macros.clone();
+        macros.extend(other.macros.clone());
+        if let Some(cycle) = macro_cycle(&macros) {
+            return Err(CollectionError::RecursiveMacro { cycle });
+        }
+
+        // Keep integer-weight mode if it was enabled
+        let mut tables = other.tables;
+        if self
+            .tables
+            .values()
+            .any(|table| table.integer_cumulative_weights.is_some())
+        {
+            for table in tables.values_mut() {
+                if table.integer_cumulative_weights.is_none() {
+                    table.integer_cumulative_weights = Some(table.integer_weights()?);
+                }
+            }
+        }
+
+        let added_dependencies: Vec<(String, String)> = other
+            .dependencies
+            .keys()
+            .filter(|key| !self.dependencies.contains_key(*key))
+            .cloned()
+            .collect();
+        self.dependencies.extend(
+            other
+                .dependencies
+                .into_iter()
+                .filter(|(key, _)| added_dependencies.contains(key)),
+        );
+        self.tables.extend(tables);
+        let mut table_order = self.table_order.clone();
+        table_order.extend(other.table_order.iter().cloned());
+
+        let validated = if self.references_validated {
+            Self::validate_tables(&self.tables, &table_order, &self.dependencies)
+        } else {
+            Ok(())
+        };
+        if let Err(error) = validated {
+            for table_id in &other.table_order {
+                self.tables.remove(table_id);
+            }
+            for key in &added_dependencies {
+                self.dependencies.remove(key);
+            }
+            return Err(error);
+        }
+
+        self.table_order = table_order;
+        self.decks.extend(other.decks);
+        for (name, value) in other.macros {
+            self.macros.entry(name).or_insert(value);
+        }
+        for (name, modifier) in other.custom_modifiers {
+            self.custom_modifiers.entry(name).or_insert(modifier);
+        }
+        self.warnings.extend(other.warnings);
+        self.unknown_modifiers = unknown_modifier_uses(&self.tables, &self.table_order);
+        self.reset_coverage(&other.table_order);
+        self.source = None;
+        self.clear_preview_cache();
+        Ok(())
+    }
+
+    /// Counter-based RNG for a single index, mixing it into the base seed
+    fn indexed_rng(&self, index: u64) -> CollectionRng {
+        self.rng_kind.seeded(mix_seed(self.seed, index))
+    }
+
+    /// Derive each top-level generation's RNG from the call itself
+    ///
+    /// When enabled, the RNG stream for every top-level result of `generate`
+    /// and `roll` is seeded from a stable hash of `(table_id, call_counter)`
+    /// instead of the collection's running RNG. Replaying the same sequence of
+    /// calls on a fresh collection built from the same source reproduces the
+    /// outputs exactly, regardless of the random seed chosen at construction.
+    ///
+    /// Unlike indexed generation, which keys streams on the base seed and an
+    /// explicit caller-supplied index, this mode ignores the seed entirely and
+    /// counts calls implicitly. Enabling or disabling it resets the counter.
+    /// Deck draws are unaffected.
+    pub fn set_call_hashing(&mut self, enabled: bool) {
+        self.call_hashing = enabled;
+        self.call_counter = 0;
+    }
+
+    /// Generate a single result from a table using the collection's own RNG
+    fn generate_single(&mut self, table_id: &str) -> CollectionResult<String> {
+        let table_id = self.table_key(table_id);
+        match self.max_output_len {
+            Some(limit) => self.generate_fitting(&table_id, limit),
+            None => {
+                let output = self.generate_unlimited(&table_id)?;
+                Ok(self.finish(output))
+            }
+        }
+    }
+
+    /// Generate one top-level result, ignoring any output length limit and the hook
+    fn generate_unlimited(&mut self, table_id: &str) -> CollectionResult<String> {
+        if self.call_hashing {
+            let mut rng = self
+                .rng_kind
+                .seeded(mix_seed(stable_hash(table_id), self.call_counter));
+            self.call_counter += 1;
+            self.expand(table_id, &mut rng)
+        } else {
+            // Expansion borrows `self` immutably, so move the RNG out while it runs
+            let mut rng = self.rng.take();
+            let result = self.expand(table_id, &mut rng);
+            self.rng = rng;
+            result
+        }
+    }
+
+    /// Run the generation hook, if any, on a finished top-level result
+    fn finish(&mut self, output: String) -> String {
+        match &mut self.generation_hook {
+            Some(GenerationHook(hook)) => hook(output),
+            None => output,
+        }
+    }
+
+    /// Expand a table into text, drawing all randomness from `rng`
+    ///
+    /// Each call is one top-level generation, with its own variable bindings.
+    /// Nested references are expanded on an explicit stack of frames rather
+    /// than by recursion, so however deep a reference chain `max_depth`
+    /// allows, it never exhausts the thread's stack.
+    /// (optimized with pre-computed weights)
+    fn expand<R: Rng + ?Sized>(&self, table_id: &str, rng: &mut R) -> CollectionResult<String> {
+        self.expand_in(table_id, rng, &mut Generation::default())
+    }
+
+    /// Expand a table into text as `expand` does, within the given generation state
+    fn expand_in<R: Rng + ?Sized>(
+        &self,
+        table_id: &str,
+        rng: &mut R,
+        generation: &mut Generation,
+    ) -> CollectionResult<String> {
+        let table_id = self.table_key(table_id);
+        let step = self.table_frame(&table_id, rng, Vec::new(), 1, generation, Vec::new())?;
+        self.run(step, rng, generation)
+            .map(|output| self.trim_result(output))
+    }
+
+    /// Trim a top-level result, unless `set_trim_output(false)` turned trimming off
+    ///
+    /// Nested results are never trimmed, so whitespace between references survives.
+    fn trim_result(&self, mut output: String) -> String {
+        if self.trim_output {
+            trim_in_place(&mut output);
+        }
+        output
+    }
+
+    /// Start expanding a table: select its rule and set up a frame to render it
+    ///
+    /// `literal_modifiers` apply to the selected rule's own text only, `depth`
+    /// is the number of tables being expanded including this one, and `after`
+    /// is what the referencing content does with the output. A rule of plain
+    /// text is rendered right away, without a frame.
+    fn table_frame<'c, R: Rng + ?Sized>(
+        &'c self,
+        table_id: &'c str,
+        rng: &mut R,
+        literal_modifiers: Vec<&'c str>,
+        depth: usize,
+        generation: &mut Generation,
+        after: Vec<After<'c>>,
+    ) -> CollectionResult<Step<'c>> {
+        if depth > self.max_depth {
+            return Err(CollectionError::MaxDepthExceeded {
+                table_id: table_id.to_string(),
+                depth: self.max_depth,
+            });
+        }
+
+        let table = self
+            .tables
+            .get(table_id)
+            .ok_or_else(|| CollectionError::TableNotFound(table_id.to_string()))?;
+
+        // Get the rule using optimized selection
+        let rule_index = self.select_rule(table_id, table, rng, generation)?;
+        let content = &table.rules[rule_index].value.content;
+
+        // Fast path: a pure-text rule needs no expansion. Without macros or
+        // modifiers its output is one copy of the text, later trimmed in place
+        if let [RuleContent::Text(text)] = content.as_slice() {
+            let output = self.render_text(text, &literal_modifiers, false);
+            let output = self.apply_finish_modifiers(table, content, output);
+            return Ok(Step::Done(apply_after(output, after, generation)));
+        }
+
+        Ok(Step::Frame(Frame {
+            collection: self,
+            table_id,
+            content,
+            next: 0,
+            output: String::new(),
+            literal_modifiers,
+            depth,
+            previous_reference: None,
+            rerolls: 0,
+            table: Some(table),
+            after,
+        }))
+    }
+
+    /// Drop every cached preview, after a change that could alter generation
+    fn clear_preview_cache(&mut self) {
+        if let Some(cache) = &mut self.preview_cache {
+            cache
+                .get_mut()
+                .unwrap_or_else(|e| e.into_inner())
+                .entries
+                .clear();
+        }
+    }
+
+    /// Forget coverage hits for tables whose rules have been rebuilt
+    fn reset_coverage<S: AsRef<str>>(&mut self, table_ids: &[S]) {
+        if let Some(coverage) = &mut self.coverage {
+            let coverage = coverage.get_mut().unwrap_or_else(|e| e.into_inner());
+            for table_id in table_ids {
+                coverage.remove(table_id.as_ref());
+            }
+        }
+    }
+
+    /// Select the rule to expand
+    ///
+    /// `dry_run` takes the first rule; otherwise it is chosen at random and
+    /// recorded for coverage.
+    fn select_rule<R: Rng + ?Sized>(
+        &self,
+        table_id: &str,
+        table: &OptimizedTable,
+        rng: &mut R,
+        generation: &Generation,
+    ) -> CollectionResult<usize> {
+        if generation.first_rules {
+            self.check_rule_modifiers(table_id, 0)?;
+            return Ok(0);
+        }
+        let rule_index = self.choose_rule(table_id, table, rng)?;
+        self.record_coverage(table_id, table, rule_index);
+        Ok(rule_index)
+    }
+
+    /// Select a rule from a table, honoring any biases from `generate_with_bias`
+    fn choose_rule<R: Rng + ?Sized>(
+        &self,
+        table_id: &str,
+        table: &OptimizedTable,
+        rng: &mut R,
+    ) -> CollectionResult<usize> {
+        let rule_biases: Vec<f64> = match &self.biases {
+            None => Vec::new(),
+            Some(biases) => {
+                let table_bias = biases.get(table_id).copied().unwrap_or(1.0);
+                table
+                    .rules
+                    .iter()
+                    .map(|rule| table_bias * self.option_bias(&rule.value.content))
+                    .collect()
+            }
+        };
+        let rule_index = if rule_biases.iter().all(|&bias| bias == 1.0) {
+            table.choose_rule_index(rng)
+        } else {
+            table
+                .choose_biased_rule_index(&rule_biases, rng)
+                .ok_or_else(|| {
+                    CollectionError::GenerationError(format!(
+                        "Biases leave every rule of table '{}' with zero weight",
+                        table_id
+                    ))
+                })?
+        };
+        self.check_rule_modifiers(table_id, rule_index)?;
+        Ok(rule_index)
+    }
+
+    /// The `generate_with_bias` bias of a rule or choice option that is a single table reference
+    ///
+    /// Other rules and options, and all of them outside `generate_with_bias`, weigh as written.
+    fn option_bias(&self, option: &[RuleContent]) -> f64 {
+        let Some(biases) = &self.biases else {
+            return 1.0;
+        };
+        let mut parts = option
+            .iter()
+            .filter(|part| !matches!(part, RuleContent::Text(text) if text.trim().is_empty()));
+        match (parts.next(), parts.next()) {
+            (Some(RuleContent::Expression(Expression::TableReference { table_id, .. })), None) => {
+                biases.get(table_id).copied().unwrap_or(1.0)
+            }
+            _ => 1.0,
+        }
+    }
+
+    /// Mark a rule as hit, if coverage tracking is enabled
+    fn record_coverage(&self, table_id: &str, table: &OptimizedTable, rule_index: usize) {
+        let Some(coverage) = &self.coverage else {
+            return;
+        };
+
+        let mut coverage = coverage.lock().unwrap_or_else(|e| e.into_inner());
+        match coverage.get_mut(table_id) {
+            Some(hits) => hits[rule_index] = true,
+            None => {
+                let mut hits = vec![false; table.rules.len()];
+                hits[rule_index] = true;
+                coverage.insert(table_id.to_string(), hits);
+            }
+        }
+    }
+
+    /// Apply a table's `[finish: ...]` modifiers to its expanded output
+    ///
+    /// These run on every output of the table, whether generated directly or
+    /// through a reference, and before any reference-site modifiers. The output
+    /// first loses the separator after the rule's `weight:`.
+    fn apply_finish_modifiers(
+        &self,
+        table: &OptimizedTable,
+        rule_content: &[RuleContent],
+        output: String,
+    ) -> String {
+        let output = self.strip_rule_separator(rule_content, output);
+        let finish: Vec<&str> = table
+            .metadata
+            .finish_modifiers
+            .iter()
+            .map(String::as_str)
+            .collect();
+        self.apply_named_modifiers(output, &finish)
+    }
+
+    /// Render a selected rule's content, expanding any expressions it contains
+    fn render_content<R: Rng + ?Sized>(
+        &self,
+        table_id: &str,
+        rule_content: &[RuleContent],
+        rng: &mut R,
+    ) -> CollectionResult<String> {
+        let frame = Frame {
+            collection: self,
+            table_id,
+            content: rule_content,
+            next: 0,
+            output: String::new(),
+            literal_modifiers: Vec::new(),
+            depth: 1,
+            previous_reference: None,
+            rerolls: 0,
+            table: None,
+            after: Vec::new(),
+        };
+        self.run(Step::Frame(frame), rng, &mut Generation::default())
+    }
+
+    /// Drop the space or tab between a rule's weight and its text from its output
+    ///
+    /// Only that one character goes, so further leading whitespace is kept.
+    fn strip_rule_separator(&self, rule_content: &[RuleContent], mut output: String) -> String {
+        let separated = matches!(
+            rule_content.first(),
+            Some(RuleContent::Text(text)) if text.starts_with([' ', '\t'])
+        );
+        if separated && output.starts_with([' ', '\t']) {
+            output.remove(0);
+        }
+        output
+    }
+
+    /// Render content frames until the first one is done, returning its output
+    ///
+    /// `frame` is the innermost frame being rendered and `parents` the frames
+    /// waiting on it, outermost first. A reference pushes a frame for the
+    /// referenced table's rule instead of recursing, so expansion depth costs
+    /// heap memory rather than stack.
+    fn run<'c, R: Rng + ?Sized>(
+        &self,
+        step: Step<'c>,
+        rng: &mut R,
+        generation: &mut Generation,
+    ) -> CollectionResult<String> {
+        let mut frame = match step {
+            Step::Done(output) => return Ok(output),
+            Step::Frame(frame) => frame,
+        };
+        let mut parents: Vec<Frame<'c>> = Vec::new();
+
+        loop {
+            let content = frame.content;
+            match content.get(frame.next) {
+                None => {
+                    let output = frame.finish(generation);
+                    match parents.pop() {
+                        None => return Ok(output),
+                        Some(parent) => {
+                            frame = parent;
+                            frame.accept(output);
+                        }
+                    }
+                }
+                Some(RuleContent::Text(text)) => {
+                    let text = frame
+                        .collection
+                        .render_text(text, &frame.literal_modifiers, false);
+                    frame.output.push_str(&text);
+                    frame.next += 1;
+                }
+                Some(RuleContent::Expression(expression)) => {
+                    let step = frame.collection.start_expression(
+                        &frame,
+                        expression,
+                        rng,
+                        generation,
+                        Vec::new(),
+                    )?;
+                    match step {
+                        Step::Done(output) => frame.accept(output),
+                        Step::Frame(child) => parents.push(std::mem::replace(&mut frame, child)),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Start rendering an expression of `frame`'s content
+    ///
+    /// Returns its output when it needs no further expansion, or else the
+    /// frame that renders it. `after` is applied to the output either way.
+    fn start_expression<'c, R: Rng + ?Sized>(
+        &'c self,
+        frame: &Frame<'c>,
+        expression: &'c Expression,
+        rng: &mut R,
+        generation: &mut Generation,
+        mut after: Vec<After<'c>>,
+    ) -> CollectionResult<Step<'c>> {
+        match expression {
+            Expression::TableReference {
+                table_id,
+                modifiers,
+            } => {
+                let (literal, whole) = self.split_modifiers(modifiers);
+                after.insert(0, After::Modifiers(self, whole));
+                self.table_frame(table_id, rng, literal, frame.depth + 1, generation, after)
+            }
+            Expression::Binding { name, expression } => {
+                after.insert(0, After::Bind(name));
+                self.start_expression(frame, expression, rng, generation, after)
+            }
+            Expression::VariableRef { name, modifiers } => {
+                let value = generation.bindings.get(name).ok_or_else(|| {
+                    CollectionError::UnboundVariable {
+                        name: name.clone(),
+                        table_id: frame.table_id.to_string(),
+                    }
+                })?;
+                let modifiers: Vec<&Modifier> = modifiers.iter().collect();
+                let output = self.apply_modifiers(value, &modifiers);
+                Ok(Step::Done(apply_after(output, after, generation)))
+            }
+            Expression::Conditional {
+                var,
+                equals,
+                then,
+                otherwise,
+            } => {
+                let value = generation.bindings.get(var).ok_or_else(|| {
+                    CollectionError::UnboundVariable {
+                        name: var.clone(),
+                        table_id: frame.table_id.to_string(),
+                    }
+                })?;
+                let branch = if value == equals {
+                    then
+                } else {
+                    match otherwise {
+                        Some(otherwise) => otherwise,
+                        None => {
+                            return Ok(Step::Done(apply_after(String::new(), after, generation)));
+                        }
+                    }
+                };
+                Ok(Step::Frame(frame.nested(self, branch, after)))
+            }
+            Expression::ExternalTableReference {
+                publisher,
+                collection,
+                table_id,
+                modifiers,
+            } => {
+                let dependency = self
+                    .dependencies
+                    .get(&(publisher.clone(), collection.clone()))
+                    .ok_or_else(|| CollectionError::MissingDependency {
+                        publisher: publisher.clone(),
+                        collection: collection.clone(),
+                        table_id: table_id.clone(),
+                        referencing_table: frame.table_id.to_string(),
+                    })?;
+
+                // Generate from the dependency with our RNG, then apply modifiers here
+                let (literal, whole) = self.split_modifiers(modifiers);
+                after.insert(0, After::Modifiers(self, whole));
+                dependency.table_frame(table_id, rng, literal, frame.depth + 1, generation, after)
+            }
+            Expression::DiceRoll {
+                count,
+                sides,
+                keep,
+                modifier,
+                modifiers,
+            } => {
+                let output = self.roll_dice(*count, *sides, *keep, *modifier, modifiers, rng)?;
+                Ok(Step::Done(apply_after(output, after, generation)))
+            }
+            Expression::InlineChoice { options, weights } => {
+                let cumulative_weights: Vec<f64> = weights
+                    .iter()
+                    .zip(options)
+                    .scan(0.0, |total, (weight, option)| {
+                        *total += weight * self.option_bias(option);
+                        Some(*total)
+                    })
+                    .collect();
+                let chosen = cumulative_weights
+                    .last()
+                    .filter(|&&total| total > 0.0)
+                    .and_then(|&total| {
+                        options.get(select_weighted_index(
+                            &cumulative_weights,
+                            rng.gen_range(0.0..total),
+                        ))
+                    });
+                let Some(option) = chosen else {
+                    return Ok(Step::Done(apply_after(String::new(), after, generation)));
+                };
+
+                // Options are literal rule content, so literal modifiers reach them too.
+                // `{a | b}` reads as a choice between "a" and "b", so options are trimmed
+                after.insert(0, After::Trim(self));
+                Ok(Step::Frame(frame.nested(self, option, after)))
+            }
+        }
+    }
+
+    /// Roll a dice expression, returning its total (and breakdown, if annotated) as text
+    fn roll_dice<R: Rng + ?Sized>(
+        &self,
+        count: Option<u32>,
+        sides: u32,
+        keep: Option<KeepMode>,
+        modifier: Option<i32>,
+        modifiers: &[Modifier],
+        rng: &mut R,
+    ) -> CollectionResult<String> {
+        // Roll dice and add the result, in i64 so large dice can't overflow
+        let dice_count = count.unwrap_or(1);
+        if dice_count > self.max_dice {
+            return Err(CollectionError::DiceLimitExceeded {
+                count: dice_count,
+                sides,
+            });
+        }
+        let (total, rolls): (i64, Vec<u32>) = if keep.is_none() && !self.annotate_dice {
+            let mut total = 0;
+            for _ in 0..dice_count {
+                total += i64::from(rng.gen_range(1..=sides));
+            }
+            (total, Vec::new())
+        } else {
+            let rolls: Vec<u32> = (0..dice_count).map(|_| rng.gen_range(1..=sides)).collect();
+            let sum = |rolls: &[u32]| rolls.iter().copied().map(i64::from).sum();
+            let total = match keep {
+                None => sum(&rolls),
+                // Only the kept dice count toward the total
+                Some(keep) => {
+                    let mut sorted = rolls.clone();
+                    sorted.sort_unstable();
+                    let kept = (keep.count() as usize).min(sorted.len());
+                    match keep {
+                        KeepMode::Highest(_) => sum(&sorted[sorted.len() - kept..]),
+                        KeepMode::Lowest(_) => sum(&sorted[..kept]),
+                    }
+                }
+            };
+            (total, rolls)
+        };
+
+        // A penalty can take the total below zero; it isn't clamped
+        let total = total + i64::from(modifier.unwrap_or(0));
+        let modifiers: Vec<&Modifier> = modifiers.iter().collect();
+        let total = self.apply_modifiers(&total.to_string(), &modifiers);
+        // A silenced roll outputs nothing, its breakdown included
+        if !self.annotate_dice || self.is_silent(&modifiers) {
+            return Ok(total);
+        }
+
+        Ok(format!(
+            "{} ({}: {})",
+            total,
+            dice_notation(count, sides, keep, modifier),
+            dice_breakdown(&rolls, keep, modifier)
+        ))
+    }
+
+    /// Render a literal text segment: expand its macros, then apply `literal_modifiers`
+    ///
+    /// With `trim`, surrounding whitespace is removed before the modifiers run.
+    fn render_text(&self, text: &str, literal_modifiers: &[&str], trim: bool) -> String {
+        // Borrowed text is copied once here; text with macros is already owned
+        let mut text = self.expand_macros(text).into_owned();
+        if trim {
+            trim_in_place(&mut text);
+        }
+        self.apply_named_modifiers(text, literal_modifiers)
+    }
+
+    /// Replace `%name%` macros in literal text, expanding macro values recursively
+    ///
+    /// Macros that expand into each other are rejected when they're defined,
+    /// so expansion always ends.
+    fn expand_macros<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if self.macros.is_empty() || !text.contains('%') {
+            return Cow::Borrowed(text);
+        }
+
+        let mut output = String::new();
+        self.expand_macros_into(text, &mut output);
+        Cow::Owned(output)
+    }
+
+    /// Expand `text` into `output`, replacing macros with their expanded values
+    fn expand_macros_into(&self, text: &str, output: &mut String) {
+        let mut rest = text;
+        while let Some((before, _, value, after)) = next_macro(rest, &self.macros) {
+            output.push_str(before);
+            self.expand_macros_into(value, output);
+            rest = after;
+        }
+        output.push_str(rest);
+    }
+
+    /// Split reference-site modifiers into (literal-text-only, whole-result) sets
+    ///
+    /// When modifiers cascade everything applies to the whole expanded result.
+    fn split_modifiers<'a>(&self, modifiers: &'a [Modifier]) -> (Vec<&'a str>, Vec<&'a Modifier>) {
+        if self.cascading_modifiers {
+            return (Vec::new(), modifiers.iter().collect());
+        }
+
+        let (literal, whole): (Vec<&Modifier>, Vec<&Modifier>) = modifiers
+            .iter()
+            .partition(|modifier| matches!(modifier.name.as_str(), "uppercase" | "lowercase"));
+        let literal = literal
+            .into_iter()
+            .map(|modifier| modifier.name.as_str())
+            .collect();
+        (literal, whole)
+    }
+
+    /// Apply a chain of modifiers to generated text, in order
+    ///
+    /// Chains run strictly left to right, each modifier seeing the previous
+    /// one's output: `{#x|indefinite|capitalize}` gives "An apple" while
+    /// `{#x|capitalize|indefinite}` gives "an Apple". `lint_modifiers` flags
+    /// orderings whose result is probably not what was meant.
+    ///
+    /// The exception is `silent`, which wins wherever it appears in the chain:
+    /// the expression has already been expanded, so its side effects happen,
+    /// but it contributes nothing.
+    fn apply_modifiers(&self, text: &str, modifiers: &[&Modifier]) -> String {
+        if self.is_silent(modifiers) {
+            return String::new();
+        }
+
+        let mut text = text.to_string();
+        for modifier in modifiers {
+            text = self.apply_modifier(&text, &modifier.name, modifier.arg.as_deref());
+        }
+        text
+    }
+
+    /// Whether a chain contains the built-in `silent`, which empties the whole output
+    fn is_silent(&self, modifiers: &[&Modifier]) -> bool {
+        modifiers.iter().any(|modifier| modifier.name == "silent")
+            && !self.custom_modifiers.contains_key("silent")
+    }
+
+    /// Apply a chain of modifiers given by name alone, like a table's `finish` flag
+    fn apply_named_modifiers(&self, mut text: String, names: &[&str]) -> String {
+        for name in names {
+            text = self.apply_modifier(&text, name, None);
+        }
+        text
+    }
+
+    /// Apply a modifier to generated text, preferring a registered modifier over a built-in
+    ///
+    /// Registered modifiers don't see `arg`. Built-ins that need one leave text
+    /// unchanged when it's missing or not a number.
+    fn apply_modifier(&self, text: &str, name: &str, arg: Option<&str>) -> String {
+        if let Some(CustomModifier(f)) = self.custom_modifiers.get(name) {
+            return f(text);
+        }
+
+        let count = arg.and_then(|arg| arg.parse::<usize>().ok());
+        match name {
+            "capitalize" => {
+                let mut chars: Vec<char> = text.chars().collect();
+                if let Some(first_char) = chars.get_mut(0) {
+                    *first_char = first_char.to_uppercase().next().unwrap_or(*first_char);
+                }
+                chars.into_iter().collect()
+            }
+            "uppercase" => text.to_uppercase(),
+            "titlecase" => text
+                .split(' ')
+                .map(|word| self.apply_modifier(word, "capitalize", None))
+                .collect::<Vec<_>>()
+                .join(" "),
+            "lowercase" => text.to_lowercase(),
+            "indefinite" => {
+                let first_char = text
+                    .chars()
+                    .next()
+                    .unwrap_or(' ')
+                    .to_lowercase()
+                    .next()
+                    .unwrap_or(' ');
+                let article = if "aeiou".contains(first_char) {
+                    "an"
+                } else {
+                    "a"
+                };
+                format!("{} {}", article, text)
+            }
+            "definite" => format!("the {}", text),
+            // Numeric modifiers leave text that isn't a whole number unchanged
+            "ordinal" => match text.trim().parse::<i64>() {
+                Ok(number) => format!("{}{}", number, ordinal_suffix(number)),
+                Err(_) => text.to_string(),
+            },
+            "number_word" => match text.trim().parse::<i64>() {
+                Ok(number) => number_word(number),
+                Err(_) => text.to_string(),
+            },
+            "repeat" => match count {
+                Some(count) => vec![text; count.min(MAX_MODIFIER_REPEAT)].join(" "),
+                None => text.to_string(),
+            },
+            "truncate" => match count {
+                Some(count) => LengthUnit::Chars.truncate(text, count),
+                None => text.to_string(),
+            },
+            "silent" => String::new(),
+            _ => text.to_string(), // Unknown modifier, return unchanged
+        }
+    }
+
+    /// List the tables a table references directly, without duplicates
+    ///
+    /// Internal tables are listed by ID and external ones as
+    /// `@publisher/collection#table`, in order of first reference.
+    pub fn get_table_dependencies(&self, table_id: &str) -> CollectionResult<Vec<String>> {
+        let table_id = self.table_key(table_id);
+        let table = self
+            .tables
+            .get(&*table_id)
+            .ok_or_else(|| CollectionError::TableNotFound(table_id.to_string()))?;
+        Ok(table
+            .dependency_names()
+            .iter()
+            .map(|name| self.declared_id(name).to_string())
+            .collect())
+    }
+
+    /// Longest chain of nested tables that expanding a table can produce
+    ///
+    /// Counts tables the way `set_max_depth` does: a table without references
+    /// has depth 1, and each nested reference adds one, following external
+    /// references into their collections. A table that can reach a reference
+    /// cycle gives `ReferenceDepth::Cyclic` with the first cycle found, since
+    /// generation may then nest arbitrarily deep; `max_depth` is what bounds
+    /// such tables.
+    pub fn max_reference_depth(&self, table_id: &str) -> CollectionResult<ReferenceDepth> {
+        let (table_id, _) = self
+            .tables
+            .get_key_value(&*self.table_key(table_id))
+            .ok_or_else(|| CollectionError::TableNotFound(table_id.to_string()))?;
+        self.reference_depth(table_id, &mut HashMap::new(), &mut Vec::new())
+    }
+
+    /// Depth-first search behind `max_reference_depth`
+    ///
+    /// `depths` memoizes finished tables and `path` holds the tables being
+    /// expanded, so meeting one of them again means a cycle.
+    fn reference_depth<'a>(
+        &'a self,
+        table_id: &'a str,
+        depths: &mut HashMap<&'a str, ReferenceDepth>,
+        path: &mut Vec<&'a str>,
+    ) -> CollectionResult<ReferenceDepth> {
+        if let Some(depth) = depths.get(table_id) {
+            return Ok(depth.clone());
+        }
+        if let Some(cycle_start) = path.iter().position(|&id| id == table_id) {
+            let cycle = path[cycle_start..]
+                .iter()
+                .chain([&table_id])
+                .map(|id| self.declared_id(id).to_string())
+                .collect();
+            return Ok(ReferenceDepth::Cyclic { cycle });
+        }
+
+        let table = self
+            .tables
+            .get(table_id)
+            .ok_or_else(|| CollectionError::TableNotFound(table_id.to_string()))?;
+        path.push(table_id);
+        let mut deepest = 0;
+        for expression in table
+            .rules
+            .iter()
+            .flat_map(|rule| nested_expressions(&rule.value.content))
+        {
+            let depth = match expression {
+                Expression::TableReference {
+                    table_id: ref_id, ..
+                } => self.reference_depth(ref_id, depths, path)?,
+                Expression::ExternalTableReference {
+                    publisher,
+                    collection,
+                    table_id: ext_table_id,
+                    ..
+                } => self
+                    .dependencies
+                    .get(&(publisher.clone(), collection.clone()))
+                    .ok_or_else(|| CollectionError::MissingDependency {
+                        publisher: publisher.clone(),
+                        collection: collection.clone(),
+                        table_id: ext_table_id.clone(),
+                        referencing_table: table_id.to_string(),
+                    })?
+                    .max_reference_depth(ext_table_id)?,
+                _ => continue,
+            };
+            match depth {
+                ReferenceDepth::Bounded(depth) => deepest = deepest.max(depth),
+                cyclic => {
+                    path.pop();
+                    depths.insert(table_id, cyclic.clone());
+                    return Ok(cyclic);
+                }
+            }
+        }
+
+        path.pop();
+        let depth = ReferenceDepth::Bounded(deepest + 1);
+        depths.insert(table_id, depth.clone());
+        Ok(depth)
+    }
+
+    /// Count how many different outputs a table can produce, or `None` if unbounded
+    ///
+    /// Counts the ways generation can go: each rule contributes the product
+    /// of its references' and inline choices' counts, and a table the sum
+    /// over its rules. Rules and choice options without expressions count
+    /// once per distinct text, so `~` and an empty rule are one variant. When
+    /// different paths render the same string (`{#a}{#b}` giving "ab" two
+    /// ways, say), the count is only an upper bound on the distinct outputs.
+    /// Tables that can reach a dice roll or a reference cycle, or whose count
+    /// overflows `usize`, give `None`. External references are counted in
+    /// their collections, and both branches of a conditional count whatever
+    /// its variable may hold.
+    pub fn count_variants(&self, table_id: &str) -> CollectionResult<Option<usize>> {
+        let (table_id, _) = self
+            .tables
+            .get_key_value(&*self.table_key(table_id))
+            .ok_or_else(|| CollectionError::TableNotFound(table_id.to_string()))?;
+        self.variant_count(table_id, &mut HashMap::new(), &mut HashSet::new())
+    }
+
+    /// Depth-first search behind `count_variants`, memoized like `reference_depth`
+    fn variant_count<'a>(
+        &'a self,
+        table_id: &'a str,
+        counts: &mut HashMap<&'a str, Option<usize>>,
+        active: &mut HashSet<&'a str>,
+    ) -> CollectionResult<Option<usize>> {
+        if let Some(&count) = counts.get(table_id) {
+            return Ok(count);
+        }
+        if !active.insert(table_id) {
+            return Ok(None);
+        }
+
+        let table = self
+            .tables
+            .get(table_id)
+            .ok_or_else(|| CollectionError::TableNotFound(table_id.to_string()))?;
+        let mut total = Some(0usize);
+        let mut literals = HashSet::new();
+        for rule in &table.rules {
+            if literal_text(&rule.value.content).is_some_and(|text| !literals.insert(text)) {
+                continue;
+            }
+            let count =
+                self.content_variant_count(table_id, &rule.value.content, counts, active)?;
+            total = total
+                .zip(count)
+                .and_then(|(total, count)| total.checked_add(count));
+            if total.is_none() {
+                break;
+            }
+        }
+
+        active.remove(table_id);
+        counts.insert(table_id, total);
+        Ok(total)
+    }
+
+    /// Number of ways a piece of rule content can generate, for `count_variants`
+    fn content_variant_count<'a>(
+        &'a self,
+        table_id: &'a str,
+        content: &'a [RuleContent],
+        counts: &mut HashMap<&'a str, Option<usize>>,
+        active: &mut HashSet<&'a str>,
+    ) -> CollectionResult<Option<usize>> {
+        let mut product = Some(1usize);
+        for piece in content {
+            let RuleContent::Expression(expression) = piece else {
+                continue;
+            };
+            let count = self.expression_variant_count(table_id, expression, counts, active)?;
+            product = product
+                .zip(count)
+                .and_then(|(product, count)| product.checked_mul(count));
+            if product.is_none() {
+                break;
+            }
+        }
+        Ok(product)
+    }
+
+    /// Number of ways a single expression can generate, for `count_variants`
+    ///
+    /// A variable only repeats a result bound earlier, so it adds no variants.
+    fn expression_variant_count<'a>(
+        &'a self,
+        table_id: &'a str,
+        expression: &'a Expression,
+        counts: &mut HashMap<&'a str, Option<usize>>,
+        active: &mut HashSet<&'a str>,
+    ) -> CollectionResult<Option<usize>> {
+        Ok(match expression {
+            Expression::TableReference {
+                table_id: ref_id, ..
+            } => self.variant_count(ref_id, counts, active)?,
+            Expression::ExternalTableReference {
+                publisher,
+                collection,
+                table_id: ext_table_id,
+                ..
+            } => self
+                .dependencies
+                .get(&(publisher.clone(), collection.clone()))
+                .ok_or_else(|| CollectionError::MissingDependency {
+                    publisher: publisher.clone(),
+                    collection: collection.clone(),
+                    table_id: ext_table_id.clone(),
+                    referencing_table: table_id.to_string(),
+                })?
+                .count_variants(ext_table_id)?,
+            Expression::DiceRoll { .. } => None,
+            Expression::InlineChoice { options, .. } => {
+                let mut sum = Some(0usize);
+                let mut literals = HashSet::new();
+                for option in options {
+                    // Options are trimmed when rendered, so compare them trimmed
+                    if literal_text(option)
+                        .is_some_and(|text| !literals.insert(text.trim().to_string()))
+                    {
+                        continue;
+                    }
+                    let count = self.content_variant_count(table_id, option, counts, active)?;
+                    sum = sum
+                        .zip(count)
+                        .and_then(|(sum, count)| sum.checked_add(count));
+                }
+                sum
+            }
+            Expression::Binding { expression, .. } => {
+                self.expression_variant_count(table_id, expression, counts, active)?
+            }
+            Expression::VariableRef { .. } => Some(1),
+            // Either branch may be taken, and a missing `otherwise` outputs nothing
+            Expression::Conditional {
+                then, otherwise, ..
+            } => {
+                let then = self.content_variant_count(table_id, then, counts, active)?;
+                let otherwise = match otherwise {
+                    Some(otherwise) => {
+                        self.content_variant_count(table_id, otherwise, counts, active)?
+                    }
+                    None => Some(1),
+                };
+                then.zip(otherwise)
+                    .and_then(|(then, otherwise)| then.checked_add(otherwise))
+            }
+        })
+    }
+
+    /// Map every table to its direct dependencies, as in `get_table_dependencies`
+    pub fn get_all_dependencies(&self) -> HashMap<String, Vec<String>> {
+        self.tables
+            .iter()
+            .map(|(table_id, table)| {
+                let dependencies = table
+                    .dependency_names()
+                    .iter()
+                    .map(|name| self.declared_id(name).to_string())
+                    .collect();
+                (self.declared_id(table_id).to_string(), dependencies)
+            })
+            .collect()
+    }
+
+    /// Compute every table that generating from `table_id` could ever expand into
+    ///
+    /// This follows internal references transitively (breadth-first), so it
+    /// includes indirect dependencies as well as direct ones. The start table
+    /// is only included if it can reach itself through a cycle.
+    pub fn reachable_from(&self, table_id: &str) -> CollectionResult<HashSet<String>> {
+        let table_id = self.table_key(table_id);
+        let start = self
+            .tables
+            .get(&*table_id)
+            .ok_or_else(|| CollectionError::TableNotFound(table_id.to_string()))?;
+
+        let mut reachable = HashSet::new();
+        let mut queue: VecDeque<&OptimizedTable> = VecDeque::from([start]);
+
+        while let Some(table) = queue.pop_front() {
+            for ref_id in table.referenced_table_ids() {
+                if reachable.insert(self.declared_id(ref_id).to_string())
+                    && let Some(referenced) = self.tables.get(ref_id)
+                {
+                    queue.push_back(referenced);
+                }
+            }
+        }
+
+        Ok(reachable)
+    }
+
+    /// Find rules whose source text contains `needle`
+    ///
+    /// Matches against `content_text()`, so expression forms like `{#color}`
+    /// or `{2d6}` are searchable too. Returns `(table_id, rule_index)` pairs in
+    /// source order.
+    pub fn rules_containing(&self, needle: &str) -> Vec<(String, usize)> {
+        self.find_rules(|text| text.contains(needle))
+    }
+
+    /// Case-insensitive variant of [`rules_containing`](Self::rules_containing)
+    pub fn rules_containing_ignore_case(&self, needle: &str) -> Vec<(String, usize)> {
+        let needle = needle.to_lowercase();
+        self.find_rules(|text| text.to_lowercase().contains(&needle))
+    }
+
+    fn find_rules(&self, matches: impl Fn(&str) -> bool) -> Vec<(String, usize)> {
+        let mut found = Vec::new();
+
+        for table_id in &self.table_order {
+            for (index, rule) in self.tables[table_id].rules.iter().enumerate() {
+                if matches(&rule.value.content_text()) {
+                    found.push((self.declared_id(table_id).to_string(), index));
+                }
+            }
+        }
+
+        found
+    }
+
+    /// The trailing line comment on a rule's source line, as a designer annotation
+    ///
+    /// For `1.0: red // the classic` this returns `Some("the classic")`. Only a
+    /// `//` comment on the same line as the rule counts. Reads the retained
+    /// source, so returns `None` after `without_source`, as well as for an
+    /// unknown table or rule index.
+    pub fn rule_annotation(&self, table_id: &str, rule_index: usize) -> Option<&str> {
+        let table_id = self.table_key(table_id);
+        let rule = self.tables.get(&*table_id)?.rules.get(rule_index)?;
+        let source = self.source.as_deref()?;
+
+        // Spans count chars, so convert the rule's start into a byte offset
+        let start = source
+            .char_indices()
+            .nth(rule.span.start)
+            .map_or(source.len(), |(offset, _)| offset);
+        let rest = &source[start..];
+        let line = &rest[..rest.find('\n').unwrap_or(rest.len())];
+
+        // Rule text never contains "//" (the lexer ends text there), so the
+        // first occurrence on the line starts the comment
+        let comment = line[line.find("//")? + 2..].trim();
+        (!comment.is_empty()).then_some(comment)
+    }
+
+    /// Warnings found while building the collection
+    ///
+    /// These never prevent generation. Currently this flags rules whose
+    /// content is only whitespace, like `1.0:    `, which always generate "".
+    pub fn warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+
+    /// Check that every exported table can generate along one fixed path
+    ///
+    /// Each exported table is expanded without randomness: every table on the
+    /// way, including external ones, takes its first rule, every inline
+    /// choice its first option, and every die shows a one. A table whose
+    /// expansion fails is reported as an error, and one that generates only
+    /// whitespace as a warning, each pointing at the table's first rule. An
+    /// empty result means every exported table produced output.
+    ///
+    /// This complements sampling by catching branches that always fail, but a
+    /// path that recurses through first rules can exceed `max_depth` even when
+    /// random generation would terminate. Dry runs don't count toward coverage.
+    pub fn dry_run(&self) -> Vec<Diagnostic> {
+        let collector = DiagnosticCollector::new(self.source.clone().unwrap_or_default());
+        let mut diagnostics = Vec::new();
+
+        for table_id in self.get_exported_table_ids() {
+            let first_rule = &self.tables[&*self.table_key(&table_id)].rules[0];
+            let mut rng = StepRng::new(0, 0);
+            let mut generation = Generation {
+                first_rules: true,
+                ..Generation::default()
+            };
+            let diagnostic = match self.expand_in(&table_id, &mut rng, &mut generation) {
+                Ok(output) if !output.trim().is_empty() => continue,
+                Ok(_) => collector
+                    .semantic_warning_span(
+                        first_rule.span.start,
+                        first_rule.span.end,
+                        format!("Exported table '{}' generates empty output", table_id),
+                    )
+                    .with_suggestion("Give the table's first rule some content".to_string()),
+                Err(error) => collector
+                    .semantic_error_span(
+                        first_rule.span.start,
+                        first_rule.span.end,
+                        format!("Exported table '{}' fails to generate: {}", table_id, error),
+                    )
+                    .with_suggestion(
+                        "Check the tables this rule references, and the rules they pick first"
+                            .to_string(),
+                    ),
+            };
+            diagnostics.push(diagnostic);
+        }
+
+        diagnostics
+    }
+
+    /// Find rules whose content is entirely whitespace
+    fn blank_rule_warnings(&self) -> Vec<Diagnostic> {
+        let collector = DiagnosticCollector::new(self.source.clone().unwrap_or_default());
+        let mut diagnostics = Vec::new();
+
+        for table_id in &self.table_order {
+            for rule in &self.tables[table_id].rules {
+                // A rule with no content at all is an intentional `~`
+                let blank = !rule.value.content.is_empty()
+                    && rule.value.content.iter().all(
+                    |content| matches!(content, RuleContent::Text(text) if text.trim().is_empty()),
+                );
+                if blank {
+                    diagnostics.push(
+                        collector
+                            .semantic_warning_span(
+                                rule.span.start,
+                                rule.span.end,
+                                format!("Rule in table '{}' has no content", table_id),
+                            )
+                            .with_suggestion(
+                                "Add text after the ':' or remove the rule".to_string(),
+                            ),
+                    );
+                }
+            }
+        }
+
+        diagnostics
+    }
+
+    /// Describe the collection as a tool-neutral JSON grammar
+    ///
+    /// Each table becomes a production with weighted alternatives, in source
+    /// order. An alternative is a sequence of symbols, each an object whose
+    /// `type` is one of:
+    ///
+    /// - `terminal`: literal `text`
+    /// - `nonterminal`: a reference to the production `name`
+    /// - `external`: a reference to table `name` of another collection, by
+    ///   `publisher` and `collection`
+    /// - `dice`: a roll of `count` dice with `sides` sides, an optional `keep`
+    ///   (`mode` and `count`) and a flat `modifier`
+    /// - `choice`: `options`, each a sequence of symbols, with their `weights`
+    /// - `binding`: a `symbol` whose output is kept under `name`
+    /// - `variable`: the output bound to `name`
+    /// - `conditional`: `then` when `variable` equals `equals`, otherwise
+    ///   `otherwise` (null if not written), both sequences of symbols
+    ///
+    /// References, dice and variables carry their `modifiers` as a list of
+    /// names. Macros are listed separately and left unexpanded in terminal
+    /// text.
+    #[cfg(feature = "serde")]
+    pub fn export_grammar(&self) -> String {
+        use serde_json::{Value, json};
+
+        /// Modifiers as written in source, like `truncate:10`
+        fn modifier_list(modifiers: &[Modifier]) -> Vec<String> {
+            modifiers.iter().map(ToString::to_string).collect()
+        }
+
+        fn symbol(collection: &Collection, content: &RuleContent) -> Value {
+            let symbols = |content: &[RuleContent]| {
+                content
+                    .iter()
+                    .map(|content| symbol(collection, content))
+                    .collect::<Vec<_>>()
+            };
+            match content {
+                RuleContent::Text(text) => json!({ "type": "terminal", "text": text }),
+                RuleContent::Expression(Expression::TableReference {
+                    table_id,
+                    modifiers,
+                }) => json!({
+                    "type": "nonterminal",
+                    "name": collection.declared_id(table_id),
+                    "modifiers": modifier_list(modifiers),
+                }),
+                RuleContent::Expression(Expression::ExternalTableReference {
+                    publisher,
+                    collection,
+                    table_id,
+                    modifiers,
+                }) => json!({
+                    "type": "external",
+                    "publisher": publisher,
+                    "collection": collection,
+                    "name": table_id,
+                    "modifiers": modifier_list(modifiers),
+                }),
+                RuleContent::Expression(Expression::DiceRoll {
+                    count,
+                    sides,
+                    keep,
+                    modifier,
+                    modifiers,
+                }) => {
+                    let keep = keep.map(|keep| match keep {
+                        KeepMode::Highest(n) => json!({ "mode": "highest", "count": n }),
+                        KeepMode::Lowest(n) => json!({ "mode": "lowest", "count": n }),
+                    });
+                    json!({
+                        "type": "dice",
+                        "count": count.unwrap_or(1),
+                        "sides": sides,
+                        "keep": keep,
+                        "modifier": modifier.unwrap_or(0),
+                        "modifiers": modifier_list(modifiers),
+                    })
+                }
+                RuleContent::Expression(Expression::InlineChoice { options, weights }) => json!({
+                    "type": "choice",
+                    "weights": weights,
+                    "options": options
+                        .iter()
+                        .map(|option| symbols(option))
+                        .collect::<Vec<_>>(),
+                }),
+                RuleContent::Expression(Expression::Binding { name, expression }) => json!({
+                    "type": "binding",
+                    "name": name,
+                    "symbol": symbol(
+                        collection,
+                        &RuleContent::Expression(expression.as_ref().clone())
+                    ),
+                }),
+                RuleContent::Expression(Expression::VariableRef { name, modifiers }) => json!({
+                    "type": "variable",
+                    "name": name,
+                    "modifiers": modifier_list(modifiers),
+                }),
+                RuleContent::Expression(Expression::Conditional {
+                    var,
+                    equals,
+                    then,
+                    otherwise,
+                }) => json!({
+                    "type": "conditional",
+                    "variable": var,
+                    "equals": equals,
+                    "then": symbols(then),
+                    "otherwise": otherwise.as_ref().map(|otherwise| symbols(otherwise)),
+                }),
+            }
+        }
+
+        let productions: Vec<Value> = self
+            .table_order
+            .iter()
+            .map(|table_id| {
+                let table = &self.tables[table_id];
+                let alternatives: Vec<Value> = table
+                    .rules
+                    .iter()
+                    .map(|rule| {
+                        json!({
+                            "weight": rule.value.weight,
+                            "symbols": rule
+                                .value
+                                .content
+                                .iter()
+                                .map(|content| symbol(self, content))
+                                .collect::<Vec<_>>(),
+                        })
+                    })
+                    .collect();
+
+                json!({
+                    "name": self.declared_id(table_id),
+                    "exported": table.metadata.export,
+                    "weight": table.metadata.default_weight.unwrap_or(1.0),
+                    "finish_modifiers": table.metadata.finish_modifiers,
+                    "attributes": table.metadata.attributes,
+                    "alternatives": alternatives,
+                })
+            })
+            .collect();
+
+        let mut macros: Vec<(&String, &String)> = self.macros.iter().collect();
+        macros.sort();
+
+        json!({
+            "format": "tbl-grammar",
+            "version": 1,
+            "productions": productions,
+            "macros": macros
+                .into_iter()
+                .map(|(name, value)| json!({ "name": name, "value": value }))
+                .collect::<Vec<_>>(),
+        })
+        .to_string()
+    }
+
+    /// Run every lint: unused tables, then modifier chains
+    ///
+    /// Like the individual lints, this never generates; every finding is a
+    /// `Severity::Warning` diagnostic.
+    pub fn lint(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = self.lint_unused_tables();
+        diagnostics.extend(self.lint_modifiers());
+        diagnostics
+    }
+
+    /// Warn about tables that are neither exported nor referenced by another table
+    ///
+    /// Nothing can generate from such a table except a direct call, so it is
+    /// usually a leftover helper. A table that only references itself still
+    /// counts as unused. Each warning spans the table's declaration.
+    pub fn lint_unused_tables(&self) -> Vec<Diagnostic> {
+        let collector = DiagnosticCollector::new(self.source.clone().unwrap_or_default());
+
+        let referenced: HashSet<&str> = self
+            .tables
+            .iter()
+            .flat_map(|(table_id, table)| {
+                table
+                    .referenced_table_ids()
+                    .filter(move |ref_id| ref_id != table_id)
+            })
+            .collect();
+
+        self.table_order
+            .iter()
+            .filter(|table_id| {
+                !self.tables[*table_id].metadata.export && !referenced.contains(table_id.as_str())
+            })
+            .map(|table_id| {
+                let span = self.tables[table_id].span;
+                let table_id = self.declared_id(table_id);
+                collector
+                    .semantic_warning_span(
+                        span.start,
+                        span.end,
+                        format!("Table '{}' is never referenced and not exported", table_id),
+                    )
+                    .with_suggestion(format!(
+                        "Mark it as #{}[export] if it is meant to be used directly, or remove it",
+                        table_id
+                    ))
+            })
+            .collect()
+    }
+
+    /// Lint modifier chains for redundant, cancelling, or likely-mistaken combinations
+    ///
+    /// This is purely analytical and never generates. Each finding is a
+    /// `Severity::Warning` diagnostic spanning the expression with the
+    /// modifiers, or its whole rule if the expression's span is unknown.
+    pub fn lint_modifiers(&self) -> Vec<Diagnostic> {
+        let collector = DiagnosticCollector::new(self.source.clone().unwrap_or_default());
+        let mut diagnostics = Vec::new();
+
+        for table_id in &self.table_order {
+            let table = &self.tables[table_id];
+            for rule in &table.rules {
+                for (expression, span) in rule.value.expressions() {
+                    let span = span.unwrap_or(rule.span);
+                    let modifiers = expression.modifiers();
+
+                    for modifier in modifiers {
+                        if let Some((message, suggestion)) = modifier_argument_warning(modifier) {
+                            diagnostics.push(
+                                collector
+                                    .semantic_warning_span(span.start, span.end, message)
+                                    .with_suggestion(suggestion),
+                            );
+                        }
+                        if !MODIFIER_KEYWORDS.contains(&modifier.name.as_str())
+                            && !self.custom_modifiers.contains_key(&modifier.name)
+                        {
+                            diagnostics.push(
+                                collector
+                                    .semantic_warning_span(
+                                        span.start,
+                                        span.end,
+                                        format!(
+                                            "Unknown modifier '{}' fails generation until it is registered",
+                                            modifier.name
+                                        ),
+                                    )
+                                    .with_suggestion(format!(
+                                        "Register it with Collection::register_modifier, or use one of: {}",
+                                        MODIFIER_KEYWORDS.join(", ")
+                                    )),
+                            );
+                        }
+                    }
+
+                    for (message, suggestion) in modifier_chain_warnings(modifiers) {
+                        diagnostics.push(
+                            collector
+                                .semantic_warning_span(span.start, span.end, message)
+                                .with_suggestion(suggestion),
+                        );
+                    }
+                }
+            }
+        }
+
+        diagnostics
+    }
+
+    /// Check that every reference resolves and no table recurses forever
+    ///
+    /// `Collection::new` does this while building; collections built with
+    /// `CollectionBuilder::defer_validation` skip it until this is called,
+    /// typically after merging every part together. Fails with the same
+    /// errors building would, such as `InvalidTableReference` or
+    /// `InfiniteRecursion`. Once it succeeds, later `merge`s validate again.
+    pub fn validate(&mut self) -> CollectionResult<()> {
+        Self::validate_tables(&self.tables, &self.table_order, &self.dependencies)?;
+        self.references_validated = true;
+        Ok(())
+    }
+
+    /// Validate references, then reject tables that can never finish expanding
+    fn validate_tables(
+        tables: &HashMapType<String, OptimizedTable>,
+        table_order: &[String],
+        dependencies: &HashMap<(String, String), Collection>,
+    ) -> CollectionResult<()> {
+        Self::validate_table_references(tables, dependencies)?;
+        Self::detect_infinite_recursion(tables, table_order)
+    }
+
+    /// Validate that all table references point to existing tables
+    fn validate_table_references(
+        tables: &HashMapType<String, OptimizedTable>,
+        dependencies: &HashMap<(String, String), Collection>,
+    ) -> CollectionResult<()> {
+        for (table_id, table) in tables {
+            for rule in &table.rules {
+                for expression in nested_expressions(&rule.value.content) {
+                    match expression {
+                        Expression::TableReference {
+                            table_id: ref_id,
+                            modifiers: _,
+                        } if !tables.contains_key(ref_id) => {
+                            return Err(CollectionError::InvalidTableReference {
+                                table_id: ref_id.clone(),
+                                referencing_table: table_id.clone(),
+                            });
+                        }
+                        Expression::ExternalTableReference {
+                            publisher,
+                            collection,
+                            table_id: ext_table_id,
+                            modifiers: _,
+                        } => match dependencies.get(&(publisher.clone(), collection.clone())) {
+                            None => {
+                                return Err(CollectionError::MissingDependency {
+                                    publisher: publisher.clone(),
+                                    collection: collection.clone(),
+                                    table_id: ext_table_id.clone(),
+                                    referencing_table: table_id.clone(),
+                                });
+                            }
+                            Some(dependency) if !dependency.exports_table(ext_table_id) => {
+                                return Err(CollectionError::ExternalTableNotFound {
+                                    publisher: publisher.clone(),
+                                    collection: collection.clone(),
+                                    table_id: ext_table_id.clone(),
+                                    referencing_table: table_id.clone(),
+                                });
+                            }
+                            Some(_) => {}
+                        },
+                        _ => {} // Other expressions (dice rolls, choices) don't need validation
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reject tables that can never finish expanding
+    ///
+    /// A table can terminate if at least one of its rules only references
+    /// tables that can terminate (external references are assumed to). Any
+    /// table left over after that fixed point recurses forever whichever rule
+    /// is picked, so the first such table in source order is reported along
+    /// with the reference cycle it gets stuck in. Tables with at least one
+    /// non-recursive rule are fine, since they terminate with probability 1.
+    fn detect_infinite_recursion(
+        tables: &HashMapType<String, OptimizedTable>,
+        table_order: &[String],
+    ) -> CollectionResult<()> {
+        let mut terminating: HashSet<&str> = HashSet::new();
+
+        loop {
+            let before = terminating.len();
+            // Later tables are usually the leaves, so visiting them first lets
+            // top-down reference chains settle in a single pass
+            for table_id in table_order.iter().rev() {
+                if terminating.contains(table_id.as_str()) {
+                    continue;
+                }
+                let can_terminate = tables[table_id]
+                    .rules
+                    .iter()
+                    .any(|rule| content_can_terminate(&rule.value.content, &terminating));
+                if can_terminate {
+                    terminating.insert(table_id);
+                }
+            }
+            if terminating.len() == before {
+                break;
+            }
+        }
+
+        let Some(start) = table_order
+            .iter()
+            .find(|table_id| !terminating.contains(table_id.as_str()))
+        else {
+            return Ok(());
+        };
+
+        // Every rule of a non-terminating table references another non-terminating
+        // table, so following those references must eventually revisit a table
+        let mut path: Vec<&str> = vec![start];
+        loop {
+            let current = path[path.len() - 1];
+            let next = internal_references(&tables[current].rules[0].value.content)
+                .find(|ref_id| !terminating.contains(ref_id))
+                .expect("non-terminating rule references a non-terminating table");
+
+            if let Some(cycle_start) = path.iter().position(|&table_id| table_id == next) {
+                let mut cycle: Vec<String> = path[cycle_start..]
+                    .iter()
+                    .map(|id| id.to_string())
+                    .collect();
+                cycle.push(next.to_string());
+                return Err(CollectionError::InfiniteRecursion { cycle });
+            }
+            path.push(next);
+        }
+    }
+
+    /// Check if a table exists in the collection
+    pub fn has_table(&self, table_id: &str) -> bool {
+        self.tables.contains_key(self.table_key(table_id).as_ref())
+    }
+
+    /// Check if a table exists and is marked `[export]`
+    pub fn exports_table(&self, table_id: &str) -> bool {
+        self.tables
+            .get(self.table_key(table_id).as_ref())
+            .is_some_and(|table| table.metadata.export)
+    }
+
+    /// Get a list of all table IDs in the collection
+    pub fn get_table_ids(&self) -> Vec<String> {
+        // Return table IDs in the order they appear in the source, as declared
+        self.table_order
+            .iter()
+            .map(|table_id| self.declared_id(table_id).to_string())
+            .collect()
+    }
+
+    /// The parsed rules of a table, in source order, for inspecting its AST
+    ///
+    /// Rules keep their spans and expression spans from parsing, so tools can
+    /// inspect a collection without parsing its source again. Returns `None`
+    /// for an unknown table.
+    pub fn table_rules(&self, table_id: &str) -> Option<&[Node<Rule>]> {
+        let table_id = self.table_key(table_id);
+        self.tables
+            .get(&*table_id)
+            .map(|table| table.rules.as_slice())
+    }
+
+    /// Weight of a table when choosing between tables, from its `[weight: N]` flag
+    ///
+    /// Tables without the flag weigh 1.0.
+    pub fn table_weight(&self, table_id: &str) -> CollectionResult<f64> {
+        let table_id = self.table_key(table_id);
+        self.tables
+            .get(&*table_id)
+            .map(|table| table.metadata.default_weight.unwrap_or(1.0))
+            .ok_or_else(|| CollectionError::TableNotFound(table_id.to_string()))
+    }
+
+    /// A table's `key=value` attributes, for tools that sort or filter tables
+    ///
+    /// Values are kept as written, without quotes, so `min_level=5` gives
+    /// `"5"`. Returns `None` for an unknown table.
+    pub fn table_attributes(&self, table_id: &str) -> Option<&HashMap<String, String>> {
+        let table_id = self.table_key(table_id);
+        self.tables
+            .get(&*table_id)
+            .map(|table| &table.metadata.attributes)
+    }
+
+    /// Get a list of exported table IDs in the collection
+    pub fn get_exported_table_ids(&self) -> Vec<String> {
+        // Return exported table IDs in the order they appear in the source
+        self.table_order
+            .iter()
+            .filter(|table_id| self.tables[table_id.as_str()].metadata.export)
+            .map(|table_id| self.declared_id(table_id).to_string())
+            .collect()
+    }
+}
+
+/// Writes the same text as `to_source`, without checking that its ids parse back
+impl std::fmt::Display for Collection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source_program().to_source())
+    }
+}
+
+/// Combine a seed with a counter into a well-distributed 64-bit seed
+///
+/// SplitMix64-style mixing, so neighbouring counters get unrelated streams.
+fn mix_seed(seed: u64, counter: u64) -> u64 {
+    let mut z = seed ^ counter.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// FNV-1a hash of a string, stable across builds and Rust versions
+/// (unlike `DefaultHasher`)
+fn stable_hash(text: &str) -> u64 {
+    text.bytes().fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    })
+}
+
+/// Suffix of an ordinal number: "st" for 1 and 21, "th" for 11, 12 and 13
+fn ordinal_suffix(number: i64) -> &'static str {
+    let number = number.unsigned_abs();
+    match (number % 10, number % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    }
+}
+
+/// Spell out a whole number in English: 3 is "three", 121 is "one hundred twenty-one"
+fn number_word(number: i64) -> String {
+    const ONES: [&str; 20] = [
+        "zero",
+        "one",
+        "two",
+        "three",
+        "four",
+        "five",
+        "six",
+        "seven",
+        "eight",
+        "nine",
+        "ten",
+        "eleven",
+        "twelve",
+        "thirteen",
+        "fourteen",
+        "fifteen",
+        "sixteen",
+        "seventeen",
+        "eighteen",
+        "nineteen",
+    ];
+    const TENS: [&str; 10] = [
+        "", "", "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety",
+    ];
+    const SCALES: [(u64, &str); 6] = [
+        (1_000_000_000_000_000_000, "quintillion"),
+        (1_000_000_000_000_000, "quadrillion"),
+        (1_000_000_000_000, "trillion"),
+        (1_000_000_000, "billion"),
+        (1_000_000, "million"),
+        (1_000, "thousand"),
+    ];
+
+    fn below_thousand(number: u64) -> String {
+        let mut words = Vec::new();
+        if number >= 100 {
+            words.push(format!("{} hundred", ONES[(number / 100) as usize]));
+        }
+        match number % 100 {
+            0 => {}
+            n @ 1..20 => words.push(ONES[n as usize].to_string()),
+            n if n % 10 == 0 => words.push(TENS[(n / 10) as usize].to_string()),
+            n => words.push(format!(
+                "{}-{}",
+                TENS[(n / 10) as usize],
+                ONES[(n % 10) as usize]
+            )),
+        }
+        words.join(" ")
+    }
+
+    if number == 0 {
+        return ONES[0].to_string();
+    }
+
+    let mut words = Vec::new();
+    if number < 0 {
+        words.push("minus".to_string());
+    }
+    let mut rest = number.unsigned_abs();
+    for (scale, name) in SCALES {
+        if rest >= scale {
+            words.push(format!("{} {}", below_thousand(rest / scale), name));
+            rest %= scale;
+        }
+    }
+    if rest > 0 {
+        words.push(below_thousand(rest));
+    }
+    words.join(" ")
+}
+
+/// Whether a table id can be declared in source, as `#id`
+fn is_table_name(table_id: &str) -> bool {
+    !table_id.is_empty()
+        && table_id
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Strip leading and trailing whitespace without copying the rest of the text
+fn trim_in_place(text: &mut String) {
+    text.truncate(text.trim_end().len());
+    let leading = text.len() - text.trim_start().len();
+    text.drain(..leading);
+}
+
+/// The text of rule content without expressions, or `None` if it has any
+fn literal_text(content: &[RuleContent]) -> Option<String> {
+    content
+        .iter()
+        .map(|part| match part {
+            RuleContent::Text(text) => Some(text.as_str()),
+            RuleContent::Expression(_) => None,
+        })
+        .collect()
+}
+
+/// List a roll's dice in the order rolled, then its flat modifier, as in `4+[1]+4+1+2`
+///
+/// Dice a `kh`/`kl` suffix drops are bracketed. Among equal dice, the ones
+/// rolled first are dropped by `kh` and the ones rolled last by `kl`.
+fn dice_breakdown(rolls: &[u32], keep: Option<KeepMode>, modifier: Option<i32>) -> String {
+    let mut dropped = vec![false; rolls.len()];
+    if let Some(keep) = keep {
+        let mut order: Vec<usize> = (0..rolls.len()).collect();
+        order.sort_by_key(|&index| rolls[index]);
+        let kept = (keep.count() as usize).min(rolls.len());
+        let drops = match keep {
+            KeepMode::Highest(_) => &order[..rolls.len() - kept],
+            KeepMode::Lowest(_) => &order[kept..],
+        };
+        for &index in drops {
+            dropped[index] = true;
+        }
+    }
+
+    let mut breakdown = rolls
+        .iter()
+        .zip(dropped)
+        .map(|(roll, dropped)| {
+            if dropped {
+                format!("[{}]", roll)
+            } else {
+                roll.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("+");
+    if let Some(modifier) = modifier {
+        breakdown.push_str(&format!("{:+}", modifier));
+    }
+    breakdown
+}
+
+/// Every use of a modifier that isn't built in, as (modifier, table, rule), in source order
+///
+/// Covers `finish` flags and the modifiers of references and dice rolls.
+fn unknown_modifier_uses(
+    tables: &HashMapType<String, OptimizedTable>,
+    table_order: &[String],
+) -> Vec<(String, String, Option<usize>)> {
+    let mut uses = Vec::new();
+    for table_id in table_order {
+        let table = &tables[table_id];
+        let finish_modifiers = table
+            .metadata
+            .finish_modifiers
+            .iter()
+            .map(|name| (name, None));
+        let reference_modifiers = table.rules.iter().enumerate().flat_map(|(index, rule)| {
+            nested_expressions(&rule.value.content)
+                .into_iter()
+                .flat_map(Expression::modifiers)
+                .map(move |modifier| (&modifier.name, Some(index)))
+        });
+        for (name, rule_index) in finish_modifiers.chain(reference_modifiers) {
+            if !MODIFIER_KEYWORDS.contains(&name.as_str()) {
+                uses.push((name.clone(), table_id.clone(), rule_index));
+            }
+        }
+    }
+    uses
+}
+
+/// Find the first `%name%` reference to a defined macro in `text`
+///
+/// Returns the text before it, the macro's name and value, and the text after
+/// it. A '%' that doesn't start a macro reference is kept in the text before.
+fn next_macro<'a, 'm>(
+    text: &'a str,
+    macros: &'m HashMap<String, String>,
+) -> Option<(&'a str, &'m String, &'m String, &'a str)> {
+    let mut offset = 0;
+    while let Some(start) = text[offset..].find('%') {
+        let after = &text[offset + start + 1..];
+        let name_len = after
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+            .unwrap_or(after.len());
+        if after[name_len..].starts_with('%')
+            && let Some((name, value)) = macros.get_key_value(&after[..name_len])
+        {
+            return Some((&text[..offset + start], name, value, &after[name_len + 1..]));
+        }
+        offset += start + 1;
+    }
+    None
+}
+
+/// Find macros that expand into each other, as the chain of names around a cycle
+fn macro_cycle(macros: &HashMap<String, String>) -> Option<Vec<String>> {
+    let mut names: Vec<&String> = macros.keys().collect();
+    names.sort();
+    let mut finished = HashSet::new();
+    names
+        .into_iter()
+        .find_map(|name| macro_cycle_from(name, macros, &mut Vec::new(), &mut finished))
+}
+
+/// Depth-first search behind `macro_cycle`, with `path` holding the macros being expanded
+fn macro_cycle_from<'m>(
+    name: &'m String,
+    macros: &'m HashMap<String, String>,
+    path: &mut Vec<&'m String>,
+    finished: &mut HashSet<&'m String>,
+) -> Option<Vec<String>> {
+    if finished.contains(name) {
+        return None;
+    }
+    if let Some(start) = path.iter().position(|active| *active == name) {
+        let mut cycle: Vec<String> = path[start..].iter().map(|name| name.to_string()).collect();
+        cycle.push(name.clone());
+        return Some(cycle);
+    }
+
+    path.push(name);
+    let mut rest = macros[name].as_str();
+    while let Some((_, used, _, after)) = next_macro(rest, macros) {
+        if let Some(cycle) = macro_cycle_from(used, macros, path, finished) {
+            return Some(cycle);
+        }
+        rest = after;
+    }
+    path.pop();
+    finished.insert(name);
+    None
+}
+
+/// Index of the entry a random value in `0.0..total` lands on, by binary search
+/// over cumulative weights
+fn select_weighted_index(cumulative_weights: &[f64], random_value: f64) -> usize {
+    match cumulative_weights.binary_search_by(|&weight| {
+        if weight < random_value {
+            std::cmp::Ordering::Less
+        } else {
+            std::cmp::Ordering::Greater
+        }
+    }) {
+        Ok(index) => index,
+        Err(index) => index.min(cumulative_weights.len() - 1),
+    }
+}
+
+/// Internal table ids referenced by a rule's content, including inside inline choices, in order
+fn internal_references(content: &[RuleContent]) -> impl Iterator<Item = &str> {
+    nested_expressions(content)
+        .into_iter()
+        .filter_map(|expression| match expression {
+            Expression::TableReference { table_id, .. } => Some(table_id.as_str()),
+            _ => None,
+        })
+}
+
+/// Point every internal reference in `tables` at the id `rename` maps it to
+fn rename_references(
+    tables: &mut HashMapType<String, OptimizedTable>,
+    rename: impl Fn(&str) -> String,
+) {
+    for table in tables.values_mut() {
+        for rule in &mut table.rules {
+            let renamed = try_for_each_expression_mut(&mut rule.value.content, &mut |expression| {
+                if let Expression::TableReference { table_id, .. } = expression {
+                    *table_id = rename(table_id);
+                }
+                Ok::<(), std::convert::Infallible>(())
+            });
+            let Ok(()) = renamed;
+        }
+    }
+}
+
+/// Whether content can finish expanding, given the tables known to terminate
+///
+/// Every reference outside a choice must terminate, while a choice only
+/// needs one option that can.
+fn content_can_terminate(content: &[RuleContent], terminating: &HashSet<&str>) -> bool {
+    content.iter().all(|content| match content {
+        RuleContent::Expression(expression) => expression_can_terminate(expression, terminating),
+        RuleContent::Text(_) => true,
+    })
+}
+
+/// Whether an expression can finish expanding, for `content_can_terminate`
+fn expression_can_terminate(expression: &Expression, terminating: &HashSet<&str>) -> bool {
+    match expression {
+        Expression::TableReference { table_id, .. } => terminating.contains(table_id.as_str()),
+        Expression::InlineChoice { options, .. } => options
+            .iter()
+            .any(|option| content_can_terminate(option, terminating)),
+        Expression::Binding { expression, .. } => expression_can_terminate(expression, terminating),
+        Expression::Conditional {
+            then, otherwise, ..
+        } => {
+            content_can_terminate(then, terminating)
+                || otherwise
+                    .as_ref()
+                    .is_none_or(|otherwise| content_can_terminate(otherwise, terminating))
+        }
+        _ => true,
+    }
+}
+
+/// Describe a built-in modifier's missing, invalid or unexpected argument
+fn modifier_argument_warning(modifier: &Modifier) -> Option<(String, String)> {
+    let name = &modifier.name;
+    match (name.as_str(), modifier.arg.as_deref()) {
+        ("repeat" | "truncate", Some(arg)) if arg.parse::<usize>().is_ok() => None,
+        ("repeat" | "truncate", _) => Some((
+            format!("Modifier '{}' needs a whole number argument", name),
+            format!("Write it like '|{}:3'", name),
+        )),
+        (_, Some(arg)) if MODIFIER_KEYWORDS.contains(&name.as_str()) => Some((
+            format!("Modifier '{}' ignores its argument '{}'", name, arg),
+            format!("Remove ':{}'", arg),
+        )),
+        _ => None,
+    }
+}
+
+/// Inspect a modifier chain and describe any suspicious combinations
+///
+/// Modifiers apply left to right, so only the last case modifier decides the
+/// final casing, and an article added after `capitalize` stays lowercase.
+fn modifier_chain_warnings(modifiers: &[Modifier]) -> Vec<(String, String)> {
+    let mut warnings = Vec::new();
+    let is_case = |m: &str| matches!(m, "capitalize" | "titlecase" | "uppercase" | "lowercase");
+    let is_article = |m: &str| matches!(m, "indefinite" | "definite");
+
+    let names: Vec<&str> = modifiers.iter().map(|m| m.name.as_str()).collect();
+    for (i, &modifier) in names.iter().enumerate() {
+        let earlier = &names[..i];
+
+        if earlier.contains(&modifier) {
+            warnings.push((
+                format!("Modifier '{}' is applied more than once", modifier),
+                format!("Remove the repeated '|{}'", modifier),
+            ));
+            continue;
+        }
+
+        match modifier {
+            "uppercase" | "lowercase" => {
+                if let Some(previous) = earlier.iter().rev().find(|m| is_case(m)) {
+                    warnings.push((
+                        format!("'{}' overrides the earlier '{}'", modifier, previous),
+                        format!("Remove '|{}' from the chain", previous),
+                    ));
+                }
+            }
+            "capitalize" | "titlecase" if earlier.contains(&"uppercase") => {
+                warnings.push((
+                    format!("'{}' has no effect after 'uppercase'", modifier),
+                    format!("Remove '|{}' from the chain", modifier),
+                ));
+            }
+            "indefinite" | "definite" => {
+                if let Some(previous) = earlier.iter().find(|m| is_article(m)) {
+                    warnings.push((
+                        format!("Both '{}' and '{}' add an article", previous, modifier),
+                        "Use only one of 'indefinite' or 'definite'".to_string(),
+                    ));
+                } else if let Some(previous) = earlier
+                    .iter()
+                    .find(|m| matches!(**m, "capitalize" | "titlecase"))
+                {
+                    warnings.push((
+                        format!(
+                            "'{}' before '{}' capitalizes the word, not the article",
+                            previous, modifier
+                        ),
+                        format!("Did you mean '|{}|{}'?", modifier, previous),
+                    ));
+                }
+            }
+            _ => {}
+        }
+
+        if modifier == "uppercase" && earlier.iter().any(|m| is_article(m)) {
+            warnings.push((
+                "'uppercase' after an article modifier uppercases the article too".to_string(),
+                "Apply 'uppercase' before the article modifier if only the word should change"
+                    .to_string(),
+            ));
+        }
+    }
+
+    if names.contains(&"silent") && names.iter().any(|&m| m != "silent") {
+        warnings.push((
+            "Modifiers alongside 'silent' have no effect".to_string(),
+            "Remove the other modifiers, since 'silent' discards the output".to_string(),
+        ));
+    }
+
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collection_creation() {
+        let source = r#"#color
+1.0: red
+2.0: blue
+3.0: green"#;
+
+        let collection = Collection::new(source);
+        assert!(collection.is_ok());
+
+        let collection = collection.unwrap();
+        assert!(collection.tables.contains_key("color"));
+    }
+
+    #[test]
+    fn test_simple_generation() {
+        let source = r#"#color
+1.0: red
+2.0: blue
+3.0: green"#;
+
+        let mut collection = Collection::new(source).unwrap();
+        let result = collection.generate("color", 1);
+        assert!(result.is_ok());
+
+        let generated = result.unwrap();
+        assert!(generated == "red" || generated == "blue" || generated == "green");
+    }
+
+    #[test]
+    fn test_table_reference() {
+        let source = r#"#color
+1.0: red
+2.0: blue
+
+#shape
+1.0: circle
+2.0: square
+
+#item
+1.0: {#color} {#shape}"#;
+
+        let mut collection = Collection::new(source).unwrap();
+        let result = collection.generate("item", 1);
+        assert!(result.is_ok());
+
+        let generated = result.unwrap();
+        // Should contain a color and a shape
+        assert!(generated.contains("red") || generated.contains("blue"));
+        assert!(generated.contains("circle") || generated.contains("square"));
+    }
+
+    #[test]
+    fn test_multiple_generation() {
+        let source = r#"#color
+1.0: red"#;
+
+        let mut collection = Collection::new(source).unwrap();
+        let result = collection.generate("color", 3);
+        assert!(result.is_ok());
+
+        let generated = result.unwrap();
+        assert_eq!(generated, "red, red, red");
+    }
+
+    #[test]
+    fn test_roll_single_result() {
+        let source = r#"#color
+1.0: red"#;
+
+        let mut collection = Collection::new(source).unwrap();
+        assert_eq!(collection.roll("color").unwrap(), "red");
+
+        let result = collection.roll("nonexistent");
+        assert!(matches!(result, Err(CollectionError::TableNotFound(_))));
+    }
+
+    #[test]
+    fn test_lint_modifiers() {
+        let source = r#"#animal
+1.0: cat
+
+#phrases
+1.0: a {#animal|uppercase|lowercase}
+1.0: {#animal|capitalize|capitalize}
+1.0: {#animal|indefinite|uppercase}
+1.0: {#animal|capitalize|definite}
+1.0: {#animal|indefinite|capitalize}
+1.0: {#animal|lowercase|capitalize}"#;
+
+        let collection = Collection::new(source).unwrap();
+        let diagnostics = collection.lint_modifiers();
+
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "'lowercase' overrides the earlier 'uppercase'",
+                "Modifier 'capitalize' is applied more than once",
+                "'uppercase' after an article modifier uppercases the article too",
+                "'capitalize' before 'definite' capitalizes the word, not the article",
+            ]
+        );
+
+        assert!(
+            diagnostics
+                .iter()
+                .all(|d| d.severity() == crate::diagnostic::Severity::Warning)
+        );
+        assert_eq!(diagnostics[0].location.line, 5);
+        assert_eq!(diagnostics[3].location.line, 8);
+        // Warnings point at the expression, not its whole rule
+        assert_eq!(diagnostics[0].location.column, 8);
+        assert_eq!(diagnostics[0].location.end_column, Some(37));
+    }
+
+    #[test]
+    fn test_collection_is_send_and_sync() {
+        // `generate_indexed_batch` takes `&self` so batches can be split across threads
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Collection>();
+    }
+
+    #[test]
+    fn test_generate_indexed_batch_is_reproducible() {
+        let source = r#"#tile
+1.0: grass
+1.0: water
+1.0: forest {d20}
+1.0: mountain"#;
+
+        let collection = Collection::new(source).unwrap();
+        let indices: Vec<u64> = (0..50).collect();
+
+        let first = collection.generate_indexed_batch("tile", &indices).unwrap();
+        let second = collection.generate_indexed_batch("tile", &indices).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.len(), indices.len());
+
+        // Each output depends only on its own index, not on its batch neighbours
+        let single = collection.generate_indexed_batch("tile", &[17]).unwrap();
+        assert_eq!(single[0], first[17]);
+
+        // Different indices should not all collapse to the same output
+        assert!(first.iter().any(|r| r != &first[0]));
+
+        let missing = collection.generate_indexed_batch("nonexistent", &[0]);
+        assert!(matches!(missing, Err(CollectionError::TableNotFound(_))));
+    }
+
+    #[test]
+    fn test_rules_containing() {
+        let source = r#"#monster
+1.0: goblin
+2.0: Red Dragon
+1.0: dragon whelp
+
+#lair
+1.0: cave of the {#monster|capitalize}
+1.0: {2d6} gold coins"#;
+
+        let collection = Collection::new(source).unwrap();
+
+        assert_eq!(
+            collection.rules_containing("dragon"),
+            vec![("monster".to_string(), 2)]
+        );
+        assert_eq!(
+            collection.rules_containing_ignore_case("DRAGON"),
+            vec![("monster".to_string(), 1), ("monster".to_string(), 2)]
+        );
+
+        // Expression source forms are searchable
+        assert_eq!(
+            collection.rules_containing("{#monster"),
+            vec![("lair".to_string(), 0)]
+        );
+        assert_eq!(
+            collection.rules_containing("2d6"),
+            vec![("lair".to_string(), 1)]
+        );
+        assert!(collection.rules_containing("unicorn").is_empty());
+    }
+
+    #[test]
+    fn test_with_rng_algorithm() {
+        let source = r#"#color
+1.0: red
+2.0: blue
+3.0: green"#;
+
+        let collection = Collection::new(source).unwrap();
+        assert_eq!(collection.rng_algorithm(), RngKind::Small);
+
+        let mut collection = collection.with_rng_algorithm(RngKind::Std);
+        assert_eq!(collection.rng_algorithm(), RngKind::Std);
+
+        for _ in 0..20 {
+            let generated = collection.roll("color").unwrap();
+            assert!(["red", "blue", "green"].contains(&generated.as_str()));
+        }
+
+        // Indexed generation follows the selected algorithm and stays reproducible
+        let indices: Vec<u64> = (0..20).collect();
+        let first = collection
+            .generate_indexed_batch("color", &indices)
+            .unwrap();
+        let collection = collection
+            .with_rng_algorithm(RngKind::Small)
+            .with_rng_algorithm(RngKind::Std);
+        let second = collection
+            .generate_indexed_batch("color", &indices)
+            .unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_draw_without_replacement() {
+        let source = r#"#encounter
+1.0: goblins
+5.0: wolves
+0.5: dragon
+
+#card
+1.0: {#encounter} attack"#;
+
+        let mut collection = Collection::new(source).unwrap();
+        assert_eq!(collection.remaining("encounter").unwrap(), 3);
+
+        let mut drawn: Vec<String> = (0..3)
+            .map(|_| collection.draw("encounter").unwrap())
+            .collect();
+        assert_eq!(collection.remaining("encounter").unwrap(), 0);
+
+        drawn.sort();
+        assert_eq!(drawn, vec!["dragon", "goblins", "wolves"]);
+
+        // An exhausted deck reshuffles on the next draw
+        collection.draw("encounter").unwrap();
+        assert_eq!(collection.remaining("encounter").unwrap(), 2);
+
+        collection.reshuffle("encounter").unwrap();
+        assert_eq!(collection.remaining("encounter").unwrap(), 3);
+
+        // Drawn rules are still expanded
+        let card = collection.draw("card").unwrap();
+        assert!(card.ends_with(" attack"));
+
+        assert!(matches!(
+            collection.draw("nonexistent"),
+            Err(CollectionError::TableNotFound(_))
+        ));
+        assert!(collection.reshuffle("nonexistent").is_err());
+        assert!(collection.remaining("nonexistent").is_err());
+    }
+
+    #[test]
+    fn test_generation_hook_applies_to_final_output_only() {
+        let source = r#"#noun
+1.0: dragon
+
+#sentence
+1.0: the {#noun} sleeps"#;
+
+        let mut collection = Collection::new(source).unwrap();
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorder = seen.clone();
+        collection.set_generation_hook(Box::new(move |output| {
+            recorder.lock().unwrap().push(output.clone());
+            format!("<{}>", output)
+        }));
+
+        assert_eq!(
+            collection.generate("sentence", 2).unwrap(),
+            "<the dragon sleeps>, <the dragon sleeps>"
+        );
+        assert_eq!(collection.roll("noun").unwrap(), "<dragon>");
+        assert_eq!(collection.draw("noun").unwrap(), "<dragon>");
+
+        // The nested {#noun} expansion never reached the hook
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec!["the dragon sleeps", "the dragon sleeps", "dragon", "dragon"]
+        );
+
+        collection.clear_generation_hook();
+        assert_eq!(collection.roll("noun").unwrap(), "dragon");
+
+        // Results rejected by an output limit never reach the hook
+        let mut collection = Collection::with_seed("#t\n1: ok\n9: far too long", 5).unwrap();
+        let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = calls.clone();
+        collection.set_generation_hook(Box::new(move |output| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            format!("<{}>", output)
+        }));
+        collection.set_max_output_len(Some(OutputLimit::chars(2)));
+        assert_eq!(
+            collection.generate("t", 5).unwrap(),
+            "<ok>, <ok>, <ok>, <ok>, <ok>"
+        );
+        assert_eq!(calls.load(std::sync::atomic::Ordering::Relaxed), 5);
+    }
+
+    /// Build a chain where `t0` references `t1`, ... up to a plain-text `t{depth}`
+    fn deep_chain_source(depth: usize) -> String {
+        let mut source = String::new();
+        for i in 0..depth {
+            source.push_str(&format!("#t{}\n1.0: {{#t{}}}\n\n", i, i + 1));
+        }
+        source.push_str(&format!("#t{}\n1.0: bottom", depth));
+        source
+    }
+
+    #[test]
+    fn test_deep_reference_chain_200() {
+        let mut collection = Collection::new(&deep_chain_source(200)).unwrap();
+        collection.set_max_depth(201);
+        assert_eq!(collection.roll("t0").unwrap(), "bottom");
+    }
+
+    #[test]
+    fn test_deep_reference_chain_500() {
+        let mut collection = Collection::new(&deep_chain_source(500)).unwrap();
+        collection.set_max_depth(501);
+        assert_eq!(collection.roll("t0").unwrap(), "bottom");
+        assert_eq!(collection.generate("t250", 2).unwrap(), "bottom, bottom");
+
+        let indexed = collection.generate_indexed_batch("t0", &[0, 1]).unwrap();
+        assert_eq!(indexed, vec!["bottom", "bottom"]);
+    }
+
+    #[test]
+    fn test_deep_reference_chain_on_small_stack() {
+        // Expansion keeps its frames on the heap, so even a small thread stack
+        // handles chains far deeper than the default limit
+        let chain = std::thread::Builder::new()
+            .stack_size(256 << 10)
+            .spawn(|| {
+                let mut collection = Collection::new(&deep_chain_source(5000)).unwrap();
+                collection.set_max_depth(5001);
+                assert_eq!(collection.roll("t0").unwrap(), "bottom");
+
+                collection.set_max_depth(4000);
+                assert!(matches!(
+                    collection.roll("t0"),
+                    Err(CollectionError::MaxDepthExceeded { depth: 4000, .. })
+                ));
+            })
+            .unwrap();
+        chain.join().unwrap();
+    }
+
+    #[test]
+    fn test_pure_text_fast_path_matches_full_expansion() {
+        let source = r#"#constant
+1.0:   padded constant   
+
+#mixed
+1.0:   padded {#constant} mixed   "#;
+
+        let mut collection = Collection::new(source).unwrap();
+        assert_eq!(collection.roll("constant").unwrap(), "padded constant");
+        // Only the outer result is trimmed; the referenced one keeps its padding
+        assert_eq!(
+            collection.roll("mixed").unwrap(),
+            "padded   padded constant    mixed"
+        );
+
+        // Trimming reuses the output's buffer
+        let mut output = String::with_capacity(64);
+        output.push_str(" \u{3000}wide space\t\n");
+        let buffer = output.as_ptr();
+        trim_in_place(&mut output);
+        assert_eq!(output, "wide space");
+        assert_eq!((output.as_ptr(), output.capacity()), (buffer, 64));
+    }
+
+    #[test]
+    fn test_reachable_from() {
+        let source = r#"#color
+1.0: red
+
+#adjective
+1.0: {#color}
+1.0: shiny
+
+#item
+1.0: {#adjective} sword
+1.0: {#adjective} {#color} shield
+
+#loop
+1.0: {#loop} again
+1.0: done
+
+#unrelated
+1.0: nothing"#;
+
+        let collection = Collection::new(source).unwrap();
+
+        let reachable = collection.reachable_from("item").unwrap();
+        let expected: HashSet<String> = ["adjective", "color"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(reachable, expected);
+
+        assert!(collection.reachable_from("color").unwrap().is_empty());
+        assert_eq!(
+            collection.reachable_from("loop").unwrap(),
+            HashSet::from(["loop".to_string()])
+        );
+
+        assert!(matches!(
+            collection.reachable_from("nonexistent"),
+            Err(CollectionError::TableNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_call_hashing_replays_call_sequences() {
+        let source = r#"#name
+1.0: Ada
+1.0: Brin
+1.0: Cole
+1.0: Dara
+
+#title
+1.0: {#name} the {d100}th"#;
+
+        let replay = |collection: &mut Collection| {
+            vec![
+                collection.generate("name", 3).unwrap(),
+                collection.roll("title").unwrap(),
+                collection.roll("name").unwrap(),
+            ]
+        };
+
+        let mut first = Collection::new(source).unwrap();
+        let mut second = Collection::new(source).unwrap();
+        first.set_call_hashing(true);
+        second.set_call_hashing(true);
+
+        let outputs = replay(&mut first);
+        assert_eq!(outputs, replay(&mut second));
+
+        // Re-enabling resets the counter so the sequence replays on one instance too
+        first.set_call_hashing(true);
+        assert_eq!(outputs, replay(&mut first));
+
+        // Successive calls still vary rather than repeating one result
+        let many: HashSet<String> = (0..20).map(|_| first.roll("name").unwrap()).collect();
+        assert!(many.len() > 1);
+    }
+
+    #[test]
+    fn test_required_dependencies() {
+        let source = r#"#weapon
+1.0: {@alice/weapons#sword}
+1.0: {@bob/armor#shield|capitalize} and {@alice/weapons#axe}
+
+#local
+1.0: {#weapon} with {@carol/gems#ruby}"#;
+
+        // `new` rejects unresolved externals, but dependency discovery does not
+        assert!(Collection::new(source).is_err());
+
+        let dependencies = Collection::required_dependencies(source).unwrap();
+        assert_eq!(
+            dependencies,
+            vec![
+                ("alice".to_string(), "weapons".to_string()),
+                ("bob".to_string(), "armor".to_string()),
+                ("carol".to_string(), "gems".to_string()),
+            ]
+        );
+
+        assert!(
+            Collection::required_dependencies("#plain\n1.0: text")
+                .unwrap()
+                .is_empty()
+        );
+        assert!(Collection::required_dependencies("#broken\n1.0 missing colon").is_err());
+    }
+
+    #[test]
+    fn test_with_external_resolves_dependencies() {
+        let weapons = Collection::new("#sword[export]\n1.0: longsword").unwrap();
+        let mut dependencies = HashMap::new();
+        dependencies.insert(("alice".to_string(), "weapons".to_string()), weapons);
+
+        let source = r#"#loot
+1.0: {@alice/weapons#sword|indefinite} and {d1} coin"#;
+
+        let mut collection = Collection::with_external(source, dependencies).unwrap();
+        assert_eq!(collection.roll("loot").unwrap(), "a longsword and 1 coin");
+    }
+
+    #[test]
+    fn test_with_external_reports_unresolved_references() {
+        let dependencies = || {
+            let weapons =
+                Collection::new("#sword[export]\n1.0: longsword\n#axe\n1.0: hatchet").unwrap();
+            HashMap::from([(("alice".to_string(), "weapons".to_string()), weapons)])
+        };
+
+        let missing_table = "#loot\n1.0: {@alice/weapons#bow}";
+        match Collection::with_external(missing_table, dependencies()) {
+            Err(CollectionError::ExternalTableNotFound {
+                table_id,
+                referencing_table,
+                ..
+            }) => {
+                assert_eq!(table_id, "bow");
+                assert_eq!(referencing_table, "loot");
+            }
+            other => panic!("Expected ExternalTableNotFound, got {:?}", other),
+        }
+
+        // Tables that exist but aren't exported can't be referenced either
+        assert!(matches!(
+            Collection::with_external("#loot\n1.0: {@alice/weapons#axe}", dependencies()),
+            Err(CollectionError::ExternalTableNotFound { .. })
+        ));
+
+        let missing_collection = "#loot\n1.0: {@bob/armor#shield}";
+        assert!(matches!(
+            Collection::with_external(missing_collection, dependencies()),
+            Err(CollectionError::MissingDependency { .. })
+        ));
+    }
+
+    #[test]
+    fn test_with_dependencies() {
+        let weapons = || Collection::new("#sword[export]\n1.0: longsword").unwrap();
+        let source = "#loot\n1.0: {@alice/weapons#sword|uppercase}";
+
+        let dependencies = HashMap::from([("@alice/weapons".to_string(), weapons())]);
+        let mut collection = Collection::with_dependencies(source, dependencies).unwrap();
+        assert_eq!(collection.roll("loot").unwrap(), "LONGSWORD");
+
+        for key in ["alice/weapons", "@alice", "@/weapons", "@alice/", "@a/b/c"] {
+            let dependencies = HashMap::from([(key.to_string(), weapons())]);
+            match Collection::with_dependencies(source, dependencies) {
+                Err(CollectionError::InvalidDependencyKey(bad)) => assert_eq!(bad, key),
+                other => panic!("Expected InvalidDependencyKey for {}, got {:?}", key, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_missing_dependency_names_referencing_table() {
+        let referencing_table = |result: CollectionResult<String>| match result {
+            Err(CollectionError::MissingDependency {
+                table_id,
+                referencing_table,
+                ..
+            }) => {
+                assert_eq!(table_id, "sword");
+                referencing_table
+            }
+            other => panic!("Expected MissingDependency, got {:?}", other),
+        };
+
+        for table in ["loot", "armory"] {
+            let source = format!("#{}\n1.0: {{@alice/weapons#sword}}", table);
+            let error = Collection::new(&source).map(|_| String::new());
+            assert_eq!(referencing_table(error), table);
+        }
+
+        // Generation reports the table being expanded, not the referenced one
+        let weapons = Collection::new("#sword[export]\n1.0: longsword").unwrap();
+        let source = "#loot\n1.0: {@alice/weapons#sword}\n#armory\n1.0: {@alice/weapons#sword}";
+        let mut collection = Collection::with_dependencies(
+            source,
+            HashMap::from([("@alice/weapons".to_string(), weapons)]),
+        )
+        .unwrap();
+        collection.dependencies.clear();
+        assert_eq!(referencing_table(collection.roll("loot")), "loot");
+        assert_eq!(referencing_table(collection.roll("armory")), "armory");
+    }
+
+    #[test]
+    fn test_integer_weights() {
+        let source = r#"#outcome
+3: win
+1.0: lose
+
+#roll
+1: {#outcome}"#;
+
+        let mut collection = Collection::new(source).unwrap();
+        collection.set_integer_weights(true).unwrap();
+
+        let mut wins = 0;
+        for _ in 0..4000 {
+            match collection.roll("roll").unwrap().as_str() {
+                "win" => wins += 1,
+                "lose" => {}
+                other => panic!("Unexpected output {}", other),
+            }
+        }
+        // Expect 3000 wins; allow generous statistical slack
+        assert!((2700..=3300).contains(&wins), "wins = {}", wins);
+
+        let mut fractional = Collection::new("#a\n1: x\n\n#t\n1.5: a\n2: b").unwrap();
+        match fractional.set_integer_weights(true) {
+            Err(CollectionError::NonIntegerWeight { table_id, weight }) => {
+                assert_eq!(table_id, "t");
+                assert_eq!(weight, 1.5);
+            }
+            other => panic!("Expected NonIntegerWeight, got {:?}", other),
+        }
+        // A failed switch leaves every table as it was
+        assert!(
+            fractional
+                .tables
+                .values()
+                .all(|table| table.integer_cumulative_weights.is_none())
+        );
+        assert!(["a", "b"].contains(&fractional.roll("t").unwrap().as_str()));
+
+        // Disabling integer mode works for any weights
+        assert!(fractional.set_integer_weights(false).is_ok());
+
+        // Turning it off again switches every table back
+        collection.set_integer_weights(false).unwrap();
+        assert!(
+            collection
+                .tables
+                .values()
+                .all(|table| table.integer_cumulative_weights.is_none())
+        );
+    }
+
+    #[test]
+    fn test_integer_weight_boundaries() {
+        let mut table = OptimizedTable::from_table(
+            Table::new(
+                crate::ast::TableMetadata::new("t".to_string()),
+                vec![
+                    crate::ast::Node::new(
+                        crate::ast::Rule::new_text(2.0, "a".to_string()),
+                        crate::ast::Span::new(0, 0),
+                    ),
+                    crate::ast::Node::new(
+                        crate::ast::Rule::new_text(1.0, "b".to_string()),
+                        crate::ast::Span::new(0, 0),
+                    ),
+                ],
+            ),
+            Span::new(0, 0),
+        )
+        .unwrap();
+        let cumulative = table.integer_weights().unwrap();
+        assert_eq!(cumulative, vec![2, 3]);
+        table.integer_cumulative_weights = Some(cumulative);
+
+        // `gen_range(0..3)` maps a draw `v` to floor(3v / 2^64), so these draws
+        // give the values 0, 1 and 2: the first two select rule 0, the last rule 1
+        let index_of = |draw: u64| table.choose_rule_index(&mut StepRng::new(draw, 0));
+        assert_eq!(index_of(0), 0);
+        assert_eq!(index_of(0x8000_0000_0000_0000), 0);
+        assert_eq!(index_of(0xC000_0000_0000_0000), 1);
+    }
+
+    #[test]
+    fn test_modifiers_cascade_into_nested_references() {
+        let source = r#"#noun
+1.0: cat
+
+#sentence
+1.0: the {#noun} runs
+
+#shout
+1.0: {#sentence|uppercase}
+
+#story
+1.0: {#sentence|capitalize}"#;
+
+        // Default: modifiers apply to the fully expanded result
+        let mut collection = Collection::new(source).unwrap();
+        assert_eq!(collection.generate("shout", 1).unwrap(), "THE CAT RUNS");
+        assert_eq!(collection.generate("story", 1).unwrap(), "The cat runs");
+
+        // Non-cascading: case modifiers only touch the sentence's own text
+        let mut collection = Collection::new(source)
+            .unwrap()
+            .with_cascading_modifiers(false);
+        assert_eq!(collection.generate("shout", 1).unwrap(), "THE cat RUNS");
+        assert_eq!(collection.generate("story", 1).unwrap(), "The cat runs");
+    }
+
+    #[test]
+    fn test_non_cascading_modifiers_stop_at_one_level() {
+        let source = r#"#adjective
+1.0: red
+
+#noun
+1.0: {#adjective} ball
+
+#sentence
+1.0: a {#noun|uppercase}
+
+#wrapper
+1.0: {#sentence|lowercase}"#;
+
+        let mut collection = Collection::new(source)
+            .unwrap()
+            .with_cascading_modifiers(false);
+        // `uppercase` reaches "ball" but not the adjective nested below it
+        assert_eq!(collection.generate("sentence", 1).unwrap(), "a red BALL");
+        // `lowercase` on the wrapper only reaches "a", not the uppercased noun
+        assert_eq!(collection.generate("wrapper", 1).unwrap(), "a red BALL");
+
+        let mut collection = Collection::new(source).unwrap();
+        assert_eq!(collection.generate("wrapper", 1).unwrap(), "a red ball");
+    }
+
+    #[test]
+    fn test_new_with_limits() {
+        let source = "#a\n1: x\n2: y\n\n#b\n1: z";
+
+        assert!(Collection::new_with_limits(source, Limits::default()).is_ok());
+        assert!(
+            Collection::new_with_limits(
+                source,
+                Limits {
+                    max_source_len: source.len(),
+                    max_tables: 2,
+                    max_rules_per_table: 2,
+                    ..Limits::default()
+                }
+            )
+            .is_ok()
+        );
+
+        let cases = [
+            (
+                Limits {
+                    max_source_len: 10,
+                    ..Limits::default()
+                },
+                "source length",
+                10,
+            ),
+            (
+                Limits {
+                    max_tables: 1,
+                    ..Limits::default()
+                },
+                "tables",
+                1,
+            ),
+            (
+                Limits {
+                    max_rules_per_table: 1,
+                    ..Limits::default()
+                },
+                "rules per table",
+                1,
+            ),
+        ];
+
+        for (limits, expected_which, expected_limit) in cases {
+            match Collection::new_with_limits(source, limits) {
+                Err(CollectionError::LimitExceeded { which, limit }) => {
+                    assert_eq!(which, expected_which);
+                    assert_eq!(limit, expected_limit);
+                }
+                other => panic!("Expected LimitExceeded, got {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_generate_frequencies() {
+        let source = "#coin\n9: heads\n1: tails\n\n#fixed\n1: a\n1: a\n1: b";
+        let mut collection = Collection::new(source).unwrap();
+
+        let frequencies = collection.generate_frequencies("coin", 500).unwrap();
+        assert_eq!(frequencies.iter().map(|(_, n)| n).sum::<usize>(), 500);
+        assert_eq!(frequencies[0].0, "heads");
+        assert!(frequencies.len() <= 2);
+
+        // Duplicate rules with identical output are merged into one entry
+        let frequencies = collection.generate_frequencies("fixed", 300).unwrap();
+        assert_eq!(frequencies.len(), 2);
+        assert_eq!(frequencies[0].0, "a");
+
+        assert!(
+            collection
+                .generate_frequencies("coin", 0)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(collection.generate_frequencies("missing", 1).is_err());
+    }
+
+    #[test]
+    fn test_explain_selection() {
+        let collection = Collection::new("#t\n2: first\n1: second {#u}\n\n#u\n1: x").unwrap();
+
+        let explanation = collection.explain_selection("t", 0.5).unwrap();
+        assert_eq!(explanation.total_weight, 3.0);
+        assert_eq!(explanation.cumulative_weights, vec![2.0, 3.0]);
+        assert_eq!(explanation.rule_index, 0);
+        assert_eq!(explanation.rule_content, "first");
+
+        // A value exactly on a cumulative boundary belongs to the rule ending there
+        assert_eq!(
+            collection.explain_selection("t", 0.0).unwrap().rule_index,
+            0
+        );
+        assert_eq!(
+            collection.explain_selection("t", 2.0).unwrap().rule_index,
+            0
+        );
+        let explanation = collection.explain_selection("t", 2.0001).unwrap();
+        assert_eq!(explanation.rule_index, 1);
+        assert_eq!(explanation.rule_content, "second {#u}");
+
+        assert!(collection.explain_selection("t", 3.0).is_none());
+        assert!(collection.explain_selection("t", -0.1).is_none());
+        assert!(collection.explain_selection("t", f64::NAN).is_none());
+        assert!(collection.explain_selection("missing", 0.0).is_none());
+    }
+
+    #[test]
+    fn test_finish_modifiers() {
+        let source = r#"#title[finish: titlecase]
+1.0: the {#noun} of doom
+
+#noun
+1.0: tower
+
+#heading[export finish: uppercase]
+1.0: {#title|lowercase}
+
+#line
+1.0: chapter one: {#title}"#;
+
+        let mut collection = Collection::new(source).unwrap();
+        assert_eq!(
+            collection.generate("title", 1).unwrap(),
+            "The Tower Of Doom"
+        );
+        // Finish modifiers also apply when the table is referenced
+        assert_eq!(
+            collection.generate("line", 1).unwrap(),
+            "chapter one: The Tower Of Doom"
+        );
+        // Reference-site modifiers run after the referenced table's finish
+        assert_eq!(
+            collection.generate("heading", 1).unwrap(),
+            "THE TOWER OF DOOM"
+        );
+        assert_eq!(collection.draw("title").unwrap(), "The Tower Of Doom");
+        assert_eq!(collection.get_exported_table_ids(), vec!["heading"]);
+    }
+
+    #[test]
+    fn test_modifier_pairs_apply_left_to_right() {
+        // (first, second, output for "apple pie", whether the chain is linted)
+        let cases = [
+            ("capitalize", "uppercase", "APPLE PIE", true),
+            ("capitalize", "lowercase", "apple pie", true),
+            ("capitalize", "titlecase", "Apple Pie", false),
+            ("capitalize", "indefinite", "an Apple pie", true),
+            ("capitalize", "definite", "the Apple pie", true),
+            ("uppercase", "capitalize", "APPLE PIE", true),
+            ("uppercase", "lowercase", "apple pie", true),
+            ("uppercase", "titlecase", "APPLE PIE", true),
+            ("uppercase", "indefinite", "an APPLE PIE", false),
+            ("uppercase", "definite", "the APPLE PIE", false),
+            ("lowercase", "capitalize", "Apple pie", false),
+            ("lowercase", "uppercase", "APPLE PIE", true),
+            ("lowercase", "titlecase", "Apple Pie", false),
+            ("lowercase", "indefinite", "an apple pie", false),
+            ("lowercase", "definite", "the apple pie", false),
+            ("titlecase", "capitalize", "Apple Pie", false),
+            ("titlecase", "uppercase", "APPLE PIE", true),
+            ("titlecase", "lowercase", "apple pie", true),
+            ("titlecase", "indefinite", "an Apple Pie", true),
+            ("titlecase", "definite", "the Apple Pie", true),
+            ("indefinite", "capitalize", "An apple pie", false),
+            ("indefinite", "uppercase", "AN APPLE PIE", true),
+            ("indefinite", "lowercase", "an apple pie", false),
+            ("indefinite", "titlecase", "An Apple Pie", false),
+            ("indefinite", "definite", "the an apple pie", true),
+            ("definite", "capitalize", "The apple pie", false),
+            ("definite", "uppercase", "THE APPLE PIE", true),
+            ("definite", "lowercase", "the apple pie", false),
+            ("definite", "titlecase", "The Apple Pie", false),
+            ("definite", "indefinite", "a the apple pie", true),
+        ];
+        assert_eq!(cases.len(), 30); // Every ordered pair of distinct built-ins
+
+        let mut source = String::from("#word\n1: apple pie\n");
+        for (i, (first, second, _, _)) in cases.iter().enumerate() {
+            source.push_str(&format!(
+                "\n#pair{}\n1: {{#word|{}|{}}}\n",
+                i, first, second
+            ));
+        }
+        let mut collection = Collection::new(&source).unwrap();
+
+        for (i, (first, second, expected, linted)) in cases.iter().enumerate() {
+            let output = collection.generate(&format!("pair{}", i), 1).unwrap();
+            assert_eq!(output, *expected, "{}|{}", first, second);
+
+            let chain = [Modifier::new(*first), Modifier::new(*second)];
+            assert_eq!(
+                !modifier_chain_warnings(&chain).is_empty(),
+                *linted,
+                "lint for {}|{}",
+                first,
+                second
+            );
+        }
+    }
+
+    #[test]
+    fn test_modifier_triples_compose_left_to_right() {
+        let collection = Collection::new("#t\n1: x").unwrap();
+        let apply =
+            |chain: &[&str]| collection.apply_named_modifiers("apple pie".to_string(), chain);
+
+        // Each chain and its reverse give different results, so the order is pinned
+        let chains: [(&[&str], &str); 8] = [
+            (&["uppercase", "lowercase", "capitalize"], "Apple pie"),
+            (&["capitalize", "lowercase", "uppercase"], "APPLE PIE"),
+            (&["uppercase", "indefinite", "titlecase"], "An APPLE PIE"),
+            (&["titlecase", "indefinite", "uppercase"], "AN APPLE PIE"),
+            (&["definite", "indefinite", "capitalize"], "A the apple pie"),
+            (
+                &["capitalize", "indefinite", "definite"],
+                "the an Apple pie",
+            ),
+            (&["definite", "uppercase", "definite"], "the THE APPLE PIE"),
+            (&["lowercase", "titlecase", "titlecase"], "Apple Pie"),
+        ];
+        for (chain, expected) in chains {
+            assert_eq!(apply(chain), expected, "{}", chain.join("|"));
+        }
+
+        assert_eq!(
+            apply(&["lowercase", "indefinite", "capitalize"]),
+            "An apple pie"
+        );
+        assert_eq!(
+            apply(&["titlecase", "definite", "capitalize"]),
+            "The Apple Pie"
+        );
+        assert_eq!(
+            apply(&["indefinite", "uppercase", "lowercase"]),
+            "an apple pie"
+        );
+        assert_eq!(
+            apply(&["capitalize", "indefinite", "titlecase"]),
+            "An Apple Pie"
+        );
+    }
+
+    #[test]
+    fn test_generate_fitting() {
+        let source = "#name\n1: Al\n1: Bartholomew Featherstonehaugh";
+        let mut collection = Collection::new(source).unwrap();
+
+        for _ in 0..20 {
+            let name = collection
+                .generate_fitting("name", OutputLimit::bytes(5))
+                .unwrap();
+            assert_eq!(name, "Al");
+        }
+
+        let result = collection.generate_fitting("name", OutputLimit::bytes(1));
+        assert!(matches!(result, Err(CollectionError::GenerationError(_))));
+
+        collection.set_max_output_len(Some(OutputLimit::chars(12).truncating()));
+        for _ in 0..20 {
+            let name = collection.roll("name").unwrap();
+            assert!(name == "Al" || name == "Bartholomew…", "{}", name);
+        }
+        collection.set_max_output_len(None);
+        assert_eq!(
+            collection.generate_frequencies("name", 50).unwrap().len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_length_unit_truncate() {
+        let text = "héllo wörld";
+        assert_eq!(LengthUnit::Bytes.measure(text), 13);
+        assert_eq!(LengthUnit::Chars.measure(text), 11);
+
+        assert_eq!(LengthUnit::Chars.truncate(text, 11), text);
+        assert_eq!(LengthUnit::Chars.truncate(text, 7), "héllo…");
+        // Byte truncation never splits a multi-byte character
+        assert_eq!(LengthUnit::Bytes.truncate(text, 6), "hé…");
+        // Too small for the ellipsis itself
+        assert_eq!(LengthUnit::Bytes.truncate(text, 2), "h");
+    }
+
+    #[cfg(feature = "graphemes")]
+    #[test]
+    fn test_grapheme_length_limits() {
+        // A family emoji is one grapheme made of several chars
+        let source = "#icon\n1: 👨‍👩‍👧 family";
+        let mut collection = Collection::new(source).unwrap();
+
+        assert_eq!(LengthUnit::Graphemes.measure("👨‍👩‍👧 family"), 8);
+        assert!(LengthUnit::Chars.measure("👨‍👩‍👧 family") > 8);
+
+        let output = collection
+            .generate_fitting("icon", OutputLimit::graphemes(8))
+            .unwrap();
+        assert_eq!(output, "👨‍👩‍👧 family");
+
+        let output = collection
+            .generate_fitting("icon", OutputLimit::graphemes(3).truncating())
+            .unwrap();
+        assert_eq!(output, "👨‍👩‍👧…");
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let source = "#color\n1: red\n1: blue\n\n#item\n1: {#color} ball";
+        let mut collection = Collection::new(source).unwrap();
+        collection.set_integer_weights(true).unwrap();
+        let mut reference = Collection::new(source).unwrap();
+        reference.set_integer_weights(true).unwrap();
+        reference.rng = RngKind::Small.seeded(7);
+        collection.rng = RngKind::Small.seeded(7);
+
+        assert_eq!(
+            collection.generate("item", 3).unwrap(),
+            reference.generate("item", 3).unwrap()
+        );
+        collection.draw("color").unwrap();
+        reference.draw("color").unwrap();
+
+        // The RNG continues where it left off
+        collection.rebuild().unwrap();
+        assert_eq!(
+            collection.generate("item", 3).unwrap(),
+            reference.generate("item", 3).unwrap()
+        );
+        assert_eq!(collection.get_table_ids(), vec!["color", "item"]);
+        assert_eq!(collection.remaining("color").unwrap(), 2);
+        assert!(
+            collection.tables["color"]
+                .integer_cumulative_weights
+                .is_some()
+        );
+
+        let mut collection = collection.without_source();
+        assert!(matches!(
+            collection.rebuild(),
+            Err(CollectionError::SourceNotRetained)
+        ));
+        assert!(collection.generate("item", 1).is_ok());
+    }
+
+    #[test]
+    fn test_rule_annotation() {
+        let source = r#"// Palette for the café scene
+#color
+1.0: red // the classic
+2.0: blue
+1.0: {#shade} grün //   a nested pick  
+1.0: white //
+
+#shade
+1.0: pale"#;
+
+        let collection = Collection::new(source).unwrap();
+        assert_eq!(collection.rule_annotation("color", 0), Some("the classic"));
+        assert_eq!(collection.rule_annotation("color", 1), None);
+        assert_eq!(
+            collection.rule_annotation("color", 2),
+            Some("a nested pick")
+        );
+        assert_eq!(collection.rule_annotation("color", 3), None);
+        assert_eq!(collection.rule_annotation("shade", 0), None);
+        assert_eq!(collection.rule_annotation("color", 4), None);
+        assert_eq!(collection.rule_annotation("missing", 0), None);
+
+        let collection = collection.without_source();
+        assert_eq!(collection.rule_annotation("color", 0), None);
+    }
+
+    #[test]
+    fn test_with_seed_is_reproducible() {
+        let source = "#color\n1: red\n1: blue\n1: green\n\n#item\n1: {#color} {d20}";
+
+        let mut first = Collection::with_seed(source, 42).unwrap();
+        let mut second = Collection::with_seed(source, 42).unwrap();
+        let golden = first.generate("item", 10).unwrap();
+        assert_eq!(golden, second.generate("item", 10).unwrap());
+
+        let mut other = Collection::with_seed(source, 43).unwrap();
+        assert_ne!(golden, other.generate("item", 10).unwrap());
+
+        // Re-seeding restarts the sequence
+        other.set_seed(42);
+        assert_eq!(golden, other.generate("item", 10).unwrap());
+    }
+
+    #[test]
+    fn test_merge_tables() {
+        let source = "#common\n1: a\n2: b\n3: c\n\n#rare\n1: x\n1: y\n\n#loot\n1: {#common}";
+        let mut collection = Collection::new(source).unwrap();
+
+        collection.merge_tables("common", "rare", false).unwrap();
+        let table = &collection.tables["common"];
+        assert_eq!(table.rules.len(), 5);
+        assert_eq!(table.total_weight, 8.0);
+        assert_eq!(table.cumulative_weights, vec![1.0, 3.0, 6.0, 7.0, 8.0]);
+        assert!(collection.has_table("rare"));
+
+        let outputs = collection.generate_frequencies("loot", 400).unwrap();
+        assert_eq!(outputs.len(), 5);
+
+        collection.merge_tables("common", "rare", true).unwrap();
+        assert!(!collection.has_table("rare"));
+        assert_eq!(collection.get_table_ids(), vec!["common", "loot"]);
+        assert_eq!(collection.tables["common"].rules.len(), 7);
+
+        assert!(matches!(
+            collection.merge_tables("common", "missing", false),
+            Err(CollectionError::TableNotFound(_))
+        ));
+        assert!(matches!(
+            collection.merge_tables("missing", "common", false),
+            Err(CollectionError::TableNotFound(_))
+        ));
+
+        // A table that's still referenced elsewhere can't be removed
+        match collection.merge_tables("loot", "common", true) {
+            Err(CollectionError::InvalidTableReference {
+                table_id,
+                referencing_table,
+            }) => {
+                assert_eq!(table_id, "common");
+                assert_eq!(referencing_table, "loot");
+            }
+            other => panic!("Expected InvalidTableReference, got {:?}", other),
+        }
+        assert_eq!(collection.tables["loot"].rules.len(), 1);
+    }
+
+    #[test]
+    fn test_max_depth() {
+        // t0 -> ... -> t150 needs 151 levels, past the default of 100
+        let mut collection = Collection::new(&deep_chain_source(150)).unwrap();
+        match collection.roll("t0") {
+            Err(CollectionError::MaxDepthExceeded { table_id, depth }) => {
+                assert_eq!(table_id, "t100");
+                assert_eq!(depth, 100);
+            }
+            other => panic!("Expected MaxDepthExceeded, got {:?}", other),
+        }
+        assert_eq!(collection.roll("t51").unwrap(), "bottom");
+
+        collection.set_max_depth(151);
+        assert_eq!(collection.roll("t0").unwrap(), "bottom");
+        collection.set_max_depth(150);
+        assert!(collection.roll("t0").is_err());
+
+        // Probabilistic self-recursion fails gracefully rather than overflowing
+        let mut collection = Collection::with_seed("#name\n1: {#name} jr.\n1: Bob", 3).unwrap();
+        collection.set_max_depth(3);
+        let results: Vec<_> = (0..200).map(|_| collection.roll("name")).collect();
+        assert!(
+            results
+                .iter()
+                .any(|result| matches!(result, Ok(text) if text == "Bob jr. jr."))
+        );
+        assert!(results.iter().any(|result| matches!(
+            result,
+            Err(CollectionError::MaxDepthExceeded { table_id, depth: 3 }) if table_id == "name"
+        )));
+    }
+
+    #[test]
+    fn test_table_weight() {
+        let collection = Collection::new("#rare[weight: 0.5]\n1: a\n#common\n1: b").unwrap();
+        assert_eq!(collection.table_weight("rare").unwrap(), 0.5);
+        assert_eq!(collection.table_weight("common").unwrap(), 1.0);
+        assert!(matches!(
+            collection.table_weight("missing"),
+            Err(CollectionError::TableNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_generate_many() {
+        let mut collection = Collection::with_seed("#t\n1: red, green\n1: blue", 9).unwrap();
+        let results = collection.generate_many("t", 20).unwrap();
+        assert_eq!(results.len(), 20);
+        assert!(results.iter().all(|r| r == "red, green" || r == "blue"));
+        assert!(results.iter().any(|r| r == "red, green"));
+
+        collection.set_seed(9);
+        assert_eq!(collection.generate("t", 20).unwrap(), results.join(", "));
+        assert!(collection.generate_many("t", 0).unwrap().is_empty());
+        assert!(matches!(
+            collection.generate_many("missing", 1),
+            Err(CollectionError::TableNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_whitespace_only_rule_warns() {
+        let collection = Collection::new("#t\n1.0:    \n2.0: real\n#u\n1.0: fine").unwrap();
+        let warnings = collection.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].message, "Rule in table 't' has no content");
+        assert_eq!(warnings[0].severity(), crate::diagnostic::Severity::Warning);
+        assert_eq!(warnings[0].location.line, 2);
+
+        let collection = Collection::new("#t\n1.0: real\n2.0: {#u}\n#u\n1.0: x").unwrap();
+        assert!(collection.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_generate_with_separator() {
+        let mut collection = Collection::new("#t\n1: x").unwrap();
+        assert_eq!(
+            collection.generate_with_separator("t", 3, "\n").unwrap(),
+            "x\nx\nx"
+        );
+        assert_eq!(
+            collection.generate_with_separator("t", 3, "").unwrap(),
+            "xxx"
+        );
+        assert_eq!(collection.generate("t", 2).unwrap(), "x, x");
+    }
+
+    #[test]
+    fn test_macros() {
+        let source = r#"%game% = "Dragon Quest"
+%title% = "%game% II"
+
+#quest
+1.0: Welcome to %title%! 100% free, %unknown% stays
+#ref
+1.0: [{#quest}] on %game%"#;
+
+        let mut collection = Collection::new(source).unwrap();
+        assert_eq!(
+            collection.roll("quest").unwrap(),
+            "Welcome to Dragon Quest II! 100% free, %unknown% stays"
+        );
+        assert_eq!(
+            collection.roll("ref").unwrap(),
+            "[Welcome to Dragon Quest II! 100% free, %unknown% stays] on Dragon Quest"
+        );
+
+        collection.define_macro("game", "Final Fantasy").unwrap();
+        collection.define_macro("unknown", "known").unwrap();
+        assert_eq!(
+            collection.roll("quest").unwrap(),
+            "Welcome to Final Fantasy II! 100% free, known stays"
+        );
+
+        // Macros defined only via the API work too
+        let mut collection = Collection::new("#t\n1.0: %who% waves").unwrap();
+        assert_eq!(collection.roll("t").unwrap(), "%who% waves");
+        collection.define_macro("who", "Bob").unwrap();
+        assert_eq!(collection.roll("t").unwrap(), "Bob waves");
+    }
+
+    #[test]
+    fn test_recursive_macro() {
+        // Cycles are rejected when the collection is built, not when generating
+        let source = "%a% = \"x %b%\"\n%b% = \"y %a%\"\n#t\n1.0: %a%";
+        match Collection::new(source) {
+            Err(CollectionError::RecursiveMacro { cycle }) => {
+                assert_eq!(cycle, vec!["a", "b", "a"]);
+            }
+            other => panic!("Expected RecursiveMacro, got {:?}", other),
+        }
+
+        // A macro used twice without recursing is fine
+        let source = "%a% = \"%b%-%b%\"\n%b% = \"x\"\n#t\n1.0: %a% 100%";
+        let mut collection = Collection::new(source).unwrap();
+        assert_eq!(collection.roll("t").unwrap(), "x-x 100%");
+
+        // Defining a cycle fails and keeps the previous definitions
+        match collection.define_macro("b", "%a%") {
+            Err(CollectionError::RecursiveMacro { cycle }) => {
+                assert_eq!(cycle, vec!["a", "b", "a"]);
+            }
+            other => panic!("Expected RecursiveMacro, got {:?}", other),
+        }
+        assert!(matches!(
+            collection.define_macro("self", "%self%"),
+            Err(CollectionError::RecursiveMacro { .. })
+        ));
+        assert_eq!(collection.roll("t").unwrap(), "x-x 100%");
+
+        // So does merging macros that only recurse together
+        let mut base = Collection::new("%a% = \"%b%\"\n#t\n1.0: %a%").unwrap();
+        let other = Collection::new("%b% = \"%a%\"\n#u\n1.0: u").unwrap();
+        assert!(matches!(
+            base.merge(other),
+            Err(CollectionError::RecursiveMacro { .. })
+        ));
+        assert_eq!(base.get_table_ids(), ["t"]);
+    }
+
+    #[test]
+    fn test_escaped_braces() {
+        let source = r"#color
+1.0: red
+
+#t
+1.0: use \{braces\} literally
+#u
+1.0: \{ {#color} \}";
+        let mut collection = Collection::new(source).unwrap();
+        assert_eq!(collection.roll("t").unwrap(), "use {braces} literally");
+        assert_eq!(collection.roll("u").unwrap(), "{ red }");
+    }
+
+    #[test]
+    fn test_coverage() {
+        let source = r#"#color
+1.0: red
+1.0: blue
+0.000001: unreachable
+
+#shape
+1.0: {#color} circle
+1.0: square"#;
+        let mut collection = Collection::with_seed(source, 5).unwrap();
+        collection.roll("shape").unwrap();
+        assert!(collection.coverage_report().is_empty());
+
+        collection.enable_coverage(true);
+        collection.generate("shape", 100).unwrap();
+        let report = collection.coverage_report();
+        assert_eq!(report["shape"], vec![true, true]);
+        assert_eq!(report["color"], vec![true, true, false]);
+
+        collection.enable_coverage(true);
+        assert_eq!(collection.coverage_report(), report);
+
+        collection.enable_coverage(false);
+        collection.enable_coverage(true);
+        assert_eq!(collection.coverage_report()["color"], vec![false; 3]);
+    }
+
+    #[test]
+    fn test_avoid_adjacent_repeats() {
+        let source = "#adj\n1: red\n1: old\n#item\n1: {#adj} {#adj} sword {d1}";
+
+        let mut collection = Collection::with_seed(source, 1).unwrap();
+        let outputs = collection.generate_many("item", 200).unwrap();
+        assert!(outputs.contains(&"red red sword 1".to_string()));
+
+        collection.avoid_adjacent_repeats(true);
+        let outputs = collection.generate_many("item", 200).unwrap();
+        assert!(outputs.iter().all(|output| output != "red red sword 1"));
+        assert!(outputs.iter().all(|output| output != "old old sword 1"));
+
+        // A reference with a single outcome can't avoid repeating itself
+        let mut collection = Collection::new("#a\n1: x\n#t\n1: {#a}{#a|uppercase}{#a}").unwrap();
+        collection.avoid_adjacent_repeats(true);
+        assert_eq!(collection.roll("t").unwrap(), "xXx");
+    }
+
+    #[test]
+    fn test_dice_keep() {
+        let mut collection = Collection::with_seed("#t\n1: {4d6kh3}\n#u\n1: {4d6kl1}", 2).unwrap();
+        for _ in 0..200 {
+            let high: u32 = collection.roll("t").unwrap().parse().unwrap();
+            assert!((3..=18).contains(&high));
+            let low: u32 = collection.roll("u").unwrap().parse().unwrap();
+            assert!((1..=6).contains(&low));
+        }
+
+        // Fixed draws show which dice each suffix keeps
+        let source = "#t\n1: {4d6kh3}\n#u\n1: {4d6kl1}\n#v\n1: {4d6kh2}";
+        let rolls = |annotate| {
+            let mut collection = Collection::with_rng(source, StepRng::new(0, 1 << 30)).unwrap();
+            collection.annotate_dice(annotate);
+            ["t", "u", "v"].map(|table_id| collection.roll(table_id).unwrap())
+        };
+        assert_eq!(
+            rolls(true),
+            [
+                "11 (4d6kh3: 2+4+5+[1])",
+                "1 (4d6kl1: [4]+[5]+1+[2])",
+                "9 (4d6kh2: 5+[1]+[2]+4)",
+            ]
+        );
+        assert_eq!(rolls(false), ["11", "1", "9"]);
+
+        // Flat modifiers apply after summing and may go below zero
+        let mut collection = Collection::new("#t\n1: {1d1+3} {d1-5} {2d1kh1+1}").unwrap();
+        assert_eq!(collection.roll("t").unwrap(), "4 -4 2");
+
+        // Keeping every die is the same as a plain roll
+        let mut kept = Collection::with_seed("#t\n1: {3d6kh3}", 11).unwrap();
+        let mut plain = Collection::with_seed("#t\n1: {3d6}", 11).unwrap();
+        assert_eq!(
+            kept.generate("t", 20).unwrap(),
+            plain.generate("t", 20).unwrap()
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_export_grammar() {
+        let source = r#"%who% = "Bob"
+
+#greeting[export weight: 2 finish: capitalize]
+3: hi %who%, {#color|uppercase} {4d6kh3}
+1: {@alice/colors#shade}
+
+#color
+1: red"#;
+        let mut dependencies = HashMap::new();
+        dependencies.insert(
+            ("alice".to_string(), "colors".to_string()),
+            Collection::new("#shade[export]\n1: dark").unwrap(),
+        );
+        let collection = Collection::with_external(source, dependencies).unwrap();
+
+        let grammar: serde_json::Value =
+            serde_json::from_str(&collection.export_grammar()).unwrap();
+        assert_eq!(grammar["format"], "tbl-grammar");
+        assert_eq!(grammar["macros"][0]["name"], "who");
+
+        let greeting = &grammar["productions"][0];
+        assert_eq!(greeting["name"], "greeting");
+        assert_eq!(greeting["exported"], true);
+        assert_eq!(greeting["weight"], 2.0);
+        assert_eq!(greeting["finish_modifiers"][0], "capitalize");
+        assert_eq!(grammar["productions"][1]["name"], "color");
+
+        let first = &greeting["alternatives"][0];
+        assert_eq!(first["weight"], 3.0);
+        let symbols = first["symbols"].as_array().unwrap();
+        assert_eq!(symbols[0]["type"], "terminal");
+        assert_eq!(symbols[0]["text"], " hi %who%, ");
+        assert_eq!(symbols[1]["type"], "nonterminal");
+        assert_eq!(symbols[1]["name"], "color");
+        assert_eq!(symbols[1]["modifiers"][0], "uppercase");
+        assert_eq!(symbols[3]["type"], "dice");
+        assert_eq!(symbols[3]["count"], 4);
+        assert_eq!(symbols[3]["keep"]["mode"], "highest");
+
+        let external = &greeting["alternatives"][1]["symbols"][1];
+        assert_eq!(external["type"], "external");
+        assert_eq!(external["publisher"], "alice");
+        assert_eq!(external["name"], "shade");
+    }
+
+    #[test]
+    fn test_generate_with_caller_rng() {
+        let source = "#t\n1: {d6} {#u}\n#u\n1: a\n1: b";
+        let collection = Collection::with_seed(source, 1).unwrap();
+
+        let mut first = StdRng::seed_from_u64(42);
+        let mut second = StdRng::seed_from_u64(42);
+        let results = collection.generate_with("t", 10, &mut first).unwrap();
+        assert_eq!(results.len(), 10);
+        assert_eq!(
+            results,
+            collection.generate_with("t", 10, &mut second).unwrap()
+        );
+
+        // The caller's RNG advances, the collection's doesn't
+        assert_ne!(
+            results,
+            collection.generate_with("t", 10, &mut first).unwrap()
+        );
+        let mut own = Collection::with_seed(source, 1).unwrap();
+        let mut reference = Collection::with_seed(source, 1).unwrap();
+        own.generate_with("t", 5, &mut first).unwrap();
+        assert_eq!(
+            own.generate("t", 5).unwrap(),
+            reference.generate("t", 5).unwrap()
+        );
+
+        let rng: &mut dyn RngCore = &mut SmallRng::seed_from_u64(3);
+        assert!(collection.generate_with("missing", 1, rng).is_err());
+    }
+
+    #[test]
+    fn test_table_dependencies() {
+        let source = r#"#npc
+1: {#name} the {#job}, {#name|capitalize} {d6}
+1: {@alice/jobs#job} {#job}
+
+#name
+1: Bob
+
+#job
+1: smith"#;
+        let mut dependencies = HashMap::new();
+        dependencies.insert(
+            ("alice".to_string(), "jobs".to_string()),
+            Collection::new("#job[export]\n1: baker").unwrap(),
+        );
+        let collection = Collection::with_external(source, dependencies).unwrap();
+
+        assert_eq!(
+            collection.get_table_dependencies("npc").unwrap(),
+            vec!["name", "job", "@alice/jobs#job"]
+        );
+        assert!(
+            collection
+                .get_table_dependencies("name")
+                .unwrap()
+                .is_empty()
+        );
+        assert!(matches!(
+            collection.get_table_dependencies("missing"),
+            Err(CollectionError::TableNotFound(_))
+        ));
+
+        let all = collection.get_all_dependencies();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all["npc"].len(), 3);
+        assert!(all["job"].is_empty());
+    }
+
+    #[test]
+    fn test_rule_probabilities() {
+        let source = "#t\n1: common {#u}\n3: rare\n0.5: x\n5.5: y\n#u\n2: only";
+        let collection = Collection::new(source).unwrap();
+
+        let probabilities = collection.rule_probabilities("t").unwrap();
+        assert_eq!(
+            probabilities,
+            vec![
+                ("common {#u}".to_string(), 0.1),
+                ("rare".to_string(), 0.3),
+                ("x".to_string(), 0.05),
+                ("y".to_string(), 0.55),
+            ]
+        );
+
+        assert_eq!(
+            collection.rule_probabilities("u").unwrap(),
+            vec![("only".to_string(), 1.0)]
+        );
+        assert!(matches!(
+            collection.rule_probabilities("missing"),
+            Err(CollectionError::TableNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_generate_with_bias() {
+        let source = r#"#room
+1: {empty|{#treasure}|{#monster}}
+
+#lair
+1: a {#monster} guarding {#treasure}
+1: empty
+
+#treasure
+1: gold
+
+#monster
+1: troll"#;
+        let mut collection = Collection::with_seed(source, 8).unwrap();
+
+        let biases = HashMap::from([("treasure".to_string(), 0.0)]);
+        let results = collection.generate_with_bias("room", 50, &biases).unwrap();
+        assert!(results.iter().all(|result| result != "gold"));
+
+        let biases = HashMap::from([("treasure".to_string(), 100.0)]);
+        let results = collection.generate_with_bias("room", 300, &biases).unwrap();
+        let gold = results.iter().filter(|r| *r == "gold").count();
+        assert!(gold > 290, "treasure rooms: {}", gold);
+
+        // Biases only last for the call
+        let results = collection.generate_many("room", 100).unwrap();
+        assert!(results.iter().any(|r| r == "troll"));
+
+        // Rules that only mention a biased table among other content keep their weight
+        let results = collection.generate_with_bias("lair", 300, &biases).unwrap();
+        let empty = results.iter().filter(|r| *r == "empty").count();
+        assert!((100..200).contains(&empty), "empty lairs: {}", empty);
+
+        // Rules that consist of a single reference are scaled like inline options
+        let source = r#"#room
+1: {#treasure}
+1: {#empty}
+
+#treasure
+1: gold
+
+#empty
+1: dust"#;
+        let mut rooms = Collection::with_seed(source, 3).unwrap();
+        let biases = HashMap::from([("treasure".to_string(), 10.0)]);
+        let results = rooms.generate_with_bias("room", 1100, &biases).unwrap();
+        let gold = results.iter().filter(|r| *r == "gold").count();
+        assert!(gold > 900, "treasure rooms: {}", gold);
+        let biases = HashMap::from([("treasure".to_string(), 0.0)]);
+        let results = rooms.generate_with_bias("room", 50, &biases).unwrap();
+        assert!(results.iter().all(|r| r == "dust"));
+
+        // A table with a zero bias can't be generated
+        let biases = HashMap::from([("monster".to_string(), 0.0)]);
+        let results = collection.generate_with_bias("room", 100, &biases).unwrap();
+        assert!(results.iter().all(|r| r != "troll"));
+        assert!(matches!(
+            collection.generate_with_bias("monster", 1, &biases),
+            Err(CollectionError::GenerationError(_))
+        ));
+
+        assert!(matches!(
+            collection.generate_with_bias("room", 1, &HashMap::from([("dragon".to_string(), 2.0)])),
+            Err(CollectionError::TableNotFound(_))
+        ));
+        let mut insensitive = Collection::builder()
+            .case_insensitive(true)
+            .build(source)
+            .unwrap();
+        let biases = HashMap::from([("Treasure".to_string(), 0.0)]);
+        let results = insensitive.generate_with_bias("room", 50, &biases).unwrap();
+        assert!(results.iter().all(|result| result != "gold"));
+
+        for bias in [-1.0, f64::NAN, f64::INFINITY] {
+            let biases = HashMap::from([("treasure".to_string(), bias)]);
+            assert!(matches!(
+                collection.generate_with_bias("room", 1, &biases),
+                Err(CollectionError::GenerationError(_))
+            ));
+        }
+
+        let mut collection = Collection::new("#a\n1: {#b}\n#b\n1: x").unwrap();
+        let biases = HashMap::from([("b".to_string(), 0.0)]);
+        assert!(matches!(
+            collection.generate_with_bias("a", 1, &biases),
+            Err(CollectionError::GenerationError(_))
+        ));
+        assert_eq!(collection.roll("a").unwrap(), "x");
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let source = r#"#greeting[export]
+1: hello {#name}, you rolled {2d6}
+
+#name
+1: Bob
+1: Alice
+
+#silent[export]
+1:   {#blank}
+1: never picked first
+
+#blank
+1:    
+
+#loop[export]
+1: {#loop} again
+1: done
+
+#private
+1: {#loop}"#;
+        let collection = Collection::new(source).unwrap();
+        let diagnostics = collection.dry_run();
+        assert_eq!(diagnostics.len(), 2, "{:?}", diagnostics);
+
+        assert_eq!(
+            diagnostics[0].severity(),
+            crate::diagnostic::Severity::Warning
+        );
+        assert!(
+            diagnostics[0]
+                .message
+                .contains("'silent' generates empty output")
+        );
+        assert_eq!(diagnostics[0].location.line, 9);
+
+        assert_eq!(
+            diagnostics[1].severity(),
+            crate::diagnostic::Severity::Error
+        );
+        assert!(diagnostics[1].message.contains("'loop' fails to generate"));
+        assert!(diagnostics[1].message.contains("Maximum depth exceeded"));
+
+        // The walk takes first rules and options, with dice showing 1
+        let source = "#a[export]\n1: {2d6} {#b}\n#b\n1: {x|y}\n9: z\n#c[export]\n1: {#a|uppercase}";
+        let mut collection = Collection::new(source).unwrap();
+        assert!(collection.dry_run().is_empty());
+        let mut generation = Generation {
+            first_rules: true,
+            ..Generation::default()
+        };
+        let mut rng = StepRng::new(0, 0);
+        let walked = collection.expand_in("c", &mut rng, &mut generation);
+        assert_eq!(walked.unwrap(), "2 X");
+
+        // It isn't counted as coverage
+        collection.enable_coverage(true);
+        assert!(collection.dry_run().is_empty());
+        assert!(
+            collection
+                .coverage_report()
+                .values()
+                .flatten()
+                .all(|hit| !hit)
+        );
+    }
+
+    #[test]
+    fn test_annotate_dice() {
+        // Alternates between the lowest and a middle value; choosing the rule takes the first
+        let roller = || StepRng::new(0, 1 << 31);
+        let source = "#roll\n1: {2d6} then {4d6kh3+2} and {d20-1}";
+        let mut collection = Collection::new(source).unwrap();
+
+        let plain = collection.generate_with("roll", 1, &mut roller()).unwrap();
+        assert_eq!(plain, vec!["5 then 11 and 10"]);
+
+        collection.annotate_dice(true);
+        let annotated = collection.generate_with("roll", 1, &mut roller()).unwrap();
+        assert_eq!(
+            annotated,
+            vec!["5 (2d6: 4+1) then 11 (4d6kh3+2: 4+[1]+4+1+2) and 10 (d20-1: 11-1)"]
+        );
+
+        // Only the unbracketed dice add up to the total
+        let mut kept = Collection::new("#t\n1: {3d6kl1}\n#u\n1: {3d6kh2-1}").unwrap();
+        kept.annotate_dice(true);
+        let low = kept.generate_with("t", 1, &mut roller()).unwrap();
+        assert_eq!(low, vec!["1 (3d6kl1: [4]+1+[4])"]);
+        let high = kept.generate_with("u", 1, &mut roller()).unwrap();
+        assert_eq!(high, vec!["7 (3d6kh2-1: 4+[1]+4-1)"]);
+    }
+
+    #[test]
+    fn test_max_reference_depth() {
+        let source = r#"#scene
+1: {#npc} at {#place}
+1: quiet
+
+#npc
+1: {#name} the {#job}
+1: {@alice/names#title} {#name}
+
+#name
+1: Bob
+
+#job
+1: smith
+
+#place
+1: {#place} beyond the hills
+1: a village
+
+#journey
+1: {#scene}
+1: {#place}"#;
+        let titles = Collection::new("#title[export]\n1: Sir {#suffix}\n#suffix\n1: X").unwrap();
+        let collection = Collection::with_dependencies(
+            source,
+            HashMap::from([("@alice/names".to_string(), titles)]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            collection.max_reference_depth("name").unwrap(),
+            ReferenceDepth::Bounded(1)
+        );
+        // npc -> @alice/names#title -> suffix
+        assert_eq!(
+            collection.max_reference_depth("npc").unwrap(),
+            ReferenceDepth::Bounded(3)
+        );
+
+        // `place` references itself, so anything reaching it is unbounded
+        let place_cycle = ReferenceDepth::Cyclic {
+            cycle: vec!["place".to_string(), "place".to_string()],
+        };
+        assert_eq!(
+            collection.max_reference_depth("place").unwrap(),
+            place_cycle
+        );
+        assert_eq!(
+            collection.max_reference_depth("scene").unwrap(),
+            place_cycle
+        );
+        assert_eq!(
+            collection.max_reference_depth("journey").unwrap(),
+            place_cycle
+        );
+
+        assert!(matches!(
+            collection.max_reference_depth("missing"),
+            Err(CollectionError::TableNotFound(_))
+        ));
+
+        let source = "#a\n1: {#b} {#c}\n#b\n1: {#c}\n#c\n1: {#d}\n#d\n1: end";
+        let collection = Collection::new(source).unwrap();
+        assert_eq!(
+            collection.max_reference_depth("a").unwrap(),
+            ReferenceDepth::Bounded(4)
+        );
+
+        // Longer cycles list every table on the way round
+        let source = "#a\n1: {#b}\n#b\n1: {#c}\n1: end\n#c\n1: {#b}";
+        let collection = Collection::new(source).unwrap();
+        assert_eq!(
+            collection.max_reference_depth("a").unwrap(),
+            ReferenceDepth::Cyclic {
+                cycle: vec!["b".to_string(), "c".to_string(), "b".to_string()]
+            }
+        );
+    }
+
+    #[test]
+    fn test_empty_rule_generates_nothing() {
+        let source = "#punctuation\n1: !\n1: ~\n#shout\n1: hey{#punctuation}";
+        let mut collection = Collection::with_seed(source, 3).unwrap();
+        assert!(collection.warnings().is_empty());
+
+        let results: HashSet<String> = collection
+            .generate_many("shout", 100)
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(
+            results,
+            HashSet::from(["hey".to_string(), "hey!".to_string()])
+        );
+
+        let collection = Collection::new("#t\n1: ~").unwrap();
+        assert_eq!(collection.rule_probabilities("t").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_generate_seeded_preview_cache() {
+        let source = "#color\n1: red\n1: green\n1: blue\n1: gold";
+        let mut collection = Collection::with_seed(source, 1).unwrap();
+        let preview = collection.generate_seeded("color", 5, 42).unwrap();
+        assert_eq!(collection.generate_seeded("color", 5, 42).unwrap(), preview);
+        assert_ne!(
+            collection.generate_seeded("color", 20, 7).unwrap(),
+            collection.generate_seeded("color", 20, 8).unwrap()
+        );
+
+        // Cached results are served without generating, so coverage isn't touched
+        collection.set_preview_cache_size(2);
+        assert_eq!(collection.generate_seeded("color", 5, 42).unwrap(), preview);
+        collection.enable_coverage(true);
+        assert_eq!(collection.generate_seeded("color", 5, 42).unwrap(), preview);
+        assert!(collection.coverage_report()["color"].iter().all(|hit| !hit));
+
+        // The least recently used entry is evicted first
+        collection.generate_seeded("color", 1, 1).unwrap();
+        collection.generate_seeded("color", 5, 42).unwrap();
+        collection.generate_seeded("color", 1, 2).unwrap();
+        let cached_seeds = |collection: &Collection| -> Vec<u64> {
+            let cache = collection.preview_cache.as_ref().unwrap().lock().unwrap();
+            cache
+                .entries
+                .iter()
+                .map(|((_, _, seed), _)| *seed)
+                .collect()
+        };
+        assert_eq!(cached_seeds(&collection), vec![2, 42]);
+
+        // Changing how tables generate invalidates the cache
+        collection.define_macro("unused", "x").unwrap();
+        assert!(cached_seeds(&collection).is_empty());
+        assert!(matches!(
+            collection.generate_seeded("missing", 1, 0),
+            Err(CollectionError::TableNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_lint_unused_tables() {
+        let source = r#"#name[export]
+1: {#first} {#last|capitalize}
+
+#first
+1: ann
+
+#last
+1: lee
+
+#leftover
+1: old {#leftover}
+1: idea
+
+#scratch
+1: {#first|uppercase|lowercase}"#;
+        let collection = Collection::new(source).unwrap();
+
+        let unused = collection.lint_unused_tables();
+        let messages: Vec<&str> = unused.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Table 'leftover' is never referenced and not exported",
+                "Table 'scratch' is never referenced and not exported",
+            ]
+        );
+        assert_eq!(unused[0].severity(), crate::diagnostic::Severity::Warning);
+        assert_eq!(unused[0].location.line, 10);
+        assert_eq!(unused[0].source_line, "#leftover");
+        assert!(
+            unused[0]
+                .suggestion
+                .as_ref()
+                .unwrap()
+                .contains("#leftover[export]")
+        );
+
+        // `lint` also includes the modifier lints
+        let all = collection.lint();
+        assert_eq!(all.len(), unused.len() + collection.lint_modifiers().len());
+        assert!(all.len() > unused.len());
+    }
+
+    #[test]
+    fn test_from_sources_namespaces_tables() {
+        let monsters = "#name[export]\n1.0: goblin\n\n#hoard\n1.0: {#coin} coins";
+        let treasure = "#name[export]\n1.0: ruby\n\n#coin\n1.0: gold\n\n#loot[export]\n1.0: {#monsters:name} with a {#name}";
+        let mut collection =
+            Collection::from_sources(&[("monsters", monsters), ("treasure", treasure)]).unwrap();
+
+        assert_eq!(
+            collection.get_table_ids(),
+            vec![
+                "monsters:name",
+                "monsters:hoard",
+                "treasure:name",
+                "treasure:coin",
+                "treasure:loot"
+            ]
+        );
+        // Unqualified references prefer their own namespace...
+        assert_eq!(
+            collection.roll("treasure:loot").unwrap(),
+            "goblin with a ruby"
+        );
+        // ...and otherwise fall back to the one namespace defining the table
+        assert_eq!(collection.roll("monsters:hoard").unwrap(), "gold coins");
+
+        assert!(matches!(
+            Collection::from_sources(&[("a", "#t\n1.0: x"), ("a", "#u\n1.0: y")]),
+            Err(CollectionError::DuplicateNamespace(ns)) if ns == "a"
+        ));
+        assert!(matches!(
+            Collection::from_sources(&[("a:b", "#t\n1.0: x")]),
+            Err(CollectionError::InvalidNamespace(_))
+        ));
+        match Collection::from_sources(&[
+            ("a", "#t\n1.0: x"),
+            ("b", "#t\n1.0: y"),
+            ("c", "#u\n1.0: {#t}"),
+        ]) {
+            Err(CollectionError::AmbiguousTableReference {
+                table_id,
+                referencing_table,
+                namespaces,
+            }) => {
+                assert_eq!(table_id, "t");
+                assert_eq!(referencing_table, "c:u");
+                assert_eq!(namespaces, vec!["a", "b"]);
+            }
+            other => panic!(
+                "expected an ambiguous reference, got {:?}",
+                other.map(|_| ())
+            ),
+        }
+        assert!(matches!(
+            Collection::from_sources(&[("a", "#t\n1.0: {#b:t}")]),
+            Err(CollectionError::InvalidTableReference { table_id, .. }) if table_id == "b:t"
+        ));
+        assert!(matches!(
+            Collection::from_sources(&[
+                ("a", "%who% = \"Ann\"\n#t\n1.0: %who%"),
+                ("b", "%who% = \"Bo\"\n#t\n1.0: %who%"),
+            ]),
+            Err(CollectionError::ConflictingMacro(name)) if name == "who"
+        ));
+    }
+
+    #[test]
+    fn test_generate_unique() {
+        let source = "#name[export]\n1.0: Ann\n5.0: Bo\n1.0: Cy\n1.0: {#bo}\n\n#bo\n1.0: Bo";
+        let mut collection = Collection::with_seed(source, 7).unwrap();
+
+        let mut names = collection.generate_unique("name", 3).unwrap();
+        names.sort();
+        assert_eq!(names, vec!["Ann", "Bo", "Cy"]);
+
+        // Too few variants is known before generating anything
+        match collection.generate_unique("name", 5) {
+            Err(CollectionError::NotEnoughVariants {
+                requested,
+                available,
+                ..
+            }) => {
+                assert_eq!(requested, 5);
+                assert_eq!(available, 4);
+            }
+            other => panic!("expected NotEnoughVariants, got {:?}", other),
+        }
+
+        // A rare variant is still found rather than given up on
+        let mut skewed = Collection::with_seed("#t\n1000: a\n1: b", 1).unwrap();
+        let mut results = skewed.generate_unique("t", 2).unwrap();
+        results.sort();
+        assert_eq!(results, vec!["a", "b"]);
+
+        // "{#bo}" renders the same as "Bo", so only three variants exist
+        match collection.generate_unique("name", 4) {
+            Err(CollectionError::NotEnoughVariants {
+                table_id,
+                requested,
+                available,
+            }) => {
+                assert_eq!(table_id, "name");
+                assert_eq!(requested, 4);
+                assert_eq!(available, 3);
+            }
+            other => panic!("expected NotEnoughVariants, got {:?}", other),
+        }
+
+        assert!(collection.generate_unique("name", 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_inline_choice() {
+        let source = "#hat[export]\n1.0: a {red|blue|{#shade} green} hat\n\n#shade\n1.0: pale";
+        let mut collection = Collection::with_seed(source, 3).unwrap();
+
+        let hats: HashSet<String> = collection
+            .generate_many("hat", 200)
+            .unwrap()
+            .into_iter()
+            .collect();
+        let expected: HashSet<String> = ["a red hat", "a blue hat", "a pale green hat"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(hats, expected);
+        assert_eq!(
+            collection.get_table_dependencies("hat").unwrap(),
+            vec!["shade"]
+        );
+
+        // References inside options are validated like any other
+        assert!(matches!(
+            Collection::new("#t\n1.0: {a|{#missing}}"),
+            Err(CollectionError::InvalidTableReference { table_id, .. }) if table_id == "missing"
+        ));
+
+        // A choice with a way out doesn't count as infinite recursion
+        let mut looping = Collection::with_seed("#t\n1.0: x{{#t}|.}", 1).unwrap();
+        assert!(looping.roll("t").unwrap().ends_with('.'));
+        assert!(matches!(
+            Collection::new("#t\n1.0: {{#t}|{#t}}"),
+            Err(CollectionError::InfiniteRecursion { .. })
+        ));
+    }
+
+    #[test]
+    fn test_weighted_inline_choice_distribution() {
+        let mut collection = Collection::with_seed("#t\n1.0: {3:red|1:blue}", 11).unwrap();
+        let results = collection.generate_many("t", 4000).unwrap();
+        let reds = results.iter().filter(|result| *result == "red").count();
+        assert_eq!(
+            results.len() - reds,
+            results.iter().filter(|r| *r == "blue").count()
+        );
+
+        // Expect about 75% red; allow a few percent either way
+        let share = reds as f64 / results.len() as f64;
+        assert!((0.72..0.78).contains(&share), "red share was {}", share);
+    }
+
+    #[test]
+    fn test_register_modifier() {
+        let source = "#word\n1.0: stressed\n\n#shout[finish: exclaim]\n1.0: {#word|reverse|uppercase}\n\n#title\n1.0: {#word|capitalize}";
+        assert!(matches!(
+            Collection::new(source),
+            Err(CollectionError::UnknownModifier { table_id, .. }) if table_id == "shout"
+        ));
+
+        // Allowed while building, the modifiers can be registered afterwards
+        let mut collection = Collection::builder()
+            .allow_unknown_modifiers(true)
+            .build(source)
+            .unwrap();
+        collection.allow_unknown_modifiers(false);
+        let unknown = collection.lint_modifiers();
+        assert_eq!(unknown.len(), 1);
+        assert_eq!(
+            unknown[0].message,
+            "Unknown modifier 'reverse' fails generation until it is registered"
+        );
+
+        collection.register_modifier("reverse", Box::new(|text| text.chars().rev().collect()));
+        collection.register_modifier("exclaim", Box::new(|text| format!("{}!", text)));
+        assert_eq!(collection.roll("shout").unwrap(), "DESSERTS!");
+        assert!(collection.lint_modifiers().is_empty());
+
+        // Registered modifiers override built-ins of the same name
+        assert_eq!(collection.roll("title").unwrap(), "Stressed");
+        collection.register_modifier("capitalize", Box::new(|text| format!("[{}]", text)));
+        assert_eq!(collection.roll("title").unwrap(), "[stressed]");
+    }
+
+    #[test]
+    fn test_unknown_modifiers_fail_generation() {
+        let source = "#animal\n1.0: cat\n\n#test\n1.0: {#animal|capitalise}\n\n#plain\n1.0: dog";
+        for built in [
+            Collection::new(source),
+            Collection::with_seed(source, 1),
+            Collection::new_with_limits(source, Limits::default()),
+            Collection::with_external(source, HashMap::new()),
+            Collection::from_sources(&[("zoo", source)]),
+        ] {
+            assert!(matches!(
+                built,
+                Err(CollectionError::UnknownModifier { modifier, .. }) if modifier == "capitalise"
+            ));
+        }
+
+        // Unknown modifiers still allowed at build time fail the rules that use them
+        let mut collection = Collection::builder()
+            .allow_unknown_modifiers(true)
+            .build(source)
+            .unwrap();
+        collection.allow_unknown_modifiers(false);
+        assert!(matches!(
+            collection.roll("test"),
+            Err(CollectionError::UnknownModifier { modifier, table_id })
+                if modifier == "capitalise" && table_id == "test"
+        ));
+        assert!(collection.draw("test").is_err());
+
+        // Tables that never reach the unknown modifier still generate
+        assert_eq!(collection.roll("plain").unwrap(), "dog");
+        assert_eq!(collection.draw("plain").unwrap(), "dog");
+
+        collection.allow_unknown_modifiers(true);
+        assert_eq!(collection.roll("test").unwrap(), "cat");
+
+        collection.allow_unknown_modifiers(false);
+        collection.register_modifier("capitalise", Box::new(|text| text.to_uppercase()));
+        assert_eq!(collection.roll("test").unwrap(), "CAT");
+
+        // Unknown finish modifiers are caught too
+        assert!(matches!(
+            Collection::new("#t[finish: shout]\n1.0: hi"),
+            Err(CollectionError::UnknownModifier { modifier, .. }) if modifier == "shout"
+        ));
+    }
+
+    #[test]
+    fn test_numeric_modifiers() {
+        let collection = Collection::new("#t\n1: x").unwrap();
+        let ordinal = |text| collection.apply_modifier(text, "ordinal", None);
+        assert_eq!(ordinal("1"), "1st");
+        assert_eq!(ordinal("2"), "2nd");
+        assert_eq!(ordinal("3"), "3rd");
+        assert_eq!(ordinal("11"), "11th");
+        assert_eq!(ordinal("12"), "12th");
+        assert_eq!(ordinal("21"), "21st");
+        assert_eq!(ordinal("112"), "112th");
+        assert_eq!(ordinal("-1"), "-1st");
+        assert_eq!(ordinal("cat"), "cat");
+
+        let number_word = |text| collection.apply_modifier(text, "number_word", None);
+        assert_eq!(number_word("0"), "zero");
+        assert_eq!(number_word("3"), "three");
+        assert_eq!(number_word("13"), "thirteen");
+        assert_eq!(number_word("40"), "forty");
+        assert_eq!(number_word("21"), "twenty-one");
+        assert_eq!(number_word("105"), "one hundred five");
+        assert_eq!(number_word("2024"), "two thousand twenty-four");
+        assert_eq!(number_word("-7"), "minus seven");
+        assert_eq!(number_word("cat"), "cat");
+    }
+
+    #[test]
+    fn test_dice_roll_modifiers() {
+        let source = "#place\n1.0: {d1|ordinal} place\n\n#count\n1.0: {1d1+10|ordinal} and {20d1+1|number_word|capitalize}";
+        let mut collection = Collection::new(source).unwrap();
+        assert!(collection.lint_modifiers().is_empty());
+        assert_eq!(collection.roll("place").unwrap(), "1st place");
+        assert_eq!(collection.roll("count").unwrap(), "11th and Twenty-one");
+
+        collection.annotate_dice(true);
+        assert_eq!(collection.roll("place").unwrap(), "1st (d1: 1) place");
+    }
+
+    #[test]
+    fn test_max_dice() {
+        let mut collection = Collection::new("#t\n1.0: {3d6}\n\n#one\n1.0: {d6}").unwrap();
+        collection.set_max_dice(2);
+        assert!(matches!(
+            collection.roll("t"),
+            Err(CollectionError::DiceLimitExceeded { count: 3, sides: 6 })
+        ));
+        assert!(collection.roll("one").is_ok());
+
+        // The largest roll a source can write adds up past u32::MAX without overflowing
+        let mut huge = Collection::with_seed("#t\n1.0: {1000d4294967295}", 7).unwrap();
+        let total: u64 = huge.roll("t").unwrap().parse().unwrap();
+        assert!(total > u64::from(u32::MAX));
+
+        // Larger rolls parse once the limit is raised while building
+        let source = "#t\n1.0: {2000d1}";
+        assert!(Collection::new(source).is_err());
+        let mut raised = Collection::builder().max_dice(5000).build(source).unwrap();
+        assert_eq!(raised.roll("t").unwrap(), "2000");
+        let limits = Limits {
+            max_dice: 1999,
+            ..Limits::default()
+        };
+        assert!(Collection::new_with_limits(source, limits).is_err());
+        let limits = Limits {
+            max_dice: 2000,
+            ..Limits::default()
+        };
+        assert_eq!(
+            Collection::new_with_limits(source, limits)
+                .unwrap()
+                .roll("t")
+                .unwrap(),
+            "2000"
+        );
+
+        // Raising it on a built collection applies when it's rebuilt
+        let mut rebuilt = Collection::new("#t\n1.0: {d1}").unwrap();
+        rebuilt.source = Some(source.to_string());
+        assert!(rebuilt.rebuild().is_err());
+        rebuilt.set_max_dice(5000);
+        rebuilt.rebuild().unwrap();
+        assert_eq!(rebuilt.roll("t").unwrap(), "2000");
+
+        // Rebuilding keeps the limits the collection was built with
+        let limits = Limits {
+            max_tables: 1,
+            max_dice: 2000,
+            ..Limits::default()
+        };
+        let mut limited = Collection::new_with_limits(source, limits).unwrap();
+        limited.rebuild().unwrap();
+        limited.source = Some(format!("{}\n\n#u\n1.0: u", source));
+        assert!(matches!(
+            limited.rebuild(),
+            Err(CollectionError::LimitExceeded {
+                which: "tables",
+                limit: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn test_modifiers_with_arguments() {
+        let source = "#name\n1.0: bartholomew\n\n#t\n1.0: {#name|truncate:5|capitalize}\n\n#echo\n1.0: {#name|truncate:3|repeat:3}\n\n#odd\n1.0: {#name|repeat|uppercase:2}";
+        let mut collection = Collection::new(source).unwrap();
+        assert_eq!(collection.roll("t").unwrap(), "Bart…");
+        assert_eq!(collection.roll("echo").unwrap(), "ba… ba… ba…");
+
+        // Built-ins ignore arguments they don't take, and need the ones they do
+        assert_eq!(collection.roll("odd").unwrap(), "BARTHOLOMEW");
+        let messages: Vec<_> = collection
+            .lint_modifiers()
+            .into_iter()
+            .map(|d| d.message)
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Modifier 'repeat' needs a whole number argument",
+                "Modifier 'uppercase' ignores its argument '2'",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_count_variants() {
+        let source = r#"#color
+1: red
+1: blue
+1: green
+
+#size
+1: small
+1: large
+
+#item
+1: a {#size} {#color} {box|bag}
+1: nothing
+1: ~
+
+#loot
+1: {#item} and {@alice/gear#tool}
+
+#roll
+1: {#color} {d6}
+
+#chain
+1: {#chain} again
+1: {#color}"#;
+        let gear = Collection::new("#tool[export]\n1: saw\n1: {hammer|axe}").unwrap();
+        let collection = Collection::with_dependencies(
+            source,
+            HashMap::from([("@alice/gear".to_string(), gear)]),
+        )
+        .unwrap();
+
+        assert_eq!(collection.count_variants("color").unwrap(), Some(3));
+        // 2 sizes * 3 colors * 2 containers, plus two fixed rules
+        assert_eq!(collection.count_variants("item").unwrap(), Some(14));
+        assert_eq!(collection.count_variants("loot").unwrap(), Some(42));
+
+        // Dice rolls and recursion are unbounded
+        assert_eq!(collection.count_variants("roll").unwrap(), None);
+        assert_eq!(collection.count_variants("chain").unwrap(), None);
+        assert!(matches!(
+            collection.count_variants("missing"),
+            Err(CollectionError::TableNotFound(_))
+        ));
+
+        // Repeated literal rules and options are one variant each
+        let source = "#t\n1: red\n2: red\n1: {blue|blue |green}\n\n#e\n1: ~\n1: {|}";
+        let collection = Collection::new(source).unwrap();
+        assert_eq!(collection.count_variants("t").unwrap(), Some(3));
+        // `{|}` counts once, though it renders the same as `~`
+        assert_eq!(collection.count_variants("e").unwrap(), Some(2));
+
+        // Counts that don't fit in usize are unbounded too
+        let mut wide = String::new();
+        for i in 0..65 {
+            wide.push_str(&format!("#w{}\n1: a\n1: b\n\n", i));
+        }
+        wide.push_str("#all\n1:");
+        for i in 0..65 {
+            wide.push_str(&format!(" {{#w{}}}", i));
+        }
+        let wide = Collection::new(&wide).unwrap();
+        assert_eq!(wide.count_variants("w0").unwrap(), Some(2));
+        assert_eq!(wide.count_variants("all").unwrap(), None);
+    }
+
+    #[test]
+    fn test_collection_builder() {
+        let source = "#a\n1.0: {#b}\n\n#b\n1.0: {3d6|shout}";
+
+        // Unset options keep the defaults
+        let collection = CollectionBuilder::new()
+            .allow_unknown_modifiers(true)
+            .build(source)
+            .unwrap();
+        assert_eq!(collection.max_depth, DEFAULT_MAX_DEPTH);
+        assert_eq!(collection.max_dice, MAX_DICE_COUNT);
+
+        let mut configured = Collection::builder()
+            .seed(7)
+            .max_depth(1)
+            .max_dice(2)
+            .allow_unknown_modifiers(true)
+            .build(source)
+            .unwrap();
+        assert!(matches!(
+            configured.generate("a", 1),
+            Err(CollectionError::MaxDepthExceeded { depth: 1, .. })
+        ));
+        assert!(matches!(
+            configured.generate("b", 1),
+            Err(CollectionError::DiceLimitExceeded { count: 3, .. })
+        ));
+
+        // The seed matches `with_seed`
+        let source = "#n\n1.0: {d100} {d100}";
+        let mut built = CollectionBuilder::new().seed(7).build(source).unwrap();
+        let mut seeded = Collection::with_seed(source, 7).unwrap();
+        assert_eq!(
+            built.generate("n", 5).unwrap(),
+            seeded.generate("n", 5).unwrap()
+        );
+
+        // Without `allow_unknown_modifiers` an unknown modifier fails the build
+        assert!(matches!(
+            CollectionBuilder::new().build("#b\n1.0: {d6|shout}"),
+            Err(CollectionError::UnknownModifier { modifier, .. }) if modifier == "shout"
+        ));
+        let mut registered = CollectionBuilder::new()
+            .seed(7)
+            .modifier("shout", Box::new(|text| format!("{}!", text)))
+            .build("#b\n1.0: {d6|shout}")
+            .unwrap();
+        assert!(registered.roll("b").unwrap().ends_with('!'));
+    }
+
+    #[test]
+    fn test_table_rules() {
+        let source = "#color\n1.0: red\n2.0: {#shade} blue\n\n#shade\n1.0: dark";
+        let collection = Collection::new(source).unwrap();
+
+        let rules = collection.table_rules("color").unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].value.content_text(), "red");
+        assert_eq!(rules[1].value.weight, 2.0);
+        assert!(
+            rules[1]
+                .span
+                .slice(source)
+                .starts_with("2.0: {#shade} blue")
+        );
+        assert_eq!(rules[1].value.expressions().len(), 1);
+
+        assert!(collection.table_rules("missing").is_none());
+    }
 
-        let mut cumulative_weights = Vec::with_capacity(table.rules.len());
-        let mut cumulative = 0.0;
+    #[test]
+    fn test_with_rng() {
+        let source = "#coin\n1.0: heads\n1.0: tails";
 
-        // Pre-compute cumulative weights for O(log n) binary search during generation
-        for rule in &table.rules {
-            cumulative += rule.value.weight;
-            cumulative_weights.push(cumulative);
-        }
+        // An RNG that always returns 0 picks the first rule
+        let mut collection = Collection::with_rng(source, StepRng::new(0, 0)).unwrap();
+        assert_eq!(
+            collection.generate("coin", 3).unwrap(),
+            "heads, heads, heads"
+        );
 
-        let total_weight = cumulative;
+        // Draws of 0.0 and 0.75 of the total weight pick heads, then tails
+        let mut collection = Collection::with_rng(source, StepRng::new(0, 3 << 62)).unwrap();
+        assert_eq!(collection.generate("coin", 2).unwrap(), "heads, tails");
 
-        Ok(Self {
-            metadata: table.metadata,
-            rules: table.rules,
-            cumulative_weights,
-            total_weight,
-        })
+        // One that always returns its maximum picks the last rule
+        let mut collection = Collection::with_rng(source, StepRng::new(u64::MAX, 0)).unwrap();
+        assert_eq!(collection.roll("coin").unwrap(), "tails");
+        assert_eq!(collection.draw("coin").unwrap(), "tails");
     }
 
-    /// Fast weighted rule selection using binary search on pre-computed cumulative weights
-    /// This is O(log n) instead of O(n) linear search
-    fn select_rule_index(&self, random_value: f64) -> usize {
-        match self.cumulative_weights.binary_search_by(|&weight| {
-            if weight < random_value {
-                std::cmp::Ordering::Less
-            } else {
-                std::cmp::Ordering::Greater
+    #[test]
+    fn test_merge() {
+        let mut base =
+            Collection::with_seed("%hero% = \"Ayla\"\n#intro\n1.0: %hero% meets", 1).unwrap();
+        let mut monsters =
+            Collection::new("#monster\n1.0: a {#size} troll\n\n#size\n1.0: big").unwrap();
+        monsters.register_modifier("shout", Box::new(|text| text.to_uppercase()));
+        monsters.define_macro("hero", "Ayla").unwrap();
+
+        base.merge(monsters).unwrap();
+        assert_eq!(base.get_table_ids(), ["intro", "monster", "size"]);
+        assert_eq!(base.roll("monster").unwrap(), "a big troll");
+        assert!(base.custom_modifiers.contains_key("shout"));
+        assert!(matches!(
+            base.rebuild(),
+            Err(CollectionError::SourceNotRetained)
+        ));
+
+        // A clashing table id fails and leaves the collection as it was
+        let clash = Collection::new("#extra\n1.0: x\n\n#size\n1.0: small").unwrap();
+        let size_span = base.tables["size"].span;
+        match base.merge(clash) {
+            Err(CollectionError::DuplicateMergedTable {
+                id,
+                span,
+                other_span,
+            }) => {
+                assert_eq!(id, "size");
+                assert_eq!(span, size_span);
+                assert_eq!(other_span.start, 15);
             }
-        }) {
-            Ok(index) => index,
-            Err(index) => index.min(self.rules.len() - 1),
+            other => panic!("Expected DuplicateMergedTable, got {:?}", other),
         }
+        assert_eq!(base.get_table_ids(), ["intro", "monster", "size"]);
+        assert_eq!(base.roll("size").unwrap(), "big");
+
+        let conflicting = Collection::new("%hero% = \"Crono\"\n#other\n1.0: x").unwrap();
+        assert!(matches!(
+            base.merge(conflicting),
+            Err(CollectionError::ConflictingMacro(name)) if name == "hero"
+        ));
+        assert!(!base.has_table("other"));
     }
-}
 
-/// A collection of tables that can generate random content
-#[derive(Debug)]
-pub struct Collection {
-    tables: HashMapType<String, OptimizedTable>,
-    rng: SmallRng,
-    table_order: Vec<String>, // Preserve the order tables appear in source
-}
+    #[test]
+    fn test_generate_to() {
+        let source = "#n\n1.0: {d6}\n1.0: {d20}";
+        let mut streamed = Collection::with_seed(source, 3).unwrap();
+        let mut joined = Collection::with_seed(source, 3).unwrap();
 
-impl Collection {
-    /// Create a new collection from TBL source code
-    pub fn new(source: &str) -> CollectionResult<Self> {
-        let program = parse(source).map_err(|e| CollectionError::ParseError(format!("{}", e)))?;
+        let mut out = Vec::new();
+        streamed.generate_to("n", 50, "\n", &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            joined.generate_with_separator("n", 50, "\n").unwrap()
+        );
 
-        #[cfg(feature = "wasm")]
-        let mut tables = HashMapType::with_hasher(ahash::RandomState::new());
-        #[cfg(not(feature = "wasm"))]
-        let mut tables = HashMapType::default();
-        let mut table_order = Vec::new();
+        let mut out = Vec::new();
+        streamed.generate_to("n", 0, "\n", &mut out).unwrap();
+        assert!(out.is_empty());
+        assert!(matches!(
+            streamed.generate_to("missing", 1, "\n", &mut out),
+            Err(CollectionError::TableNotFound(_))
+        ));
 
-        // First pass: collect all tables and preserve order, optimizing during parse-time
-        for table_node in program.tables {
-            let table = table_node.value;
-            let table_id = table.metadata.id.clone();
+        // Write errors surface as `Io`
+        let mut full = [0u8; 4];
+        let result = streamed.generate_to("n", 10, ", ", &mut &mut full[..]);
+        assert!(matches!(result, Err(CollectionError::Io(_))));
+    }
 
-            // Convert to optimized table with pre-computed weights (parse-time optimization)
-            let optimized_table = OptimizedTable::from_table(table)?;
+    #[test]
+    fn test_duplicate_table_declarations() {
+        let source = "#dup\n1.0: first\n\n#other\n1.0: x\n\n#dup[export]\n1.0: second";
+        match Collection::new(source) {
+            Err(CollectionError::DuplicateTable {
+                id,
+                first_span,
+                second_span,
+            }) => {
+                assert_eq!(id, "dup");
+                assert_eq!(first_span.start, 0);
+                assert_eq!(second_span.start, source.find("#dup[export]").unwrap());
+                assert!(second_span.slice(source).starts_with("#dup[export]"));
+            }
+            other => panic!("Expected DuplicateTable, got {:?}", other),
+        }
 
-            table_order.push(table_id.clone());
-            tables.insert(table_id, optimized_table);
+        let message = Collection::new(source).unwrap_err().to_string();
+        assert!(message.contains("'dup' is declared twice"), "{}", message);
+    }
+
+    #[test]
+    fn test_silent_modifier() {
+        // The silent reference still rolls, so the visible roll matches the
+        // second of two visible rolls with the same seed
+        let silent = "#t\n1.0: [{#n|silent}] {#n}\n\n#n\n1.0: {d1000}";
+        let visible = "#t\n1.0: [{#n}] {#n}\n\n#n\n1.0: {d1000}";
+        for seed in 0..10 {
+            let quiet = Collection::with_seed(silent, seed)
+                .unwrap()
+                .roll("t")
+                .unwrap();
+            let loud = Collection::with_seed(visible, seed)
+                .unwrap()
+                .roll("t")
+                .unwrap();
+            assert_eq!(quiet, format!("[] {}", loud.split(' ').nth(1).unwrap()));
         }
 
-        // Second pass: validate all table references
-        Self::validate_table_references(&tables)?;
+        // Silent wins over the rest of the chain, wherever it appears
+        let mut collection = Collection::new(
+            "#t\n1.0: <{#w|indefinite|silent}><{#w|silent|definite}><{d6|silent}>\n\n#w\n1.0: owl",
+        )
+        .unwrap();
+        assert_eq!(collection.roll("t").unwrap(), "<><><>");
 
-        Ok(Self {
-            tables,
-            rng: SmallRng::seed_from_u64(rand::random::<u64>()), // Use random seed
-            table_order,
-        })
-    }
+        // Including the breakdown of an annotated roll
+        collection.annotate_dice(true);
+        assert_eq!(collection.roll("t").unwrap(), "<><><>");
 
-    /// Generate content from a table by ID
-    pub fn generate(&mut self, table_id: &str, count: usize) -> CollectionGenResult {
-        let mut results = Vec::new();
+        let warnings = collection.lint_modifiers();
+        assert_eq!(
+            warnings
+                .iter()
+                .filter(|w| w.message.contains("alongside 'silent'"))
+                .count(),
+            2
+        );
+    }
 
-        for _ in 0..count {
-            let result = self.generate_single(table_id)?;
-            results.push(result);
+    #[test]
+    fn test_variable_binding() {
+        let source = "#story\n1.0: {#name > hero} went home. {$hero|uppercase} was tired.\n\n#name\n1.0: Ann\n1.0: Bo\n1.0: Cy\n1.0: Di";
+        for seed in 0..20 {
+            let story = Collection::with_seed(source, seed)
+                .unwrap()
+                .roll("story")
+                .unwrap();
+            let (hero, rest) = story.split_once(" went home. ").unwrap();
+            assert_eq!(rest, format!("{} was tired.", hero.to_uppercase()));
         }
 
-        Ok(results.join(", "))
+        // Bindings reach nested tables, but not the next generation
+        let mut collection = Collection::new(
+            "#outer\n1.0: {#n > x} {#inner}\n\n#inner\n1.0: and {$x}\n\n#n\n1.0: owl",
+        )
+        .unwrap();
+        assert_eq!(collection.roll("outer").unwrap(), "owl and owl");
+        match collection.roll("inner") {
+            Err(CollectionError::UnboundVariable { name, table_id }) => {
+                assert_eq!(name, "x");
+                assert_eq!(table_id, "inner");
+            }
+            other => panic!("Expected UnboundVariable, got {:?}", other),
+        }
     }
 
-    /// Generate a single result from a table (now optimized with pre-computed weights)
-    fn generate_single(&mut self, table_id: &str) -> CollectionResult<String> {
-        // Get the rule using optimized selection
-        let rule_content = {
-            let table = self
-                .tables
-                .get(table_id)
-                .ok_or_else(|| CollectionError::TableNotFound(table_id.to_string()))?;
+    #[test]
+    fn test_conditional() {
+        let source = "#line\n1.0: {#g > gender} took {$gender == she ? her : {#his}} hat{$gender == he ? !}\n\n#g\n1.0: she\n1.0: he\n\n#his\n1.0: his";
 
-            // Use pre-computed total weight (O(1) instead of O(n))
-            let random_value: f64 = self.rng.gen_range(0.0..table.total_weight);
+        // The first rule binds "she", so the first branch is taken
+        let mut collection = Collection::with_rng(source, StepRng::new(0, 0)).unwrap();
+        assert_eq!(collection.roll("line").unwrap(), "she took her hat");
 
-            // Use binary search on pre-computed cumulative weights (O(log n) instead of O(n))
-            let rule_index = table.select_rule_index(random_value);
-            let selected_rule = &table.rules[rule_index];
+        // The last binds "he", so the otherwise branch and its reference are
+        let mut collection = Collection::with_rng(source, StepRng::new(u64::MAX, 0)).unwrap();
+        assert_eq!(collection.roll("line").unwrap(), "he took his hat!");
 
-            // Clone the content so we don't hold a reference to self
-            selected_rule.value.content.clone()
-        };
+        // Variant counts don't follow bindings, so each branch counts for every name
+        assert_eq!(collection.count_variants("line").unwrap(), Some(8));
+        assert!(matches!(
+            Collection::new("#t\n1.0: {$x == y ? z}").unwrap().roll("t"),
+            Err(CollectionError::UnboundVariable { .. })
+        ));
+    }
 
-        // Process the rule content
-        let mut result = String::new();
+    #[test]
+    fn test_reset_rng() {
+        let source = "#n\n1.0: {d1000}";
+        let mut collection = Collection::with_seed(source, 7).unwrap();
+        assert_eq!(collection.current_seed(), 7);
 
-        for content in &rule_content {
-            match content {
-                RuleContent::Text(text) => {
-                    result.push_str(text);
-                }
-                RuleContent::Expression(Expression::TableReference {
-                    table_id: ref_id,
-                    modifiers,
-                }) => {
-                    // Recursively generate from the referenced table
-                    let mut generated = self.generate_single(ref_id)?;
+        let first = collection.generate("n", 5).unwrap();
+        let second = collection.generate("n", 5).unwrap();
+        collection.reset_rng();
+        assert_eq!(collection.generate("n", 5).unwrap(), first);
+        assert_eq!(collection.generate("n", 5).unwrap(), second);
 
-                    // Apply modifiers
-                    for modifier in modifiers {
-                        generated = self.apply_modifier(&generated, modifier);
-                    }
+        // An unseeded collection reports the seed it picked
+        let mut random = Collection::new(source).unwrap();
+        let output = random.generate("n", 5).unwrap();
+        let mut replay = Collection::with_seed(source, random.current_seed()).unwrap();
+        assert_eq!(replay.generate("n", 5).unwrap(), output);
+    }
 
-                    result.push_str(&generated);
-                }
-                RuleContent::Expression(Expression::ExternalTableReference {
-                    publisher,
-                    collection,
-                    table_id,
-                    modifiers: _,
-                }) => {
-                    // For now, external references always error since we don't have dependency resolution
-                    // In the future, this will be handled by the dependency resolution system
-                    return Err(CollectionError::MissingDependency {
-                        publisher: publisher.clone(),
-                        collection: collection.clone(),
-                        table_id: table_id.clone(),
-                        referencing_table: table_id.clone(), // TODO: we need to pass the current table being generated
-                    });
-                }
-                RuleContent::Expression(Expression::DiceRoll { count, sides }) => {
-                    // Roll dice and add the result
-                    let dice_count = count.unwrap_or(1);
-                    let mut total = 0;
-                    for _ in 0..dice_count {
-                        total += self.rng.gen_range(1..=*sides);
-                    }
-                    result.push_str(&total.to_string());
-                }
-            }
+    #[test]
+    fn test_collection_to_source() {
+        let source = "%hero% = \"Ann\"\n\n#quest[export]\n2: %hero% rolls {2d6+1|ordinal} vs {#foe > f}, {$f == orc ? {a|3:b} : x}\n1: ~\n\n#foe\n1: orc\n1.5: troll";
+        let mut collection = Collection::with_seed(source, 3).unwrap();
+        collection
+            .merge(Collection::new("#loot[export]\n1: gold").unwrap())
+            .unwrap();
+
+        let written = collection.to_source().unwrap();
+        assert_eq!(collection.to_string(), written);
+        let mut reparsed = Collection::with_seed(&written, 3).unwrap();
+        assert_eq!(reparsed.to_source().unwrap(), written);
+        assert_eq!(reparsed.get_table_ids(), collection.get_table_ids());
+        assert_eq!(
+            reparsed.get_exported_table_ids(),
+            collection.get_exported_table_ids()
+        );
+        for table_id in collection.get_table_ids() {
+            let original = collection.table_rules(&table_id).unwrap();
+            let copy = reparsed.table_rules(&table_id).unwrap();
+            assert_eq!(original.len(), copy.len());
+            assert!(
+                original
+                    .iter()
+                    .zip(copy)
+                    .all(|(a, b)| a.value.eq_ignoring_spans(&b.value))
+            );
         }
+        assert_eq!(
+            reparsed.generate("quest", 10).unwrap(),
+            collection.generate("quest", 10).unwrap()
+        );
 
-        Ok(result.trim().to_string())
-    }
+        // Comments on tables and rules are written back
+        let source = "// Basic colors\n#color\n// The warm one\n1: red\n1: blue // unattached";
+        let written = Collection::new(source).unwrap().to_source().unwrap();
+        assert_eq!(
+            written,
+            "// Basic colors\n#color\n// The warm one\n1: red\n1: blue\n"
+        );
 
-    /// Apply a modifier to generated text
-    fn apply_modifier(&self, text: &str, modifier: &str) -> String {
-        match modifier {
-            "capitalize" => {
-                let mut chars: Vec<char> = text.chars().collect();
-                if let Some(first_char) = chars.get_mut(0) {
-                    *first_char = first_char.to_uppercase().next().unwrap_or(*first_char);
-                }
-                chars.into_iter().collect()
-            }
-            "uppercase" => text.to_uppercase(),
-            "lowercase" => text.to_lowercase(),
-            "indefinite" => {
-                let first_char = text
-                    .chars()
-                    .next()
-                    .unwrap_or(' ')
-                    .to_lowercase()
-                    .next()
-                    .unwrap_or(' ');
-                let article = if "aeiou".contains(first_char) {
-                    "an"
-                } else {
-                    "a"
-                };
-                format!("{} {}", article, text)
-            }
-            "definite" => format!("the {}", text),
-            _ => text.to_string(), // Unknown modifier, return unchanged
-        }
+        // References are written as declared, so they parse back case-sensitively
+        let source = "#Color[export]\n1: red\n\n#shirt\n1: a {#COLOR} shirt";
+        let insensitive = Collection::builder()
+            .case_insensitive(true)
+            .build(source)
+            .unwrap();
+        let mut reparsed = Collection::new(&insensitive.to_source().unwrap()).unwrap();
+        assert_eq!(reparsed.roll("shirt").unwrap(), "a red shirt");
+
+        let namespaced = Collection::from_sources(&[("core", "#a\n1: x")]).unwrap();
+        assert!(matches!(
+            namespaced.to_source(),
+            Err(CollectionError::UnwritableTableId(id)) if id == "core:a"
+        ));
     }
 
-    /// Validate that all table references point to existing tables
-    fn validate_table_references(
-        tables: &HashMapType<String, OptimizedTable>,
-    ) -> CollectionResult<()> {
-        for (table_id, table) in tables {
-            for rule in &table.rules {
-                for content in &rule.value.content {
-                    match content {
-                        RuleContent::Expression(Expression::TableReference {
-                            table_id: ref_id,
-                            modifiers: _,
-                        }) => {
-                            if !tables.contains_key(ref_id) {
-                                return Err(CollectionError::InvalidTableReference {
-                                    table_id: ref_id.clone(),
-                                    referencing_table: table_id.clone(),
-                                });
-                            }
-                        }
-                        RuleContent::Expression(Expression::ExternalTableReference {
-                            publisher,
-                            collection,
-                            table_id: ext_table_id,
-                            modifiers: _,
-                        }) => {
-                            // External references always error in basic collections since dependencies aren't resolved
-                            return Err(CollectionError::MissingDependency {
-                                publisher: publisher.clone(),
-                                collection: collection.clone(),
-                                table_id: ext_table_id.clone(),
-                                referencing_table: table_id.clone(),
-                            });
-                        }
-                        _ => {} // Other content types (text, dice rolls) don't need validation
-                    }
-                }
+    #[test]
+    fn test_percent_weights() {
+        let mut collection =
+            Collection::new("#weather[percent]\n70: sun\n20.5: rain\n9.5: snow").unwrap();
+        assert_eq!(
+            collection.rule_probabilities("weather").unwrap()[0],
+            ("sun".to_string(), 0.7)
+        );
+        assert!(["sun", "rain", "snow"].contains(&collection.roll("weather").unwrap().as_str()));
+        assert!(
+            collection
+                .to_source()
+                .unwrap()
+                .contains("#weather[percent]")
+        );
+
+        // Float rounding is tolerated, a real shortfall is not
+        assert!(Collection::new("#t[percent]\n0.1: a\n0.2: b\n99.7: c").is_ok());
+        match Collection::new("#t[export percent]\n60: a\n30: b") {
+            Err(CollectionError::PercentSumMismatch { table_id, sum }) => {
+                assert_eq!(table_id, "t");
+                assert_eq!(sum, 90.0);
             }
+            other => panic!("Expected PercentSumMismatch, got {:?}", other),
         }
-        Ok(())
     }
 
-    /// Check if a table exists in the collection
-    pub fn has_table(&self, table_id: &str) -> bool {
-        self.tables.contains_key(table_id)
-    }
+    #[test]
+    fn test_weight_overflow() {
+        let overflows = |result: CollectionResult<Collection>| {
+            matches!(
+                result,
+                Err(CollectionError::WeightOverflow { table_id, sum })
+                    if table_id == "t" && sum == f64::INFINITY
+            )
+        };
+        assert!(overflows(Collection::new("#t\n1e308: a\n1e308: b")));
+        // Choice weights have no exponent syntax, so write 1e308 out in full
+        let huge = format!("1{}", "0".repeat(308));
+        let choice = format!("#t\n1.0: {{{huge}:a|{huge}:b}}");
+        assert!(overflows(Collection::new(&choice)));
+        let fits = format!("#t\n1e308: a\n1.0: {{{huge}:a|1:b}}");
+        assert!(Collection::new(&fits).is_ok());
 
-    /// Get a list of all table IDs in the collection
-    pub fn get_table_ids(&self) -> Vec<String> {
-        // Return table IDs in the order they appear in the source
-        self.table_order.clone()
+        let mut collection = Collection::new("#t\n1e308: a\n\n#u\n1e308: b").unwrap();
+        assert!(matches!(
+            collection.merge_tables("t", "u", false),
+            Err(CollectionError::WeightOverflow { table_id, .. }) if table_id == "t"
+        ));
+        assert_eq!(collection.generate("t", 1).unwrap(), "a");
     }
 
-    /// Get a list of exported table IDs in the collection
-    pub fn get_exported_table_ids(&self) -> Vec<String> {
-        // Return exported table IDs in the order they appear in the source
-        self.table_order
-            .iter()
-            .filter(|table_id| {
-                self.tables
-                    .get(*table_id)
-                    .map(|table| table.metadata.export)
-                    .unwrap_or(false)
-            })
-            .cloned()
-            .collect()
-    }
-}
+    #[test]
+    fn test_table_attributes() {
+        let source = "#loot[export, category=\"blades, axes and [polearms]\", min_level=5 rarity=rare]\n1.0: sword\n\n#plain\n1.0: x";
+        let collection = Collection::new(source).unwrap();
+        assert!(collection.exports_table("loot"));
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let attributes = collection.table_attributes("loot").unwrap();
+        assert_eq!(attributes.len(), 3);
+        assert_eq!(attributes["category"], "blades, axes and [polearms]");
+        assert_eq!(attributes["min_level"], "5");
+        assert_eq!(attributes["rarity"], "rare");
+        assert!(collection.table_attributes("plain").unwrap().is_empty());
+        assert!(collection.table_attributes("missing").is_none());
+
+        // Attributes survive writing the collection back out
+        let reparsed = Collection::new(&collection.to_source().unwrap()).unwrap();
+        assert_eq!(reparsed.table_attributes("loot"), Some(attributes));
+    }
 
     #[test]
-    fn test_collection_creation() {
-        let source = r#"#color
-1.0: red
-2.0: blue
-3.0: green"#;
+    fn test_set_trim_output() {
+        let source = "#t\n1.0: [{#pad}|{a | b}]\n\n#pad\n1.0:  x \n\n#lead\n1.0:   y";
+        let mut collection = Collection::with_rng(source, StepRng::new(0, 0)).unwrap();
+        assert_eq!(collection.roll("t").unwrap(), "[ x |a]");
+        assert_eq!(collection.roll("lead").unwrap(), "y");
 
-        let collection = Collection::new(source);
-        assert!(collection.is_ok());
+        // Only the space after each rule's colon goes
+        collection.set_trim_output(false);
+        assert_eq!(collection.roll("t").unwrap(), "[ x |a ]");
+        assert_eq!(collection.roll("lead").unwrap(), "  y");
+        assert_eq!(collection.draw("lead").unwrap(), "  y");
+    }
 
-        let collection = collection.unwrap();
-        assert!(collection.tables.contains_key("color"));
+    #[test]
+    fn test_nested_results_keep_whitespace() {
+        let source = "#size\n1.0: big \n\n#animal\n1.0: {#size}dog\n\n#pair\n1.0: {#size} {#size}";
+        let mut collection = Collection::new(source).unwrap();
+        assert_eq!(collection.roll("animal").unwrap(), "big dog");
+        assert_eq!(collection.roll("pair").unwrap(), "big  big");
+        assert_eq!(collection.draw("size").unwrap(), "big");
     }
 
     #[test]
-    fn test_simple_generation() {
-        let source = r#"#color
-1.0: red
-2.0: blue
-3.0: green"#;
+    fn test_iter_generate() {
+        let source = "#color\n1.0: red\n1.0: green\n1.0: blue";
+        let mut collection = Collection::with_seed(source, 7).unwrap();
+        let expected = collection.generate_many("color", 20).unwrap();
 
-        let mut collection = Collection::new(source).unwrap();
-        let result = collection.generate("color", 1);
-        assert!(result.is_ok());
+        collection.reset_rng();
+        let streamed: Vec<String> = collection
+            .iter_generate("color")
+            .take(20)
+            .collect::<CollectionResult<_>>()
+            .unwrap();
+        assert_eq!(streamed, expected);
 
-        let generated = result.unwrap();
-        assert!(generated == "red" || generated == "blue" || generated == "green");
+        let mut missing = collection.iter_generate("missing");
+        assert!(matches!(
+            missing.next(),
+            Some(Err(CollectionError::TableNotFound(_)))
+        ));
     }
 
     #[test]
-    fn test_table_reference() {
-        let source = r#"#color
-1.0: red
-2.0: blue
+    fn test_case_insensitive_tables() {
+        let source = "#Color[export]\n1.0: red\n\n#shirt\n1.0: a {#color} shirt";
+        assert!(matches!(
+            Collection::new(source),
+            Err(CollectionError::InvalidTableReference { .. })
+        ));
 
-#shape
-1.0: circle
-2.0: square
+        let mut collection = Collection::builder()
+            .case_insensitive(true)
+            .build(source)
+            .unwrap();
+        assert_eq!(collection.roll("shirt").unwrap(), "a red shirt");
+        assert_eq!(collection.roll("COLOR").unwrap(), "red");
+        assert!(collection.has_table("color"));
+        assert_eq!(collection.get_table_ids(), vec!["Color", "shirt"]);
+        assert_eq!(
+            collection.get_table_dependencies("SHIRT").unwrap(),
+            vec!["Color"]
+        );
+        assert_eq!(
+            collection.reachable_from("shirt").unwrap(),
+            HashSet::from(["Color".to_string()])
+        );
+        assert_eq!(
+            collection.get_all_dependencies(),
+            HashMap::from([
+                ("Color".to_string(), vec![]),
+                ("shirt".to_string(), vec!["Color".to_string()]),
+            ])
+        );
+        let unused = Collection::builder()
+            .case_insensitive(true)
+            .build("#Helper\n1.0: x\n\n#main[export]\n1.0: y")
+            .unwrap()
+            .lint_unused_tables();
+        assert_eq!(unused.len(), 1);
+        assert_eq!(
+            unused[0].message,
+            "Table 'Helper' is never referenced and not exported"
+        );
+        #[cfg(feature = "serde")]
+        {
+            let grammar: serde_json::Value =
+                serde_json::from_str(&collection.export_grammar()).unwrap();
+            let productions = grammar["productions"].as_array().unwrap();
+            assert_eq!(productions[0]["name"], "Color");
+            let shirt = &productions[1]["alternatives"][0]["symbols"];
+            assert_eq!(shirt[1]["name"], "Color");
+        }
 
-#item
-1.0: {#color} {#shape}"#;
+        // Every id the collection hands out works with every accessor
+        for table_id in collection.get_table_ids() {
+            let table_id = table_id.as_str();
+            assert!(collection.has_table(table_id));
+            collection.draw(table_id).unwrap();
+            collection.reshuffle(table_id).unwrap();
+            collection.remaining(table_id).unwrap();
+            collection.generate_seeded(table_id, 1, 0).unwrap();
+            collection.generate_indexed_batch(table_id, &[0]).unwrap();
+            collection.rule_probabilities(table_id).unwrap();
+            assert!(collection.explain_selection(table_id, 0.0).is_some());
+            collection.table_weight(table_id).unwrap();
+            assert!(collection.table_rules(table_id).is_some());
+            assert!(collection.table_attributes(table_id).is_some());
+            collection.get_table_dependencies(table_id).unwrap();
+            collection.max_reference_depth(table_id).unwrap();
+            collection.count_variants(table_id).unwrap();
+            collection.reachable_from(table_id).unwrap();
+        }
+        assert_eq!(collection.get_exported_table_ids(), vec!["Color"]);
+        assert!(collection.dry_run().is_empty());
+        collection.merge_tables("COLOR", "Color", false).unwrap();
+        assert_eq!(collection.table_rules("color").unwrap().len(), 2);
 
-        let mut collection = Collection::new(source).unwrap();
-        let result = collection.generate("item", 1);
-        assert!(result.is_ok());
+        // Turning it off restores the declared ids, references included
+        collection.set_case_insensitive(false).unwrap();
+        assert!(!collection.has_table("color"));
+        assert_eq!(collection.roll("shirt").unwrap(), "a red shirt");
 
-        let generated = result.unwrap();
-        // Should contain a color and a shape
-        assert!(generated.contains("red") || generated.contains("blue"));
-        assert!(generated.contains("circle") || generated.contains("square"));
+        let mut clashing = Collection::new("#Color\n1.0: red\n\n#color\n1.0: blue").unwrap();
+        assert!(matches!(
+            clashing.set_case_insensitive(true),
+            Err(CollectionError::DuplicateTable { .. })
+        ));
+        assert!(clashing.has_table("Color"));
+        assert!(!clashing.has_table("COLOR"));
     }
 
     #[test]
-    fn test_multiple_generation() {
-        let source = r#"#color
-1.0: red"#;
+    fn test_deferred_validation() {
+        let heroes = "#hero[export]\n1.0: a knight with a {#weapon}";
+        let weapons = "#weapon[export]\n1.0: sword\n1.0: spear from {#hero|definite}'s hall";
+        assert!(matches!(
+            Collection::new(heroes),
+            Err(CollectionError::InvalidTableReference { .. })
+        ));
 
-        let mut collection = Collection::new(source).unwrap();
-        let result = collection.generate("color", 3);
-        assert!(result.is_ok());
+        let deferred = || Collection::builder().seed(1).defer_validation(true);
+        let mut collection = deferred().build(heroes).unwrap();
+        assert!(matches!(
+            collection.roll("hero"),
+            Err(CollectionError::TableNotFound(id)) if id == "weapon"
+        ));
+        assert!(matches!(
+            collection.validate(),
+            Err(CollectionError::InvalidTableReference { .. })
+        ));
 
-        let generated = result.unwrap();
-        assert_eq!(generated, "red, red, red");
+        // Once merged, each file's references resolve to the other's tables
+        collection
+            .merge(deferred().build(weapons).unwrap())
+            .unwrap();
+        collection.validate().unwrap();
+        assert!(
+            collection
+                .roll("hero")
+                .unwrap()
+                .starts_with("a knight with a ")
+        );
+        assert!(matches!(
+            collection.max_reference_depth("hero").unwrap(),
+            ReferenceDepth::Cyclic { .. }
+        ));
+
+        // Validated collections check references on every merge again
+        let dangling = deferred().build("#cape\n1.0: {#cloth}").unwrap();
+        assert!(matches!(
+            collection.merge(dangling),
+            Err(CollectionError::InvalidTableReference { .. })
+        ));
+
+        // A validated cycle with no way out is still rejected
+        let mut looping = deferred().build("#a\n1.0: {#b}").unwrap();
+        looping
+            .merge(deferred().build("#b\n1.0: {#a}").unwrap())
+            .unwrap();
+        assert!(matches!(
+            looping.validate(),
+            Err(CollectionError::InfiniteRecursion { .. })
+        ));
     }
 
     #[test]
@@ -499,12 +7581,39 @@ mod tests {
     #[test]
     fn test_self_reference() {
         let source = r#"#color
+1.0: red
 1.0: {#color} variant"#;
 
         let collection = Collection::new(source);
         assert!(collection.is_ok(), "Self-references should be valid");
     }
 
+    #[test]
+    fn test_infinite_recursion_detected() {
+        // A self-reference with no way out
+        match Collection::new("#color\n1.0: {#color} variant") {
+            Err(CollectionError::InfiniteRecursion { cycle }) => {
+                assert_eq!(cycle, vec!["color", "color"]);
+            }
+            other => panic!("Expected InfiniteRecursion, got {:?}", other.map(|_| ())),
+        }
+
+        // Mutual recursion reached through another table
+        let source =
+            "#entry\n1: {#ping}\n\n#ping\n1: a {#pong}\n\n#pong\n1: b {#ping}\n2: c {#ping} {d6}";
+        match Collection::new(source) {
+            Err(CollectionError::InfiniteRecursion { cycle }) => {
+                assert_eq!(cycle, vec!["ping", "pong", "ping"]);
+            }
+            other => panic!("Expected InfiniteRecursion, got {:?}", other.map(|_| ())),
+        }
+
+        // One terminating rule anywhere in the cycle is enough
+        let source = "#entry\n1: {#ping}\n\n#ping\n1: a {#pong}\n\n#pong\n1: b {#ping}\n1: done";
+        let mut collection = Collection::new(source).unwrap();
+        assert!(collection.generate("entry", 5).is_ok());
+    }
+
     #[test]
     fn test_table_ids_order() {
         let source = r#"#zebra
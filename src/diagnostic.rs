@@ -3,6 +3,8 @@
 /// This module provides a clean separation between error data collection
 /// and error formatting/rendering.
 /// Source location information
+///
+/// Positions and columns count chars, not bytes.
 #[derive(Debug, Clone, PartialEq)]
 pub struct SourceLocation {
     pub position: usize,
@@ -12,6 +14,8 @@ pub struct SourceLocation {
     pub end_position: Option<usize>,
     /// Optional end column for span-based diagnostics  
     pub end_column: Option<usize>,
+    /// Name of the file the source came from, when known
+    pub file: Option<String>,
 }
 
 /// A diagnostic represents a structured error with source context
@@ -33,10 +37,14 @@ pub enum DiagnosticKind {
     ParseError,
     /// Semantic analysis errors (for future use)
     SemanticError,
+    /// Semantic analysis warnings (lints that don't prevent generation)
+    SemanticWarning,
 }
 
 /// Severity levels for diagnostics
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum Severity {
     Error,
     Warning,
@@ -70,6 +78,32 @@ impl Diagnostic {
             DiagnosticKind::LexError
             | DiagnosticKind::ParseError
             | DiagnosticKind::SemanticError => Severity::Error,
+            DiagnosticKind::SemanticWarning => Severity::Warning,
         }
     }
 }
+
+/// Serializes as a flat object for machine-readable output, with 1-based
+/// `line` and `column` and the end of the span (`null` when unknown):
+///
+/// `{"message", "severity", "line", "column", "end_line", "end_column", "suggestion", "file"}`
+#[cfg(feature = "serde")]
+impl serde::Serialize for Diagnostic {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        // Spans are clamped to their first line, so they end on it too
+        let end_line = self.location.end_column.map(|_| self.location.line);
+
+        let mut state = serializer.serialize_struct("Diagnostic", 8)?;
+        state.serialize_field("message", &self.message)?;
+        state.serialize_field("severity", &self.severity())?;
+        state.serialize_field("line", &self.location.line)?;
+        state.serialize_field("column", &self.location.column)?;
+        state.serialize_field("end_line", &end_line)?;
+        state.serialize_field("end_column", &self.location.end_column)?;
+        state.serialize_field("suggestion", &self.suggestion)?;
+        state.serialize_field("file", &self.location.file)?;
+        state.end()
+    }
+}
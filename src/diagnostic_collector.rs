@@ -1,138 +1,168 @@
 use crate::diagnostic::{Diagnostic, DiagnosticKind, SourceLocation};
 
 /// Collects diagnostic information from source code
+///
+/// Positions count chars, not bytes, matching the lexer and `Span`, so
+/// columns land on the right character after multibyte text.
 pub struct DiagnosticCollector {
     source: String,
+    file: Option<String>,
 }
 
 impl DiagnosticCollector {
     pub fn new(source: String) -> Self {
-        Self { source }
+        Self { source, file: None }
     }
 
-    /// Create a source location from a position
-    pub fn location_at(&self, position: usize) -> SourceLocation {
-        let lines: Vec<&str> = self.source.lines().collect();
-        let mut current_pos = 0;
-        let mut line = 1;
-        let mut column = 1;
-
-        for (line_idx, line_content) in lines.iter().enumerate() {
-            let line_end = current_pos + line_content.len();
-            if position <= line_end {
-                line = line_idx + 1;
-                column = position - current_pos + 1;
-                break;
+    /// Record the name of the file the source came from in every location
+    pub fn with_file(mut self, file: &str) -> Self {
+        self.file = Some(file.to_string());
+        self
+    }
+
+    /// Start and end position of each line, excluding its line break
+    ///
+    /// `\r\n`, a lone `\r` and `\n` each end a line, as they do in the lexer.
+    fn line_ranges(&self) -> Vec<(usize, usize)> {
+        let chars: Vec<char> = self.source.chars().collect();
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        let mut i = 0;
+        while i < chars.len() {
+            if matches!(chars[i], '\n' | '\r') {
+                ranges.push((start, i));
+                if chars[i] == '\r' && chars.get(i + 1) == Some(&'\n') {
+                    i += 1;
+                }
+                start = i + 1;
             }
-            current_pos = line_end + 1; // +1 for newline
+            i += 1;
         }
+        ranges.push((start, chars.len()));
+        ranges
+    }
 
-        // Handle case where position is at end of file
-        if line == 0 && !lines.is_empty() {
-            line = lines.len();
-            column = lines.last().unwrap_or(&"").len() + 1;
-        }
+    /// Index of the line containing `position`, with that line's range
+    ///
+    /// A position past the end of the source falls on the last line.
+    fn line_containing(&self, position: usize) -> (usize, (usize, usize)) {
+        let ranges = self.line_ranges();
+        let index = ranges
+            .iter()
+            .position(|&(_, end)| position <= end)
+            .unwrap_or(ranges.len() - 1);
+        (index, ranges[index])
+    }
+
+    /// Create a source location from a position
+    pub fn location_at(&self, position: usize) -> SourceLocation {
+        let (index, (start, _)) = self.line_containing(position);
 
         SourceLocation {
             position,
-            line,
-            column,
+            line: index + 1,
+            column: position.saturating_sub(start) + 1,
             end_position: None,
             end_column: None,
+            file: self.file.clone(),
         }
     }
 
     /// Create a source location from a span (start to end positions)
     pub fn location_span(&self, start_position: usize, end_position: usize) -> SourceLocation {
-        let lines: Vec<&str> = self.source.lines().collect();
-        let mut current_pos = 0;
-        let mut start_line = 1;
-        let mut start_column = 1;
-        let mut end_column = 1;
-
-        // Find start position
-        for (line_idx, line_content) in lines.iter().enumerate() {
-            let line_end = current_pos + line_content.len();
-            if start_position <= line_end {
-                start_line = line_idx + 1;
-                start_column = start_position - current_pos + 1;
-                
-                // Calculate end column on the same line
-                if end_position <= line_end {
-                    end_column = end_position - current_pos + 1;
-                } else {
-                    end_column = line_content.len() + 1;
-                }
-                break;
-            }
-            current_pos = line_end + 1; // +1 for newline
-        }
+        let (index, (start, end)) = self.line_containing(start_position);
 
-        // Handle case where position is at end of file
-        if start_line == 0 && !lines.is_empty() {
-            start_line = lines.len();
-            start_column = lines.last().unwrap_or(&"").len() + 1;
-        }
+        // The end column is clamped to the start line
+        let end_column = end_position.min(end).saturating_sub(start) + 1;
 
         SourceLocation {
             position: start_position,
-            line: start_line,
-            column: start_column,
+            line: index + 1,
+            column: start_position.saturating_sub(start) + 1,
             end_position: Some(end_position),
             end_column: Some(end_column),
+            file: self.file.clone(),
         }
     }
 
     /// Get the source line at a given position
     pub fn source_line_at(&self, position: usize) -> String {
-        let lines: Vec<&str> = self.source.lines().collect();
-        let mut current_pos = 0;
-
-        for line_content in lines.iter() {
-            let line_end = current_pos + line_content.len();
-            if position <= line_end {
-                return line_content.to_string();
-            }
-            current_pos = line_end + 1; // +1 for newline
-        }
-
-        // Return last line if position is at end
-        lines.last().unwrap_or(&"").to_string()
+        let (_, (start, end)) = self.line_containing(position);
+        self.source.chars().skip(start).take(end - start).collect()
     }
 
     /// Create a lexer diagnostic
     pub fn lex_error(&self, position: usize, message: String) -> Diagnostic {
         let location = self.location_at(position);
         let source_line = self.source_line_at(position);
-        
-        Diagnostic::new(
-            DiagnosticKind::LexError,
-            location,
-            message,
-            source_line,
-        )
+
+        Diagnostic::new(DiagnosticKind::LexError, location, message, source_line)
+    }
+
+    /// Create a lexer diagnostic with span highlighting
+    pub fn lex_error_span(
+        &self,
+        start_position: usize,
+        end_position: usize,
+        message: String,
+    ) -> Diagnostic {
+        let location = self.location_span(start_position, end_position);
+        let source_line = self.source_line_at(start_position);
+
+        Diagnostic::new(DiagnosticKind::LexError, location, message, source_line)
     }
 
     /// Create a parser diagnostic
     pub fn parse_error(&self, position: usize, message: String) -> Diagnostic {
         let location = self.location_at(position);
         let source_line = self.source_line_at(position);
-        
+
+        Diagnostic::new(DiagnosticKind::ParseError, location, message, source_line)
+    }
+
+    /// Create a parser diagnostic with span highlighting
+    pub fn parse_error_span(
+        &self,
+        start_position: usize,
+        end_position: usize,
+        message: String,
+    ) -> Diagnostic {
+        let location = self.location_span(start_position, end_position);
+        let source_line = self.source_line_at(start_position);
+
+        Diagnostic::new(DiagnosticKind::ParseError, location, message, source_line)
+    }
+
+    /// Create a semantic error with span highlighting
+    pub fn semantic_error_span(
+        &self,
+        start_position: usize,
+        end_position: usize,
+        message: String,
+    ) -> Diagnostic {
+        let location = self.location_span(start_position, end_position);
+        let source_line = self.source_line_at(start_position);
+
         Diagnostic::new(
-            DiagnosticKind::ParseError,
+            DiagnosticKind::SemanticError,
             location,
             message,
             source_line,
         )
     }
 
-    /// Create a parser diagnostic with span highlighting
-    pub fn parse_error_span(&self, start_position: usize, end_position: usize, message: String) -> Diagnostic {
+    /// Create a semantic warning with span highlighting
+    pub fn semantic_warning_span(
+        &self,
+        start_position: usize,
+        end_position: usize,
+        message: String,
+    ) -> Diagnostic {
         let location = self.location_span(start_position, end_position);
         let source_line = self.source_line_at(start_position);
-        
+
         Diagnostic::new(
-            DiagnosticKind::ParseError,
+            DiagnosticKind::SemanticWarning,
             location,
             message,
             source_line,
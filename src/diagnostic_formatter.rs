@@ -5,6 +5,7 @@ use std::fmt;
 pub struct DiagnosticFormatter {
     use_colors: bool,
     show_suggestions: bool,
+    context_lines: usize,
 }
 
 impl DiagnosticFormatter {
@@ -12,6 +13,7 @@ impl DiagnosticFormatter {
         Self {
             use_colors: true,
             show_suggestions: true,
+            context_lines: 0,
         }
     }
 
@@ -25,8 +27,27 @@ impl DiagnosticFormatter {
         self
     }
 
+    /// Show `lines` source lines before and after the offending line
+    ///
+    /// A diagnostic only carries its own source line, so context is only
+    /// rendered by [`format_with_source`](Self::format_with_source).
+    pub fn with_context_lines(mut self, lines: usize) -> Self {
+        self.context_lines = lines;
+        self
+    }
+
     /// Format a single diagnostic into a string
     pub fn format(&self, diagnostic: &Diagnostic) -> String {
+        self.render(diagnostic, &[])
+    }
+
+    /// Format a single diagnostic, pulling surrounding lines from the full source
+    pub fn format_with_source(&self, diagnostic: &Diagnostic, source: &str) -> String {
+        let lines: Vec<&str> = source.lines().collect();
+        self.render(diagnostic, &lines)
+    }
+
+    fn render(&self, diagnostic: &Diagnostic, source_lines: &[&str]) -> String {
         let mut output = String::new();
 
         // Error header with emoji
@@ -38,12 +59,28 @@ impl DiagnosticFormatter {
         };
 
         output.push_str(&format!("{} {}\n", severity_icon, diagnostic.message));
-        output.push_str(&format!(
-            "    ┌─ line {}:{}\n",
-            diagnostic.location.line, diagnostic.location.column
-        ));
+        let location = &diagnostic.location;
+        match &location.file {
+            Some(file) => output.push_str(&format!(
+                "    ┌─ {}:{}:{}\n",
+                file, location.line, location.column
+            )),
+            None => output.push_str(&format!(
+                "    ┌─ line {}:{}\n",
+                location.line, location.column
+            )),
+        }
         output.push_str("    │\n");
 
+        // Show preceding context lines (1-based line numbers)
+        let line = diagnostic.location.line;
+        let context_start = line.saturating_sub(self.context_lines).max(1);
+        for number in context_start..line {
+            if let Some(text) = source_lines.get(number - 1) {
+                output.push_str(&format!("{:3} │ {}\n", number, text));
+            }
+        }
+
         // Show the problematic line
         output.push_str(&format!(
             "{:3} │ {}\n",
@@ -51,16 +88,14 @@ impl DiagnosticFormatter {
         ));
 
         // Show the error pointer
-        let pointer_line = if let (Some(_end_position), Some(end_column)) = 
-            (diagnostic.location.end_position, diagnostic.location.end_column) {
+        let pointer_line = if let (Some(_end_position), Some(end_column)) = (
+            diagnostic.location.end_position,
+            diagnostic.location.end_column,
+        ) {
             // Span-based highlighting
             let start_col = diagnostic.location.column.saturating_sub(1);
             let span_length = end_column.saturating_sub(diagnostic.location.column).max(1);
-            format!(
-                "    │ {}{}",
-                " ".repeat(start_col),
-                "^".repeat(span_length)
-            )
+            format!("    │ {}{}", " ".repeat(start_col), "^".repeat(span_length))
         } else {
             // Single position highlighting
             format!(
@@ -71,14 +106,21 @@ impl DiagnosticFormatter {
         output.push_str(&pointer_line);
         output.push('\n');
 
-        // Add suggestion if provided and enabled
-        if self.show_suggestions {
-            if let Some(suggestion) = &diagnostic.suggestion {
-                output.push_str("    │\n");
-                output.push_str(&format!("    = 💡 suggestion: {}\n", suggestion));
+        // Show following context lines
+        for number in line + 1..=line + self.context_lines {
+            if let Some(text) = source_lines.get(number - 1) {
+                output.push_str(&format!("{:3} │ {}\n", number, text));
             }
         }
 
+        // Add suggestion if provided and enabled
+        if self.show_suggestions
+            && let Some(suggestion) = &diagnostic.suggestion
+        {
+            output.push_str("    │\n");
+            output.push_str(&format!("    = 💡 suggestion: {}\n", suggestion));
+        }
+
         output
     }
 
@@ -90,6 +132,16 @@ impl DiagnosticFormatter {
             .collect::<Vec<_>>()
             .join("\n")
     }
+
+    /// Format diagnostics as a JSON array, for CI tools and editors
+    ///
+    /// Each entry has `message`, `severity`, `line`, `column`, `end_line`,
+    /// `end_column` and `suggestion`; see the `Serialize` impl of
+    /// [`Diagnostic`]. Colors and the other display options don't apply.
+    #[cfg(feature = "serde")]
+    pub fn format_json(&self, diagnostics: &[Diagnostic]) -> String {
+        serde_json::to_string(diagnostics).expect("diagnostics always serialize")
+    }
 }
 
 impl Default for DiagnosticFormatter {
@@ -104,3 +156,113 @@ impl fmt::Display for Diagnostic {
         write!(f, "{}", formatter.format(self))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_format_json() {
+        let error = parse("#color\n1.0 blue").unwrap_err();
+        let warning = crate::Collection::builder()
+            .allow_unknown_modifiers(true)
+            .build("#color\n1.0: {#shade|sparkly}\n\n#shade\n1.0: dark")
+            .unwrap()
+            .lint_modifiers()
+            .remove(0);
+
+        let json = DiagnosticFormatter::new().format_json(&[error.diagnostic().clone(), warning]);
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let entries = value.as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+
+        assert_eq!(entries[0]["severity"], "error");
+        assert_eq!(entries[0]["message"], error.diagnostic().message.as_str());
+        assert_eq!(entries[0]["line"], 2);
+        assert_eq!(entries[0]["column"], 5);
+        assert!(entries[0]["suggestion"].is_string());
+
+        assert_eq!(entries[1]["severity"], "warning");
+        assert_eq!(entries[1]["line"], 2);
+        let keys: Vec<&String> = entries[1].as_object().unwrap().keys().collect();
+        assert_eq!(
+            keys,
+            [
+                "column",
+                "end_column",
+                "end_line",
+                "file",
+                "line",
+                "message",
+                "severity",
+                "suggestion"
+            ]
+        );
+        assert!(entries[1]["file"].is_null());
+
+        assert_eq!(DiagnosticFormatter::new().format_json(&[]), "[]");
+    }
+
+    #[test]
+    fn test_context_lines_surround_error() {
+        let source = "#color\n1.0: red\n2.0 blue\n3.0: green\n4.0: teal";
+        let error = parse(source).unwrap_err();
+        let diagnostic = match &error {
+            crate::ParseError::UnexpectedToken { diagnostic, .. } => diagnostic,
+            _ => panic!("Expected UnexpectedToken error"),
+        };
+
+        let output = DiagnosticFormatter::new()
+            .with_context_lines(1)
+            .format_with_source(diagnostic, source);
+
+        assert!(output.contains("  2 │ 1.0: red"));
+        assert!(output.contains("  3 │ 2.0 blue"));
+        assert!(output.contains("  4 │ 3.0: green"));
+        assert!(!output.contains("  1 │ #color"));
+        assert!(!output.contains("  5 │ 4.0: teal"));
+
+        // The pointer sits directly under the error line, before trailing context
+        let error_line = output.find("  3 │").unwrap();
+        let pointer = output.find('^').unwrap();
+        let after = output.find("  4 │").unwrap();
+        assert!(error_line < pointer && pointer < after);
+    }
+
+    #[test]
+    fn test_context_lines_clamped_at_file_edges() {
+        let source = "#color\n1.0 red";
+        let error = parse(source).unwrap_err();
+        let diagnostic = match &error {
+            crate::ParseError::UnexpectedToken { diagnostic, .. } => diagnostic,
+            _ => panic!("Expected UnexpectedToken error"),
+        };
+
+        let output = DiagnosticFormatter::new()
+            .with_context_lines(5)
+            .format_with_source(diagnostic, source);
+
+        assert!(output.contains("  1 │ #color"));
+        assert!(output.contains("  2 │ 1.0 red"));
+        assert!(!output.contains("  3 │"));
+    }
+
+    #[test]
+    fn test_format_without_source_ignores_context() {
+        let source = "#color\n1.0: red\n2.0 blue";
+        let error = parse(source).unwrap_err();
+        let diagnostic = match &error {
+            crate::ParseError::UnexpectedToken { diagnostic, .. } => diagnostic,
+            _ => panic!("Expected UnexpectedToken error"),
+        };
+
+        let output = DiagnosticFormatter::new()
+            .with_context_lines(2)
+            .format(diagnostic);
+
+        assert!(output.contains("  3 │ 2.0 blue"));
+        assert!(!output.contains("  2 │"));
+    }
+}
@@ -1,17 +1,48 @@
+use crate::ast::Span;
 use crate::diagnostic::Diagnostic;
 use std::fmt;
 
 /// Represents lexical analysis errors with diagnostic information
+///
+/// Each variant carries the span of the offending source text, which may
+/// cover several characters (a malformed number, an unterminated comment).
 #[derive(Debug, Clone, PartialEq)]
 pub enum LexError {
     InvalidCharacter {
         character: char,
         diagnostic: Box<Diagnostic>,
+        span: Span,
     },
     InvalidNumber {
         reason: String,
         diagnostic: Box<Diagnostic>,
+        span: Span,
     },
+    /// A `{` whose expression ran into the end of the line without a `}`
+    UnclosedExpression {
+        diagnostic: Box<Diagnostic>,
+        span: Span,
+    },
+}
+
+impl LexError {
+    /// Source span of the text that caused the error
+    pub fn span(&self) -> Span {
+        match self {
+            LexError::InvalidCharacter { span, .. }
+            | LexError::InvalidNumber { span, .. }
+            | LexError::UnclosedExpression { span, .. } => *span,
+        }
+    }
+
+    /// The diagnostic describing where and why lexing failed
+    pub fn diagnostic(&self) -> &Diagnostic {
+        match self {
+            LexError::InvalidCharacter { diagnostic, .. }
+            | LexError::InvalidNumber { diagnostic, .. }
+            | LexError::UnclosedExpression { diagnostic, .. } => diagnostic,
+        }
+    }
 }
 
 /// Represents parsing errors with diagnostic information
@@ -47,6 +78,19 @@ impl fmt::Display for LexError {
         match self {
             LexError::InvalidCharacter { diagnostic, .. } => write!(f, "{}", diagnostic),
             LexError::InvalidNumber { diagnostic, .. } => write!(f, "{}", diagnostic),
+            LexError::UnclosedExpression { diagnostic, .. } => write!(f, "{}", diagnostic),
+        }
+    }
+}
+
+impl ParseError {
+    /// The diagnostic describing where and why parsing failed
+    pub fn diagnostic(&self) -> &Diagnostic {
+        match self {
+            ParseError::UnexpectedToken { diagnostic, .. }
+            | ParseError::UnexpectedEof { diagnostic, .. }
+            | ParseError::InvalidCharacter { diagnostic, .. }
+            | ParseError::InvalidNumber { diagnostic, .. } => diagnostic,
         }
     }
 }
@@ -68,13 +112,19 @@ impl From<LexError> for ParseError {
             LexError::InvalidCharacter {
                 character,
                 diagnostic,
+                ..
             } => ParseError::InvalidCharacter {
                 character,
                 diagnostic,
             },
-            LexError::InvalidNumber { reason, diagnostic } => {
-                ParseError::InvalidNumber { reason, diagnostic }
-            }
+            LexError::InvalidNumber {
+                reason, diagnostic, ..
+            } => ParseError::InvalidNumber { reason, diagnostic },
+            LexError::UnclosedExpression { diagnostic, .. } => ParseError::UnexpectedToken {
+                expected: "'}'".to_string(),
+                found: "end of line".to_string(),
+                diagnostic,
+            },
         }
     }
 }
@@ -1,4 +1,5 @@
-use crate::ast::Span;
+use crate::ast::{KeepMode, Span, starts_with_choice_weight};
+use crate::diagnostic::Diagnostic;
 use crate::diagnostic_collector::DiagnosticCollector;
 use crate::errors::{LexError, LexResult};
 use std::fmt;
@@ -6,6 +7,24 @@ use std::fmt;
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
+/// Modifier keywords recognized in table references (always lowercase)
+pub(crate) const MODIFIER_KEYWORDS: [&str; 11] = [
+    "indefinite",
+    "definite",
+    "capitalize",
+    "uppercase",
+    "lowercase",
+    "titlecase",
+    "ordinal",
+    "number_word",
+    "repeat",
+    "truncate",
+    "silent",
+];
+
+/// Most dice a single roll may use by default, as in `{1000d6}`
+pub(crate) const MAX_DICE_COUNT: u32 = 1000;
+
 /// Represents the different types of tokens in our TBL language
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -31,8 +50,13 @@ pub enum TokenType {
     /// Modifier keyword for table references
     Modifier(String),
 
-    /// Dice roll expression (like "d6", "2d10")
-    DiceRoll { count: Option<u32>, sides: u32 },
+    /// Dice roll expression (like "d6", "2d10", "4d6kh3")
+    DiceRoll {
+        count: Option<u32>,
+        sides: u32,
+        keep: Option<KeepMode>,
+        modifier: Option<i32>,
+    },
 
     /// Left bracket '['
     LeftBracket,
@@ -58,6 +82,37 @@ pub enum TokenType {
     /// Forward slash '/' for external references
     Slash,
 
+    /// Dollar sign '$' for variable references like `{$hero}`
+    Dollar,
+
+    /// Greater-than sign '>' for variable bindings like `{#name > hero}`
+    Greater,
+
+    /// Double equals sign '==' comparing a variable in a conditional like `{$gender == she ? her : his}`
+    EqualEqual,
+
+    /// Question mark '?' starting the branches of a conditional
+    Question,
+
+    /// Equals sign '=' between a table attribute's key and value, as in `[category="weapons"]`
+    Equals,
+
+    /// A table attribute's value, without any quotes around it
+    AttributeValue(String),
+
+    /// A top-level macro definition like `%game% = "Dragon Quest"`
+    MacroDefinition { name: String, value: String },
+
+    /// A `// comment`, with its text after the slashes, trimmed
+    ///
+    /// Only produced by a lexer built `with_comments(true)`.
+    LineComment(String),
+
+    /// A `/* comment */`, with its text between the delimiters, trimmed
+    ///
+    /// Only produced by a lexer built `with_comments(true)`.
+    BlockComment(String),
+
     /// Newline character
     Newline,
 
@@ -65,6 +120,15 @@ pub enum TokenType {
     Eof,
 }
 
+impl TokenType {
+    /// Whether two token types are the same variant, ignoring any payload
+    ///
+    /// `Identifier("a")` and `Identifier("b")` match; `Identifier` and `Modifier` don't.
+    pub fn same_variant(&self, other: &TokenType) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
 /// A token with its type, lexeme, and position
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -82,6 +146,14 @@ impl Token {
             span,
         }
     }
+
+    /// Compare type and lexeme, ignoring the span
+    ///
+    /// Handy in tests that care what was lexed but not exactly where; the
+    /// derived `PartialEq` still compares spans too.
+    pub fn same_kind(&self, other: &Token) -> bool {
+        self.token_type == other.token_type && self.lexeme == other.lexeme
+    }
 }
 
 /// Lexer for tokenizing input source code
@@ -91,6 +163,16 @@ pub struct Lexer {
     start: usize,
     in_rule_text: bool,
     in_expression: bool,
+    in_flags: bool,
+    expression_start: usize, // Position of the '{' that opened the current expression
+    choice_starts: Vec<usize>, // Positions of the '{' of each open inline choice or conditional, outermost first
+    conditional_starts: Vec<usize>, // The entries of `choice_starts` that are conditionals
+    at_option_start: bool,     // Just after a choice's '{' or '|', where a weight may appear
+    option_weight_colon: bool, // An option weight was just lexed, so ':' follows it
+    attribute_value_follows: bool, // An attribute's '=' was just lexed, so its value follows
+    unclosed_expression: Option<LexError>, // First expression left open at a newline
+    emit_comments: bool,       // Produce comment tokens instead of skipping comments
+    max_dice: u32,             // Most dice a roll may use before it's rejected
     diagnostic_collector: DiagnosticCollector,
 }
 
@@ -103,10 +185,41 @@ impl Lexer {
             start: 0,
             in_rule_text: false,
             in_expression: false,
+            in_flags: false,
+            expression_start: 0,
+            choice_starts: Vec::new(),
+            conditional_starts: Vec::new(),
+            at_option_start: false,
+            option_weight_colon: false,
+            attribute_value_follows: false,
+            unclosed_expression: None,
+            emit_comments: false,
+            max_dice: MAX_DICE_COUNT,
             diagnostic_collector: DiagnosticCollector::new(input.to_string()),
         }
     }
 
+    /// Name the file being lexed, so diagnostics report it
+    pub fn with_file(mut self, file: &str) -> Self {
+        self.diagnostic_collector = self.diagnostic_collector.with_file(file);
+        self
+    }
+
+    /// Produce `LineComment` and `BlockComment` tokens rather than skipping comments
+    ///
+    /// Off by default. The parser attaches comments on the lines just before a
+    /// table or rule to its `doc`, for formatters and documentation tools.
+    pub fn with_comments(mut self, enabled: bool) -> Self {
+        self.emit_comments = enabled;
+        self
+    }
+
+    /// Reject dice rolls of more than `count` dice, like `{2000d6}` (default 1000)
+    pub fn with_max_dice(mut self, count: u32) -> Self {
+        self.max_dice = count;
+        self
+    }
+
     /// Tokenizes the entire input and returns a vector of tokens
     pub fn tokenize(&mut self) -> LexResult<Vec<Token>> {
         let mut tokens = Vec::new();
@@ -118,10 +231,18 @@ impl Lexer {
                     tokens.push(token);
                 }
                 Ok(None) => {} // Skip whitespace (except newlines)
-                Err(e) => return Err(e),
+                // An unclosed expression on an earlier line is the root cause
+                Err(e) => return Err(self.unclosed_expression.take().unwrap_or(e)),
             }
         }
 
+        if self.in_expression || !self.choice_starts.is_empty() {
+            return Err(self.unclosed_expression());
+        }
+        if let Some(error) = self.unclosed_expression.take() {
+            return Err(error);
+        }
+
         tokens.push(Token::new(
             TokenType::Eof,
             String::new(),
@@ -131,12 +252,62 @@ impl Lexer {
         Ok(tokens)
     }
 
+    /// Tokenizes the entire input, recovering from errors instead of stopping at the first
+    ///
+    /// Text that fails to lex is skipped and its error's diagnostic recorded,
+    /// so the token stream covers everything that did lex, in source order,
+    /// ending with `Eof`. An unclosed expression is reported at the end of its
+    /// line. The diagnostics are empty exactly when `tokenize` would succeed.
+    pub fn tokenize_with_diagnostics(&mut self) -> (Vec<Token>, Vec<Diagnostic>) {
+        let mut tokens = Vec::new();
+        let mut diagnostics = Vec::new();
+
+        while !self.is_at_end() {
+            self.start = self.current;
+            match self.scan_token() {
+                Ok(Some(token)) => tokens.push(token),
+                Ok(None) => {}
+                // The offending text is already consumed, so lexing resumes after it
+                Err(e) => diagnostics.push(e.diagnostic().clone()),
+            }
+            if let Some(error) = self.unclosed_expression.take() {
+                diagnostics.push(error.diagnostic().clone());
+            }
+        }
+
+        if self.in_expression || !self.choice_starts.is_empty() {
+            diagnostics.push(self.unclosed_expression().diagnostic().clone());
+        }
+
+        tokens.push(Token::new(
+            TokenType::Eof,
+            String::new(),
+            Span::new(self.current, self.current),
+        ));
+
+        (tokens, diagnostics)
+    }
+
     fn scan_token(&mut self) -> LexResult<Option<Token>> {
         let c = self.advance();
+        let at_option_start = std::mem::take(&mut self.at_option_start);
 
         match c {
-            // Skip spaces and tabs (except when in rule text)
-            ' ' | '\t' if !self.in_rule_text => Ok(None),
+            // A weight may start each inline choice option
+            _ if at_option_start && self.option_weight_follows() => self.option_weight(c),
+            ':' if self.option_weight_colon => {
+                self.option_weight_colon = false;
+                Ok(Some(self.make_token(TokenType::Colon)))
+            }
+
+            // A table attribute's value, after its '='
+            _ if self.attribute_value_follows && !matches!(c, ' ' | '\t') => {
+                self.attribute_value_follows = false;
+                self.attribute_value(c)
+            }
+
+            // Skip spaces and tabs (except when in rule text, outside expressions)
+            ' ' | '\t' if !self.in_rule_text || self.in_expression => Ok(None),
 
             // Handle comments and forward slash
             '/' => {
@@ -155,9 +326,10 @@ impl Lexer {
                     self.text_segment()
                 } else {
                     // Invalid '/' character outside rule text
+                    let span = Span::new(self.current - 1, self.current);
                     let diagnostic = self
                         .diagnostic_collector
-                        .lex_error(self.current - 1, "Invalid character '/'".to_string())
+                        .lex_error_span(span.start, span.end, "Invalid character '/'".to_string())
                         .with_suggestion(
                             "Only numbers, colons, and rule text are allowed in this language"
                                 .to_string(),
@@ -166,16 +338,60 @@ impl Lexer {
                     Err(LexError::InvalidCharacter {
                         character: c,
                         diagnostic: Box::new(diagnostic),
+                        span,
                     })
                 }
             }
 
+            // Macro definitions start a top-level line
+            '%' if !self.in_rule_text && !self.in_flags => self.macro_definition(),
+
+            // Table attributes, as in `[export, category="weapons"]`. Commas
+            // between flags are optional.
+            '=' if self.in_flags => {
+                self.attribute_value_follows = true;
+                Ok(Some(self.make_token(TokenType::Equals)))
+            }
+            ',' if self.in_flags => Ok(None),
+
             // At symbol for external references (only in expressions)
             '@' if self.in_expression => Ok(Some(self.make_token(TokenType::At))),
 
-            // Newlines end rule text and reset state
-            '\n' => {
+            // Variable references and bindings (only in expressions)
+            '$' if self.in_expression => Ok(Some(self.make_token(TokenType::Dollar))),
+            '>' if self.in_expression => Ok(Some(self.make_token(TokenType::Greater))),
+            '=' if self.in_expression && self.peek() == '=' => {
+                self.advance();
+                Ok(Some(self.make_token(TokenType::EqualEqual)))
+            }
+
+            // A conditional's branches after '?' are lexed as rule text, like
+            // inline choice options, separated by ':'
+            '?' if self.in_expression => {
+                self.in_expression = false;
+                self.choice_starts.push(self.expression_start);
+                self.conditional_starts.push(self.expression_start);
+                Ok(Some(self.make_token(TokenType::Question)))
+            }
+
+            // Newlines end rule text and reset state. An expression still open
+            // here is unclosed: record it and keep lexing the next line normally
+            // rather than as expression tokens. `\r\n` and a lone `\r` count as
+            // one newline too.
+            '\n' | '\r' => {
+                if c == '\r' && self.peek() == '\n' {
+                    self.advance();
+                }
+                if (self.in_expression || !self.choice_starts.is_empty())
+                    && self.unclosed_expression.is_none()
+                {
+                    self.unclosed_expression = Some(self.unclosed_expression());
+                }
+                self.in_expression = false;
+                self.choice_starts.clear();
+                self.conditional_starts.clear();
                 self.in_rule_text = false;
+                self.in_flags = false;
                 Ok(Some(self.make_token(TokenType::Newline)))
             }
 
@@ -185,29 +401,56 @@ impl Lexer {
             }
 
             // Left bracket for flags
-            '[' if !self.in_rule_text => Ok(Some(self.make_token(TokenType::LeftBracket))),
+            '[' if !self.in_rule_text => {
+                self.in_flags = true;
+                Ok(Some(self.make_token(TokenType::LeftBracket)))
+            }
 
             // Right bracket for flags
-            ']' if !self.in_rule_text => Ok(Some(self.make_token(TokenType::RightBracket))),
+            ']' if !self.in_rule_text => {
+                self.in_flags = false;
+                Ok(Some(self.make_token(TokenType::RightBracket)))
+            }
 
-            // Left brace for expressions (can appear in rule text)
+            // Left brace for expressions (can appear in rule text). An inline
+            // choice's options are lexed as rule text rather than expression tokens.
             '{' => {
-                self.in_expression = true;
+                if self.in_rule_text && !self.in_expression && self.starts_inline_choice() {
+                    self.choice_starts.push(self.start);
+                    self.at_option_start = true;
+                } else {
+                    self.in_expression = true;
+                    self.expression_start = self.start;
+                }
                 Ok(Some(self.make_token(TokenType::LeftBrace)))
             }
 
             // Right brace for expressions (can appear in rule text)
             '}' => {
-                self.in_expression = false;
+                if self.in_expression {
+                    self.in_expression = false;
+                } else if self.choice_starts.pop() == self.conditional_starts.last().copied() {
+                    self.conditional_starts.pop();
+                }
                 Ok(Some(self.make_token(TokenType::RightBrace)))
             }
 
-            // Pipe separator for modifiers (only in expressions)
-            '|' if self.in_expression => Ok(Some(self.make_token(TokenType::Pipe))),
+            // Pipe separator for modifiers and inline choice options
+            '|' if self.in_expression || self.in_inline_choice() => {
+                self.at_option_start = !self.in_expression;
+                Ok(Some(self.make_token(TokenType::Pipe)))
+            }
+
+            // Inside an expression a colon separates a namespace from a table, as in `{#ns:table}`
+            ':' if self.in_expression => Ok(Some(self.make_token(TokenType::Colon))),
+
+            // In a conditional it separates the branches, as in `{$gender == she ? her : his}`
+            ':' if self.in_conditional_branch() => Ok(Some(self.make_token(TokenType::Colon))),
 
             // Colon transitions us into rule content mode
+            // (inside a flag list, as in `[finish: uppercase]`, it's just a separator)
             ':' if !self.in_rule_text => {
-                self.in_rule_text = true;
+                self.in_rule_text = !self.in_flags;
                 Ok(Some(self.make_token(TokenType::Colon)))
             }
 
@@ -233,38 +476,68 @@ impl Lexer {
             c if c.is_alphabetic() && !self.in_rule_text => self.identifier(),
 
             // Text content when in rule text mode but not in expression
-            _ if self.in_rule_text && !self.in_expression && c != '{' && c != '}' && c != '\n' => {
+            _ if self.in_rule_text && !self.in_expression && c != '{' && c != '}' => {
                 // Backtrack and collect text segment
                 self.current -= 1;
                 self.text_segment()
             }
 
+            _ => Err(self.invalid_character(c)),
+        }
+    }
+
+    /// Error for the character just consumed, with a suggestion suited to it
+    fn invalid_character(&self, c: char) -> LexError {
+        let suggestion = match c {
+            '-' => Some(
+                "Negative numbers are not allowed. Use positive weights like 1.0, 2.5".to_string(),
+            ),
+            ':' => Some("Colons are only allowed after a weight number".to_string()),
             _ => {
-                let suggestion = match c {
-                    '-' => Some(
-                        "Negative numbers are not allowed. Use positive weights like 1.0, 2.5"
-                            .to_string(),
-                    ),
-                    ':' => Some("Colons are only allowed after a weight number".to_string()),
-                    _ => Some(
-                        "Only numbers, colons, and rule text are allowed in this language"
-                            .to_string(),
-                    ),
-                };
+                Some("Only numbers, colons, and rule text are allowed in this language".to_string())
+            }
+        };
 
-                let diagnostic = self
-                    .diagnostic_collector
-                    .lex_error(self.current - 1, format!("Invalid character '{}'", c))
-                    .with_suggestion(suggestion.unwrap());
+        let span = Span::new(self.current - 1, self.current);
+        let diagnostic = self
+            .diagnostic_collector
+            .lex_error_span(span.start, span.end, format!("Invalid character '{}'", c))
+            .with_suggestion(suggestion.unwrap());
 
-                Err(LexError::InvalidCharacter {
-                    character: c,
-                    diagnostic: Box::new(diagnostic),
-                })
+        LexError::InvalidCharacter {
+            character: c,
+            diagnostic: Box::new(diagnostic),
+            span,
+        }
+    }
+
+    /// Lex the weight at the start of an inline choice option, as in `{3:red|1:blue}`
+    ///
+    /// Leading spaces are skipped and the weight itself is lexed like a rule
+    /// weight, so zero is rejected the same way; a '-' is an invalid character.
+    fn option_weight(&mut self, c: char) -> LexResult<Option<Token>> {
+        match c {
+            ' ' | '\t' => {
+                self.at_option_start = true;
+                Ok(None)
+            }
+            '-' => Err(self.invalid_character(c)),
+            _ => {
+                self.option_weight_colon = true;
+                self.number()
             }
         }
     }
 
+    /// Whether an option weight starts at the character just consumed
+    fn option_weight_follows(&self) -> bool {
+        let option: String = self.input[self.current - 1..]
+            .iter()
+            .take_while(|&&c| !matches!(c, '|' | '}' | '{' | '\n' | '\r'))
+            .collect();
+        starts_with_choice_weight(&option)
+    }
+
     fn number(&mut self) -> LexResult<Option<Token>> {
         // Parse integer part
         while self.peek().is_ascii_digit() {
@@ -280,11 +553,68 @@ impl Lexer {
             }
         }
 
+        // A second decimal point, as in 1.5.5, makes the whole run one bad number
+        if self.peek() == '.' && self.peek_next().is_ascii_digit() {
+            while self.peek().is_ascii_digit() || self.peek() == '.' {
+                self.advance();
+            }
+
+            let reason = format!("'{}' is not a valid number", self.lexeme());
+            let span = Span::new(self.start, self.current);
+            let diagnostic = self
+                .diagnostic_collector
+                .lex_error_span(span.start, span.end, reason.clone())
+                .with_suggestion(
+                    "Numbers can have at most one decimal point, like 1.5".to_string(),
+                );
+
+            return Err(LexError::InvalidNumber {
+                reason,
+                diagnostic: Box::new(diagnostic),
+                span,
+            });
+        }
+
+        // Optional exponent, as in 1e3 or 2.5E-1
+        if matches!(self.peek(), 'e' | 'E') {
+            self.advance(); // consume 'e'
+            if matches!(self.peek(), '+' | '-') {
+                self.advance();
+            }
+
+            if !self.peek().is_ascii_digit() {
+                let reason = format!("'{}' is missing exponent digits", self.lexeme());
+                let span = Span::new(self.start, self.current);
+                let diagnostic = self
+                    .diagnostic_collector
+                    .lex_error_span(span.start, span.end, reason.clone())
+                    .with_suggestion(
+                        "Write the exponent as digits after the 'e', like 1e3 or 2.5e-1"
+                            .to_string(),
+                    );
+
+                return Err(LexError::InvalidNumber {
+                    reason,
+                    diagnostic: Box::new(diagnostic),
+                    span,
+                });
+            }
+
+            while self.peek().is_ascii_digit() {
+                self.advance();
+            }
+        }
+
         let lexeme = self.lexeme();
         let value = lexeme.parse::<f64>().map_err(|_| {
+            let span = Span::new(self.start, self.current);
             let diagnostic = self
                 .diagnostic_collector
-                .lex_error(self.start, format!("'{}' is not a valid number", lexeme))
+                .lex_error_span(
+                    span.start,
+                    span.end,
+                    format!("'{}' is not a valid number", lexeme),
+                )
                 .with_suggestion(
                     "Numbers should be positive decimal values like 1.5, 2.0, or 42".to_string(),
                 );
@@ -292,15 +622,34 @@ impl Lexer {
             LexError::InvalidNumber {
                 reason: format!("'{}' is not a valid number", lexeme),
                 diagnostic: Box::new(diagnostic),
+                span,
             }
         })?;
 
+        // Ensure it's finite (large exponents overflow to infinity)
+        if !value.is_finite() {
+            let reason = format!("'{}' is too large to be a weight", lexeme);
+            let span = Span::new(self.start, self.current);
+            let diagnostic = self
+                .diagnostic_collector
+                .lex_error_span(span.start, span.end, reason.clone())
+                .with_suggestion("Use a smaller number, like 1e300 or less".to_string());
+
+            return Err(LexError::InvalidNumber {
+                reason,
+                diagnostic: Box::new(diagnostic),
+                span,
+            });
+        }
+
         // Ensure it's positive
         if value <= 0.0 {
+            let span = Span::new(self.start, self.current);
             let diagnostic = self
                 .diagnostic_collector
-                .lex_error(
-                    self.start,
+                .lex_error_span(
+                    span.start,
+                    span.end,
                     format!("Weight must be positive, but got {}", value),
                 )
                 .with_suggestion("Try using a positive number like 1.0, 2.5, or 10".to_string());
@@ -308,6 +657,7 @@ impl Lexer {
             return Err(LexError::InvalidNumber {
                 reason: format!("Weight must be positive, but got {}", value),
                 diagnostic: Box::new(diagnostic),
+                span,
             });
         }
 
@@ -330,9 +680,7 @@ impl Lexer {
         let token_type = match text.as_str() {
             "export" => TokenType::Export,
             // Check if this is a known modifier keyword
-            "indefinite" | "definite" | "capitalize" | "uppercase" | "lowercase" => {
-                TokenType::Modifier(text.clone())
-            }
+            _ if MODIFIER_KEYWORDS.contains(&text.as_str()) => TokenType::Modifier(text.clone()),
             // All other identifiers (including unknown modifiers) become regular identifiers
             _ => TokenType::Identifier(text.clone()),
         };
@@ -344,32 +692,230 @@ impl Lexer {
         )))
     }
 
+    fn macro_definition(&mut self) -> LexResult<Option<Token>> {
+        // Name between percent signs
+        let name_start = self.current;
+        while self.peek().is_alphanumeric() || self.peek() == '_' || self.peek() == '-' {
+            self.advance();
+        }
+        let name: String = self.input[name_start..self.current].iter().collect();
+        if name.is_empty() || self.peek() != '%' {
+            return Err(self.macro_error("Expected a macro name like '%game%'"));
+        }
+        self.advance(); // consume closing '%'
+
+        while self.peek() == ' ' || self.peek() == '\t' {
+            self.advance();
+        }
+        if self.peek() != '=' {
+            return Err(self.macro_error("Expected '=' after the macro name"));
+        }
+        self.advance(); // consume '='
+
+        while self.peek() == ' ' || self.peek() == '\t' {
+            self.advance();
+        }
+        if self.peek() != '"' {
+            return Err(self.macro_error("Expected a quoted macro value"));
+        }
+        self.advance(); // consume opening '"'
+
+        let value_start = self.current;
+        while !self.is_at_end() && self.peek() != '"' && !self.at_line_break() {
+            self.advance();
+        }
+        if self.peek() != '"' {
+            return Err(self.macro_error("Unterminated macro value"));
+        }
+        let value: String = self.input[value_start..self.current].iter().collect();
+        self.advance(); // consume closing '"'
+
+        Ok(Some(
+            self.make_token(TokenType::MacroDefinition { name, value }),
+        ))
+    }
+
+    /// Lex a table attribute's value, starting at the character just consumed
+    ///
+    /// A value is either quoted, and may then hold spaces, commas and ']', or
+    /// runs to the next space, comma or ']'. A value can't span lines.
+    fn attribute_value(&mut self, c: char) -> LexResult<Option<Token>> {
+        let value = if c == '"' {
+            // `\"`, `\\` and `\n` stand for a quote, a backslash and a line break
+            let mut value = String::new();
+            while !self.is_at_end() && self.peek() != '"' && !self.at_line_break() {
+                let escaped = match (self.peek(), self.peek_next()) {
+                    ('\\', '"') => Some('"'),
+                    ('\\', '\\') => Some('\\'),
+                    ('\\', 'n') => Some('\n'),
+                    _ => None,
+                };
+                if let Some(escaped) = escaped {
+                    self.advance();
+                    value.push(escaped);
+                } else {
+                    value.push(self.peek());
+                }
+                self.advance();
+            }
+            if self.peek() != '"' {
+                let span = Span::new(self.start, self.current);
+                let diagnostic = self
+                    .diagnostic_collector
+                    .lex_error_span(
+                        span.start,
+                        span.end,
+                        "Unterminated attribute value".to_string(),
+                    )
+                    .with_suggestion(
+                        "Close the value with '\"', like category=\"weapons\"".to_string(),
+                    );
+                return Err(LexError::InvalidCharacter {
+                    character: c,
+                    diagnostic: Box::new(diagnostic),
+                    span,
+                });
+            }
+            self.advance(); // consume closing '"'
+            value
+        } else if matches!(c, ',' | ']' | '\n' | '\r') {
+            // Nothing after the '=': put the character back for the parser to report
+            self.current -= 1;
+            return Ok(None);
+        } else {
+            while !self.is_at_end()
+                && !matches!(self.peek(), ' ' | '\t' | ',' | ']')
+                && !self.at_line_break()
+            {
+                self.advance();
+            }
+            self.lexeme()
+        };
+
+        Ok(Some(self.make_token(TokenType::AttributeValue(value))))
+    }
+
+    /// Error for a malformed macro definition, pointing at the current character
+    fn macro_error(&self, message: &str) -> LexError {
+        let character = self.peek();
+        let span = Span::new(self.current, self.current + 1);
+        let diagnostic = self
+            .diagnostic_collector
+            .lex_error_span(span.start, span.end, message.to_string())
+            .with_suggestion("Define macros like: %game% = \"Dragon Quest\"".to_string());
+
+        LexError::InvalidCharacter {
+            character,
+            diagnostic: Box::new(diagnostic),
+            span,
+        }
+    }
+
     fn text_segment(&mut self) -> LexResult<Option<Token>> {
         // Don't skip whitespace - we want to preserve spaces between expressions
-        // Collect text until we hit a brace, newline, comment, or EOF
+        // Collect text until we hit a brace, newline, comment, or EOF (or a
+        // '|' inside an inline choice, or a ':' inside a conditional).
+        // `\{`, `\}`, `\\` and `\~` stand for the literal character, as do
+        // `\|` inside an inline choice and `\:` inside a conditional.
+        let in_choice = self.in_inline_choice();
+        let in_branch = self.in_conditional_branch();
+        let mut text = String::new();
         while !self.is_at_end()
             && self.peek() != '{'
             && self.peek() != '}'
-            && self.peek() != '\n'
+            && !self.at_line_break()
+            && !(in_choice && self.peek() == '|')
+            && !(in_branch && self.peek() == ':')
             && !(self.peek() == '/' && (self.peek_next() == '/' || self.peek_next() == '*'))
         {
-            self.advance();
+            if self.peek() == '\\'
+                && (matches!(self.peek_next(), '{' | '}' | '\\' | '~')
+                    || (in_choice && self.peek_next() == '|')
+                    || (in_branch && self.peek_next() == ':'))
+            {
+                self.advance(); // consume the backslash
+            }
+            text.push(self.advance());
         }
 
-        let text = self.lexeme();
-
         if text.is_empty() {
             return Ok(None); // Skip empty text segments
         }
 
         Ok(Some(Token::new(
-            TokenType::TextSegment(text.clone()),
-            text.clone(),
+            TokenType::TextSegment(text),
+            self.lexeme(),
             Span::new(self.start, self.current),
         )))
     }
 
     // Helper methods
+    /// Whether the innermost open brace is an inline choice, whose options are being lexed
+    fn in_inline_choice(&self) -> bool {
+        !self.choice_starts.is_empty() && !self.in_conditional_branch()
+    }
+
+    /// Whether the innermost open brace is a conditional, whose branches are being lexed
+    fn in_conditional_branch(&self) -> bool {
+        !self.choice_starts.is_empty()
+            && self.conditional_starts.last() == self.choice_starts.last()
+    }
+
+    /// Whether the '{' just consumed opens an inline choice like `{red|blue}`
+    ///
+    /// That's any expression not starting with '#', '@' or '$' that has a '|' on
+    /// the same line outside nested braces, before its closing '}', unless
+    /// the text before that '|' is a dice roll taking modifiers, as in `{d6|ordinal}`.
+    fn starts_inline_choice(&self) -> bool {
+        if matches!(self.peek(), '#' | '@' | '$') {
+            return false;
+        }
+
+        let mut depth = 0;
+        let mut i = self.current;
+        while i < self.input.len() {
+            match self.input[i] {
+                '\\' => i += 1, // Skip the escaped character
+                '{' => depth += 1,
+                '}' if depth == 0 => return false,
+                '}' => depth -= 1,
+                '|' if depth == 0 => {
+                    let first: String = self.input[self.current..i].iter().collect();
+                    return !is_dice_notation(&first);
+                }
+                '\n' | '\r' => return false,
+                _ => {}
+            }
+            i += 1;
+        }
+        false
+    }
+
+    /// Error for an expression that reached the end of its line without a closing '}'
+    ///
+    /// Inside an inline choice, the error points at the outermost open choice.
+    fn unclosed_expression(&self) -> LexError {
+        let start = self
+            .choice_starts
+            .first()
+            .copied()
+            .unwrap_or(self.expression_start);
+        let span = Span::new(start, self.current);
+        let diagnostic = self
+            .diagnostic_collector
+            .lex_error_span(
+                span.start,
+                span.end,
+                "Unclosed '{' in rule: expressions must end with '}' on the same line".to_string(),
+            )
+            .with_suggestion("Add a closing '}' to finish the expression".to_string());
+
+        LexError::UnclosedExpression {
+            diagnostic: Box::new(diagnostic),
+            span,
+        }
+    }
+
     fn advance(&mut self) -> char {
         self.current += 1;
         self.input[self.current - 1]
@@ -391,6 +937,11 @@ impl Lexer {
         }
     }
 
+    /// Whether the next character ends a line, as `\n`, `\r\n` or a lone `\r`
+    fn at_line_break(&self) -> bool {
+        matches!(self.peek(), '\n' | '\r')
+    }
+
     fn is_at_end(&self) -> bool {
         self.current >= self.input.len()
     }
@@ -412,12 +963,15 @@ impl Lexer {
         self.advance();
 
         // Consume characters until end of line or end of file
-        while !self.is_at_end() && self.peek() != '\n' {
+        while !self.is_at_end() && !self.at_line_break() {
             self.advance();
         }
 
-        // Return None to skip this comment
-        Ok(None)
+        if !self.emit_comments {
+            return Ok(None);
+        }
+        let text = self.lexeme()[2..].trim().to_string();
+        Ok(Some(self.make_token(TokenType::LineComment(text))))
     }
 
     fn block_comment(&mut self) -> LexResult<Option<Token>> {
@@ -434,7 +988,7 @@ impl Lexer {
             }
 
             // If we encounter a newline, reset rule text state
-            if self.peek() == '\n' {
+            if self.at_line_break() {
                 self.in_rule_text = false;
             }
 
@@ -447,19 +1001,34 @@ impl Lexer {
                 && self.input[self.input.len() - 2] == '*'
                 && self.input[self.input.len() - 1] == '/')
         {
+            let span = Span::new(self.start, self.current);
             let diagnostic = self
                 .diagnostic_collector
-                .lex_error(self.start, "Unterminated block comment".to_string())
+                .lex_error_span(
+                    span.start,
+                    span.end,
+                    "Unterminated block comment".to_string(),
+                )
                 .with_suggestion("Add */ to close the block comment".to_string());
 
             return Err(LexError::InvalidCharacter {
                 character: '*',
                 diagnostic: Box::new(diagnostic),
+                span,
             });
         }
 
-        // Return None to skip this comment
-        Ok(None)
+        if !self.emit_comments {
+            return Ok(None);
+        }
+        let lexeme = self.lexeme();
+        let text = lexeme
+            .strip_prefix("/*")
+            .and_then(|text| text.strip_suffix("*/"))
+            .unwrap_or_default()
+            .trim()
+            .to_string();
+        Ok(Some(self.make_token(TokenType::BlockComment(text))))
     }
 
     fn peek_for_dice(&self) -> bool {
@@ -493,9 +1062,14 @@ impl Lexer {
 
             let count_str: String = self.input[start_pos..self.current].iter().collect();
             count = Some(count_str.parse::<u32>().map_err(|_| {
+                let span = Span::new(start_pos, self.current);
                 let diagnostic = self
                     .diagnostic_collector
-                    .lex_error(start_pos, format!("Invalid dice count: {}", count_str))
+                    .lex_error_span(
+                        span.start,
+                        span.end,
+                        format!("Invalid dice count: {}", count_str),
+                    )
                     .with_suggestion(
                         "Dice count should be a positive integer like 2, 10, or 100".to_string(),
                     );
@@ -503,17 +1077,59 @@ impl Lexer {
                 LexError::InvalidNumber {
                     reason: format!("Invalid dice count: {}", count_str),
                     diagnostic: Box::new(diagnostic),
+                    span,
                 }
             })?);
+
+            if count == Some(0) {
+                let span = Span::new(start_pos, self.current);
+                let diagnostic = self
+                    .diagnostic_collector
+                    .lex_error_span(
+                        span.start,
+                        span.end,
+                        "Dice count must be at least 1".to_string(),
+                    )
+                    .with_suggestion(
+                        "Roll at least one die like 'd6' or '1d6', or remove the expression"
+                            .to_string(),
+                    );
+
+                return Err(LexError::InvalidNumber {
+                    reason: "Dice count must be at least 1".to_string(),
+                    diagnostic: Box::new(diagnostic),
+                    span,
+                });
+            }
+
+            if count > Some(self.max_dice) {
+                let reason = format!("Can't roll more than {} dice at once", self.max_dice);
+                let span = Span::new(start_pos, self.current);
+                let diagnostic = self
+                    .diagnostic_collector
+                    .lex_error_span(span.start, span.end, reason.clone())
+                    .with_suggestion(format!(
+                        "Use at most {} dice, or multiply a smaller roll's result",
+                        self.max_dice
+                    ));
+
+                return Err(LexError::InvalidNumber {
+                    reason,
+                    diagnostic: Box::new(diagnostic),
+                    span,
+                });
+            }
         } else if current_char == 'd' {
             // We start with 'd', no count specified (defaults to 1)
             // The 'd' is already consumed, so we continue to parse sides
         } else {
             // This shouldn't happen given our calling logic
+            let span = Span::new(self.current - 1, self.current);
             let diagnostic = self
                 .diagnostic_collector
-                .lex_error(
-                    self.current - 1,
+                .lex_error_span(
+                    span.start,
+                    span.end,
                     "Expected digit or 'd' in dice roll".to_string(),
                 )
                 .with_suggestion("Dice rolls should start with a number or 'd'".to_string());
@@ -521,6 +1137,7 @@ impl Lexer {
             return Err(LexError::InvalidCharacter {
                 character: current_char,
                 diagnostic: Box::new(diagnostic),
+                span,
             });
         }
 
@@ -529,10 +1146,12 @@ impl Lexer {
             if !self.is_at_end() && self.peek() == 'd' {
                 self.advance(); // consume 'd'
             } else {
+                let span = Span::new(self.current, self.current + 1);
                 let diagnostic = self
                     .diagnostic_collector
-                    .lex_error(
-                        self.current,
+                    .lex_error_span(
+                        span.start,
+                        span.end,
                         "Expected 'd' in dice roll expression".to_string(),
                     )
                     .with_suggestion(
@@ -542,6 +1161,7 @@ impl Lexer {
                 return Err(LexError::InvalidCharacter {
                     character: self.peek(),
                     diagnostic: Box::new(diagnostic),
+                    span,
                 });
             }
         }
@@ -553,10 +1173,12 @@ impl Lexer {
         }
 
         if self.current == sides_start {
+            let span = Span::new(self.current, self.current + 1);
             let diagnostic = self
                 .diagnostic_collector
-                .lex_error(
-                    self.current,
+                .lex_error_span(
+                    span.start,
+                    span.end,
                     "Expected number of sides after 'd'".to_string(),
                 )
                 .with_suggestion(
@@ -567,14 +1189,20 @@ impl Lexer {
             return Err(LexError::InvalidCharacter {
                 character: self.peek(),
                 diagnostic: Box::new(diagnostic),
+                span,
             });
         }
 
         let sides_str: String = self.input[sides_start..self.current].iter().collect();
         let sides = sides_str.parse::<u32>().map_err(|_| {
+            let span = Span::new(sides_start, self.current);
             let diagnostic = self
                 .diagnostic_collector
-                .lex_error(sides_start, format!("Invalid dice sides: {}", sides_str))
+                .lex_error_span(
+                    span.start,
+                    span.end,
+                    format!("Invalid dice sides: {}", sides_str),
+                )
                 .with_suggestion(
                     "Dice sides should be a positive integer like 6, 10, or 20".to_string(),
                 );
@@ -582,13 +1210,19 @@ impl Lexer {
             LexError::InvalidNumber {
                 reason: format!("Invalid dice sides: {}", sides_str),
                 diagnostic: Box::new(diagnostic),
+                span,
             }
         })?;
 
         if sides == 0 {
+            let span = Span::new(sides_start, self.current);
             let diagnostic = self
                 .diagnostic_collector
-                .lex_error(sides_start, "Dice must have at least 1 side".to_string())
+                .lex_error_span(
+                    span.start,
+                    span.end,
+                    "Dice must have at least 1 side".to_string(),
+                )
                 .with_suggestion(
                     "Use positive numbers for dice sides like 6, 10, or 20".to_string(),
                 );
@@ -596,15 +1230,129 @@ impl Lexer {
             return Err(LexError::InvalidNumber {
                 reason: "Dice must have at least 1 side".to_string(),
                 diagnostic: Box::new(diagnostic),
+                span,
             });
         }
 
+        let keep = self.dice_keep(count.unwrap_or(1))?;
+        let modifier = self.dice_modifier()?;
+
         Ok(Some(Token::new(
-            TokenType::DiceRoll { count, sides },
+            TokenType::DiceRoll {
+                count,
+                sides,
+                keep,
+                modifier,
+            },
             self.lexeme(),
             Span::new(self.start, self.current),
         )))
     }
+
+    /// Parse an optional `+<n>`/`-<n>` flat modifier after a dice roll
+    fn dice_modifier(&mut self) -> LexResult<Option<i32>> {
+        if !matches!(self.peek(), '+' | '-') {
+            return Ok(None);
+        }
+
+        let sign_pos = self.current;
+        self.advance(); // consume the sign
+        while !self.is_at_end() && self.peek().is_ascii_digit() {
+            self.advance();
+        }
+        let modifier_str: String = self.input[sign_pos..self.current].iter().collect();
+
+        modifier_str.parse::<i32>().map(Some).map_err(|_| {
+            let reason = if modifier_str.len() == 1 {
+                format!("Expected a number after '{}' in dice roll", modifier_str)
+            } else {
+                format!("Invalid dice modifier: {}", modifier_str)
+            };
+            let span = Span::new(sign_pos, self.current);
+            let diagnostic = self
+                .diagnostic_collector
+                .lex_error_span(span.start, span.end, reason.clone())
+                .with_suggestion(
+                    "Add or subtract a whole number, like '2d6+3' or 'd20-1'".to_string(),
+                );
+
+            LexError::InvalidNumber {
+                reason,
+                diagnostic: Box::new(diagnostic),
+                span,
+            }
+        })
+    }
+
+    /// Parse an optional `kh<n>`/`kl<n>` suffix keeping some of `count` dice
+    fn dice_keep(&mut self, count: u32) -> LexResult<Option<KeepMode>> {
+        if self.peek() != 'k' || !matches!(self.peek_next(), 'h' | 'l') {
+            return Ok(None);
+        }
+
+        self.advance(); // consume 'k'
+        let highest = self.advance() == 'h';
+
+        let kept_start = self.current;
+        while !self.is_at_end() && self.peek().is_ascii_digit() {
+            self.advance();
+        }
+        let kept_str: String = self.input[kept_start..self.current].iter().collect();
+
+        let kept = match kept_str.parse::<u32>() {
+            Ok(kept) if (1..=count).contains(&kept) => kept,
+            _ => {
+                let reason = if kept_str.is_empty() {
+                    "Expected how many dice to keep after 'kh' or 'kl'".to_string()
+                } else {
+                    format!("Can't keep {} of {} dice", kept_str, count)
+                };
+                let span = Span::new(kept_start - 2, self.current);
+                let diagnostic = self
+                    .diagnostic_collector
+                    .lex_error_span(span.start, span.end, reason.clone())
+                    .with_suggestion(format!(
+                        "Keep between 1 and {} dice, like '4d6kh3' or '2d20kl1'",
+                        count
+                    ));
+
+                return Err(LexError::InvalidNumber {
+                    reason,
+                    diagnostic: Box::new(diagnostic),
+                    span,
+                });
+            }
+        };
+
+        Ok(Some(if highest {
+            KeepMode::Highest(kept)
+        } else {
+            KeepMode::Lowest(kept)
+        }))
+    }
+}
+
+/// Whether `text` is exactly a dice roll like `d6`, `2d10`, `4d6kh3` or `2d6+3`
+fn is_dice_notation(text: &str) -> bool {
+    let digits =
+        |text: &str| text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+
+    let rest = &text[digits(text)..];
+    let Some(rest) = rest.strip_prefix('d') else {
+        return false;
+    };
+    let sides = digits(rest);
+    if sides == 0 {
+        return false;
+    }
+    let mut rest = &rest[sides..];
+    if let Some(kept) = rest.strip_prefix("kh").or_else(|| rest.strip_prefix("kl")) {
+        rest = &kept[digits(kept)..];
+    }
+    if let Some(bonus) = rest.strip_prefix(['+', '-']) {
+        rest = &bonus[digits(bonus)..];
+    }
+    rest.is_empty()
 }
 
 impl fmt::Display for TokenType {
@@ -617,10 +1365,24 @@ impl fmt::Display for TokenType {
             TokenType::Hash => write!(f, "#"),
             TokenType::Identifier(name) => write!(f, "{}", name),
             TokenType::Modifier(name) => write!(f, "{}", name),
-            TokenType::DiceRoll { count, sides } => match count {
-                Some(c) => write!(f, "{}d{}", c, sides),
-                None => write!(f, "d{}", sides),
-            },
+            TokenType::DiceRoll {
+                count,
+                sides,
+                keep,
+                modifier,
+            } => {
+                if let Some(c) = count {
+                    write!(f, "{}", c)?;
+                }
+                write!(f, "d{}", sides)?;
+                if let Some(keep) = keep {
+                    write!(f, "{}", keep)?;
+                }
+                if let Some(modifier) = modifier {
+                    write!(f, "{:+}", modifier)?;
+                }
+                Ok(())
+            }
             TokenType::LeftBracket => write!(f, "["),
             TokenType::RightBracket => write!(f, "]"),
             TokenType::LeftBrace => write!(f, "{{"),
@@ -629,6 +1391,15 @@ impl fmt::Display for TokenType {
             TokenType::Pipe => write!(f, "|"),
             TokenType::At => write!(f, "@"),
             TokenType::Slash => write!(f, "/"),
+            TokenType::Dollar => write!(f, "$"),
+            TokenType::Greater => write!(f, ">"),
+            TokenType::EqualEqual => write!(f, "=="),
+            TokenType::Question => write!(f, "?"),
+            TokenType::Equals => write!(f, "="),
+            TokenType::AttributeValue(value) => write!(f, "\"{}\"", value),
+            TokenType::MacroDefinition { name, value } => write!(f, "%{}% = \"{}\"", name, value),
+            TokenType::LineComment(text) => write!(f, "// {}", text),
+            TokenType::BlockComment(text) => write!(f, "/* {} */", text),
             TokenType::Newline => write!(f, "\\n"),
             TokenType::Eof => write!(f, "EOF"),
         }
@@ -10,8 +10,15 @@ pub mod parser;
 #[cfg(feature = "wasm")]
 pub mod wasm;
 
-pub use ast::{Expression, Node, Program, Rule, RuleContent, Span, Table, TableMetadata};
-pub use collection::{Collection, CollectionError, CollectionGenResult, CollectionResult};
+pub use ast::{
+    Expression, FormatOptions, KeepMode, MacroDefinition, Modifier, Node, Program, Rule,
+    RuleContent, RuleOrder, Span, Table, TableMetadata, Visitor, WeightStyle, walk_program,
+    walk_table,
+};
+pub use collection::{
+    Collection, CollectionBuilder, CollectionError, CollectionGenResult, CollectionResult,
+    LengthUnit, Limits, OutputLimit, ReferenceDepth, RngKind, SelectionExplanation,
+};
 pub use diagnostic::{Diagnostic, DiagnosticKind, Severity, SourceLocation};
 pub use diagnostic_collector::DiagnosticCollector;
 pub use diagnostic_formatter::DiagnosticFormatter;
@@ -40,12 +47,56 @@ use crate::parser::Parser;
 /// }
 /// ```
 pub fn parse(source: &str) -> ParseResult<Program> {
-    let mut lexer = Lexer::new(source);
+    parse_with_lexer(source, Lexer::new(source))
+}
+
+/// Parse source code with a lexer configured for it
+pub(crate) fn parse_with_lexer(source: &str, mut lexer: Lexer) -> ParseResult<Program> {
     let tokens = lexer.tokenize()?;
     let mut parser = Parser::from_source(tokens, source.to_string());
     parser.parse()
 }
 
+/// Parse source code read from `filename`, naming the file in diagnostics
+///
+/// Errors are the same as from `parse`, but their `SourceLocation`s carry
+/// the file name and `DiagnosticFormatter` prints `file:line:column`. Use it
+/// for projects split across several files.
+///
+/// # Examples
+///
+/// ```
+/// use table_collection::parse_named;
+///
+/// let error = parse_named("#color\n1.0 red", "colors.tbl").unwrap_err();
+/// assert_eq!(error.diagnostic().location.file.as_deref(), Some("colors.tbl"));
+/// ```
+pub fn parse_named(source: &str, filename: &str) -> ParseResult<Program> {
+    let mut lexer = Lexer::new(source).with_file(filename);
+    let tokens = lexer.tokenize()?;
+    let mut parser = Parser::from_source(tokens, source.to_string()).with_file(filename);
+    parser.parse()
+}
+
+/// Parse source code, keeping comments as docs on the tables and rules they precede
+///
+/// Like `parse`, but comments on the lines just before a table declaration or
+/// rule are kept in its `doc`, and `Program::to_source` writes them back.
+/// Other comments are still dropped.
+///
+/// # Examples
+///
+/// ```
+/// use table_collection::parse_with_comments;
+///
+/// let program = parse_with_comments("// Basic colors\n#color\n1.0: red")?;
+/// assert_eq!(program.tables[0].value.doc.as_deref(), Some("Basic colors"));
+/// # Ok::<(), table_collection::ParseError>(())
+/// ```
+pub fn parse_with_comments(source: &str) -> ParseResult<Program> {
+    parse_with_lexer(source, Lexer::new(source).with_comments(true))
+}
+
 /// Tokenize source code into tokens
 ///
 /// This function takes source code and returns a vector of tokens or an error.
@@ -71,6 +122,27 @@ pub fn tokenize(source: &str) -> Result<Vec<Token>, LexError> {
     lexer.tokenize()
 }
 
+/// Tokenize source code, collecting a diagnostic for every lex error
+///
+/// Unlike `tokenize`, this doesn't stop at the first error: invalid text is
+/// skipped and lexing continues, so tools like syntax highlighters get a
+/// token stream for the whole source even when parts of it are invalid.
+///
+/// # Examples
+///
+/// ```
+/// use table_collection::tokenize_with_diagnostics;
+///
+/// let (tokens, diagnostics) = tokenize_with_diagnostics("#t\n1.0: ok\n-2.0: bad");
+/// assert_eq!(diagnostics.len(), 1);
+/// assert_eq!(diagnostics[0].location.line, 3);
+/// assert!(!tokens.is_empty());
+/// ```
+pub fn tokenize_with_diagnostics(source: &str) -> (Vec<Token>, Vec<Diagnostic>) {
+    let mut lexer = Lexer::new(source);
+    lexer.tokenize_with_diagnostics()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -139,11 +211,936 @@ mod tests {
         assert!(matches!(tokens.last().unwrap().token_type, TokenType::Eof));
     }
 
+    #[test]
+    fn test_token_same_kind_ignores_span() {
+        let tokens = tokenize("#test\n1.5: rule").unwrap();
+
+        let expected = [
+            Token::new(TokenType::Hash, "#".to_string(), Span::new(0, 0)),
+            Token::new(
+                TokenType::Identifier("test".to_string()),
+                "test".to_string(),
+                Span::new(0, 0),
+            ),
+        ];
+        assert!(tokens[0].same_kind(&expected[0]));
+        assert!(tokens[1].same_kind(&expected[1]));
+        assert_ne!(tokens[1], expected[1]); // Derived equality still checks spans
+        assert!(!tokens[0].same_kind(&expected[1]));
+
+        assert!(
+            tokens[1]
+                .token_type
+                .same_variant(&TokenType::Identifier("other".to_string()))
+        );
+        assert!(
+            !tokens[1]
+                .token_type
+                .same_variant(&TokenType::Modifier("test".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_finish_flag() {
+        let program = parse("#title[finish: capitalize titlecase]\n1.0: a tale").unwrap();
+        let metadata = &program.tables[0].value.metadata;
+        assert_eq!(metadata.finish_modifiers, vec!["capitalize", "titlecase"]);
+        assert!(!metadata.export);
+
+        let program = parse("#title[finish: uppercase export]\n1.0: a tale").unwrap();
+        let metadata = &program.tables[0].value.metadata;
+        assert_eq!(metadata.finish_modifiers, vec!["uppercase"]);
+        assert!(metadata.export);
+
+        // Rule text colons are unaffected by the flag-list colon
+        let program = parse("#t[finish: uppercase]\n1.0: time: now").unwrap();
+        assert_eq!(
+            program.tables[0].value.rules[0].value.content_text(),
+            "time: now"
+        );
+
+        assert!(parse("#title[finish:]\n1.0: a tale").is_err());
+        assert!(parse("#title[finish uppercase]\n1.0: a tale").is_err());
+    }
+
+    #[test]
+    fn test_weight_flag() {
+        let program = parse("#encounter[weight: 3.0]\n1.0: goblins").unwrap();
+        assert_eq!(program.tables[0].value.metadata.default_weight, Some(3.0));
+
+        let program = parse("#encounter[export weight: 2 finish: uppercase]\n1.0: a").unwrap();
+        let metadata = &program.tables[0].value.metadata;
+        assert_eq!(metadata.default_weight, Some(2.0));
+        assert_eq!(metadata.finish_modifiers, vec!["uppercase"]);
+        assert!(metadata.export);
+
+        let program = parse("#plain\n1.0: a").unwrap();
+        assert_eq!(program.tables[0].value.metadata.default_weight, None);
+
+        assert!(parse("#t[weight:]\n1.0: a").is_err());
+        assert!(parse("#t[weight: 0]\n1.0: a").is_err());
+        assert!(parse("#t[weight 3]\n1.0: a").is_err());
+    }
+
+    #[test]
+    fn test_macro_definitions() {
+        let source = r#"%game% = "Dragon Quest"
+#t
+1.0: %game%
+%ed% = "2nd" // trailing
+#u
+1.0: x"#;
+        let program = parse(source).unwrap();
+        let macros: Vec<_> = program
+            .macros
+            .iter()
+            .map(|m| (m.value.name.as_str(), m.value.value.as_str()))
+            .collect();
+        assert_eq!(macros, vec![("game", "Dragon Quest"), ("ed", "2nd")]);
+        assert_eq!(program.tables.len(), 2);
+        assert_eq!(program.tables[0].value.rules.len(), 1);
+
+        assert!(parse("%game = \"x\"\n#t\n1.0: a").is_err());
+        assert!(parse("%game% \"x\"\n#t\n1.0: a").is_err());
+        assert!(parse("%game% = x\n#t\n1.0: a").is_err());
+        assert!(parse("%game% = \"x\n#t\n1.0: a").is_err());
+        assert!(parse("%game% = \"x\" #t\n1.0: a").is_err());
+    }
+
+    #[test]
+    fn test_escaped_braces_in_rule_text() {
+        let program = parse(
+            r"#t
+1.0: use \{braces\} literally
+2.0: \{ {#color} \}
+3.0: back\\slash and C:\path",
+        )
+        .unwrap();
+        let rules = &program.tables[0].value.rules;
+
+        assert_eq!(
+            rules[0].value.content,
+            vec![RuleContent::Text(" use {braces} literally".to_string())]
+        );
+        assert_eq!(
+            rules[1].value.content,
+            vec![
+                RuleContent::Text(" { ".to_string()),
+                RuleContent::Expression(Expression::TableReference {
+                    table_id: "color".to_string(),
+                    modifiers: vec![],
+                }),
+                RuleContent::Text(" }".to_string()),
+            ]
+        );
+        assert_eq!(
+            rules[2].value.content,
+            vec![RuleContent::Text(r" back\slash and C:\path".to_string())]
+        );
+
+        // content_text() escapes again, so it reads back the same
+        assert_eq!(rules[1].value.content_text(), r"\{ {#color} \}");
+        assert_eq!(rules[2].value.content_text(), r"back\\slash and C:\\path");
+
+        let mut lexer = Lexer::new(
+            r"#t
+1.0: a\{b",
+        );
+        let tokens = lexer.tokenize().unwrap();
+        let segment = tokens
+            .iter()
+            .find(|token| matches!(token.token_type, TokenType::TextSegment(_)))
+            .unwrap();
+        assert_eq!(
+            segment.token_type,
+            TokenType::TextSegment(" a{b".to_string())
+        );
+        assert_eq!(segment.lexeme, r" a\{b");
+    }
+
+    #[test]
+    fn test_unclosed_expression_points_at_opening_brace() {
+        let source = "#t\n1.0: broken {#color\n2.0: next";
+
+        match tokenize(source) {
+            Err(LexError::UnclosedExpression { diagnostic, .. }) => {
+                assert_eq!(diagnostic.location.line, 2);
+                assert_eq!(diagnostic.location.column, 13);
+                assert!(diagnostic.message.contains("Unclosed '{'"));
+            }
+            other => panic!("Expected UnclosedExpression, got {:?}", other),
+        }
+
+        // Parsing surfaces the same diagnostic rather than a later token
+        let error = parse(source).unwrap_err();
+        assert!(error.to_string().contains("Unclosed '{'"));
+
+        // An expression left open at end of input is caught too
+        assert!(matches!(
+            tokenize("#t\n1.0: {#color|capitalize"),
+            Err(LexError::UnclosedExpression { .. })
+        ));
+    }
+
+    #[test]
+    fn test_lexer_recovers_after_unclosed_expression() {
+        // '!' is only valid as rule text; if the open expression leaked past the
+        // newline, the next line would fail with an invalid character instead
+        let source = "#t\n1.0: {#a\n2.0: wow!\n3.0: {#b}";
+        match tokenize(source) {
+            Err(LexError::UnclosedExpression { diagnostic, .. }) => {
+                assert_eq!(diagnostic.location.line, 2);
+                assert_eq!(diagnostic.location.column, 6);
+            }
+            other => panic!("Expected UnclosedExpression, got {:?}", other),
+        }
+
+        // The unclosed expression is the only problem, and the lines after it
+        // lex as ordinary rules
+        let (tokens, diagnostics) = tokenize_with_diagnostics(source);
+        let positions: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.location.line, d.location.column))
+            .collect();
+        assert_eq!(positions, vec![(2, 6)]);
+        let after: Vec<_> = tokens
+            .iter()
+            .skip_while(|t| t.token_type != TokenType::Identifier("a".to_string()))
+            .skip(1)
+            .map(|t| t.token_type.clone())
+            .collect();
+        assert_eq!(
+            after,
+            vec![
+                TokenType::Newline,
+                TokenType::Number(2.0),
+                TokenType::Colon,
+                TokenType::TextSegment(" wow!".to_string()),
+                TokenType::Newline,
+                TokenType::Number(3.0),
+                TokenType::Colon,
+                TokenType::TextSegment(" ".to_string()),
+                TokenType::LeftBrace,
+                TokenType::Hash,
+                TokenType::Identifier("b".to_string()),
+                TokenType::RightBrace,
+                TokenType::Eof,
+            ]
+        );
+
+        // With several unclosed expressions, the first one is reported
+        let source = "#t\n1.0: {#a\n2.0: text {#b\n3.0: ok";
+        match tokenize(source) {
+            Err(LexError::UnclosedExpression { diagnostic, .. }) => {
+                assert_eq!(diagnostic.location.line, 2);
+            }
+            other => panic!("Expected UnclosedExpression, got {:?}", other),
+        }
+        let (_, diagnostics) = tokenize_with_diagnostics(source);
+        let positions: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.location.line, d.location.column))
+            .collect();
+        assert_eq!(positions, vec![(2, 6), (3, 11)]);
+
+        // A later lex error doesn't mask the earlier unclosed expression
+        match tokenize("#t\n1.0: {#a\n-2.0: next") {
+            Err(LexError::UnclosedExpression { diagnostic, .. }) => {
+                assert_eq!(diagnostic.location.line, 2);
+            }
+            other => panic!("Expected UnclosedExpression, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_malformed_number_is_underlined_in_full() {
+        let source = "#t\n1.5.5.5: x";
+
+        let error = tokenize(source).unwrap_err();
+        assert_eq!(error.span(), Span::new(3, 10));
+        match &error {
+            LexError::InvalidNumber {
+                reason, diagnostic, ..
+            } => {
+                assert_eq!(reason, "'1.5.5.5' is not a valid number");
+                assert_eq!(diagnostic.location.column, 1);
+                assert_eq!(diagnostic.location.end_column, Some(8));
+
+                let rendered = DiagnosticFormatter::new().format(diagnostic);
+                assert!(
+                    rendered.contains("  2 │ 1.5.5.5: x\n    │ ^^^^^^^\n"),
+                    "{}",
+                    rendered
+                );
+            }
+            other => panic!("Expected InvalidNumber, got {:?}", other),
+        }
+        assert!(parse(source).is_err());
+    }
+
+    #[test]
+    fn test_parse_errors_span_the_offending_token() {
+        let location = |source: &str| {
+            let location = parse(source).unwrap_err().diagnostic().location.clone();
+            (location.column, location.end_column)
+        };
+
+        assert_eq!(location("#t[invalidflag]\n1.0: x"), (3, Some(16)));
+        assert_eq!(location("#t\nabc: x"), (1, Some(4)));
+        assert_eq!(location("#t\n1.0: {#a|Capitalize}"), (10, Some(20)));
+    }
+
+    #[test]
+    fn test_namespaced_table_reference() {
+        let program = parse("#t\n1.0:{#monsters:goblin|capitalize}").unwrap();
+        assert_eq!(
+            program.tables[0].value.rules[0].value.content,
+            vec![RuleContent::Expression(Expression::TableReference {
+                table_id: "monsters:goblin".to_string(),
+                modifiers: vec![Modifier::new("capitalize")],
+            })]
+        );
+
+        // The table name is still required after the namespace
+        let err = parse("#t\n1.0: {#monsters:}").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Expected table identifier after ':', but found }")
+        );
+    }
+
+    #[test]
+    fn test_inline_choice() {
+        let program = parse("#t\n1.0: a {red|{#color|capitalize} or \\| bar|} hat").unwrap();
+        assert_eq!(
+            program.tables[0].value.rules[0].value.content,
+            vec![
+                RuleContent::Text(" a ".to_string()),
+                RuleContent::Expression(Expression::InlineChoice {
+                    options: vec![
+                        vec![RuleContent::Text("red".to_string())],
+                        vec![
+                            RuleContent::Expression(Expression::TableReference {
+                                table_id: "color".to_string(),
+                                modifiers: vec![Modifier::new("capitalize")],
+                            }),
+                            RuleContent::Text(" or | bar".to_string()),
+                        ],
+                        vec![],
+                    ],
+                    weights: vec![1.0, 1.0, 1.0],
+                }),
+                RuleContent::Text(" hat".to_string()),
+            ]
+        );
+
+        // Without a '|' the braces are still an ordinary expression
+        assert!(parse("#t\n1.0: {red}").is_err());
+
+        // An unclosed choice is reported from its opening brace
+        let err = parse("#t\n1.0: a {red|blue\n2.0: b").unwrap_err();
+        assert!(err.to_string().contains("Unclosed '{' in rule"));
+        assert_eq!(err.diagnostic().location.column, 8);
+    }
+
+    #[test]
+    fn test_weighted_inline_choice() {
+        let program = parse("#t\n1.0:{3:red| 0.5:blue|green}").unwrap();
+        let RuleContent::Expression(Expression::InlineChoice { options, weights }) =
+            &program.tables[0].value.rules[0].value.content[0]
+        else {
+            panic!("expected an inline choice");
+        };
+        assert_eq!(weights, &vec![3.0, 0.5, 1.0]);
+        assert_eq!(options[1], vec![RuleContent::Text("blue".to_string())]);
+
+        // Weights follow the same rules as rule weights
+        let err = parse("#t\n1.0: {0:red|blue}").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Weight must be positive, but got 0")
+        );
+        let err = parse("#t\n1.0: {red|-1:blue}").unwrap_err();
+        assert!(err.to_string().contains("Invalid character '-'"));
+        assert_eq!(err.diagnostic().location.column, 11);
+
+        // Digits without a colon are just text
+        let program = parse("#t\n1.0: {1st|2nd}").unwrap();
+        let RuleContent::Expression(Expression::InlineChoice { weights, .. }) =
+            &program.tables[0].value.rules[0].value.content[1]
+        else {
+            panic!("expected an inline choice");
+        };
+        assert_eq!(weights, &vec![1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_tokenize_with_diagnostics_recovers() {
+        let (tokens, diagnostics) = tokenize_with_diagnostics("#t\n1.0: ok");
+        assert!(diagnostics.is_empty());
+        assert_eq!(tokens, tokenize("#t\n1.0: ok").unwrap());
+
+        // Each invalid character is reported and skipped; lexing continues after it
+        let source = "#t$\n-1.0: a {#b\n2.0: {d0}\n3.0: {#c}";
+        let (tokens, diagnostics) = tokenize_with_diagnostics(source);
+        let positions: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.location.line, d.location.column))
+            .collect();
+        assert_eq!(positions, vec![(1, 3), (2, 1), (2, 9), (3, 8)]);
+        assert!(diagnostics.iter().all(|d| d.severity() == Severity::Error));
+        assert_eq!(
+            diagnostics[0].message,
+            tokenize("#t$").unwrap_err().diagnostic().message
+        );
+
+        // Tokens after the errors are still produced
+        let identifiers: Vec<_> = tokens
+            .iter()
+            .filter_map(|t| match &t.token_type {
+                TokenType::Identifier(id) => Some(id.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(identifiers, vec!["t", "b", "c"]);
+        assert_eq!(tokens.last().unwrap().token_type, TokenType::Eof);
+    }
+
+    #[test]
+    fn test_crlf_and_lone_cr_newlines() {
+        let program = parse("#t\r\n1.0: x\r\n").unwrap();
+        let rule = &program.tables[0].value.rules[0].value;
+        assert_eq!(rule.content, vec![RuleContent::Text(" x".to_string())]);
+        assert_eq!(rule.content_text(), "x");
+
+        for source in [
+            "#t\r\n1.0: x {#u}\r\n\r\n#u\r\n1.0: y",
+            "#t\r1.0: x {#u}\r\r#u\r1.0: y",
+        ] {
+            let program = parse(source).unwrap();
+            assert_eq!(program.tables.len(), 2);
+            let mut collection = Collection::new(source).unwrap();
+            assert_eq!(collection.generate("t", 1).unwrap(), "x y");
+        }
+
+        // Diagnostics count a CRLF as one line break
+        match parse("#t\r\n1.0: ok\r\n-1.0: bad") {
+            Err(ParseError::InvalidCharacter { diagnostic, .. }) => {
+                assert_eq!(diagnostic.location.line, 3);
+                assert_eq!(diagnostic.location.column, 1);
+                assert_eq!(diagnostic.source_line, "-1.0: bad");
+            }
+            other => panic!("Expected InvalidCharacter, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_columns_count_chars() {
+        let source = "#t\n1.0: 🎲 café {0d6}";
+        match tokenize(source) {
+            Err(LexError::InvalidNumber { diagnostic, .. }) => {
+                assert_eq!(diagnostic.location.line, 2);
+                assert_eq!(diagnostic.location.column, 14);
+                assert_eq!(diagnostic.source_line, "1.0: 🎲 café {0d6}");
+
+                // The position indexes chars, landing on the dice count
+                let at = source.chars().nth(diagnostic.location.position);
+                assert_eq!(at, Some('0'));
+            }
+            other => panic!("Expected InvalidNumber, got {:?}", other),
+        }
+
+        // Multibyte text on earlier lines doesn't shift later columns
+        match parse("#tëst\n1.0: ñ\n-1.0: bad") {
+            Err(ParseError::InvalidCharacter { diagnostic, .. }) => {
+                assert_eq!(diagnostic.location.line, 3);
+                assert_eq!(diagnostic.location.column, 1);
+            }
+            other => panic!("Expected InvalidCharacter, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_comments_attach_as_docs() {
+        let source = "// Colors for\n// the paint shop\n#color[export]\n/* the usual */\n\
+                      1.0: red // not a doc\n2.0: blue\n\n// detached\n\n3.0: green";
+
+        // Comment tokens are only produced on request
+        let tokens = Lexer::new(source).with_comments(true).tokenize().unwrap();
+        assert_eq!(
+            tokens[0].token_type,
+            TokenType::LineComment("Colors for".to_string())
+        );
+        let block = TokenType::BlockComment("the usual".to_string());
+        assert!(tokens.iter().any(|t| t.token_type == block));
+        assert!(tokenize(source).unwrap().iter().all(|t| !matches!(
+            t.token_type,
+            TokenType::LineComment(_) | TokenType::BlockComment(_)
+        )));
+
+        let program = parse_with_comments(source).unwrap();
+        let table = &program.tables[0].value;
+        assert_eq!(table.doc.as_deref(), Some("Colors for\nthe paint shop"));
+        let docs: Vec<Option<&str>> = table.rules.iter().map(|r| r.value.doc.as_deref()).collect();
+        assert_eq!(docs, vec![Some("the usual"), None, None]);
+        assert_eq!(table.rules[0].value.content_text(), "red");
+
+        // Plain parsing is unchanged
+        let plain = parse(source).unwrap();
+        assert_eq!(plain.tables[0].value.doc, None);
+        assert!(
+            plain.tables[0]
+                .value
+                .rules
+                .iter()
+                .all(|r| r.value.doc.is_none())
+        );
+
+        // Docs survive a round trip through to_source
+        let written = program.to_source();
+        assert!(written.starts_with(
+            "// Colors for\n// the paint shop\n#color[export]\n// the usual\n1: red\n"
+        ));
+        let reparsed = parse_with_comments(&written).unwrap();
+        assert_eq!(reparsed.tables[0].value.doc, table.doc);
+        assert_eq!(
+            reparsed.tables[0].value.rules[0].value.doc.as_deref(),
+            Some("the usual")
+        );
+    }
+
+    #[test]
+    fn test_parse_named_reports_file() {
+        // A parse error
+        let error = parse_named("#color\n1.0 red", "colors.tbl").unwrap_err();
+        let location = &error.diagnostic().location;
+        assert_eq!(location.file.as_deref(), Some("colors.tbl"));
+        assert!(format!("{}", error).contains("┌─ colors.tbl:2:5"));
+
+        // A lex error
+        let error = parse_named("#t\n-1.0: bad", "dir/t.tbl").unwrap_err();
+        assert_eq!(
+            error.diagnostic().location.file.as_deref(),
+            Some("dir/t.tbl")
+        );
+
+        // Without a name, the header only has the line and column
+        let error = parse("#color\n1.0 red").unwrap_err();
+        assert_eq!(error.diagnostic().location.file, None);
+        assert!(format!("{}", error).contains("┌─ line 2:5"));
+
+        assert_eq!(
+            parse_named("#t\n1.0: ok", "t.tbl").unwrap(),
+            parse("#t\n1.0: ok").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_modifier_names_work_as_table_names() {
+        let source = "#silent[export]\n1.0: {#ordinal|silent}\n\n#ordinal\n1.0: x";
+        let program = parse(source).unwrap();
+        assert_eq!(program.tables[0].value.metadata.id, "silent");
+        let rule = &program.tables[0].value.rules[0].value;
+        assert_eq!(
+            rule.content[1],
+            RuleContent::Expression(Expression::TableReference {
+                table_id: "ordinal".to_string(),
+                modifiers: vec![Modifier::new("silent")],
+            })
+        );
+        assert!(parse("#t\n1.0: {@capitalize/truncate#repeat}").is_ok());
+    }
+
+    #[test]
+    fn test_variable_binding_syntax() {
+        let source = "#t\n1.0: {#name|capitalize > hero} met {$hero|uppercase} after {2d6 > roll}";
+        let program = parse(source).unwrap();
+        let rule = &program.tables[0].value.rules[0].value;
+        assert_eq!(
+            rule.content[1],
+            RuleContent::Expression(Expression::Binding {
+                name: "hero".to_string(),
+                expression: Box::new(Expression::TableReference {
+                    table_id: "name".to_string(),
+                    modifiers: vec![Modifier::new("capitalize")],
+                }),
+            })
+        );
+        assert_eq!(
+            rule.content[3],
+            RuleContent::Expression(Expression::VariableRef {
+                name: "hero".to_string(),
+                modifiers: vec![Modifier::new("uppercase")],
+            })
+        );
+
+        // A binding and the expression it binds share a span
+        let spans: Vec<_> = rule
+            .expression_spans
+            .iter()
+            .map(|s| (s.start, s.end))
+            .collect();
+        assert_eq!(spans.len(), 5);
+        assert_eq!(spans[0], spans[1]);
+        assert_eq!(spans[3], spans[4]);
+
+        assert_eq!(
+            parse(&program.to_source()).unwrap().to_source(),
+            program.to_source()
+        );
+        assert!(program.to_source().contains("{#name|capitalize > hero}"));
+
+        assert!(parse("#t\n1.0: {$}").is_err());
+        assert!(parse("#t\n1.0: {#name > }").is_err());
+    }
+
+    #[test]
+    fn test_conditional_syntax() {
+        let source = "#t\n1.0: {$gender == she ? her {#hat} : his \\: {a|b}} and {$n == 3 ? three}";
+        let program = parse(source).unwrap();
+        let rule = &program.tables[0].value.rules[0].value;
+        let RuleContent::Expression(Expression::Conditional {
+            var,
+            equals,
+            then,
+            otherwise,
+        }) = &rule.content[1]
+        else {
+            panic!("Expected a conditional, got {:?}", rule.content[1]);
+        };
+        assert_eq!((var.as_str(), equals.as_str()), ("gender", "she"));
+        assert_eq!(then.len(), 2);
+        assert_eq!(then[0], RuleContent::Text("her ".to_string()));
+        let otherwise = otherwise.as_ref().unwrap();
+        assert_eq!(otherwise[0], RuleContent::Text("his : ".to_string()));
+        assert!(matches!(
+            otherwise[1],
+            RuleContent::Expression(Expression::InlineChoice { .. })
+        ));
+        assert!(matches!(
+            &rule.content[3],
+            RuleContent::Expression(Expression::Conditional {
+                otherwise: None,
+                ..
+            })
+        ));
+
+        // The conditional's span comes before those of the expressions in its branches
+        assert_eq!(rule.expression_spans.len(), 4);
+        assert!(rule.expression_spans[0].end > rule.expression_spans[2].end);
+
+        assert_eq!(
+            parse(&program.to_source()).unwrap().to_source(),
+            program.to_source()
+        );
+        assert!(parse("#t\n1.0: {$g == ? a}").is_err());
+        assert!(parse("#t\n1.0: {$g == she a}").is_err());
+    }
+
+    #[test]
+    fn test_table_attribute_syntax() {
+        let program = parse("#t[finish: uppercase tag=\"a b, c\" percent]\n100: x").unwrap();
+        let metadata = &program.tables[0].value.metadata;
+        assert_eq!(metadata.finish_modifiers, vec!["uppercase".to_string()]);
+        assert_eq!(metadata.attributes["tag"], "a b, c");
+        assert!(metadata.percent);
+
+        // Any key is accepted with a value, but unknown bare flags are still errors
+        assert!(parse("#t[whatever=1]\n1: x").is_ok());
+        assert!(parse("#t[whatever]\n1: x").is_err());
+        assert!(parse("#t[tag=]\n1: x").is_err());
+        assert!(parse("#t[tag=\"open]\n1: x").is_err());
+
+        // Quotes, backslashes and line breaks in values are escaped when written back
+        let mut program = parse("#t[k=a\"b, q=\"say \\\"hi\\\" \\\\ bye\"]\n1: x").unwrap();
+        assert_eq!(program.tables[0].value.metadata.attributes["k"], "a\"b");
+        assert_eq!(
+            program.tables[0].value.metadata.attributes["q"],
+            "say \"hi\" \\ bye"
+        );
+        let metadata = program.tables[0].value.metadata.clone();
+        program.tables[0].value.metadata =
+            metadata.with_attribute("note".to_string(), "a \"quoted\"\nline\\".to_string());
+        let written = program.to_source();
+        assert!(written.contains(r#"k="a\"b""#));
+        let reparsed = parse(&written).unwrap();
+        assert_eq!(
+            reparsed.tables[0].value.metadata.attributes,
+            program.tables[0].value.metadata.attributes
+        );
+    }
+
+    #[test]
+    fn test_zero_dice_count_is_rejected() {
+        match tokenize("#t\n1.0: {0d6}") {
+            Err(LexError::InvalidNumber {
+                reason, diagnostic, ..
+            }) => {
+                assert_eq!(reason, "Dice count must be at least 1");
+                assert_eq!(diagnostic.location.line, 2);
+                assert_eq!(diagnostic.location.column, 7);
+            }
+            other => panic!("Expected InvalidNumber, got {:?}", other),
+        }
+
+        assert!(parse("#t\n1.0: {00d20}").is_err());
+        assert!(tokenize("#t\n1.0: {1d6} {10d6}").is_ok());
+    }
+
+    #[test]
+    fn test_huge_dice_count_is_rejected() {
+        match parse("#t\n1.0: {4000000000d6}") {
+            Err(ParseError::InvalidNumber { reason, diagnostic }) => {
+                assert_eq!(reason, "Can't roll more than 1000 dice at once");
+                assert_eq!(diagnostic.location.column, 7);
+            }
+            other => panic!("Expected InvalidNumber, got {:?}", other),
+        }
+        assert!(parse("#t\n1.0: {1001d6}").is_err());
+        assert!(parse("#t\n1.0: {1000d6}").is_ok());
+    }
+
+    #[test]
+    fn test_scientific_notation_weights() {
+        let weights = |source: &str| -> Vec<f64> {
+            parse(source).unwrap().tables[0]
+                .value
+                .rules
+                .iter()
+                .map(|rule| rule.value.weight)
+                .collect()
+        };
+
+        assert_eq!(
+            weights("#t\n1e3: a\n2.5E-1: b\n3e+2: c\n4: d"),
+            vec![1000.0, 0.25, 300.0, 4.0]
+        );
+
+        for source in ["#t\n1e: a", "#t\n1.5e-: a", "#t\n2E+: a"] {
+            match parse(source) {
+                Err(ParseError::InvalidNumber { reason, .. }) => {
+                    assert!(reason.contains("missing exponent digits"), "{}", reason)
+                }
+                other => panic!("Expected InvalidNumber for {:?}, got {:?}", source, other),
+            }
+        }
+
+        assert!(parse("#t\n1e999: a").is_err());
+        assert!(parse("#t\n1e-999: a").is_err());
+        assert!(parse("#t\n-1e2: a").is_err());
+    }
+
+    #[test]
+    fn test_dice_keep_suffix() {
+        let program = parse("#t\n1.0: {4d6kh3} {2d20kl1} {d8kh1} {3d6}").unwrap();
+        let keeps: Vec<_> = program.tables[0].value.rules[0]
+            .value
+            .content
+            .iter()
+            .filter_map(|content| match content {
+                RuleContent::Expression(Expression::DiceRoll { keep, .. }) => Some(*keep),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            keeps,
+            vec![
+                Some(KeepMode::Highest(3)),
+                Some(KeepMode::Lowest(1)),
+                Some(KeepMode::Highest(1)),
+                None
+            ]
+        );
+        assert_eq!(
+            program.tables[0].value.rules[0].value.content_text(),
+            "{4d6kh3} {2d20kl1} {d8kh1} {3d6}"
+        );
+
+        for source in ["#t\n1.0: {4d6kh5}", "#t\n1.0: {4d6kl0}", "#t\n1.0: {d6kh}"] {
+            match parse(source) {
+                Err(ParseError::InvalidNumber { .. }) => {}
+                other => panic!("Expected InvalidNumber for {:?}, got {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_dice_flat_modifier() {
+        let source = "#t\n1.0: {2d6+3} {d20-1} {4d6kh3+2} {2d6+0} {2d6}";
+        let program = parse(source).unwrap();
+        let rule = &program.tables[0].value.rules[0].value;
+        let modifiers: Vec<_> = rule
+            .content
+            .iter()
+            .filter_map(|content| match content {
+                RuleContent::Expression(Expression::DiceRoll { modifier, .. }) => Some(*modifier),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(modifiers, vec![Some(3), Some(-1), Some(2), Some(0), None]);
+
+        // Display and content_text() reproduce the source exactly
+        assert_eq!(
+            rule.content_text(),
+            "{2d6+3} {d20-1} {4d6kh3+2} {2d6+0} {2d6}"
+        );
+        assert_eq!(
+            rule.to_string(),
+            "1:  {2d6+3} {d20-1} {4d6kh3+2} {2d6+0} {2d6}"
+        );
+        let reparsed = parse(&format!("#t\n1.0: {}", rule.content_text())).unwrap();
+        assert_eq!(&reparsed.tables[0].value.rules[0].value, rule);
+
+        // Other spellings of the same roll are written in canonical form, which round-trips
+        let program = parse("#t\n1.0: {2d6-0} {2d6+01} {02d6-007}").unwrap();
+        let rule = &program.tables[0].value.rules[0].value;
+        assert_eq!(rule.content_text(), "{2d6+0} {2d6+1} {2d6-7}");
+        let reparsed = parse(&format!("#t\n1.0: {}", rule.content_text())).unwrap();
+        assert!(
+            reparsed.tables[0].value.rules[0]
+                .value
+                .eq_ignoring_spans(rule)
+        );
+
+        for source in [
+            "#t\n1.0: {2d6+}",
+            "#t\n1.0: {2d6-x}",
+            "#t\n1.0: {d6+99999999999}",
+        ] {
+            match parse(source) {
+                Err(ParseError::InvalidNumber { .. }) => {}
+                other => panic!("Expected InvalidNumber for {:?}, got {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_dice_roll_text_modifiers() {
+        let source = "#t\n1.0: {d6|ordinal} {2d6+1|number_word|capitalize} {red|d6}";
+        let program = parse(source).unwrap();
+        let rule = &program.tables[0].value.rules[0].value;
+        let modifiers: Vec<_> = rule
+            .content
+            .iter()
+            .filter_map(|content| match content {
+                RuleContent::Expression(Expression::DiceRoll { modifiers, .. }) => {
+                    Some(modifiers.clone())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            modifiers,
+            vec![vec!["ordinal"], vec!["number_word", "capitalize"]]
+        );
+
+        // Text before the first '|' that isn't a dice roll still makes a choice
+        assert!(matches!(
+            rule.content.last(),
+            Some(RuleContent::Expression(Expression::InlineChoice { .. }))
+        ));
+        assert_eq!(
+            rule.content_text(),
+            "{d6|ordinal} {2d6+1|number_word|capitalize} {red|d6}"
+        );
+    }
+
+    #[test]
+    fn test_modifier_arguments() {
+        let source = "#t\n1.0: {#name|truncate:10|capitalize} {@p/c#x|repeat:3} {d6|mine:up}";
+        let program = parse(source).unwrap();
+        let rule = &program.tables[0].value.rules[0].value;
+        let modifiers: Vec<_> = rule
+            .expressions()
+            .into_iter()
+            .map(|(expression, _)| expression.modifiers().to_vec())
+            .collect();
+        assert_eq!(
+            modifiers,
+            vec![
+                vec![
+                    Modifier::with_arg("truncate", "10"),
+                    Modifier::new("capitalize")
+                ],
+                vec![Modifier::with_arg("repeat", "3")],
+                vec![Modifier::with_arg("mine", "up")],
+            ]
+        );
+        assert_eq!(
+            rule.content_text(),
+            "{#name|truncate:10|capitalize} {@p/c#x|repeat:3} {d6|mine:up}"
+        );
+
+        match parse("#t\n1.0: {#name|truncate:}") {
+            Err(ParseError::UnexpectedToken { diagnostic, .. }) => {
+                assert!(
+                    diagnostic
+                        .message
+                        .starts_with("Expected an argument after 'truncate:'")
+                );
+            }
+            other => panic!("Expected UnexpectedToken, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_miscased_modifier_suggests_lowercase() {
+        for (source, expected) in [
+            ("#t\n1.0: {#x|Capitalize}\n#x\n1.0: a", "capitalize"),
+            ("#t\n1.0: {#x|UPPERCASE}\n#x\n1.0: a", "uppercase"),
+        ] {
+            match parse(source) {
+                Err(ParseError::UnexpectedToken { diagnostic, .. }) => {
+                    assert!(diagnostic.message.contains("modifiers are lowercase"));
+                    assert_eq!(
+                        diagnostic.suggestion.as_deref(),
+                        Some(format!("Did you mean '{}'?", expected).as_str())
+                    );
+                }
+                other => panic!("Expected UnexpectedToken for {:?}, got {:?}", source, other),
+            }
+        }
+
+        // Other identifiers may name registered modifiers, so they parse
+        assert!(parse("#t\n1.0: {#x|shout}\n#x\n1.0: a").is_ok());
+
+        // Anything else after '|' gets the list of built-in modifiers
+        match parse("#t\n1.0: {#x|2d6}\n#x\n1.0: a") {
+            Err(ParseError::UnexpectedToken { diagnostic, .. }) => {
+                assert!(
+                    diagnostic
+                        .suggestion
+                        .unwrap()
+                        .starts_with("Built-in modifiers are:")
+                );
+            }
+            other => panic!("Expected UnexpectedToken, got {:?}", other),
+        }
+    }
+
     #[test]
     fn test_invalid_negative_weight() {
         let source = "#test\n-1.0: invalid rule";
-        let result = parse(source);
-        assert!(result.is_err());
+        match parse(source) {
+            Err(ParseError::InvalidCharacter {
+                character,
+                diagnostic,
+                ..
+            }) => {
+                assert_eq!(character, '-');
+                // The diagnostic covers just the '-'
+                assert_eq!(diagnostic.location.line, 2);
+                assert_eq!(diagnostic.location.column, 1);
+                assert_eq!(diagnostic.location.end_column, Some(2));
+                assert!(
+                    diagnostic
+                        .suggestion
+                        .unwrap()
+                        .starts_with("Negative numbers")
+                );
+            }
+            other => panic!("Expected InvalidCharacter, got {:?}", other),
+        }
     }
 
     #[test]
@@ -216,7 +1213,10 @@ mod tests {
             _ => panic!("Expected text content"),
         }
         match &rule1.content[1] {
-            RuleContent::Expression(Expression::TableReference { table_id, modifiers }) => {
+            RuleContent::Expression(Expression::TableReference {
+                table_id,
+                modifiers,
+            }) => {
                 assert_eq!(table_id, "color");
                 assert!(modifiers.is_empty());
             }
@@ -227,7 +1227,10 @@ mod tests {
             _ => panic!("Expected text content"),
         }
         match &rule1.content[3] {
-            RuleContent::Expression(Expression::TableReference { table_id, modifiers }) => {
+            RuleContent::Expression(Expression::TableReference {
+                table_id,
+                modifiers,
+            }) => {
                 assert_eq!(table_id, "shape");
                 assert!(modifiers.is_empty());
             }
@@ -437,7 +1440,7 @@ mod tests {
         let rule1 = &program.tables[0].value.rules[0].value;
         assert_eq!(rule1.content.len(), 2); // "roll " and dice expression
         match &rule1.content[1] {
-            RuleContent::Expression(Expression::DiceRoll { count, sides }) => {
+            RuleContent::Expression(Expression::DiceRoll { count, sides, .. }) => {
                 assert_eq!(*count, None);
                 assert_eq!(*sides, 6);
             }
@@ -471,7 +1474,10 @@ mod tests {
         let generated = generation_result.unwrap();
         println!("Generated with dice: {}", generated);
         // Should contain numeric results from dice rolls
-        assert!(generated.contains(char::is_numeric), "Should contain dice roll results");
+        assert!(
+            generated.contains(char::is_numeric),
+            "Should contain dice roll results"
+        );
     }
 
     #[test]
@@ -482,30 +1488,31 @@ mod tests {
 
         let tokens = result.unwrap();
         // Find the dice roll tokens
-        let dice_tokens: Vec<_> = tokens.iter()
+        let dice_tokens: Vec<_> = tokens
+            .iter()
             .filter(|t| matches!(t.token_type, TokenType::DiceRoll { .. }))
             .collect();
-        
+
         assert_eq!(dice_tokens.len(), 3, "Should have 3 dice roll tokens");
-        
+
         // Check first dice roll (d6)
-        if let TokenType::DiceRoll { count, sides } = &dice_tokens[0].token_type {
+        if let TokenType::DiceRoll { count, sides, .. } = &dice_tokens[0].token_type {
             assert_eq!(*count, None);
             assert_eq!(*sides, 6);
         } else {
             panic!("Expected dice roll token");
         }
-        
+
         // Check second dice roll (2d10)
-        if let TokenType::DiceRoll { count, sides } = &dice_tokens[1].token_type {
+        if let TokenType::DiceRoll { count, sides, .. } = &dice_tokens[1].token_type {
             assert_eq!(*count, Some(2));
             assert_eq!(*sides, 10);
         } else {
             panic!("Expected dice roll token");
         }
-        
+
         // Check third dice roll (100d20)
-        if let TokenType::DiceRoll { count, sides } = &dice_tokens[2].token_type {
+        if let TokenType::DiceRoll { count, sides, .. } = &dice_tokens[2].token_type {
             assert_eq!(*count, Some(100));
             assert_eq!(*sides, 20);
         } else {
@@ -520,7 +1527,10 @@ mod tests {
 2.0: {2d4} {#potion} bottles"#;
 
         let result = parse(source);
-        assert!(result.is_ok(), "Should parse mixed table references and dice rolls");
+        assert!(
+            result.is_ok(),
+            "Should parse mixed table references and dice rolls"
+        );
 
         let program = result.unwrap();
         let rule1 = &program.tables[0].value.rules[0].value;
@@ -528,14 +1538,17 @@ mod tests {
         // Should have: text, table_ref, text, dice_roll, text
         assert_eq!(rule1.content.len(), 5);
         match &rule1.content[1] {
-            RuleContent::Expression(Expression::TableReference { table_id, modifiers }) => {
+            RuleContent::Expression(Expression::TableReference {
+                table_id,
+                modifiers,
+            }) => {
                 assert_eq!(table_id, "color");
                 assert!(modifiers.is_empty());
             }
             _ => panic!("Expected table reference"),
         }
         match &rule1.content[3] {
-            RuleContent::Expression(Expression::DiceRoll { count, sides }) => {
+            RuleContent::Expression(Expression::DiceRoll { count, sides, .. }) => {
                 assert_eq!(*count, None);
                 assert_eq!(*sides, 6);
             }
@@ -557,7 +1570,10 @@ mod tests {
 1.0: {#animal|indefinite|capitalize}"#;
 
         let result = parse(source);
-        assert!(result.is_ok(), "Should parse table references with modifiers");
+        assert!(
+            result.is_ok(),
+            "Should parse table references with modifiers"
+        );
 
         let program = result.unwrap();
         assert_eq!(program.tables.len(), 2);
@@ -568,7 +1584,11 @@ mod tests {
 
         // Check the first rule has capitalize modifier
         let rule1 = &test_table.rules[0].value;
-        if let RuleContent::Expression(Expression::TableReference { table_id, modifiers }) = &rule1.content[1] {
+        if let RuleContent::Expression(Expression::TableReference {
+            table_id,
+            modifiers,
+        }) = &rule1.content[1]
+        {
             assert_eq!(table_id, "animal");
             assert_eq!(modifiers, &vec!["capitalize"]);
         } else {
@@ -577,7 +1597,11 @@ mod tests {
 
         // Check the last rule has multiple modifiers
         let rule6 = &test_table.rules[5].value;
-        if let RuleContent::Expression(Expression::TableReference { table_id, modifiers }) = &rule6.content[1] {
+        if let RuleContent::Expression(Expression::TableReference {
+            table_id,
+            modifiers,
+        }) = &rule6.content[1]
+        {
             assert_eq!(table_id, "animal");
             assert_eq!(modifiers, &vec!["indefinite", "capitalize"]);
         } else {
@@ -586,13 +1610,13 @@ mod tests {
 
         // Test collection generation with modifiers
         let mut collection = Collection::new(source).unwrap();
-        
+
         // Generate multiple times to test modifier application
         for _ in 0..10 {
             let result = collection.generate("test_modifiers", 1);
             assert!(result.is_ok(), "Should generate with modifiers");
             let generated = result.unwrap();
-            
+
             // Should contain modified text based on the modifiers
             assert!(!generated.is_empty(), "Generated text should not be empty");
         }
@@ -604,15 +1628,37 @@ mod tests {
 1.0: cat
 
 #test
-1.0: {#animal|invalidmodifier}"#;
+1.0: {#animal|}"#;
 
         let result = parse(source);
-        assert!(result.is_err(), "Should reject invalid modifiers");
-        
+        assert!(result.is_err(), "Should reject a missing modifier");
+
         let error = result.unwrap_err();
         let error_string = format!("{}", error);
-        assert!(error_string.contains("Expected modifier"), "Error should mention expected modifier");
-        assert!(error_string.contains("invalidmodifier"), "Error should mention the invalid modifier");
+        assert!(
+            error_string.contains("Expected modifier"),
+            "Error should mention expected modifier"
+        );
+
+        // Unknown names are left for the collection, which may register them
+        let source = "#animal\n1.0: cat\n\n#test\n1.0: {#animal|invalidmodifier}";
+        let program = parse(source).unwrap();
+        assert_eq!(
+            program.tables[1].value.rules[0].value.content,
+            vec![
+                RuleContent::Text(" ".to_string()),
+                RuleContent::Expression(Expression::TableReference {
+                    table_id: "animal".to_string(),
+                    modifiers: vec![Modifier::new("invalidmodifier")],
+                })
+            ]
+        );
+
+        // Building a collection rejects them unless they're registered
+        assert!(matches!(
+            Collection::new(source),
+            Err(CollectionError::UnknownModifier { modifier, .. }) if modifier == "invalidmodifier"
+        ));
     }
 
     #[test]
@@ -636,29 +1682,29 @@ mod tests {
 1.0: {#word|definite}"#;
 
         let mut collection = Collection::new(source).unwrap();
-        
+
         // Test each modifier type individually for consistency
         for _ in 0..5 {
             // Test capitalize
             let result = collection.generate("capitalize_test", 1);
             assert!(result.is_ok());
             assert_eq!(result.unwrap(), "Apple");
-            
+
             // Test uppercase
             let result = collection.generate("uppercase_test", 1);
             assert!(result.is_ok());
             assert_eq!(result.unwrap(), "APPLE");
-            
+
             // Test lowercase
             let result = collection.generate("lowercase_test", 1);
             assert!(result.is_ok());
             assert_eq!(result.unwrap(), "apple");
-            
+
             // Test indefinite (should be "an apple" for vowel sound)
             let result = collection.generate("indefinite_test", 1);
             assert!(result.is_ok());
             assert_eq!(result.unwrap(), "an apple");
-            
+
             // Test definite
             let result = collection.generate("definite_test", 1);
             assert!(result.is_ok());
@@ -1,37 +1,110 @@
-use crate::ast::{Node, Program, Rule, Span, Table, TableMetadata};
+use crate::ast::{
+    EMPTY_RULE, MacroDefinition, Modifier, Node, Program, Rule, Span, Table, TableMetadata,
+};
 use crate::diagnostic_collector::DiagnosticCollector;
 use crate::errors::{ParseError, ParseResult};
-use crate::lexer::{Token, TokenType};
+use crate::lexer::{MODIFIER_KEYWORDS, Token, TokenType};
+use std::collections::HashMap;
 
 /// Simple parser for our weight: rule language
 pub struct Parser {
     tokens: Vec<Token>,
     current: usize,
     diagnostic_collector: DiagnosticCollector,
+    expression_spans: Vec<Span>, // Spans of the current rule's expressions, in source order
+    docs: HashMap<usize, String>, // Leading comments, keyed by the index of the token they precede
+}
+
+/// Set comment tokens aside, keeping the leading ones as docs
+///
+/// Consecutive comments that each start a line become the doc of a table
+/// declaration or rule on the line right after them, keyed by the index of
+/// its first token among the remaining tokens. Comments after code on the
+/// same line, or separated from the next table or rule by a blank line, are
+/// dropped.
+fn split_comments(tokens: Vec<Token>) -> (Vec<Token>, HashMap<usize, String>) {
+    let mut kept = Vec::with_capacity(tokens.len());
+    let mut docs = HashMap::new();
+    let mut pending: Vec<String> = Vec::new();
+    let mut line_start = true; // Nothing but comments since the last line break
+    let mut line_breaks = 0; // Line breaks since the last pending comment
+
+    for token in tokens {
+        match &token.token_type {
+            TokenType::LineComment(text) | TokenType::BlockComment(text) => {
+                if line_start {
+                    pending.push(text.clone());
+                    line_breaks = 0;
+                }
+                continue;
+            }
+            TokenType::Newline => {
+                line_start = true;
+                line_breaks += 1;
+                if line_breaks > 1 {
+                    pending.clear();
+                }
+            }
+            TokenType::Hash | TokenType::Number(_) if line_start && !pending.is_empty() => {
+                docs.insert(kept.len(), pending.join("\n"));
+                pending.clear();
+                line_start = false;
+            }
+            _ => {
+                pending.clear();
+                line_start = false;
+            }
+        }
+        kept.push(token);
+    }
+
+    (kept, docs)
+}
+
+/// Drop the whitespace at the start and end of a conditional branch, as in `? her : his`
+fn trim_branch(branch: &mut Vec<crate::ast::RuleContent>) {
+    use crate::ast::RuleContent;
+
+    if let Some(RuleContent::Text(text)) = branch.first_mut() {
+        *text = text.trim_start().to_string();
+    }
+    if let Some(RuleContent::Text(text)) = branch.last_mut() {
+        *text = text.trim_end().to_string();
+    }
+    branch.retain(|content| !matches!(content, RuleContent::Text(text) if text.is_empty()));
 }
 
 impl Parser {
     /// Creates a new parser with the given tokens
     pub fn new(tokens: Vec<Token>) -> Self {
-        Self {
-            tokens,
-            current: 0,
-            diagnostic_collector: DiagnosticCollector::new(String::new()),
-        }
+        Self::from_source(tokens, String::new())
     }
 
     /// Creates a new parser with tokens and source for better error reporting
+    ///
+    /// Comment tokens, from a lexer built `with_comments(true)`, are set aside
+    /// here; those on the lines just before a table or rule become its `doc`.
     pub fn from_source(tokens: Vec<Token>, source: String) -> Self {
+        let (tokens, docs) = split_comments(tokens);
         Self {
             tokens,
             current: 0,
             diagnostic_collector: DiagnosticCollector::new(source),
+            expression_spans: Vec::new(),
+            docs,
         }
     }
 
+    /// Name the file being parsed, so diagnostics report it
+    pub fn with_file(mut self, file: &str) -> Self {
+        self.diagnostic_collector = self.diagnostic_collector.with_file(file);
+        self
+    }
+
     /// Parses the tokens into an AST containing tables
     pub fn parse(&mut self) -> ParseResult<Program> {
         let mut tables = Vec::new();
+        let mut macros = Vec::new();
 
         while !self.is_at_end() {
             // Skip newlines at the top level
@@ -40,6 +113,11 @@ impl Parser {
                 continue;
             }
 
+            if self.at_macro_definition() {
+                macros.push(self.macro_definition()?);
+                continue;
+            }
+
             tables.push(self.table()?);
         }
 
@@ -55,25 +133,62 @@ impl Parser {
             });
         }
 
-        Ok(Program::new(tables))
+        Ok(Program::new(tables).with_macros(macros))
+    }
+
+    /// Whether the current token is a `%name% = "value"` macro definition
+    fn at_macro_definition(&self) -> bool {
+        matches!(self.peek().token_type, TokenType::MacroDefinition { .. })
+    }
+
+    /// Parses a macro definition, which must sit on its own line
+    fn macro_definition(&mut self) -> ParseResult<Node<MacroDefinition>> {
+        let token = self.advance().clone();
+        let TokenType::MacroDefinition { name, value } = token.token_type else {
+            unreachable!("macro_definition called on a non-macro token");
+        };
+
+        if !self.check(&TokenType::Newline) && !self.is_at_end() {
+            let next = self.peek();
+            let diagnostic = self
+                .diagnostic_collector
+                .parse_error_span(
+                    next.span.start,
+                    next.span.end,
+                    format!("Unexpected {} after macro definition", next.token_type),
+                )
+                .with_suggestion("Put each macro definition on its own line".to_string());
+
+            return Err(ParseError::UnexpectedToken {
+                expected: "newline".to_string(),
+                found: format!("{}", next.token_type),
+                diagnostic: Box::new(diagnostic),
+            });
+        }
+
+        Ok(Node::new(MacroDefinition::new(name, value), token.span))
     }
 
     /// Parses a table: #id[flags] followed by rules
     fn table(&mut self) -> ParseResult<Node<Table>> {
         let start_pos = self.peek().span.start;
+        let doc = self.docs.remove(&self.current);
 
         // Expect hash symbol
         self.consume(&TokenType::Hash, "Expected '#' to start table declaration")?;
 
         // Expect table identifier
-        let table_id = if let TokenType::Identifier(name) = &self.advance().token_type {
+        let table_id = if let TokenType::Identifier(name) | TokenType::Modifier(name) =
+            &self.advance().token_type
+        {
             name.clone()
         } else {
             let token = self.previous();
             let diagnostic = self
                 .diagnostic_collector
-                .parse_error(
+                .parse_error_span(
                     token.span.start,
+                    token.span.end,
                     format!(
                         "Expected table identifier after '#', but found {}",
                         token.token_type
@@ -103,6 +218,35 @@ impl Parser {
                 if self.check(&TokenType::Export) {
                     self.advance();
                     metadata = metadata.with_export(true);
+                } else if matches!(
+                    self.tokens.get(self.current + 1),
+                    Some(Token {
+                        token_type: TokenType::Equals,
+                        ..
+                    })
+                ) {
+                    let (key, value) = self.attribute()?;
+                    metadata = metadata.with_attribute(key, value);
+                } else if matches!(
+                    &self.peek().token_type,
+                    TokenType::Identifier(name) if name == "percent"
+                ) {
+                    self.advance();
+                    metadata = metadata.with_percent(true);
+                } else if matches!(
+                    &self.peek().token_type,
+                    TokenType::Identifier(name) if name == "finish"
+                ) {
+                    self.advance(); // consume 'finish'
+                    self.consume(&TokenType::Colon, "Expected ':' after 'finish'")?;
+                    metadata = metadata.with_finish_modifiers(self.finish_modifiers()?);
+                } else if matches!(
+                    &self.peek().token_type,
+                    TokenType::Identifier(name) if name == "weight"
+                ) {
+                    self.advance(); // consume 'weight'
+                    self.consume(&TokenType::Colon, "Expected ':' after 'weight'")?;
+                    metadata = metadata.with_default_weight(self.table_weight()?);
                 } else {
                     // Calculate span from opening bracket to current position (or closing bracket if found)
                     let mut error_end = self.peek().span.end;
@@ -134,10 +278,14 @@ impl Parser {
                             error_end,
                             format!("Unknown flag '{}' in table declaration", token.token_type),
                         )
-                        .with_suggestion("Valid flags are: export".to_string());
+                        .with_suggestion(
+                            "Valid flags are: export, percent, finish: <modifier>, weight: <number>, and key=\"value\" attributes"
+                                .to_string(),
+                        );
 
                     return Err(ParseError::UnexpectedToken {
-                        expected: "export flag or ']'".to_string(),
+                        expected: "export, percent, finish or weight flag, attribute, or ']'"
+                            .to_string(),
                         found: format!("{}", token.token_type),
                         diagnostic: Box::new(diagnostic),
                     });
@@ -154,7 +302,7 @@ impl Parser {
 
         // Parse rules for this table
         let mut rules = Vec::new();
-        while !self.is_at_end() && !self.check(&TokenType::Hash) {
+        while !self.is_at_end() && !self.check(&TokenType::Hash) && !self.at_macro_definition() {
             // Skip newlines between rules
             if self.check(&TokenType::Newline) {
                 self.advance();
@@ -170,13 +318,140 @@ impl Parser {
             self.previous().span.end
         };
 
-        let table = Table::new(metadata, rules);
+        let mut table = Table::new(metadata, rules);
+        table.doc = doc;
         Ok(Node::new(table, Span::new(start_pos, end_pos)))
     }
 
+    /// Parse a `key=value` or `key="value"` table attribute
+    fn attribute(&mut self) -> ParseResult<(String, String)> {
+        let key_token = self.advance().clone();
+        let key = match key_token.token_type {
+            TokenType::Identifier(key) | TokenType::Modifier(key) => key,
+            _ => {
+                let diagnostic = self
+                    .diagnostic_collector
+                    .parse_error_span(
+                        key_token.span.start,
+                        key_token.span.end,
+                        format!(
+                            "Expected an attribute name, but found {}",
+                            key_token.token_type
+                        ),
+                    )
+                    .with_suggestion(
+                        "Attribute names are identifiers, like category=\"weapons\"".to_string(),
+                    );
+
+                return Err(ParseError::UnexpectedToken {
+                    expected: "attribute name".to_string(),
+                    found: format!("{}", key_token.token_type),
+                    diagnostic: Box::new(diagnostic),
+                });
+            }
+        };
+        self.advance(); // consume '='
+
+        if let TokenType::AttributeValue(value) = &self.peek().token_type {
+            let value = value.clone();
+            self.advance();
+            return Ok((key, value));
+        }
+
+        let token = self.peek();
+        let diagnostic = self
+            .diagnostic_collector
+            .parse_error_span(
+                token.span.start,
+                token.span.end,
+                format!("Expected a value for attribute '{}' after '='", key),
+            )
+            .with_suggestion(
+                "Give the attribute a value, like min_level=5 or category=\"weapons\"".to_string(),
+            );
+
+        Err(ParseError::UnexpectedToken {
+            expected: "attribute value".to_string(),
+            found: format!("{}", token.token_type),
+            diagnostic: Box::new(diagnostic),
+        })
+    }
+
+    /// Parses the modifier names following `finish:` in a table's flag list
+    fn finish_modifiers(&mut self) -> ParseResult<Vec<String>> {
+        let mut modifiers = Vec::new();
+
+        while let TokenType::Modifier(name) | TokenType::Identifier(name) = &self.peek().token_type
+        {
+            // A name followed by ':' or '=' starts the next flag, as in `weight: 3`
+            // or `category="weapons"`
+            let next = self.tokens.get(self.current + 1);
+            if next.is_some_and(|token| {
+                matches!(token.token_type, TokenType::Colon | TokenType::Equals)
+            }) {
+                break;
+            }
+            modifiers.push(name.clone());
+            self.advance();
+        }
+
+        if modifiers.is_empty() {
+            let token = self.peek();
+            let diagnostic = self
+                .diagnostic_collector
+                .parse_error_span(
+                    token.span.start,
+                    token.span.end,
+                    format!(
+                        "Expected a modifier after 'finish:', but found {}",
+                        token.token_type
+                    ),
+                )
+                .with_suggestion("Try something like [finish: capitalize]".to_string());
+
+            return Err(ParseError::UnexpectedToken {
+                expected: "modifier".to_string(),
+                found: format!("{}", token.token_type),
+                diagnostic: Box::new(diagnostic),
+            });
+        }
+
+        Ok(modifiers)
+    }
+
+    /// Parses the number following `weight:` in a table's flag list
+    fn table_weight(&mut self) -> ParseResult<f64> {
+        let token = self.peek();
+        if let TokenType::Number(weight) = token.token_type
+            && weight > 0.0
+        {
+            self.advance();
+            return Ok(weight);
+        }
+
+        let diagnostic = self
+            .diagnostic_collector
+            .parse_error_span(
+                token.span.start,
+                token.span.end,
+                format!(
+                    "Expected a positive number after 'weight:', but found {}",
+                    token.token_type
+                ),
+            )
+            .with_suggestion("Try something like [weight: 3]".to_string());
+
+        Err(ParseError::UnexpectedToken {
+            expected: "positive number (table weight)".to_string(),
+            found: format!("{}", token.token_type),
+            diagnostic: Box::new(diagnostic),
+        })
+    }
+
     /// Parses a single rule: weight: rule_text
     fn rule(&mut self) -> ParseResult<Node<Rule>> {
         let start_pos = self.peek().span.start;
+        let doc = self.docs.remove(&self.current);
 
         // Expect a number (weight)
         let weight = if let TokenType::Number(n) = &self.advance().token_type {
@@ -192,8 +467,9 @@ impl Parser {
 
             let diagnostic = self
                 .diagnostic_collector
-                .parse_error(
+                .parse_error_span(
                     token.span.start,
+                    token.span.end,
                     format!(
                         "Expected positive number (weight), but found {}",
                         token.token_type
@@ -220,7 +496,9 @@ impl Parser {
         }
 
         let end_pos = self.previous().span.end;
-        let rule = Rule::new(weight, content);
+        let mut rule = Rule::new(weight, content);
+        rule.expression_spans = std::mem::take(&mut self.expression_spans);
+        rule.doc = doc;
 
         Ok(Node::new(rule, Span::new(start_pos, end_pos)))
     }
@@ -229,12 +507,16 @@ impl Parser {
         use crate::ast::RuleContent;
 
         let mut content = Vec::new();
+        let mut only_tilde = false;
 
         // Parse until we hit a newline or end of file
         while !self.is_at_end() && !self.check(&TokenType::Newline) && !self.check(&TokenType::Hash)
         {
             if self.check(&TokenType::TextSegment("".to_string())) {
-                if let TokenType::TextSegment(text) = &self.advance().token_type {
+                let token = self.advance();
+                // Check the raw lexeme so an escaped `\~` stays literal text
+                only_tilde = content.is_empty() && token.lexeme.trim() == EMPTY_RULE;
+                if let TokenType::TextSegment(text) = &token.token_type {
                     content.push(RuleContent::Text(text.clone()));
                 }
             } else if self.check(&TokenType::LeftBrace) {
@@ -251,8 +533,9 @@ impl Parser {
                 let token = self.peek();
                 let diagnostic = self
                     .diagnostic_collector
-                    .parse_error(
+                    .parse_error_span(
                         token.span.start,
+                        token.span.end,
                         format!("Unexpected token in rule content: {}", token.token_type),
                     )
                     .with_suggestion(
@@ -267,16 +550,26 @@ impl Parser {
             }
         }
 
+        // A lone `~` marks a rule that intentionally generates nothing
+        if only_tilde && content.len() == 1 {
+            return Ok(Vec::new());
+        }
+
         // If no content was parsed, it's an error
         if content.is_empty() {
             let token = self.peek();
             let diagnostic = self
                 .diagnostic_collector
-                .parse_error(
+                .parse_error_span(
                     token.span.start,
+                    token.span.end,
                     "Missing rule content after colon".to_string(),
                 )
-                .with_suggestion("Add some text or expressions after the colon".to_string());
+                .with_suggestion(
+                    "Add some text or expressions after the colon, or '~' for a rule that \
+                     generates nothing"
+                        .to_string(),
+                );
 
             return Err(ParseError::UnexpectedToken {
                 expected: "rule content".to_string(),
@@ -288,8 +581,26 @@ impl Parser {
         Ok(content)
     }
 
-    /// Parses an expression within curly braces
+    /// Parses an expression within curly braces, recording its span
+    ///
+    /// The span is reserved before parsing so that an inline choice's span
+    /// comes before those of the expressions in its options. The expression
+    /// inside a binding shares the binding's span.
     fn parse_expression(&mut self) -> ParseResult<crate::ast::Expression> {
+        let start = self.peek().span.start;
+        let index = self.expression_spans.len();
+        self.expression_spans.push(Span::new(start, start));
+
+        let expression = self.parse_expression_body()?;
+        self.expression_spans[index].end = self.previous().span.end;
+        if let crate::ast::Expression::Binding { .. } = expression {
+            self.expression_spans.push(self.expression_spans[index]);
+        }
+        Ok(expression)
+    }
+
+    /// Parses the kind of expression that follows a '{', through its closing '}'
+    fn parse_expression_body(&mut self) -> ParseResult<crate::ast::Expression> {
         use crate::ast::Expression;
 
         // Consume '{'
@@ -302,74 +613,307 @@ impl Parser {
         } else if self.check(&TokenType::At) {
             // External table reference: {@publisher/collection#table_name}
             self.parse_external_table_reference()
-        } else if let TokenType::DiceRoll { count, sides } = &self.peek().token_type {
-            // Dice roll expression: {d6} or {2d10}
+        } else if self.check(&TokenType::Dollar) {
+            // Variable reference: {$hero} or {$hero|uppercase}
+            self.advance(); // consume '$'
+            let name = self.expect_variable_name("variable name", "$")?;
+            if self.check(&TokenType::EqualEqual) {
+                // Conditional: {$gender == she ? her : his}
+                return self.parse_conditional(name);
+            }
+            let modifiers = self.parse_modifiers()?;
+            self.consume(&TokenType::RightBrace, "Expected '}' to close expression")?;
+            Ok(Expression::VariableRef { name, modifiers })
+        } else if let TokenType::DiceRoll {
+            count,
+            sides,
+            keep,
+            modifier,
+        } = &self.peek().token_type
+        {
+            // Dice roll expression: {d6}, {2d10}, {4d6kh3}, {2d6+3} or {d6|ordinal}
             let count = *count;
             let sides = *sides;
+            let keep = *keep;
+            let modifier = *modifier;
             self.advance(); // consume the dice roll token
 
-            // Consume '}'
-            self.consume(&TokenType::RightBrace, "Expected '}' to close expression")?;
+            // Parse optional modifiers
+            let modifiers = self.parse_modifiers()?;
 
-            Ok(Expression::DiceRoll { count, sides })
+            self.close_expression(Expression::DiceRoll {
+                count,
+                sides,
+                keep,
+                modifier,
+                modifiers,
+            })
+        } else if matches!(
+            self.peek().token_type,
+            TokenType::TextSegment(_)
+                | TokenType::LeftBrace
+                | TokenType::Pipe
+                | TokenType::Number(_)
+        ) {
+            // Inline choice: {red|blue|green} or {3:red|1:blue}
+            self.parse_inline_choice()
         } else {
             // Unknown expression type
             let token = self.peek();
             let diagnostic = self
                 .diagnostic_collector
-                .parse_error(
+                .parse_error_span(
                     token.span.start,
+                    token.span.end,
                     format!("Unexpected token in expression: {}", token.token_type),
                 )
-                .with_suggestion("Expressions should be table references like {#table}, external references like {@user/collection#table}, or dice rolls like {d6} or {2d10}".to_string());
+                .with_suggestion("Expressions should be table references like {#table}, external references like {@user/collection#table}, dice rolls like {d6} or {2d10}, choices like {red|blue}, or variables like {$hero}".to_string());
 
             Err(ParseError::UnexpectedToken {
-                expected: "table reference, external reference, or dice roll".to_string(),
+                expected:
+                    "table reference, external reference, dice roll, inline choice, or variable"
+                        .to_string(),
                 found: format!("{}", token.token_type),
                 diagnostic: Box::new(diagnostic),
             })
         }
     }
 
-    /// Parse a regular table reference: {#table_name|modifiers}
+    /// Parse an inline choice: {option|option|...}, where each option is rule content
+    /// with an optional leading `weight:`
+    fn parse_inline_choice(&mut self) -> ParseResult<crate::ast::Expression> {
+        use crate::ast::{Expression, RuleContent};
+
+        let mut options = Vec::new();
+        let mut weights = Vec::new();
+        let mut option = Vec::new();
+        loop {
+            if weights.len() == options.len() {
+                weights.push(self.option_weight()?);
+            }
+
+            match &self.peek().token_type {
+                TokenType::TextSegment(text) => {
+                    option.push(RuleContent::Text(text.clone()));
+                    self.advance();
+                }
+                TokenType::LeftBrace => {
+                    option.push(RuleContent::Expression(self.parse_expression()?));
+                }
+                TokenType::Pipe => {
+                    self.advance();
+                    options.push(std::mem::take(&mut option));
+                }
+                TokenType::RightBrace => {
+                    self.advance();
+                    options.push(option);
+                    return Ok(Expression::InlineChoice { options, weights });
+                }
+                _ => {
+                    let token = self.peek();
+                    let diagnostic = self
+                        .diagnostic_collector
+                        .parse_error_span(
+                            token.span.start,
+                            token.span.end,
+                            format!(
+                                "Expected '|' or '}}' in inline choice, but found {}",
+                                token.token_type
+                            ),
+                        )
+                        .with_suggestion("Inline choices look like {red|blue|green}".to_string());
+
+                    return Err(ParseError::UnexpectedToken {
+                        expected: "'|' or '}'".to_string(),
+                        found: format!("{}", token.token_type),
+                        diagnostic: Box::new(diagnostic),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Parse the rest of a conditional after its variable: `== value ? then : otherwise}`
+    ///
+    /// Each branch is rule content, and the whitespace around it is dropped.
+    fn parse_conditional(&mut self, var: String) -> ParseResult<crate::ast::Expression> {
+        use crate::ast::{Expression, RuleContent};
+
+        self.advance(); // consume '=='
+        let equals = self.expect_variable_name("value", "==")?;
+        self.consume(
+            &TokenType::Question,
+            "Expected '?' after the value in a conditional",
+        )?;
+
+        let mut then = Vec::new();
+        let mut otherwise = None;
+        loop {
+            let branch = otherwise.as_mut().unwrap_or(&mut then);
+            match &self.peek().token_type {
+                TokenType::TextSegment(text) => {
+                    branch.push(RuleContent::Text(text.clone()));
+                    self.advance();
+                }
+                TokenType::LeftBrace => {
+                    branch.push(RuleContent::Expression(self.parse_expression()?));
+                }
+                TokenType::Colon if otherwise.is_none() => {
+                    self.advance();
+                    otherwise = Some(Vec::new());
+                }
+                TokenType::RightBrace => {
+                    self.advance();
+                    trim_branch(&mut then);
+                    if let Some(otherwise) = &mut otherwise {
+                        trim_branch(otherwise);
+                    }
+                    return Ok(Expression::Conditional {
+                        var,
+                        equals,
+                        then,
+                        otherwise,
+                    });
+                }
+                _ => {
+                    let token = self.peek();
+                    let diagnostic = self
+                        .diagnostic_collector
+                        .parse_error_span(
+                            token.span.start,
+                            token.span.end,
+                            format!(
+                                "Expected ':' or '}}' in conditional, but found {}",
+                                token.token_type
+                            ),
+                        )
+                        .with_suggestion(
+                            "Conditionals look like {$gender == she ? her : his}".to_string(),
+                        );
+
+                    return Err(ParseError::UnexpectedToken {
+                        expected: "':' or '}'".to_string(),
+                        found: format!("{}", token.token_type),
+                        diagnostic: Box::new(diagnostic),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Parse the optional `weight:` starting an inline choice option, defaulting to 1.0
+    fn option_weight(&mut self) -> ParseResult<f64> {
+        let TokenType::Number(weight) = self.peek().token_type else {
+            return Ok(1.0);
+        };
+        self.advance();
+        self.consume(&TokenType::Colon, "Expected ':' after choice weight")?;
+        Ok(weight)
+    }
+
+    /// Parse a regular table reference: {#table_name|modifiers} or {#namespace:table_name}
     fn parse_table_reference(&mut self) -> ParseResult<crate::ast::Expression> {
         use crate::ast::Expression;
 
         self.advance(); // consume '#'
 
         // Expect table identifier
-        let table_id = if let TokenType::Identifier(name) = &self.advance().token_type {
-            name.clone()
+        let mut table_id = self.expect_reference_identifier('#')?;
+
+        // A namespace-qualified reference keeps the full `namespace:table` id
+        if self.check(&TokenType::Colon) {
+            self.advance(); // consume ':'
+            let name = self.expect_reference_identifier(':')?;
+            table_id = format!("{}:{}", table_id, name);
+        }
+
+        // Parse optional modifiers
+        let modifiers = self.parse_modifiers()?;
+
+        self.close_expression(Expression::TableReference {
+            table_id,
+            modifiers,
+        })
+    }
+
+    /// Consume the '}' ending an expression, binding the expression's result
+    /// to a variable first if `> name` follows, as in `{#name > hero}`
+    fn close_expression(
+        &mut self,
+        expression: crate::ast::Expression,
+    ) -> ParseResult<crate::ast::Expression> {
+        let expression = if self.check(&TokenType::Greater) {
+            self.advance(); // consume '>'
+            let name = self.expect_variable_name("variable name", ">")?;
+            crate::ast::Expression::Binding {
+                name,
+                expression: Box::new(expression),
+            }
+        } else {
+            expression
+        };
+
+        self.consume(&TokenType::RightBrace, "Expected '}' to close expression")?;
+        Ok(expression)
+    }
+
+    /// Consume the identifier naming a variable in a binding or variable reference,
+    /// or the value a conditional compares a variable with
+    ///
+    /// `what` names the expected identifier in the error, and `after` the token before it.
+    fn expect_variable_name(&mut self, what: &str, after: &str) -> ParseResult<String> {
+        if let TokenType::Identifier(name) | TokenType::Modifier(name) = &self.advance().token_type
+        {
+            Ok(name.clone())
         } else {
             let token = self.previous();
             let diagnostic = self
                 .diagnostic_collector
-                .parse_error(
+                .parse_error_span(
                     token.span.start,
+                    token.span.end,
                     format!(
-                        "Expected table identifier after '#', but found {}",
-                        token.token_type
+                        "Expected {} after '{}', but found {}",
+                        what, after, token.token_type
                     ),
                 )
-                .with_suggestion("Table references should look like {#table_name}".to_string());
+                .with_suggestion(
+                    "Bind a variable with {#table > name}, use it with {$name} and test it with {$name == value ? yes : no}".to_string(),
+                );
 
-            return Err(ParseError::UnexpectedToken {
-                expected: "table identifier".to_string(),
+            Err(ParseError::UnexpectedToken {
+                expected: what.to_string(),
                 found: format!("{}", token.token_type),
                 diagnostic: Box::new(diagnostic),
-            });
-        };
-
-        // Parse optional modifiers
-        let modifiers = self.parse_modifiers()?;
+            })
+        }
+    }
 
-        // Consume '}'
-        self.consume(&TokenType::RightBrace, "Expected '}' to close expression")?;
+    /// Consume the identifier naming a table (or its namespace) in a reference
+    fn expect_reference_identifier(&mut self, after: char) -> ParseResult<String> {
+        if let TokenType::Identifier(name) | TokenType::Modifier(name) = &self.advance().token_type
+        {
+            Ok(name.clone())
+        } else {
+            let token = self.previous();
+            let diagnostic = self
+                .diagnostic_collector
+                .parse_error_span(
+                    token.span.start,
+                    token.span.end,
+                    format!(
+                        "Expected table identifier after '{}', but found {}",
+                        after, token.token_type
+                    ),
+                )
+                .with_suggestion("Table references should look like {#table_name}".to_string());
 
-        Ok(Expression::TableReference {
-            table_id,
-            modifiers,
-        })
+            Err(ParseError::UnexpectedToken {
+                expected: "table identifier".to_string(),
+                found: format!("{}", token.token_type),
+                diagnostic: Box::new(diagnostic),
+            })
+        }
     }
 
     /// Parse an external table reference: {@publisher/collection#table_name|modifiers}
@@ -379,20 +923,26 @@ impl Parser {
         self.advance(); // consume '@'
 
         // Expect publisher identifier
-        let publisher = if let TokenType::Identifier(name) = &self.advance().token_type {
+        let publisher = if let TokenType::Identifier(name) | TokenType::Modifier(name) =
+            &self.advance().token_type
+        {
             name.clone()
         } else {
             let token = self.previous();
             let diagnostic = self
                 .diagnostic_collector
-                .parse_error(
+                .parse_error_span(
                     token.span.start,
+                    token.span.end,
                     format!(
                         "Expected publisher name after '@', but found {}",
                         token.token_type
                     ),
                 )
-                .with_suggestion("External references should look like {@publisher/collection#table}".to_string());
+                .with_suggestion(
+                    "External references should look like {@publisher/collection#table}"
+                        .to_string(),
+                );
 
             return Err(ParseError::UnexpectedToken {
                 expected: "publisher identifier".to_string(),
@@ -405,20 +955,26 @@ impl Parser {
         self.consume(&TokenType::Slash, "Expected '/' after publisher name")?;
 
         // Expect collection identifier
-        let collection = if let TokenType::Identifier(name) = &self.advance().token_type {
+        let collection = if let TokenType::Identifier(name) | TokenType::Modifier(name) =
+            &self.advance().token_type
+        {
             name.clone()
         } else {
             let token = self.previous();
             let diagnostic = self
                 .diagnostic_collector
-                .parse_error(
+                .parse_error_span(
                     token.span.start,
+                    token.span.end,
                     format!(
                         "Expected collection name after '/', but found {}",
                         token.token_type
                     ),
                 )
-                .with_suggestion("External references should look like {@publisher/collection#table}".to_string());
+                .with_suggestion(
+                    "External references should look like {@publisher/collection#table}"
+                        .to_string(),
+                );
 
             return Err(ParseError::UnexpectedToken {
                 expected: "collection identifier".to_string(),
@@ -431,20 +987,26 @@ impl Parser {
         self.consume(&TokenType::Hash, "Expected '#' after collection name")?;
 
         // Expect table identifier
-        let table_id = if let TokenType::Identifier(name) = &self.advance().token_type {
+        let table_id = if let TokenType::Identifier(name) | TokenType::Modifier(name) =
+            &self.advance().token_type
+        {
             name.clone()
         } else {
             let token = self.previous();
             let diagnostic = self
                 .diagnostic_collector
-                .parse_error(
+                .parse_error_span(
                     token.span.start,
+                    token.span.end,
                     format!(
                         "Expected table identifier after '#', but found {}",
                         token.token_type
                     ),
                 )
-                .with_suggestion("External references should look like {@publisher/collection#table}".to_string());
+                .with_suggestion(
+                    "External references should look like {@publisher/collection#table}"
+                        .to_string(),
+                );
 
             return Err(ParseError::UnexpectedToken {
                 expected: "table identifier".to_string(),
@@ -456,10 +1018,7 @@ impl Parser {
         // Parse optional modifiers
         let modifiers = self.parse_modifiers()?;
 
-        // Consume '}'
-        self.consume(&TokenType::RightBrace, "Expected '}' to close expression")?;
-
-        Ok(Expression::ExternalTableReference {
+        self.close_expression(Expression::ExternalTableReference {
             publisher,
             collection,
             table_id,
@@ -467,30 +1026,54 @@ impl Parser {
         })
     }
 
-    /// Parse modifiers (shared between table reference and external table reference)
-    fn parse_modifiers(&mut self) -> ParseResult<Vec<String>> {
+    /// Parse modifiers (shared between table references, external references and dice rolls)
+    ///
+    /// Each modifier may take an argument after a colon, as in `|truncate:10`.
+    fn parse_modifiers(&mut self) -> ParseResult<Vec<Modifier>> {
         let mut modifiers = Vec::new();
 
         while self.check(&TokenType::Pipe) {
             self.advance(); // consume '|'
 
-            // Expect a modifier keyword or identifier
+            // Expect a modifier keyword or any other identifier, which may name a
+            // modifier registered on the collection
+            let lowercase = self.peek().lexeme.to_lowercase();
+            let miscased = MODIFIER_KEYWORDS.contains(&lowercase.as_str());
             match &self.advance().token_type {
-                TokenType::Modifier(modifier) => {
-                    modifiers.push(modifier.clone());
+                TokenType::Modifier(name) => {
+                    let name = name.clone();
+                    modifiers.push(self.modifier_argument(name)?);
+                }
+                TokenType::Identifier(name) if !miscased => {
+                    let name = name.clone();
+                    modifiers.push(self.modifier_argument(name)?);
                 }
                 _ => {
                     let token = self.previous();
-                    let diagnostic = self
-                        .diagnostic_collector
-                        .parse_error(
-                            token.span.start,
+
+                    // A known modifier in the wrong case, like `Capitalize`
+                    let (message, suggestion) = if miscased {
+                        (
+                            format!(
+                                "Unknown modifier '{}': modifiers are lowercase",
+                                token.lexeme
+                            ),
+                            format!("Did you mean '{}'?", lowercase),
+                        )
+                    } else {
+                        (
                             format!(
                                 "Expected modifier after '|', but found {}",
                                 token.token_type
                             ),
+                            format!("Built-in modifiers are: {}", MODIFIER_KEYWORDS.join(", ")),
                         )
-                        .with_suggestion("Valid modifiers are: indefinite, definite, capitalize, uppercase, lowercase".to_string());
+                    };
+
+                    let diagnostic = self
+                        .diagnostic_collector
+                        .parse_error_span(token.span.start, token.span.end, message)
+                        .with_suggestion(suggestion);
 
                     return Err(ParseError::UnexpectedToken {
                         expected: "modifier keyword".to_string(),
@@ -504,6 +1087,43 @@ impl Parser {
         Ok(modifiers)
     }
 
+    /// Parse the optional `:argument` after a modifier's name
+    fn modifier_argument(&mut self, name: String) -> ParseResult<Modifier> {
+        if !self.check(&TokenType::Colon) {
+            return Ok(Modifier::new(name));
+        }
+        self.advance(); // consume ':'
+
+        match &self.advance().token_type {
+            TokenType::Identifier(arg) | TokenType::Modifier(arg) => {
+                Ok(Modifier::with_arg(name, arg.clone()))
+            }
+            _ => {
+                let token = self.previous();
+                let diagnostic = self
+                    .diagnostic_collector
+                    .parse_error_span(
+                        token.span.start,
+                        token.span.end,
+                        format!(
+                            "Expected an argument after '{}:', but found {}",
+                            name, token.token_type
+                        ),
+                    )
+                    .with_suggestion(format!(
+                        "Write the argument right after the colon, like '{}:3'",
+                        name
+                    ));
+
+                Err(ParseError::UnexpectedToken {
+                    expected: "modifier argument".to_string(),
+                    found: format!("{}", token.token_type),
+                    diagnostic: Box::new(diagnostic),
+                })
+            }
+        }
+    }
+
     // Utility methods
     fn check(&self, token_type: &TokenType) -> bool {
         if self.is_at_end() {
@@ -553,7 +1173,7 @@ impl Parser {
 
             let diagnostic = self
                 .diagnostic_collector
-                .parse_error(token.span.start, message.to_string())
+                .parse_error_span(token.span.start, token.span.end, message.to_string())
                 .with_suggestion(suggestion.unwrap());
 
             Err(ParseError::UnexpectedToken {
@@ -1,5 +1,6 @@
 //! WebAssembly bindings for the TBL parser and collection generator
 
+use crate::lexer::{Lexer, TokenType};
 use crate::{Collection, parse};
 use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::*;
@@ -30,6 +31,35 @@ impl WasmParser {
         }
     }
 
+    /// Tokenize TBL source code and return a JSON array of tokens for syntax highlighting
+    ///
+    /// Each token is an object of the form `{ type, lexeme, start, end }`, where
+    /// `start`/`end` are character offsets into the source. Comments come
+    /// through as `line_comment` and `block_comment` tokens so they can be
+    /// highlighted too.
+    #[wasm_bindgen]
+    pub fn tokenize(source: &str) -> Result<String, String> {
+        set_panic_hook();
+
+        match Lexer::new(source).with_comments(true).tokenize() {
+            Ok(tokens) => {
+                let tokens: Vec<WasmToken> = tokens
+                    .into_iter()
+                    .map(|token| WasmToken {
+                        token_type: token_type_name(&token.token_type).to_string(),
+                        lexeme: token.lexeme,
+                        start: token.span.start as u32,
+                        end: token.span.end as u32,
+                    })
+                    .collect();
+
+                serde_json::to_string(&tokens)
+                    .map_err(|e| format!("JSON serialization error: {}", e))
+            }
+            Err(e) => Err(format!("Lex error: {}", e)),
+        }
+    }
+
     /// Validate TBL source code without returning the AST
     #[wasm_bindgen]
     pub fn validate(source: &str) -> bool {
@@ -73,25 +103,7 @@ impl WasmParser {
                 }
             }
             Err(parse_error) => {
-                // Convert parse error to diagnostic with proper position info
-                let diagnostic = match &parse_error {
-                    crate::errors::ParseError::UnexpectedToken { diagnostic, .. }
-                    | crate::errors::ParseError::UnexpectedEof { diagnostic, .. }
-                    | crate::errors::ParseError::InvalidCharacter { diagnostic, .. }
-                    | crate::errors::ParseError::InvalidNumber { diagnostic, .. } => {
-                        // Extract position information from the diagnostic
-                        let location = &diagnostic.location;
-                        WasmDiagnostic {
-                            message: parse_error.to_string(),
-                            severity: "error".to_string(),
-                            line: location.line as u32,
-                            column: location.column as u32,
-                            end_line: location.line as u32, // For now, same line (could extend to multi-line)
-                            end_column: location.end_column.unwrap_or(location.column + 1) as u32,
-                            source: diagnostic.source_line.clone(),
-                        }
-                    }
-                };
+                let diagnostic = WasmDiagnostic::from_parse_error(&parse_error);
 
                 WasmParseResult {
                     success: false,
@@ -115,25 +127,7 @@ impl WasmParser {
         let diagnostics = match parse(source) {
             Ok(_) => vec![], // No diagnostics for successful parse
             Err(parse_error) => {
-                // Convert parse error to diagnostic with proper position info
-                let diagnostic = match &parse_error {
-                    crate::errors::ParseError::UnexpectedToken { diagnostic, .. }
-                    | crate::errors::ParseError::UnexpectedEof { diagnostic, .. }
-                    | crate::errors::ParseError::InvalidCharacter { diagnostic, .. }
-                    | crate::errors::ParseError::InvalidNumber { diagnostic, .. } => {
-                        // Extract position information from the diagnostic
-                        let location = &diagnostic.location;
-                        WasmDiagnostic {
-                            message: parse_error.to_string(),
-                            severity: "error".to_string(),
-                            line: location.line as u32,
-                            column: location.column as u32,
-                            end_line: location.line as u32, // For now, same line (could extend to multi-line)
-                            end_column: location.end_column.unwrap_or(location.column + 1) as u32,
-                            source: diagnostic.source_line.clone(),
-                        }
-                    }
-                };
+                let diagnostic = WasmDiagnostic::from_parse_error(&parse_error);
                 vec![diagnostic]
             }
         };
@@ -248,6 +242,74 @@ pub struct WasmDiagnostic {
     pub source: String,
 }
 
+impl WasmDiagnostic {
+    /// Convert a parse error, covering the whole offending token where known
+    ///
+    /// Diagnostics built from a token's span carry its end column. Those that
+    /// only point at a position are widened to the single character there.
+    fn from_parse_error(parse_error: &crate::errors::ParseError) -> Self {
+        let diagnostic = parse_error.diagnostic();
+        let location = &diagnostic.location;
+        Self {
+            message: parse_error.to_string(),
+            severity: "error".to_string(),
+            line: location.line as u32,
+            column: location.column as u32,
+            // Spans are clamped to their first line, so they end on it too
+            end_line: location.line as u32,
+            end_column: location.end_column.unwrap_or(location.column + 1) as u32,
+            source: diagnostic.source_line.clone(),
+        }
+    }
+}
+
+/// A single lexer token for editor syntax highlighting
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WasmToken {
+    /// The token kind (e.g. "identifier", "number", "text_segment")
+    #[serde(rename = "type")]
+    pub token_type: String,
+    /// The source text of the token
+    pub lexeme: String,
+    /// Start character offset (inclusive)
+    pub start: u32,
+    /// End character offset (exclusive)
+    pub end: u32,
+}
+
+/// Stable, JS-friendly name for a token kind
+fn token_type_name(token_type: &TokenType) -> &'static str {
+    match token_type {
+        TokenType::Number(_) => "number",
+        TokenType::Colon => "colon",
+        TokenType::RuleText(_) => "rule_text",
+        TokenType::TextSegment(_) => "text_segment",
+        TokenType::Hash => "hash",
+        TokenType::Identifier(_) => "identifier",
+        TokenType::Modifier(_) => "modifier",
+        TokenType::DiceRoll { .. } => "dice_roll",
+        TokenType::LeftBracket => "left_bracket",
+        TokenType::RightBracket => "right_bracket",
+        TokenType::LeftBrace => "left_brace",
+        TokenType::RightBrace => "right_brace",
+        TokenType::Export => "export",
+        TokenType::Pipe => "pipe",
+        TokenType::At => "at",
+        TokenType::Slash => "slash",
+        TokenType::Dollar => "dollar",
+        TokenType::Greater => "greater",
+        TokenType::EqualEqual => "equal_equal",
+        TokenType::Question => "question",
+        TokenType::Equals => "equals",
+        TokenType::AttributeValue(_) => "attribute_value",
+        TokenType::MacroDefinition { .. } => "macro_definition",
+        TokenType::LineComment(_) => "line_comment",
+        TokenType::BlockComment(_) => "block_comment",
+        TokenType::Newline => "newline",
+        TokenType::Eof => "eof",
+    }
+}
+
 /// Parse result with diagnostics for language server
 #[derive(Debug, Serialize, Deserialize)]
 pub struct WasmParseResult {
@@ -279,6 +341,15 @@ mod wasm_tests {
         assert!(json.contains("hello world"));
     }
 
+    #[wasm_bindgen_test]
+    fn test_wasm_tokenize() {
+        let json = WasmParser::tokenize("// Colors\n#color\n1.0: {#shade} /* dark */").unwrap();
+        assert!(json.contains(r#""type":"identifier""#));
+        assert!(json.contains(r#""lexeme":"color""#));
+        assert!(json.contains(r#""type":"line_comment""#));
+        assert!(json.contains(r#""type":"block_comment""#));
+    }
+
     #[wasm_bindgen_test]
     fn test_wasm_collection() {
         let source = r#"#color
@@ -293,6 +364,15 @@ mod wasm_tests {
         assert!(generated == "red" || generated == "blue");
     }
 
+    #[wasm_bindgen_test]
+    fn test_wasm_diagnostic_covers_whole_token() {
+        let json = WasmParser::validate_with_diagnostics("#t[invalidflag]\n1.0: x");
+        let result: WasmParseResult = serde_json::from_str(&json).unwrap();
+        let diagnostic = &result.diagnostics[0];
+        assert_eq!((diagnostic.line, diagnostic.column), (1, 3));
+        assert_eq!((diagnostic.end_line, diagnostic.end_column), (1, 16));
+    }
+
     #[wasm_bindgen_test]
     fn test_wasm_utils() {
         let version = WasmUtils::version();